@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use eyre::{bail, Result, WrapErr};
+
+use crate::{
+    config::Config,
+    fetcher,
+    policy::{self, ImagePolicy},
+    sources::RatioRule,
+};
+
+/// How long each stage of the pipeline took over a whole directory of images
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub images: usize,
+    pub rejected: usize,
+    pub bytes: u64,
+    /// Decoding the image and checking it against the policy
+    pub evaluate: Duration,
+    pub resize: Duration,
+    pub hash: Duration,
+}
+
+impl Timings {
+    /// Run every image in `dir` through the pipeline the fetcher and picker use, as if for monitors of the given sizes.
+    pub fn measure(config: &Config, dir: &Path, monitors: Vec<(u32, u32)>) -> Result<Self> {
+        let policy = ImagePolicy::new(monitors).configure(config);
+        let hasher = image_hasher::HasherConfig::new().to_hasher();
+
+        let mut timings = Self::default();
+        for entry in dir.read_dir().wrap_err("Could not read images directory")? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let body = std::fs::read(&path)?;
+            timings.images += 1;
+            timings.bytes += body.len() as u64;
+
+            let start = Instant::now();
+            let evaluated = fetcher::evaluate(config, &policy, RatioRule::default(), &body);
+            timings.evaluate += start.elapsed();
+            let Ok(evaluated) = evaluated else {
+                timings.rejected += 1;
+                continue;
+            };
+
+            let start = Instant::now();
+            let image = evaluated.scaled();
+            timings.resize += start.elapsed();
+
+            let start = Instant::now();
+            hasher.hash_image(&image);
+            timings.hash += start.elapsed();
+        }
+        Ok(timings)
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Rejected images never make it past the first stage
+        let accepted = self.images - self.rejected;
+        writeln!(f, "{:<18} {:>7} {:>12} {:>12}", "stage", "images", "total", "per image")?;
+        for (stage, count, elapsed) in [
+            ("decode + policy", self.images, self.evaluate),
+            ("resize", accepted, self.resize),
+            ("hash", accepted, self.hash),
+        ] {
+            let per_image = elapsed.checked_div(count as u32).unwrap_or_default();
+            writeln!(f, "{stage:<18} {count:>7} {elapsed:>12.2?} {per_image:>12.2?}")?;
+        }
+
+        let total = self.evaluate + self.resize + self.hash;
+        let secs = total.as_secs_f64();
+        if secs > 0.0 {
+            writeln!(
+                f,
+                "{} images ({} rejected) in {total:.2?}: {:.2} images/s, {:.2} MB/s",
+                self.images,
+                self.rejected,
+                self.images as f64 / secs,
+                self.bytes as f64 / 1_000_000.0 / secs,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a screen size given as `WIDTHxHEIGHT`.
+fn parse_screen(size: &str) -> Result<(u32, u32)> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| eyre::eyre!("Expected WIDTHxHEIGHT"))?;
+    Ok((width.trim().parse()?, height.trim().parse()?))
+}
+
+/// Run `redditbg bench --images <dir> [--screen WIDTHxHEIGHT]... [--output <file>]`, printing how long each stage
+/// took.
+///
+/// Every `--screen` given stands for a monitor, primary first, and the attached ones are used if there are none. As we
+/// may not have a console to print to, the table can also be written to a file with `--output`.
+pub fn run(config: &Config, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let (mut dir, mut screens, mut output) = (None, Vec::new(), None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--images" => dir = args.next(),
+            "--output" => output = args.next(),
+            "--screen" => {
+                let size = args.next().unwrap_or_default();
+                screens.push(parse_screen(&size).wrap_err("Invalid screen size")?);
+            }
+            arg => bail!("unknown argument {arg:?}"),
+        }
+    }
+    let Some(dir) = dir else {
+        bail!("usage: redditbg bench --images <dir> [--screen WIDTHxHEIGHT]... [--output <file>]")
+    };
+    if screens.is_empty() {
+        screens = policy::monitors()?;
+    }
+
+    let table = Timings::measure(config, Path::new(&dir), screens)?.to_string();
+    print!("{table}");
+    if let Some(output) = output {
+        std::fs::write(&output, table).wrap_err_with(|| format!("Could not write results to {output:?}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a tiny set of images, one of which fits a 16:9 monitor, along with a file that isn't an image at all.
+    fn fixtures(dir: &Path) {
+        image::RgbImage::from_fn(1920, 1080, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 128]))
+            .save(dir.join("landscape.png"))
+            .unwrap();
+        image::RgbImage::from_fn(800, 800, |x, y| image::Rgb([(y % 256) as u8, (x % 256) as u8, 64]))
+            .save(dir.join("square.png"))
+            .unwrap();
+        std::fs::write(dir.join("notes.txt"), "not an image").unwrap();
+        std::fs::create_dir(dir.join("nested")).unwrap();
+    }
+
+    #[test]
+    fn the_pipeline_runs_over_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fixtures(dir.path());
+
+        let timings = Timings::measure(&Config::default(), dir.path(), vec![(1920, 1080)]).unwrap();
+        assert_eq!(timings.images, 3);
+        assert_eq!(timings.rejected, 2);
+        assert!(timings.bytes > 0);
+
+        let table = timings.to_string();
+        for stage in ["decode + policy", "resize", "hash"] {
+            assert!(table.contains(stage), "{}", table);
+        }
+        assert!(table.contains("3 images (2 rejected)"), "{}", table);
+    }
+
+    #[test]
+    fn results_can_be_written_to_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let images = dir.path().join("images");
+        std::fs::create_dir(&images).unwrap();
+        fixtures(&images);
+        let output = dir.path().join("bench.txt");
+
+        let args = [
+            "--images",
+            images.to_str().unwrap(),
+            "--screen",
+            "1920x1080",
+            "--output",
+            output.to_str().unwrap(),
+        ];
+        run(&Config::default(), args.iter().map(|&arg| arg.to_owned())).unwrap();
+        assert!(std::fs::read_to_string(&output)
+            .unwrap()
+            .contains("3 images (2 rejected)"));
+    }
+
+    #[test]
+    fn screens_are_width_by_height() {
+        assert_eq!(parse_screen("2560x1440").unwrap(), (2560, 1440));
+        assert_eq!(parse_screen(" 1080 x 1920 ").unwrap(), (1080, 1920));
+        assert!(parse_screen("2560").is_err());
+        assert!(parse_screen("widexhigh").is_err());
+    }
+}
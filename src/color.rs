@@ -0,0 +1,122 @@
+use std::io::Cursor;
+
+use eyre::{eyre, Result};
+use image::{
+    codecs::{jpeg::JpegDecoder, png::PngDecoder},
+    DynamicImage, ImageDecoder, ImageFormat,
+};
+use lcms2::{Intent, PixelFormat, Profile, Transform};
+
+/// Get the ICC profile embedded in an encoded image, for the formats whose decoders expose it.
+pub fn icc_profile(body: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => JpegDecoder::new(Cursor::new(body)).ok()?.icc_profile(),
+        ImageFormat::Png => PngDecoder::new(Cursor::new(body)).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+/// Convert an image's pixels from the color space described by `profile` to sRGB.
+///
+/// Windows assumes backgrounds are sRGB, and we store images without a profile, so wide-gamut images would look
+/// washed out if we didn't do this.
+pub fn to_srgb(img: DynamicImage, profile: &[u8]) -> Result<DynamicImage> {
+    let source = Profile::new_icc(profile).map_err(|error| eyre!("Invalid ICC profile: {error}"))?;
+    let transform = Transform::new(
+        &source,
+        PixelFormat::RGBA_8,
+        &Profile::new_srgb(),
+        PixelFormat::RGBA_8,
+        Intent::Perceptual,
+    )
+    .map_err(|error| eyre!("Could not convert ICC profile to sRGB: {error}"))?;
+
+    let mut pixels = img.into_rgba8();
+    transform.transform_in_place(&mut pixels);
+    Ok(DynamicImage::ImageRgba8(pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lcms2::{CIExyY, CIExyYTRIPLE, ToneCurve};
+    use std::convert::TryFrom;
+
+    /// Display P3, as embedded by phones and Macs: the P3 primaries with sRGB's white point and transfer function.
+    fn display_p3() -> Vec<u8> {
+        let xy = |x, y| CIExyY { x, y, Y: 1.0 };
+        let curve = ToneCurve::new_parametric(4, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045]).unwrap();
+        let primaries = CIExyYTRIPLE {
+            Red: xy(0.680, 0.320),
+            Green: xy(0.265, 0.690),
+            Blue: xy(0.150, 0.060),
+        };
+        Profile::new_rgb(&xy(0.3127, 0.3290), &primaries, &[&curve, &curve, &curve])
+            .unwrap()
+            .icc()
+            .unwrap()
+    }
+
+    /// A JPEG of a single color.
+    fn jpeg(color: [u8; 3]) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(16, 16, image::Rgb(color)));
+        let mut jpeg = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(100))
+            .unwrap();
+        jpeg
+    }
+
+    /// A JPEG of a single color, with `profile` embedded in an APP2 segment the way cameras do it.
+    fn jpeg_with_profile(color: [u8; 3], profile: &[u8]) -> Vec<u8> {
+        let mut jpeg = jpeg(color);
+        let mut segment = vec![0xFF, 0xE2];
+        segment.extend(u16::try_from(2 + 14 + profile.len()).unwrap().to_be_bytes());
+        segment.extend(b"ICC_PROFILE\0");
+        segment.extend([1, 1]);
+        segment.extend(profile);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    fn center(image: &DynamicImage) -> [u8; 4] {
+        image.to_rgba8().get_pixel(8, 8).0
+    }
+
+    fn assert_close(actual: [u8; 4], expected: [u8; 4]) {
+        assert!(
+            actual.iter().zip(expected).all(|(&a, e)| a.abs_diff(e) <= 3),
+            "{:?} instead of {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn display_p3_images_are_converted_to_srgb() {
+        let profile = display_p3();
+        let body = jpeg_with_profile([200, 100, 100], &profile);
+        assert_eq!(icc_profile(&body, ImageFormat::Jpeg), Some(profile.clone()));
+
+        // P3's red is redder than sRGB's, so the same numbers need more red and less of the others to look the same
+        let image = image::load_from_memory(&body).unwrap();
+        assert_close(center(&image), [200, 100, 100, 255]);
+        assert_close(center(&to_srgb(image, &profile).unwrap()), [215, 92, 96, 255]);
+    }
+
+    #[test]
+    fn srgb_images_are_left_as_they_are() {
+        let profile = Profile::new_srgb().icc().unwrap();
+        let image = image::load_from_memory(&jpeg_with_profile([200, 100, 100], &profile)).unwrap();
+        assert_close(center(&to_srgb(image, &profile).unwrap()), [200, 100, 100, 255]);
+    }
+
+    #[test]
+    fn images_without_a_profile_or_with_a_broken_one_are_told_apart() {
+        let plain = jpeg([200, 100, 100]);
+        assert_eq!(icc_profile(&plain, ImageFormat::Jpeg), None);
+        assert_eq!(icc_profile(&plain, ImageFormat::Gif), None);
+        let image = image::load_from_memory(&plain).unwrap();
+        assert!(to_srgb(image, b"not a profile").is_err());
+    }
+}
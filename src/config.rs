@@ -0,0 +1,595 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use eyre::{bail, Result, WrapErr};
+use serde::Deserialize;
+use tracing::warn;
+
+pub use crate::{
+    export::ExportConfig, platform::WallpaperStyle, schedule::FetchSchedule, watch::ForeignWallpaperPolicy,
+};
+use crate::{
+    processing::Filter,
+    sources::{self, SourceSpec},
+    DIRS,
+};
+
+/// The profile that exists even without any configuration, fed by `subreddits` or `subreddits.txt`
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// What we write to `config.toml` when there's no configuration at all, so that there's something to start from
+const DEFAULT_CONFIG: &str = r#"# The subreddits to take backgrounds from
+subreddits = ["EarthPorn", "SkyPorn"]
+
+# How many minutes to keep each background up for
+# change_interval_minutes = 60
+
+# How far an image's aspect ratio may be from the screen's
+# aspect_ratio_epsilon = 0.01
+
+# How far an image's aspect ratio may be from the screen's for it to be cropped to fit
+# aspect_ratio_crop_epsilon = 0.25
+
+# How many images to keep downloaded for each orientation
+# max_cached = 25
+
+# Every other setting is listed in the readme
+"#;
+
+/// How to let the user know the background changed on its own
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeCue {
+    #[default]
+    None,
+    Sound,
+    Toast,
+    Both,
+}
+
+impl ChangeCue {
+    pub fn sound(self) -> bool {
+        matches!(self, Self::Sound | Self::Both)
+    }
+
+    pub fn toast(self) -> bool {
+        matches!(self, Self::Toast | Self::Both)
+    }
+}
+
+/// What to do the first time we're launched, before we've ever changed the background
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FirstRun {
+    /// Fill the cache, then wait for the user to click "Change now"
+    #[default]
+    Ask,
+    /// Change the background right away, like on every other launch
+    Apply,
+}
+
+/// What happens to backgrounds once they've been applied
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// They're deleted, and we keep fetching new ones
+    #[default]
+    Consume,
+    /// They're kept, and we rotate through them once the new ones run out
+    Archive,
+}
+
+/// A named group of subreddits with its own slice of the image cache
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub subreddits: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The default profile's subreddits, replacing `subreddits.txt`.
+    pub subreddits: Option<Vec<String>>,
+
+    /// How many minutes to keep each background up for.
+    pub change_interval_minutes: u64,
+
+    /// How far an image's aspect ratio may be from its monitor's.
+    pub aspect_ratio_epsilon: f64,
+
+    /// How far past that an image's aspect ratio may be from its monitor's for it to be cropped to fit instead of
+    /// turned down.
+    pub aspect_ratio_crop_epsilon: f64,
+
+    /// How many images to keep downloaded for each orientation.
+    pub max_cached: usize,
+
+    /// Where to keep downloaded images, the archive and logs instead of the local data directory.
+    pub cache_root: Option<PathBuf>,
+
+    /// Whether to show a notification summarizing our state when we start up.
+    pub notify_on_start: bool,
+
+    /// Whether to add a Start Menu shortcut so that notifications show up under our own name rather than PowerShell's.
+    pub register_app_id: bool,
+
+    /// Whether to leave the user's background alone on first launch until they ask for a new one.
+    pub first_run: FirstRun,
+
+    /// How long to wait after starting before the first change; a minute when started with `--autostart`, and no
+    /// time at all otherwise, if unset.
+    pub startup_delay_seconds: Option<u64>,
+
+    /// The smallest fraction of the screen's size an image may be scaled up from; smaller ones are turned down, and
+    /// any size is accepted if unset.
+    pub allow_upscale_below: Option<f64>,
+
+    /// The smallest width and height an image may have in either orientation, whatever our monitors' sizes.
+    pub min_resolution: Option<(u32, u32)>,
+
+    /// The most megapixels an image may have, as huge ones take long to decode and scale for little gain.
+    pub max_megapixels: Option<f64>,
+
+    /// The range an image's average brightness has to be in, from 0 for black to 1 for white.
+    pub brightness_range: Option<(f64, f64)>,
+
+    /// Whether to store every image losslessly as a PNG instead of keeping JPEGs as they are.
+    pub force_png: bool,
+
+    /// Whether to give every monitor a background of its own rather than the same one on all of them.
+    pub per_monitor_backgrounds: bool,
+
+    /// How Windows places backgrounds that don't exactly match the screen.
+    pub wallpaper_style: WallpaperStyle,
+
+    /// Whether to crop images to exactly the screen's aspect ratio around their most interesting part.
+    pub smart_crop: bool,
+
+    /// Whether to put portrait images that don't fit any monitor on a landscape one, with a blurred copy of themselves
+    /// filling the bars on either side.
+    pub blur_fill: bool,
+
+    /// Whether to show a summary of the week's backgrounds on Sunday evenings.
+    pub weekly_digest: bool,
+
+    /// Appended to our user agent, e.g. to include contact info as Reddit's API guidelines ask.
+    pub user_agent_suffix: Option<String>,
+
+    /// Replaces our user agent entirely.
+    pub user_agent: Option<String>,
+
+    /// A command to run after every background change, with details about the new background in its environment.
+    pub on_change_command: Option<String>,
+
+    /// Whether to also run `on_change_command` when we put the current background back up instead of a new one.
+    pub on_change_command_on_reapply: bool,
+
+    /// How to let the user know when the background changes on its own.
+    pub on_change_cue: ChangeCue,
+
+    /// What to do when another program changes the background.
+    pub foreign_wallpaper: ForeignWallpaperPolicy,
+
+    /// How many of the last applied backgrounds' subreddits to avoid repeating, if anything else is cached.
+    pub variety: usize,
+
+    /// Whether to delete backgrounds once they've been applied or keep them around to rotate through.
+    pub mode: Mode,
+
+    /// How many new images to download per day in archive mode.
+    pub archive_daily_images: usize,
+
+    /// How many days an archived background has to go unshown before it's shown again, unless nothing else is left.
+    pub archive_repeat_days: u32,
+
+    /// Color filters to run the background through before setting it, in order.
+    pub filters: Vec<Filter>,
+
+    /// The lowest score a post may have for us to look at it, unless its subreddit sets its own.
+    pub min_score: i64,
+
+    /// How many sources to fetch from per cycle, taking turns, instead of all of them at once.
+    pub sources_per_cycle: Option<usize>,
+
+    /// Whether to include posts marked NSFW.
+    pub include_nsfw: bool,
+
+    /// Whether to include posts pinned by the moderators, which are hardly ever images.
+    pub include_stickied: bool,
+
+    /// Whether to include posts marked as spoilers.
+    pub include_spoilers: bool,
+
+    /// Whether to opt in to seeing quarantined subreddits, which Reddit otherwise refuses to list.
+    pub allow_quarantined: bool,
+
+    /// Whether to stop Windows from re-encoding backgrounds as lower quality JPEGs while we're running.
+    pub max_jpeg_quality: bool,
+
+    /// Whether to check GitHub for new releases once a day.
+    pub check_for_updates: bool,
+
+    /// How many megabytes we may download per day, after which we stop fetching until midnight.
+    pub daily_budget_mb: Option<u64>,
+
+    /// Whether to only connect over IPv4, for networks whose IPv6 is broken.
+    pub prefer_ipv4: bool,
+
+    /// How many megabytes must stay free on the disk holding the cache, below which we stop fetching.
+    pub min_free_disk_mb: u64,
+
+    /// When to fetch new images; if unset, we fetch right after every background change.
+    pub fetch_schedule: Option<FetchSchedule>,
+
+    /// Where to export the originals of the backgrounds we apply, if anywhere.
+    pub export: Option<ExportConfig>,
+
+    /// The most detailed level of our own events that gets logged, from `error` to `trace`.
+    pub log_level: Option<String>,
+
+    /// How many days to keep old log files around for.
+    pub log_retention_days: Option<u64>,
+
+    /// How many megabytes the log files may take up, after which the oldest ones are deleted.
+    pub log_max_mb: Option<u64>,
+
+    /// Named groups of subreddits that can be switched between from the tray.
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            subreddits: None,
+            change_interval_minutes: 60,
+            aspect_ratio_epsilon: 0.01,
+            aspect_ratio_crop_epsilon: 0.25,
+            // This value is kinda arbitrary but there are 25 potential images in one reddit page
+            max_cached: 25,
+            cache_root: None,
+            notify_on_start: false,
+            register_app_id: false,
+            first_run: FirstRun::default(),
+            startup_delay_seconds: None,
+            allow_upscale_below: None,
+            min_resolution: None,
+            max_megapixels: None,
+            brightness_range: None,
+            force_png: false,
+            per_monitor_backgrounds: false,
+            wallpaper_style: WallpaperStyle::default(),
+            smart_crop: false,
+            blur_fill: false,
+            weekly_digest: false,
+            on_change_command: None,
+            on_change_command_on_reapply: false,
+            on_change_cue: ChangeCue::default(),
+            foreign_wallpaper: ForeignWallpaperPolicy::default(),
+            variety: 2,
+            mode: Mode::default(),
+            archive_daily_images: 5,
+            archive_repeat_days: 7,
+            min_score: 0,
+            sources_per_cycle: None,
+            include_nsfw: false,
+            include_stickied: false,
+            include_spoilers: false,
+            allow_quarantined: false,
+            filters: Vec::new(),
+            max_jpeg_quality: true,
+            check_for_updates: false,
+            daily_budget_mb: None,
+            min_free_disk_mb: 2000,
+            prefer_ipv4: false,
+            user_agent_suffix: None,
+            user_agent: None,
+            fetch_schedule: None,
+            export: None,
+            log_level: None,
+            log_retention_days: None,
+            log_max_mb: None,
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from `config.toml`, using the defaults if it doesn't exist.
+    ///
+    /// If there's no `subreddits.txt` either, this is our first run, so we write a default `config.toml` to edit. A
+    /// `config.toml` that can't be parsed is moved to `config.toml.bak` and replaced the same way, as failing here
+    /// would leave every cycle failing until the user noticed.
+    pub fn load() -> Result<Self> {
+        let (config, reset) = Self::load_quietly()?;
+        if let Some(reset) = reset {
+            reset.notify();
+        }
+        Ok(config)
+    }
+
+    /// Like [`Config::load`], but leave telling the user their `config.toml` was replaced to the caller, for when
+    /// logging isn't up yet.
+    pub fn load_quietly() -> Result<(Self, Option<Reset>)> {
+        Self::load_from(DIRS.config_dir())
+    }
+
+    fn load_from(dir: &Path) -> Result<(Self, Option<Reset>)> {
+        let path = dir.join("config.toml");
+        match fs::read_to_string(&path) {
+            Ok(contents) => match toml::from_str(&contents) {
+                Ok(config) => Ok((config, None)),
+                Err(error) => {
+                    let backup = path.with_extension("toml.bak");
+                    fs::rename(&path, &backup).wrap_err("Could not back up config.toml")?;
+                    Ok((Self::create(dir)?, Some(Reset { backup, error })))
+                }
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok((Self::create(dir)?, None)),
+            Err(error) => Err(error).wrap_err("Could not read config.toml"),
+        }
+    }
+
+    /// Write a default `config.toml` to `dir`, unless `subreddits.txt` is there to configure us instead.
+    fn create(dir: &Path) -> Result<Self> {
+        if dir.join("subreddits.txt").exists() {
+            return Ok(Self::default());
+        }
+        fs::write(dir.join("config.toml"), DEFAULT_CONFIG).wrap_err("Could not write default config.toml")?;
+        toml::from_str(DEFAULT_CONFIG).wrap_err("Could not parse default config.toml")
+    }
+
+    /// Warn about settings that are still honored but on their way out.
+    pub fn warn_deprecated(&self) {
+        if self.subreddits.is_some() && DIRS.config_dir().join("subreddits.txt").exists() {
+            warn!(
+                target: "notification",
+                "subreddits.txt is deprecated and ignored as config.toml lists subreddits, so it can be deleted"
+            );
+        }
+    }
+
+    /// How long to keep each background up for.
+    pub fn change_interval(&self) -> Duration {
+        Duration::from_secs(self.change_interval_minutes.max(1) * 60)
+    }
+
+    /// List every profile the user can switch to, starting with the default one.
+    pub fn profile_names(&self) -> Vec<String> {
+        std::iter::once(DEFAULT_PROFILE.to_owned())
+            .chain(self.profiles.keys().filter(|name| *name != DEFAULT_PROFILE).cloned())
+            .collect()
+    }
+
+    /// Get the sources feeding the given profile.
+    ///
+    /// The default profile reads them from `subreddits`, or from `subreddits.txt` if that's unset, unless a profile
+    /// of the same name overrides it.
+    pub fn sources(&self, profile: &str) -> Result<Vec<SourceSpec>> {
+        self.sources_in(DIRS.config_dir(), profile)
+    }
+
+    fn sources_in(&self, dir: &Path, profile: &str) -> Result<Vec<SourceSpec>> {
+        if let Some(profile) = self.profiles.get(profile) {
+            return Ok(sources::from_names(&profile.subreddits));
+        }
+
+        if profile != DEFAULT_PROFILE {
+            bail!("Unknown profile {profile:?}");
+        }
+
+        if let Some(ref subreddits) = self.subreddits {
+            return Ok(sources::from_names(subreddits));
+        }
+
+        let subreddits_txt =
+            fs::read_to_string(dir.join("subreddits.txt")).wrap_err("Could not read subreddits.txt")?;
+        sources::parse(&subreddits_txt).wrap_err("Could not parse subreddits.txt")
+    }
+}
+
+/// A `config.toml` we couldn't parse, and so moved out of the way
+#[derive(Debug)]
+pub struct Reset {
+    pub backup: PathBuf,
+    pub error: toml::de::Error,
+}
+
+impl Reset {
+    /// Tell the user where their old configuration went.
+    pub fn notify(&self) {
+        warn!(
+            target: "notification",
+            error = %self.error,
+            "config.toml could not be parsed, so it was moved to {} and replaced with the defaults",
+            self.backup.display()
+        );
+    }
+}
+
+/// Change a single setting in `config.toml`, leaving the rest of the file, comments included, as it is.
+///
+/// The file is replaced atomically, so that it's never left half-written. If it can't be parsed, it's backed up to
+/// `config.toml.bak` and we start over from an empty file, as failing here would only leave the setting unchangeable.
+pub fn set(key: &str, value: impl Into<toml_edit::Value>) -> Result<()> {
+    set_in(DIRS.config_dir(), key, value)
+}
+
+fn set_in(dir: &Path, key: &str, value: impl Into<toml_edit::Value>) -> Result<()> {
+    let path = dir.join("config.toml");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(error).wrap_err("Could not read config.toml"),
+    };
+
+    let mut document = match contents.parse::<toml_edit::Document>() {
+        Ok(document) => document,
+        Err(error) => {
+            let backup = path.with_extension("toml.bak");
+            fs::copy(&path, &backup).wrap_err("Could not back up config.toml")?;
+            warn!(
+                target: "notification",
+                ?error,
+                "config.toml could not be parsed, so it was moved to {} and replaced",
+                backup.display()
+            );
+            toml_edit::Document::new()
+        }
+    };
+    document[key] = toml_edit::value(value);
+
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(document.to_string().as_bytes())?;
+    file.persist(path).wrap_err("Could not write config.toml")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_missing_config_is_written_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, reset) = Config::load_from(dir.path()).unwrap();
+        assert!(reset.is_none());
+        assert_eq!(config.subreddits.as_deref().map(<[_]>::len), Some(2));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config.toml")).unwrap(),
+            DEFAULT_CONFIG
+        );
+    }
+
+    #[test]
+    fn a_malformed_config_is_backed_up_and_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let malformed = "subreddits = [\"EarthPorn\"\nchange_interval_minutes = 5\n";
+        fs::write(dir.path().join("config.toml"), malformed).unwrap();
+
+        let (config, reset) = Config::load_from(dir.path()).unwrap();
+        let reset = reset.unwrap();
+        assert_eq!(reset.backup, dir.path().join("config.toml.bak"));
+        assert_eq!(fs::read_to_string(&reset.backup).unwrap(), malformed);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config.toml")).unwrap(),
+            DEFAULT_CONFIG
+        );
+        assert_eq!(
+            config.change_interval_minutes,
+            Config::default().change_interval_minutes
+        );
+
+        // Having been replaced, it loads cleanly from then on
+        assert!(Config::load_from(dir.path()).unwrap().1.is_none());
+    }
+
+    #[test]
+    fn setting_a_value_back_leaves_the_file_as_it_was() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = format!("{DEFAULT_CONFIG}include_nsfw = false\n");
+        fs::write(&path, &contents).unwrap();
+
+        set_in(dir.path(), "include_nsfw", true).unwrap();
+        assert_ne!(fs::read_to_string(&path).unwrap(), contents);
+        set_in(dir.path(), "include_nsfw", false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn setting_a_value_leaves_comments_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, DEFAULT_CONFIG).unwrap();
+
+        set_in(dir.path(), "wallpaper_style", "fill").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        for line in DEFAULT_CONFIG.lines() {
+            assert!(contents.lines().any(|other| other == line), "lost {:?}", line);
+        }
+        let config = toml::from_str::<Config>(&contents).unwrap();
+        assert_eq!(config.subreddits.as_deref().map(<[_]>::len), Some(2));
+    }
+
+    #[test]
+    fn subreddits_txt_keeps_configuring_us_until_the_config_lists_subreddits() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("subreddits.txt"), "wallpapers\nEarthPorn\n").unwrap();
+
+        // Nothing's written over the configuration we already have
+        let (config, _) = Config::load_from(dir.path()).unwrap();
+        assert!(!dir.path().join("config.toml").exists());
+        let names = |config: &Config, profile| {
+            config
+                .sources_in(dir.path(), profile)
+                .unwrap()
+                .into_iter()
+                .map(|source| source.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&config, DEFAULT_PROFILE), ["wallpapers", "earthporn"]);
+
+        let config = Config {
+            subreddits: Some(vec!["SkyPorn".to_owned()]),
+            ..Config::default()
+        };
+        assert_eq!(names(&config, DEFAULT_PROFILE), ["skyporn"]);
+
+        // A profile of the same name wins over both
+        let config = Config {
+            subreddits: Some(vec!["SkyPorn".to_owned()]),
+            profiles: BTreeMap::from([(
+                DEFAULT_PROFILE.to_owned(),
+                Profile {
+                    subreddits: vec!["CityPorn".to_owned()],
+                },
+            )]),
+            ..Config::default()
+        };
+        assert_eq!(names(&config, DEFAULT_PROFILE), ["cityporn"]);
+        assert!(config.sources_in(dir.path(), "work").is_err());
+    }
+
+    #[test]
+    fn the_default_config_spells_out_the_defaults() {
+        let uncommented = DEFAULT_CONFIG
+            .lines()
+            .map(|line| {
+                line.strip_prefix("# ")
+                    .filter(|line| line.contains(" = "))
+                    .unwrap_or(line)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config = toml::from_str::<Config>(&uncommented).unwrap();
+        let default = Config::default();
+        assert_eq!(config.change_interval(), default.change_interval());
+        assert_eq!(config.aspect_ratio_epsilon, default.aspect_ratio_epsilon);
+        assert_eq!(config.max_cached, default.max_cached);
+    }
+
+    #[test]
+    fn stickied_and_spoiler_posts_are_skipped_unless_asked_for() {
+        let config = toml::from_str::<Config>("").unwrap();
+        assert!(!config.include_stickied && !config.include_spoilers);
+
+        let config = toml::from_str::<Config>("include_stickied = true\ninclude_spoilers = true").unwrap();
+        assert!(config.include_stickied && config.include_spoilers);
+    }
+
+    #[test]
+    fn the_change_interval_is_at_least_a_minute() {
+        let config = Config {
+            change_interval_minutes: 0,
+            ..Config::default()
+        };
+        assert_eq!(config.change_interval(), Duration::from_secs(60));
+        assert_eq!(Config::default().change_interval(), Duration::from_secs(60 * 60));
+    }
+}
@@ -0,0 +1,47 @@
+-- The schema as it was before we started versioning it, which is why every table may already exist
+CREATE TABLE IF NOT EXISTS PersistentSets (
+    name TEXT NOT NULL,
+    timestamp TEXT DEFAULT CURRENT_TIMESTAMP,
+    url TEXT NOT NULL,
+
+    PRIMARY KEY (name, url)
+);
+CREATE TABLE IF NOT EXISTS ImageMetadata (
+    url TEXT NOT NULL PRIMARY KEY,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS ImageSources (
+    url TEXT NOT NULL PRIMARY KEY,
+    subreddit TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS ImageTitles (
+    url TEXT NOT NULL PRIMARY KEY,
+    title TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS ProbedImages (
+    body_hash BLOB NOT NULL PRIMARY KEY,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS AppliedImages (
+    image_hash BLOB NOT NULL PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS AppliedHistory (
+    timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    image_hash BLOB NOT NULL,
+    url TEXT,
+    subreddit TEXT
+);
+CREATE TABLE IF NOT EXISTS DailyBandwidth (
+    day TEXT NOT NULL PRIMARY KEY,
+    bytes INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS Exports (
+    path TEXT NOT NULL PRIMARY KEY,
+    bytes INTEGER NOT NULL,
+    timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE IF NOT EXISTS UpdateChecks (
+    timestamp INTEGER NOT NULL
+);
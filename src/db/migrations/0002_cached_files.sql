@@ -0,0 +1,5 @@
+-- Lets us name cached images after a hash of their url when encoding it would make the filename too long
+CREATE TABLE CachedFiles (
+    filename TEXT NOT NULL PRIMARY KEY,
+    url TEXT NOT NULL
+);
@@ -0,0 +1,5 @@
+-- How often applying each image has failed, so that we can quarantine the ones that keep failing
+CREATE TABLE ApplyFailures (
+    image_hash BLOB NOT NULL PRIMARY KEY,
+    failures INTEGER NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Which way round each image is, so that we can keep both landscape and portrait monitors stocked
+ALTER TABLE ImageMetadata ADD COLUMN orientation TEXT;
+
+UPDATE ImageMetadata SET orientation = CASE
+    WHEN width > height THEN 'landscape'
+    WHEN width < height THEN 'portrait'
+    ELSE 'square'
+END;
@@ -0,0 +1,5 @@
+-- What each cached file holds, so that we needn't open it to find out
+ALTER TABLE CachedFiles ADD COLUMN format TEXT;
+ALTER TABLE CachedFiles ADD COLUMN width INTEGER;
+ALTER TABLE CachedFiles ADD COLUMN height INTEGER;
+ALTER TABLE CachedFiles ADD COLUMN bytes INTEGER;
@@ -0,0 +1,5 @@
+-- Single values we keep across restarts, such as until when the background is snoozed
+CREATE TABLE AppState (
+    key TEXT NOT NULL PRIMARY KEY,
+    value TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Whether a cached file had to be scaled up to fit its monitor, making it blurrier than the rest
+ALTER TABLE CachedFiles ADD COLUMN upscaled INTEGER NOT NULL DEFAULT 0;
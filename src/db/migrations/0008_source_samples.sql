@@ -0,0 +1,5 @@
+-- When each source was last among those we fetched from, for when we only fetch from some of them per cycle
+CREATE TABLE SourceSamples (
+    name TEXT NOT NULL PRIMARY KEY,
+    last_sampled INTEGER NOT NULL
+);
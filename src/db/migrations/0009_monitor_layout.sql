@@ -0,0 +1,2 @@
+-- The monitor arrangement each background was applied under, so that history from another one doesn't count
+ALTER TABLE AppliedHistory ADD COLUMN layout INTEGER;
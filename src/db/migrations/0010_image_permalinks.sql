@@ -0,0 +1,5 @@
+-- Where to find the post each image came from, for opening it from the tray
+CREATE TABLE ImagePermalinks (
+    url TEXT NOT NULL PRIMARY KEY,
+    permalink TEXT NOT NULL
+);
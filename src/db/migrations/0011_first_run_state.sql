@@ -0,0 +1,8 @@
+-- The first run flag moves to AppState, and anyone we've already given a background to is past their first run
+INSERT OR IGNORE INTO AppState(key, value)
+SELECT 'first_run_complete', '1'
+WHERE EXISTS (SELECT 1 FROM PersistentSets WHERE name = 'flags' AND url = 'first_run_complete')
+    OR EXISTS (SELECT 1 FROM AppliedImages)
+    OR EXISTS (SELECT 1 FROM AppliedHistory);
+
+DELETE FROM PersistentSets WHERE name = 'flags';
@@ -0,0 +1,5 @@
+-- The days we told the user about their download budget become the last such day, in AppState
+INSERT OR IGNORE INTO AppState(key, value)
+SELECT 'budget_notice', MAX(url) FROM PersistentSets WHERE name = 'budget_notices' HAVING COUNT(*) > 0;
+
+DELETE FROM PersistentSets WHERE name = 'budget_notices';
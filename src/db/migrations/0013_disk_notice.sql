@@ -0,0 +1,5 @@
+-- The days we told the user the disk was nearly full become the last such day, in AppState
+INSERT OR IGNORE INTO AppState(key, value)
+SELECT 'disk_notice', MAX(url) FROM PersistentSets WHERE name = 'disk_notices' HAVING COUNT(*) > 0;
+
+DELETE FROM PersistentSets WHERE name = 'disk_notices';
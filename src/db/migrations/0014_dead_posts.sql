@@ -0,0 +1,10 @@
+-- Deleted posts get a table of their own, keyed by post id rather than posing as urls
+CREATE TABLE DeadPosts (
+    id TEXT NOT NULL PRIMARY KEY,
+    timestamp TEXT DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT OR IGNORE INTO DeadPosts(id, timestamp)
+SELECT url, timestamp FROM PersistentSets WHERE name = 'dead_posts';
+
+DELETE FROM PersistentSets WHERE name = 'dead_posts';
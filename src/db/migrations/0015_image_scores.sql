@@ -0,0 +1,5 @@
+-- How the post each image came from was received, for showing alongside it
+CREATE TABLE ImageScores (
+    url TEXT NOT NULL PRIMARY KEY,
+    score INTEGER NOT NULL
+);
@@ -0,0 +1,782 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use image::ImageFormat;
+
+use eyre::{Result, WrapErr};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior};
+use tracing::{debug, warn};
+
+use crate::{processing::Orientation, DIRS};
+
+// Every change to the schema, in order; a database at version N has had the first N applied
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/0001_initial.sql"),
+    include_str!("migrations/0002_cached_files.sql"),
+    include_str!("migrations/0003_apply_failures.sql"),
+    include_str!("migrations/0004_orientation.sql"),
+    include_str!("migrations/0005_stored_files.sql"),
+    include_str!("migrations/0006_app_state.sql"),
+    include_str!("migrations/0007_upscaled.sql"),
+    include_str!("migrations/0008_source_samples.sql"),
+    include_str!("migrations/0009_monitor_layout.sql"),
+    include_str!("migrations/0010_image_permalinks.sql"),
+    include_str!("migrations/0011_first_run_state.sql"),
+    include_str!("migrations/0012_budget_notice.sql"),
+    include_str!("migrations/0013_disk_notice.sql"),
+    include_str!("migrations/0014_dead_posts.sql"),
+    include_str!("migrations/0015_image_scores.sql"),
+];
+
+/// Get the path to the database everything we persist across runs lives in
+pub fn path() -> PathBuf {
+    DIRS.data_local_dir().join("db.sqlite3")
+}
+
+/// Get how many migrations the database has had.
+fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    let version = conn.pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))?;
+    if version != 0 {
+        return Ok(version);
+    }
+
+    // Before the version lived in the database header, it had a table of its own
+    let legacy = conn
+        .query_row(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'SchemaVersion'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?;
+    match legacy {
+        Some(()) => conn
+            .query_row("SELECT version FROM SchemaVersion", [], |row| row.get(0))
+            .optional()
+            .map(Option::unwrap_or_default),
+        None => Ok(0),
+    }
+}
+
+/// Bring the schema up to date, applying any migrations it hasn't had yet.
+///
+/// This happens on every connection we open, so the common case of there being nothing to do only reads.
+pub fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    if schema_version(conn)? == MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    // Take the write lock up front, and check again in case another connection migrated while we waited for it
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version = schema_version(&tx)?;
+    if version > MIGRATIONS.len() {
+        // A newer version of us has been here; its migrations should only ever have added things
+        warn!(version, known = MIGRATIONS.len(), "database is newer than we are");
+        return Ok(());
+    }
+
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        debug!(version = idx + 1, "applying migration");
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()
+}
+
+/// Open the database, bringing its schema up to date.
+pub fn open() -> Result<Connection> {
+    crate::utils::check_storage(DIRS.data_local_dir())?;
+    let mut conn = Connection::open(path())?;
+    migrate(&mut conn).wrap_err("Could not migrate database")?;
+    Ok(conn)
+}
+
+/// Open the database without changing anything, returning `None` if it doesn't exist yet.
+///
+/// Tables may be missing if we haven't gotten around to migrating, so queries should treat errors as empty results.
+pub fn open_read_only() -> Result<Option<Connection>> {
+    let path = path();
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?))
+}
+
+/// What a cached file holds, as opposed to what it was downloaded as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoredFile {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+    /// Whether it had to be scaled up to fit its monitor
+    pub upscaled: bool,
+}
+
+/// The backgrounds we've applied, by perceptual hash
+pub struct AppliedImagesRepo<'conn>(&'conn Connection);
+
+impl<'conn> AppliedImagesRepo<'conn> {
+    pub fn new(conn: &'conn Connection) -> Self {
+        Self(conn)
+    }
+
+    /// How many distinct images we've ever applied.
+    pub fn count(&self) -> rusqlite::Result<usize> {
+        self.0
+            .query_row("SELECT COUNT(*) FROM AppliedImages", [], |row| row.get(0))
+    }
+
+    pub fn contains(&self, image_hash: &[u8]) -> rusqlite::Result<bool> {
+        self.0.query_row(
+            "SELECT COUNT(*) FROM AppliedImages WHERE image_hash = ?",
+            [image_hash],
+            |row| Ok(row.get::<_, usize>(0)? != 0),
+        )
+    }
+
+    /// Record that we've applied an image, along with where it came from and the monitor layout it was applied under.
+    ///
+    /// Archived images get applied more than once, which only adds to the history.
+    pub fn insert(
+        &self,
+        image_hash: &[u8],
+        url: Option<&str>,
+        subreddit: Option<&str>,
+        layout: Option<u64>,
+    ) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT OR IGNORE INTO AppliedImages(image_hash) VALUES (?)",
+            [image_hash],
+        )?;
+        self.0.execute(
+            "INSERT INTO AppliedHistory(image_hash, url, subreddit, layout) VALUES (?, ?, ?, ?)",
+            params![image_hash, url, subreddit, layout.map(|layout| layout as i64)],
+        )?;
+        Ok(())
+    }
+
+    /// Get the subreddits of the last `n` backgrounds we know the source of, most recent first.
+    ///
+    /// Only backgrounds applied under the given monitor layout count, unless there aren't `n` of them yet.
+    pub fn recent_subreddits(&self, n: usize, layout: Option<u64>) -> rusqlite::Result<Vec<String>> {
+        if let Some(layout) = layout {
+            let subreddits = self
+                .0
+                .prepare(
+                    "SELECT subreddit FROM AppliedHistory WHERE subreddit IS NOT NULL AND layout = ?
+                     ORDER BY rowid DESC LIMIT ?",
+                )?
+                .query_map(params![layout as i64, n], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            if subreddits.len() >= n {
+                return Ok(subreddits);
+            }
+        }
+
+        self.0
+            .prepare("SELECT subreddit FROM AppliedHistory WHERE subreddit IS NOT NULL ORDER BY rowid DESC LIMIT ?")?
+            .query_map([n], |row| row.get(0))?
+            .collect()
+    }
+
+    /// Record that applying an image failed, returning how many times it has failed so far.
+    pub fn record_failure(&self, image_hash: &[u8]) -> rusqlite::Result<u32> {
+        self.0.query_row(
+            "INSERT INTO ApplyFailures(image_hash, failures) VALUES (?, 1)
+             ON CONFLICT(image_hash) DO UPDATE SET failures = failures + 1
+             RETURNING failures",
+            [image_hash],
+            |row| row.get(0),
+        )
+    }
+
+    /// How many times applying an image has failed.
+    pub fn failures(&self, image_hash: &[u8]) -> rusqlite::Result<u32> {
+        self.0
+            .query_row(
+                "SELECT failures FROM ApplyFailures WHERE image_hash = ?",
+                [image_hash],
+                |row| row.get(0),
+            )
+            .optional()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Get when an image was last applied, if it was applied after we started keeping a history.
+    pub fn last_applied(&self, image_hash: &[u8]) -> rusqlite::Result<Option<String>> {
+        self.0.query_row(
+            "SELECT MAX(timestamp) FROM AppliedHistory WHERE image_hash = ?",
+            [image_hash],
+            |row| row.get(0),
+        )
+    }
+
+    /// Get when the image downloaded from `url` was last applied, if it ever was.
+    pub fn last_applied_url(&self, url: &str) -> rusqlite::Result<Option<String>> {
+        self.0.query_row(
+            "SELECT MAX(timestamp) FROM AppliedHistory WHERE url = ?",
+            [url],
+            |row| row.get(0),
+        )
+    }
+
+    /// Whether the image downloaded from `url` was applied within the last `days` days.
+    pub fn applied_within(&self, url: &str, days: u32) -> rusqlite::Result<bool> {
+        self.0.query_row(
+            "SELECT EXISTS(SELECT 1 FROM AppliedHistory WHERE url = ? AND timestamp > datetime('now', ?))",
+            params![url, format!("-{days} days")],
+            |row| row.get(0),
+        )
+    }
+}
+
+/// Named sets of URLs, e.g. the ones we've already downloaded or found to be invalid
+pub struct VisitedRepo<'conn>(&'conn Connection);
+
+impl<'conn> VisitedRepo<'conn> {
+    pub fn new(conn: &'conn Connection) -> Self {
+        Self(conn)
+    }
+
+    pub fn insert(&self, name: &str, url: &str) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT OR IGNORE INTO PersistentSets(name, url) VALUES (?, ?)",
+            params![name, url],
+        )?;
+        Ok(())
+    }
+
+    /// Insert every url in one transaction, which is much cheaper than separate inserts on slow disks.
+    pub fn insert_many(&self, name: &str, urls: &[String]) -> rusqlite::Result<()> {
+        let tx = self.0.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare("INSERT OR IGNORE INTO PersistentSets(name, url) VALUES (?, ?)")?;
+            for url in urls {
+                stmt.execute(params![name, url])?;
+            }
+        }
+        tx.commit()
+    }
+
+    pub fn contains(&self, name: &str, url: &str) -> rusqlite::Result<bool> {
+        self.0
+            .query_row(
+                "SELECT rowid FROM PersistentSets WHERE name = ? AND url = ?",
+                params![name, url],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|o| o.is_some())
+    }
+
+    /// How many urls were added to the set today, local time.
+    pub fn count_today(&self, name: &str) -> rusqlite::Result<usize> {
+        self.0.query_row(
+            "SELECT COUNT(*) FROM PersistentSets
+             WHERE name = ? AND date(timestamp, 'localtime') = date('now', 'localtime')",
+            [name],
+            |row| row.get(0),
+        )
+    }
+
+    /// Get every set the url is in, along with when it was added to it.
+    pub fn memberships(&self, url: &str) -> rusqlite::Result<Vec<(String, String)>> {
+        self.0
+            .prepare("SELECT name, timestamp FROM PersistentSets WHERE url = ? ORDER BY timestamp")?
+            .query_map([url], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+}
+
+/// Information about downloaded images that we can't get from the stored files themselves
+pub struct MetadataRepo<'conn>(&'conn Connection);
+
+impl<'conn> MetadataRepo<'conn> {
+    pub fn new(conn: &'conn Connection) -> Self {
+        Self(conn)
+    }
+
+    /// Record the original dimensions of the image downloaded from `url`, before any resizing, along with its
+    /// orientation.
+    pub fn insert_dimensions(&self, url: &str, width: u32, height: u32) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT OR REPLACE INTO ImageMetadata(url, width, height, orientation) VALUES (?, ?, ?, ?)",
+            params![url, width, height, Orientation::of(width, height).as_str()],
+        )?;
+        Ok(())
+    }
+
+    pub fn dimensions(&self, url: &str) -> rusqlite::Result<Option<(u32, u32)>> {
+        self.0
+            .query_row("SELECT width, height FROM ImageMetadata WHERE url = ?", [url], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()
+    }
+
+    pub fn orientation(&self, url: &str) -> rusqlite::Result<Option<Orientation>> {
+        self.0
+            .query_row("SELECT orientation FROM ImageMetadata WHERE url = ?", [url], |row| {
+                row.get::<_, Option<String>>(0)
+            })
+            .optional()
+            .map(|orientation| orientation.flatten().as_deref().and_then(Orientation::parse))
+    }
+
+    /// Record which subreddit the image downloaded from `url` was posted in, and the title and link of the post.
+    pub fn insert_source(
+        &self,
+        url: &str,
+        subreddit: &str,
+        title: &str,
+        permalink: Option<&str>,
+        score: i64,
+    ) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT OR REPLACE INTO ImageSources(url, subreddit) VALUES (?, ?)",
+            params![url, subreddit],
+        )?;
+        self.0.execute(
+            "INSERT OR REPLACE INTO ImageTitles(url, title) VALUES (?, ?)",
+            params![url, title],
+        )?;
+        if let Some(permalink) = permalink {
+            self.0.execute(
+                "INSERT OR REPLACE INTO ImagePermalinks(url, permalink) VALUES (?, ?)",
+                params![url, permalink],
+            )?;
+        }
+        self.0.execute(
+            "INSERT OR REPLACE INTO ImageScores(url, score) VALUES (?, ?)",
+            params![url, score],
+        )?;
+        Ok(())
+    }
+
+    pub fn subreddit(&self, url: &str) -> rusqlite::Result<Option<String>> {
+        self.0
+            .query_row("SELECT subreddit FROM ImageSources WHERE url = ?", [url], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+
+    pub fn title(&self, url: &str) -> rusqlite::Result<Option<String>> {
+        self.0
+            .query_row("SELECT title FROM ImageTitles WHERE url = ?", [url], |row| row.get(0))
+            .optional()
+    }
+
+    /// Get the link to the post the image downloaded from `url` came from, if Reddit told us.
+    pub fn permalink(&self, url: &str) -> rusqlite::Result<Option<String>> {
+        self.0
+            .query_row("SELECT permalink FROM ImagePermalinks WHERE url = ?", [url], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+
+    /// Get the score the post the image downloaded from `url` came from had when we fetched it.
+    pub fn score(&self, url: &str) -> rusqlite::Result<Option<i64>> {
+        self.0
+            .query_row("SELECT score FROM ImageScores WHERE url = ?", [url], |row| row.get(0))
+            .optional()
+    }
+
+    /// Record that the image downloaded from `url` is cached under the given filename.
+    pub fn insert_file(&self, filename: &str, url: &str) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT OR REPLACE INTO CachedFiles(filename, url) VALUES (?, ?)",
+            params![filename, url],
+        )?;
+        Ok(())
+    }
+
+    /// Record what the file cached under the given filename holds.
+    pub fn insert_stored(&self, filename: &str, url: &str, stored: StoredFile) -> rusqlite::Result<()> {
+        let format = stored.format.extensions_str().first().copied();
+        self.0.execute(
+            "INSERT INTO CachedFiles(filename, url, format, width, height, bytes, upscaled) VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(filename) DO UPDATE SET
+                format = excluded.format, width = excluded.width, height = excluded.height, bytes = excluded.bytes,
+                upscaled = excluded.upscaled",
+            params![filename, url, format, stored.width, stored.height, stored.bytes, stored.upscaled],
+        )?;
+        Ok(())
+    }
+
+    /// Get what the file cached under the given filename holds, if we recorded it.
+    pub fn stored(&self, filename: &str) -> rusqlite::Result<Option<StoredFile>> {
+        let row = self
+            .0
+            .query_row(
+                "SELECT format, width, height, bytes, upscaled FROM CachedFiles WHERE filename = ? AND format IS NOT NULL",
+                [filename],
+                |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+            )
+            .optional()?;
+        Ok(row.and_then(|(format, width, height, bytes, upscaled)| {
+            Some(StoredFile {
+                format: ImageFormat::from_extension(format)?,
+                width,
+                height,
+                bytes,
+                upscaled,
+            })
+        }))
+    }
+
+    pub fn url_for_file(&self, filename: &str) -> rusqlite::Result<Option<String>> {
+        self.0
+            .query_row("SELECT url FROM CachedFiles WHERE filename = ?", [filename], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+
+    /// Whether we've recorded downloading `url`, under any filename.
+    pub fn is_recorded(&self, url: &str) -> rusqlite::Result<bool> {
+        self.0.query_row(
+            "SELECT EXISTS (SELECT 1 FROM ImageMetadata WHERE url = ?1)
+                 OR EXISTS (SELECT 1 FROM CachedFiles WHERE url = ?1)",
+            [url],
+            |row| row.get(0),
+        )
+    }
+
+    /// Record the dimensions of the image with the given body, as they were when we checked its aspect ratio.
+    pub fn insert_probed(&self, body_hash: u64, width: u32, height: u32) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT OR REPLACE INTO ProbedImages(body_hash, width, height) VALUES (?, ?, ?)",
+            params![body_hash.to_be_bytes(), width, height],
+        )?;
+        Ok(())
+    }
+
+    /// Get the dimensions we recorded for an image with the given body, if we've seen identical bytes before.
+    pub fn probed(&self, body_hash: u64) -> rusqlite::Result<Option<(u32, u32)>> {
+        self.0
+            .query_row(
+                "SELECT width, height FROM ProbedImages WHERE body_hash = ?",
+                [body_hash.to_be_bytes()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+}
+
+/// How many bytes we've downloaded each (local) day
+pub struct BandwidthRepo<'conn>(&'conn Connection);
+
+impl<'conn> BandwidthRepo<'conn> {
+    pub fn new(conn: &'conn Connection) -> Self {
+        Self(conn)
+    }
+
+    /// Add `bytes` to today's total.
+    pub fn record(&self, bytes: u64) -> rusqlite::Result<()> {
+        let day: String = self
+            .0
+            .query_row("SELECT date('now', 'localtime')", [], |row| row.get(0))?;
+        self.record_on(&day, bytes)
+    }
+
+    /// Add `bytes` to the total of `day`, as in `2024-01-31`.
+    pub fn record_on(&self, day: &str, bytes: u64) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT INTO DailyBandwidth(day, bytes) VALUES (?, ?)
+             ON CONFLICT(day) DO UPDATE SET bytes = bytes + excluded.bytes",
+            params![day, bytes],
+        )?;
+        Ok(())
+    }
+
+    /// Get today's date along with how many bytes we've downloaded so far today.
+    pub fn today(&self) -> rusqlite::Result<(String, u64)> {
+        let day: String = self
+            .0
+            .query_row("SELECT date('now', 'localtime')", [], |row| row.get(0))?;
+        let bytes = self.on(&day)?;
+        Ok((day, bytes))
+    }
+
+    /// How many bytes we downloaded on `day`, as in `2024-01-31`.
+    pub fn on(&self, day: &str) -> rusqlite::Result<u64> {
+        self.0.query_row(
+            "SELECT COALESCE((SELECT bytes FROM DailyBandwidth WHERE day = ?), 0)",
+            [day],
+            |row| row.get(0),
+        )
+    }
+}
+
+/// Posts whose image was deleted, by id
+pub struct DeadPostsRepo<'conn>(&'conn Connection);
+
+impl<'conn> DeadPostsRepo<'conn> {
+    pub fn new(conn: &'conn Connection) -> Self {
+        Self(conn)
+    }
+
+    pub fn insert(&self, id: &str) -> rusqlite::Result<()> {
+        self.0.execute("INSERT OR IGNORE INTO DeadPosts(id) VALUES (?)", [id])?;
+        Ok(())
+    }
+
+    pub fn contains(&self, id: &str) -> rusqlite::Result<bool> {
+        self.0
+            .query_row("SELECT 1 FROM DeadPosts WHERE id = ?", [id], |_| Ok(()))
+            .optional()
+            .map(|o| o.is_some())
+    }
+}
+
+/// Single values we keep across restarts, by name
+pub struct AppStateRepo<'conn>(&'conn Connection);
+
+impl<'conn> AppStateRepo<'conn> {
+    pub fn new(conn: &'conn Connection) -> Self {
+        Self(conn)
+    }
+
+    pub fn get(&self, key: &str) -> rusqlite::Result<Option<String>> {
+        self.0
+            .query_row("SELECT value FROM AppState WHERE key = ?", [key], |row| row.get(0))
+            .optional()
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> rusqlite::Result<()> {
+        self.0.execute(
+            "INSERT OR REPLACE INTO AppState(key, value) VALUES (?, ?)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> rusqlite::Result<()> {
+        self.0.execute("DELETE FROM AppState WHERE key = ?", [key])?;
+        Ok(())
+    }
+}
+
+/// When each source was last fetched from, as unix timestamps
+pub struct SourceSamplesRepo<'conn>(&'conn Connection);
+
+impl<'conn> SourceSamplesRepo<'conn> {
+    pub fn new(conn: &'conn Connection) -> Self {
+        Self(conn)
+    }
+
+    pub fn all(&self) -> rusqlite::Result<HashMap<String, i64>> {
+        let mut stmt = self.0.prepare("SELECT name, last_sampled FROM SourceSamples")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    pub fn record(&self, names: &[&str], at: i64) -> rusqlite::Result<()> {
+        let mut stmt = self
+            .0
+            .prepare("INSERT OR REPLACE INTO SourceSamples(name, last_sampled) VALUES (?, ?)")?;
+        for name in names {
+            stmt.execute(params![name, at])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_from_scratch() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        // Every table the repositories use is there
+        conn.execute("INSERT INTO ImagePermalinks(url, permalink) VALUES ('a', 'b')", [])
+            .unwrap();
+        conn.execute("INSERT INTO AppliedHistory(image_hash, layout) VALUES (x'00', 1)", [])
+            .unwrap();
+    }
+
+    #[test]
+    fn upgrading_users_are_past_their_first_run() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..10] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 10).unwrap();
+        conn.execute("INSERT INTO AppliedImages(image_hash) VALUES (x'00')", [])
+            .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert!(AppStateRepo::new(&conn).get("first_run_complete").unwrap().is_some());
+    }
+
+    #[test]
+    fn budget_notices_become_the_last_day_noticed() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..11] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 11).unwrap();
+        VisitedRepo::new(&conn)
+            .insert_many("budget_notices", &["2024-03-01".to_owned(), "2024-03-04".to_owned()])
+            .unwrap();
+
+        migrate(&mut conn).unwrap();
+        let state = AppStateRepo::new(&conn);
+        assert_eq!(state.get("budget_notice").unwrap().as_deref(), Some("2024-03-04"));
+        assert!(!VisitedRepo::new(&conn)
+            .contains("budget_notices", "2024-03-04")
+            .unwrap());
+    }
+
+    #[test]
+    fn disk_notices_become_the_last_day_noticed() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..12] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 12).unwrap();
+        VisitedRepo::new(&conn).insert("disk_notices", "2024-03-02").unwrap();
+
+        migrate(&mut conn).unwrap();
+        let state = AppStateRepo::new(&conn);
+        assert_eq!(state.get("disk_notice").unwrap().as_deref(), Some("2024-03-02"));
+        assert!(state.get("budget_notice").unwrap().is_none());
+    }
+
+    #[test]
+    fn dead_posts_move_to_their_own_table() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..13] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 13).unwrap();
+        VisitedRepo::new(&conn).insert("dead_posts", "abc123").unwrap();
+        VisitedRepo::new(&conn).insert("invalid", "def456").unwrap();
+
+        migrate(&mut conn).unwrap();
+        let dead = DeadPostsRepo::new(&conn);
+        assert!(dead.contains("abc123").unwrap());
+        assert!(!dead.contains("def456").unwrap());
+        assert!(!VisitedRepo::new(&conn).contains("dead_posts", "abc123").unwrap());
+        assert!(VisitedRepo::new(&conn).contains("invalid", "def456").unwrap());
+    }
+
+    #[test]
+    fn new_users_are_not_past_their_first_run() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert!(AppStateRepo::new(&conn).get("first_run_complete").unwrap().is_none());
+    }
+
+    #[test]
+    fn reopening_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.sqlite3");
+        for _ in 0..3 {
+            let mut conn = Connection::open(&path).unwrap();
+            migrate(&mut conn).unwrap();
+            assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+        }
+    }
+
+    #[test]
+    fn picks_up_from_the_legacy_version_table() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..5] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.execute_batch(
+            "CREATE TABLE SchemaVersion (version INTEGER NOT NULL); INSERT INTO SchemaVersion VALUES (5)",
+        )
+        .unwrap();
+
+        // Applying the first five again would fail, as some of them alter tables
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn leaves_newer_databases_alone() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len() + 1);
+    }
+
+    #[test]
+    fn only_applications_inside_the_window_count() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO AppliedHistory(image_hash, url, subreddit, timestamp)
+             VALUES (x'00', 'https://i.redd.it/a.png', 'wallpapers', datetime('now', '-3 days'))",
+            [],
+        )
+        .unwrap();
+
+        let applied = AppliedImagesRepo::new(&conn);
+        assert!(applied.applied_within("https://i.redd.it/a.png", 7).unwrap());
+        assert!(!applied.applied_within("https://i.redd.it/a.png", 2).unwrap());
+        assert!(!applied.applied_within("https://i.redd.it/b.png", 7).unwrap());
+    }
+
+    #[test]
+    fn recent_subreddits_prefer_the_current_layout_while_it_has_enough() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        for (subreddit, layout) in [("a", 1), ("b", 2), ("c", 1), ("d", 2), ("e", 2)] {
+            conn.execute(
+                "INSERT INTO AppliedHistory(image_hash, subreddit, layout) VALUES (x'00', ?, ?)",
+                params![subreddit, layout],
+            )
+            .unwrap();
+        }
+        conn.execute("INSERT INTO AppliedHistory(image_hash) VALUES (x'00')", [])
+            .unwrap();
+
+        let applied = AppliedImagesRepo::new(&conn);
+        assert_eq!(applied.recent_subreddits(2, Some(1)).unwrap(), ["c", "a"]);
+        assert_eq!(applied.recent_subreddits(3, Some(2)).unwrap(), ["e", "d", "b"]);
+
+        // Not enough history for this layout, or none known, goes by all of it
+        assert_eq!(applied.recent_subreddits(3, Some(1)).unwrap(), ["e", "d", "c"]);
+        assert_eq!(applied.recent_subreddits(2, Some(3)).unwrap(), ["e", "d"]);
+        assert_eq!(applied.recent_subreddits(2, None).unwrap(), ["e", "d"]);
+    }
+
+    #[test]
+    fn many_urls_are_recorded_in_one_transaction() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let visited = VisitedRepo::new(&conn);
+        let urls = (0..1000)
+            .map(|i| format!("https://i.redd.it/{i}.png"))
+            .collect::<Vec<_>>();
+        visited.insert_many("downloaded", &urls).unwrap();
+        assert!(urls.iter().all(|url| visited.contains("downloaded", url).unwrap()));
+
+        // A row failing halfway through takes the whole batch with it, which it wouldn't if each row were its own
+        // transaction
+        conn.execute_batch(
+            "CREATE TEMP TRIGGER poison BEFORE INSERT ON PersistentSets WHEN NEW.url = 'poison'
+             BEGIN SELECT RAISE(ABORT, 'poisoned'); END",
+        )
+        .unwrap();
+        let mut batch = (0..1000)
+            .map(|i| format!("https://i.imgur.com/{i}.png"))
+            .collect::<Vec<_>>();
+        batch.insert(500, "poison".to_owned());
+        assert!(visited.insert_many("downloaded", &batch).is_err());
+        assert!(!visited.contains("downloaded", &batch[0]).unwrap());
+        assert!(!visited.contains("downloaded", &batch[499]).unwrap());
+        assert!(conn.is_autocommit());
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use eyre::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::{debug, info};
+
+use crate::db;
+
+// The digest is shown from this hour onwards on Sundays
+const DIGEST_HOUR: u32 = 18;
+
+/// A summary of what was applied over the last week
+#[derive(Debug)]
+pub struct Digest {
+    pub applied: usize,
+    pub subreddits: usize,
+    pub top_subreddit: Option<String>,
+}
+
+impl Digest {
+    /// Aggregate the applied history over the last seven days.
+    pub fn query(db: &Connection) -> Result<Self> {
+        let (applied, subreddits) = db.query_row(
+            "SELECT COUNT(*), COUNT(DISTINCT subreddit) FROM AppliedHistory
+             WHERE timestamp >= datetime('now', '-7 days')",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let top_subreddit = db
+            .query_row(
+                "SELECT subreddit FROM AppliedHistory
+                 WHERE timestamp >= datetime('now', '-7 days') AND subreddit IS NOT NULL
+                 GROUP BY subreddit ORDER BY COUNT(*) DESC, subreddit LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(Self {
+            applied,
+            subreddits,
+            top_subreddit,
+        })
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "This week: {} wallpaper{}",
+            self.applied,
+            if self.applied == 1 { "" } else { "s" }
+        )?;
+        if self.subreddits > 0 {
+            write!(
+                f,
+                " from {} subreddit{}",
+                self.subreddits,
+                if self.subreddits == 1 { "" } else { "s" }
+            )?;
+        }
+        if let Some(ref top) = self.top_subreddit {
+            write!(f, ", most from r/{top}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Show the weekly digest if it's Sunday evening and we haven't shown it yet this week.
+#[tracing::instrument]
+pub fn maybe_notify() -> Result<()> {
+    let db = db::open()?;
+
+    // SQLite knows the local time, which saves us from pulling in a date library
+    let (weekday, hour, week): (u32, u32, String) = db.query_row(
+        "SELECT CAST(strftime('%w', 'now', 'localtime') AS INTEGER),
+                CAST(strftime('%H', 'now', 'localtime') AS INTEGER),
+                strftime('%Y-%W', 'now', 'localtime')",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    if weekday != 0 || hour < DIGEST_HOUR {
+        return Ok(());
+    }
+
+    // We persist which weeks we've already shown so that restarts don't show it twice
+    let already_shown = db
+        .query_row(
+            "SELECT rowid FROM PersistentSets WHERE name = 'digests' AND url = ?",
+            [&week],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if already_shown {
+        debug!(%week, "digest already shown this week");
+        return Ok(());
+    }
+
+    let digest = Digest::query(&db)?;
+    if digest.applied > 0 {
+        info!(target: "notification", "{digest}");
+    } else {
+        debug!("nothing applied this week, skipping digest");
+    }
+
+    db.execute(
+        "INSERT OR IGNORE INTO PersistentSets(name, url) VALUES ('digests', ?)",
+        params![week],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! Fetching from a fake of Reddit and picking out of what we got, the way a cycle does.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use reqwest::Client;
+use serde_json::json;
+
+use crate::{
+    config::Config,
+    db::{MetadataRepo, VisitedRepo},
+    fake_server::{FakeServer, Response},
+    fetcher::{self, FetchReport, Rejection},
+    picker,
+    policy::ImagePolicy,
+    reddit,
+    sources::Sort,
+    DIRS,
+};
+
+/// A post in a listing, the way Reddit sends it.
+fn post(id: &str, url: &str) -> serde_json::Value {
+    json!({
+        "kind": "t3",
+        "data": {
+            "id": id,
+            "url": url,
+            "subreddit": "wallpapers",
+            "title": format!("The {id} one"),
+            "permalink": format!("/r/wallpapers/comments/{id}/"),
+            "score": 100,
+            "over_18": false,
+        }
+    })
+}
+
+/// An imgur album page, which only lists its images in a script.
+fn imgur_page(urls: &[String]) -> String {
+    let media = urls
+        .iter()
+        .map(|url| json!({"url": url, "type": "image"}))
+        .collect::<Vec<_>>();
+    let data = json!({ "media": media }).to_string();
+    format!(
+        "<!DOCTYPE html><html><head><script>window.postDataJSON = {}</script></head><body></body></html>",
+        serde_json::to_string(&data).unwrap()
+    )
+}
+
+/// A reddit gallery page, with its images in the state the page starts out with.
+fn reddit_gallery_page(urls: &[String]) -> String {
+    let metadata = urls
+        .iter()
+        .enumerate()
+        .map(|(i, url)| (format!("item{i}"), json!({"m": "image/png", "s": {"u": url}})))
+        .collect::<serde_json::Map<_, _>>();
+    let state = json!({"posts": {"models": {"gallery": {"media": {"mediaMetadata": metadata}}}}});
+    format!("<!DOCTYPE html><html><head><script>window.___r = {state}</script></head><body></body></html>")
+}
+
+/// Fetch the wallpapers subreddit's hot posts from `server` into `profile`.
+fn fetch(runtime: &tokio::runtime::Runtime, server: &FakeServer, config: &Config, profile: &str) -> FetchReport {
+    let client = Client::builder().no_proxy().build().unwrap();
+    let base_url = server.url("");
+    let rejections = fetcher::Rejections::default();
+    let posts = reddit::Posts::new(
+        &client,
+        &[("wallpapers", Sort::Hot)],
+        reddit::Filter::default(),
+        false,
+        &rejections,
+    )
+    .with_base_url(&base_url);
+    runtime
+        .block_on(fetcher::fetch(&client, config, profile, posts))
+        .unwrap()
+}
+
+#[test]
+fn fetched_images_are_recorded_and_picked_and_not_downloaded_again() {
+    std::fs::create_dir_all(DIRS.data_local_dir()).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let config = Config::default();
+    let profile = "end-to-end";
+
+    let server = FakeServer::builder();
+    let (wide, square, imgur, gallery) = (
+        server.url("/wide.png"),
+        server.url("/square.png"),
+        server.url("/imgur.png"),
+        server.url("/gallery.png"),
+    );
+    let listing = json!({
+        "kind": "Listing",
+        "data": {
+            "after": null,
+            "children": [
+                post("wide", &wide),
+                post("square", &square),
+                post("imgur", &server.url("/a/album")),
+                post("gallery", &server.url("/gallery/abc")),
+            ],
+        }
+    });
+    let server = server
+        .route("/r/wallpapers/hot.json", Response::json(&listing))
+        .route("/wide.png", Response::png((1920, 1080)))
+        .route("/square.png", Response::png((1080, 1080)))
+        .route("/imgur.png", Response::png((2560, 1440)))
+        .route("/gallery.png", Response::png((2560, 1440)))
+        .route("/a/album", Response::html(imgur_page(std::slice::from_ref(&imgur))))
+        .route(
+            "/gallery/abc",
+            Response::html(reddit_gallery_page(std::slice::from_ref(&gallery))),
+        )
+        .start();
+
+    let report = fetch(&runtime, &server, &config, profile);
+    assert_eq!(report.fetched, 3);
+    assert_eq!(report.rejections, BTreeMap::from([(Rejection::AspectRatio, 1)]));
+
+    // A file for each image that fits the monitor, and nothing for the one that doesn't
+    let db = crate::db::open().unwrap();
+    let metadata = MetadataRepo::new(&db);
+    let stored = std::fs::read_dir(fetcher::images_dir(profile))
+        .unwrap()
+        .map(|entry| {
+            fetcher::url_for_file(&metadata, &entry.unwrap().path())
+                .unwrap()
+                .unwrap()
+        })
+        .collect::<BTreeSet<_>>();
+    assert_eq!(stored, BTreeSet::from([wide.clone(), imgur.clone(), gallery.clone()]));
+
+    // Gallery images are recorded as coming from the gallery's post
+    for (url, dimensions, id) in [
+        (&wide, (1920, 1080), "wide"),
+        (&imgur, (2560, 1440), "imgur"),
+        (&gallery, (2560, 1440), "gallery"),
+    ] {
+        assert_eq!(metadata.dimensions(url).unwrap(), Some(dimensions));
+        assert_eq!(metadata.subreddit(url).unwrap().as_deref(), Some("wallpapers"));
+        assert_eq!(metadata.title(url).unwrap(), Some(format!("The {id} one")));
+        assert_eq!(
+            metadata.permalink(url).unwrap(),
+            Some(format!("https://www.reddit.com/r/wallpapers/comments/{id}/"))
+        );
+        assert_eq!(metadata.score(url).unwrap(), Some(100));
+    }
+    let visited = VisitedRepo::new(&db);
+    assert!(visited.contains("invalid", &square).unwrap());
+    assert!(!visited.contains(&format!("downloaded/{profile}"), &square).unwrap());
+    for url in [&wide, &imgur, &gallery] {
+        assert!(visited.contains(&format!("downloaded/{profile}"), url).unwrap());
+    }
+
+    let policy = ImagePolicy::current().unwrap().configure(&config);
+    let picked = picker::pick(profile, &config, &[], &policy).unwrap();
+    assert!(picked.url.as_ref().is_some_and(|url| stored.contains(url)));
+    assert_eq!(picked.subreddit.as_deref(), Some("wallpapers"));
+    assert_eq!(picked.path.parent(), Some(fetcher::images_dir(profile).as_path()));
+
+    // Everything in the listing has been seen to, so only the listing itself is asked for again
+    let report = fetch(&runtime, &server, &config, profile);
+    assert_eq!(report.fetched, 0);
+    assert_eq!(report.rejections, BTreeMap::from([(Rejection::AlreadySeen, 4)]));
+    assert_eq!(server.hits("/r/wallpapers/hot.json"), 2);
+    for path in [
+        "/wide.png",
+        "/square.png",
+        "/imgur.png",
+        "/gallery.png",
+        "/a/album",
+        "/gallery/abc",
+    ] {
+        assert_eq!(server.hits(path), 1, "{}", path);
+    }
+}
+
+#[test]
+fn galleries_linking_to_galleries_are_not_followed_around() {
+    std::fs::create_dir_all(DIRS.data_local_dir()).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let config = Config::default();
+    let profile = "gallery-cycle";
+
+    // Each gallery links to the other, and the first one to itself too
+    let server = FakeServer::builder();
+    let (first, second, image) = (
+        server.url("/gallery/first"),
+        server.url("/gallery/second"),
+        server.url("/in-gallery.png"),
+    );
+    let listing = json!({
+        "kind": "Listing",
+        "data": { "after": null, "children": [post("first", &first)] }
+    });
+    let server = server
+        .route("/r/wallpapers/hot.json", Response::json(&listing))
+        .route(
+            "/gallery/first",
+            Response::html(reddit_gallery_page(&[first.clone(), second.clone(), image.clone()])),
+        )
+        .route(
+            "/gallery/second",
+            Response::html(reddit_gallery_page(std::slice::from_ref(&first))),
+        )
+        .route("/in-gallery.png", Response::png((1920, 1080)))
+        .start();
+
+    // The image is fetched, while the first gallery isn't expanded inside itself and the second one not at all
+    let report = fetch(&runtime, &server, &config, profile);
+    assert_eq!(report.fetched, 1);
+    assert_eq!(report.rejections, BTreeMap::from([(Rejection::Gallery, 2)]));
+    assert_eq!(server.hits("/gallery/first"), 1);
+    assert_eq!(server.hits("/gallery/second"), 1);
+    assert_eq!(server.hits("/in-gallery.png"), 1);
+
+    // Neither gallery is worth looking at again
+    let db = crate::db::open().unwrap();
+    let visited = VisitedRepo::new(&db);
+    assert!(visited.contains("invalid", &first).unwrap());
+    assert!(visited.contains("invalid", &second).unwrap());
+    assert!(visited.contains(&format!("downloaded/{profile}"), &image).unwrap());
+}
+
+#[test]
+fn big_galleries_are_only_downloaded_from_as_much_as_needed() {
+    std::fs::create_dir_all(DIRS.data_local_dir()).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let config = Config {
+        max_cached: 2,
+        ..Config::default()
+    };
+    let profile = "big-gallery";
+
+    let server = FakeServer::builder();
+    let gallery = server.url("/gallery/big");
+    let images = (0..20)
+        .map(|i| server.url(&format!("/big/{i}.png")))
+        .collect::<Vec<_>>();
+    let listing = json!({
+        "kind": "Listing",
+        "data": { "after": null, "children": [post("big", &gallery)] }
+    });
+    let server = (0..20)
+        .fold(server, |server, i| {
+            // Slow enough that every download we start is still going when the next one is started
+            let image = Response::png((1920, 1080)).after(std::time::Duration::from_millis(300));
+            server.route(&format!("/big/{i}.png"), image)
+        })
+        .route("/r/wallpapers/hot.json", Response::json(&listing))
+        .route("/gallery/big", Response::html(reddit_gallery_page(&images)))
+        .start();
+
+    // Only as many downloads as we need go at once, and the one started as the first finished is all that's wasted
+    let report = fetch(&runtime, &server, &config, profile);
+    assert_eq!(report.fetched, 2);
+    assert!(server.peak_load() <= 2, "{}", server.peak_load());
+    assert!(server.hits_under("/big/") <= 3, "{}", server.hits_under("/big/"));
+}
@@ -0,0 +1,204 @@
+use std::{fs, io, path::PathBuf};
+
+use bytes::Bytes;
+use eyre::{Result, WrapErr};
+use futures::prelude::*;
+use reqwest::Client;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use tokio::runtime::Handle;
+use tracing::{debug, info, warn};
+
+use crate::{
+    db,
+    utils::{with_backoff, Bandwidth, TASKS},
+};
+
+// How much of the title we keep in the exported file's name
+const MAX_TITLE_CHARS: usize = 80;
+
+/// Where and how to export the originals of the backgrounds we apply
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExportConfig {
+    /// The directory to export to, e.g. a folder synced to the cloud
+    pub dir: PathBuf,
+
+    /// How many megabytes the exports may take up, after which the oldest ones are deleted
+    pub max_mb: Option<u64>,
+}
+
+/// Turn a post's title into something that's safe to use in a filename.
+fn sanitize_title(title: &str) -> String {
+    let sanitized = title
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_TITLE_CHARS)
+        .collect::<String>();
+
+    // Windows doesn't like names ending in dots or spaces
+    sanitized.trim().trim_end_matches('.').to_owned()
+}
+
+/// Write the original to the export directory and delete the oldest exports if we're over budget.
+fn save(config: &ExportConfig, body: &[u8], title: Option<&str>) -> Result<()> {
+    let extension = image::guess_format(body)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("dat");
+    let date = time::OffsetDateTime::now_local()
+        .unwrap_or_else(|_| time::OffsetDateTime::now_utc())
+        .date();
+
+    fs::create_dir_all(&config.dir).wrap_err("Could not create export directory")?;
+    let stem = match title.map(sanitize_title) {
+        Some(title) if !title.is_empty() => format!("{date} {title}"),
+        _ => date.to_string(),
+    };
+
+    // Don't overwrite an earlier export with the same title on the same day
+    let mut path = config.dir.join(format!("{stem}.{extension}"));
+    let mut n = 1;
+    while path.exists() {
+        n += 1;
+        path = config.dir.join(format!("{stem} ({n}).{extension}"));
+    }
+    fs::write(&path, body).wrap_err("Could not write export")?;
+    info!(path = %path.display(), "exported original");
+
+    let db = db::open()?;
+    db.execute(
+        "INSERT OR REPLACE INTO Exports(path, bytes) VALUES (?, ?)",
+        params![path.to_string_lossy(), body.len()],
+    )?;
+
+    if let Some(max_mb) = config.max_mb {
+        evict(&db, max_mb.saturating_mul(1_000_000))?;
+    }
+
+    Ok(())
+}
+
+/// Delete the oldest exports until they fit in `budget` bytes.
+///
+/// We only ever delete files we exported ourselves, as the export directory may well hold other things too.
+fn evict(db: &Connection, budget: u64) -> Result<()> {
+    let mut total: u64 = db.query_row("SELECT COALESCE(SUM(bytes), 0) FROM Exports", [], |row| row.get(0))?;
+    let oldest = db
+        .prepare("SELECT path, bytes FROM Exports ORDER BY timestamp, rowid")?
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (path, bytes) in oldest {
+        if total <= budget {
+            break;
+        }
+
+        debug!(%path, bytes, "evicting export");
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            // The user may well have deleted it themselves
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error).wrap_err_with(|| format!("Could not delete export {path:?}")),
+        }
+        db.execute("DELETE FROM Exports WHERE path = ?", [&path])?;
+        total = total.saturating_sub(bytes);
+    }
+
+    Ok(())
+}
+
+async fn export(client: Client, config: ExportConfig, url: String, title: Option<String>) -> Result<()> {
+    // We only keep resized copies around, so we go back to the source for the original
+    let body: Bytes = with_backoff(|| client.get(&url).send().and_then(reqwest::Response::bytes))
+        .await
+        .wrap_err_with(|| format!("Failed to fetch {url:?}"))?;
+    Bandwidth::new().await?.record(body.len() as u64).await?;
+
+    TASKS
+        .spawn_blocking("save export", move || save(&config, &body, title.as_deref()))
+        .await?
+}
+
+/// Export the original of the background we just applied in the background.
+pub fn spawn(runtime: &Handle, client: &Client, config: ExportConfig, url: String, title: Option<String>) {
+    let client = client.clone();
+    let _guard = runtime.enter();
+    TASKS.spawn("export original", async move {
+        if let Err(error) = export(client, config, url, title).await {
+            warn!(?error, "could not export original");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_oldest_exports_are_evicted_first_and_nothing_else_is_touched() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = Connection::open_in_memory().unwrap();
+        db::migrate(&mut db).unwrap();
+
+        // Recorded out of order, with the two newest exported in the same second
+        let exports = [
+            ("newest", 300, "2024-01-03 10:00:00"),
+            ("oldest", 100, "2024-01-01 10:00:00"),
+            ("deleted", 100, "2024-01-02 09:00:00"),
+            ("middle", 100, "2024-01-02 10:00:00"),
+            ("newer", 100, "2024-01-03 10:00:00"),
+        ];
+        for (name, bytes, timestamp) in exports {
+            let path = dir.path().join(format!("{name}.png"));
+            if name != "deleted" {
+                fs::write(&path, vec![0; bytes]).unwrap();
+            }
+            db.execute(
+                "INSERT INTO Exports(path, bytes, timestamp) VALUES (?, ?, ?)",
+                params![path.to_string_lossy(), bytes, timestamp],
+            )
+            .unwrap();
+        }
+        let theirs = dir.path().join("holiday.jpg");
+        fs::write(&theirs, vec![0; 1000]).unwrap();
+
+        let left = |db: &Connection| {
+            let mut left = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            left.sort();
+            let recorded: usize = db
+                .query_row("SELECT COUNT(*) FROM Exports", [], |row| row.get(0))
+                .unwrap();
+            (left, recorded)
+        };
+
+        // Within budget, nothing goes
+        evict(&db, 700).unwrap();
+        assert_eq!(left(&db).1, 5);
+
+        // Over it, the oldest go until the rest fit, whether or not they're still there
+        evict(&db, 450).unwrap();
+        assert_eq!(
+            left(&db),
+            (
+                vec![
+                    "holiday.jpg".to_owned(),
+                    "newer.png".to_owned(),
+                    "newest.png".to_owned()
+                ],
+                2
+            )
+        );
+
+        // And of two exported at the same time, the one recorded first goes first
+        evict(&db, 300).unwrap();
+        assert_eq!(left(&db), (vec!["holiday.jpg".to_owned(), "newer.png".to_owned()], 1));
+        assert_eq!(fs::read(&theirs).unwrap().len(), 1000);
+    }
+}
@@ -0,0 +1,241 @@
+//! A stand-in for Reddit and the image hosts, serving canned responses from a local port for tests to fetch from.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+
+/// What the server answers a route with
+#[derive(Clone, Debug)]
+pub struct Response {
+    status: StatusCode,
+    content_type: &'static str,
+    body: Vec<u8>,
+    delay: Duration,
+}
+
+impl Response {
+    pub fn json(value: &serde_json::Value) -> Self {
+        Self {
+            status: StatusCode::OK,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn html(body: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::OK,
+            content_type: "text/html; charset=utf-8",
+            body: body.into().into_bytes(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// A PNG of the given size, with a gradient in it so that it doesn't look like it's all border.
+    pub fn png((width, height): (u32, u32)) -> Self {
+        let image = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        });
+        let mut body = Vec::new();
+        image::DynamicImage::ImageRgb8(image)
+            .write_to(&mut std::io::Cursor::new(&mut body), image::ImageOutputFormat::Png)
+            .unwrap();
+        Self {
+            status: StatusCode::OK,
+            content_type: "image/png",
+            body,
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Nothing but the given status, like the error pages of image hosts.
+    pub fn status(status: u16) -> Self {
+        Self {
+            status: StatusCode::from_u16(status).expect("invalid status"),
+            content_type: "text/plain",
+            body: Vec::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Take `delay` to answer, like a slow host.
+    pub fn after(self, delay: Duration) -> Self {
+        Self { delay, ..self }
+    }
+
+    fn write_to(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or_default(),
+            self.content_type,
+            self.body.len()
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+/// Sets up the routes of a [`FakeServer`], which is already listening so that responses can link to each other.
+pub struct Builder {
+    listener: TcpListener,
+    routes: HashMap<String, Response>,
+}
+
+impl Builder {
+    /// The URL `path` will be served at.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.listener.local_addr().unwrap())
+    }
+
+    /// Answer requests for `path`, whatever their query, with `response`.
+    pub fn route(mut self, path: &str, response: Response) -> Self {
+        self.routes.insert(path.to_owned(), response);
+        self
+    }
+
+    /// Start answering requests, with a 404 for any path without a route.
+    pub fn start(self) -> FakeServer {
+        let Self { listener, routes } = self;
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let load = Arc::new(Load::default());
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        std::thread::spawn({
+            let routes = Arc::new(routes);
+            let requests = Arc::clone(&requests);
+            let load = Arc::clone(&load);
+            let stopped = Arc::clone(&stopped);
+            move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else { continue };
+                    let routes = Arc::clone(&routes);
+                    let requests = Arc::clone(&requests);
+                    let load = Arc::clone(&load);
+                    std::thread::spawn(move || serve(stream, &routes, &requests, &load));
+                }
+            }
+        });
+
+        FakeServer {
+            address,
+            requests,
+            load,
+            stopped,
+        }
+    }
+}
+
+/// How many requests we're answering at once
+#[derive(Default)]
+struct Load {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Answer the one request on `stream`, recording its path.
+fn serve(mut stream: TcpStream, routes: &HashMap<String, Response>, requests: &Mutex<Vec<String>>, load: &Load) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    // Skip the headers, as only the path matters to us
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|read| read > 2) {
+        header.clear();
+    }
+
+    // Requests sent through us as a proxy name the host too
+    let target = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let target = match target.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+        None => target,
+    };
+    let path = target.split('?').next().unwrap_or_default().to_owned();
+
+    let not_found = Response::status(404);
+    let response = routes.get(&path).unwrap_or(&not_found);
+    requests.lock().unwrap().push(path);
+    let current = load.current.fetch_add(1, Ordering::SeqCst) + 1;
+    load.peak.fetch_max(current, Ordering::SeqCst);
+    std::thread::sleep(response.delay);
+    let _ = response.write_to(&mut stream);
+    load.current.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// A local HTTP server answering with canned responses, which stops once dropped.
+pub struct FakeServer {
+    address: SocketAddr,
+    /// The path of every request we've answered, in order
+    requests: Arc<Mutex<Vec<String>>>,
+    load: Arc<Load>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl FakeServer {
+    pub fn builder() -> Builder {
+        Builder {
+            listener: TcpListener::bind("127.0.0.1:0").expect("could not bind fake server"),
+            routes: HashMap::new(),
+        }
+    }
+
+    /// The URL `path` is served at.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.address)
+    }
+
+    /// How many times `path` has been asked for.
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&request| request == path)
+            .count()
+    }
+
+    /// How many requests whose path starts with `prefix` have been asked for.
+    pub fn hits_under(&self, prefix: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.starts_with(prefix))
+            .count()
+    }
+
+    /// The most requests we've been answering at once.
+    pub fn peak_load(&self) -> usize {
+        self.load.peak.load(Ordering::SeqCst)
+    }
+
+    /// Have every plain HTTP request go through us, so that we can stand in for hosts we can't give a URL of our own.
+    pub fn proxy(&self) -> reqwest::Proxy {
+        reqwest::Proxy::http(self.url("")).unwrap()
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the listener up so that it notices
+        let _ = TcpStream::connect(self.address);
+    }
+}
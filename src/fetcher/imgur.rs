@@ -4,7 +4,8 @@ use eyre::{ensure, format_err, Result, WrapErr};
 use futures::stream;
 use tracing::{debug, trace};
 
-use super::Fetcher;
+use super::{Fetcher, Rejection};
+use crate::reddit::Post;
 
 #[derive(serde::Deserialize)]
 struct ImgurGallery {
@@ -14,55 +15,124 @@ struct ImgurGallery {
 #[derive(serde::Deserialize, Debug)]
 struct ImgurMedia {
     url: String,
+    /// Either `image` or `video`
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+}
+
+impl ImgurMedia {
+    /// Whether this is a still image, going by its type; older galleries don't say, and only ever held images.
+    fn is_image(&self) -> bool {
+        matches!(self.kind.as_deref(), None | Some("image"))
+    }
+}
+
+/// Pull a gallery's media out of its album page, as the images to fetch and how many videos were skipped over.
+fn gallery_media(body: &[u8]) -> Result<(Vec<ImgurMedia>, usize)> {
+    // Parse HTML and ensure there were no errors
+    let html = scraper::Html::parse_document(std::str::from_utf8(body).wrap_err("Body was not valid UTF-8.")?);
+    ensure!(html.errors.is_empty(), "html.errors was not empty");
+
+    // Extract a script tag containing the text "postDataJSON"
+    let script = html
+        .select(
+            &scraper::Selector::parse("script")
+                .map_err(|_| format_err!("Could not parse `script` selector. In other news, 1 = 2."))?,
+        )
+        .find(|tag| tag.text().any(|text| text.contains("postDataJSON")))
+        .ok_or_else(|| format_err!("Could not find postDataJSON in body."))?;
+    let text = script.text().collect::<String>();
+
+    // That script that will be of the format `window.postDataJSON = "..."`. We're
+    // interested in just the "..." bit, so extract that.
+    let start = text
+        .find(&['\'', '"'][..])
+        .ok_or_else(|| format_err!("Could not find starting quote"))?;
+    let end = text
+        .rfind(&['\'', '"'][..])
+        .ok_or_else(|| format_err!("Could not find ending quote"))?;
+    let code = &text[start..=end];
+
+    // Parse the javascript string as a String and then parse its contents as a gallery
+    let data: String = serde_json::from_str(code).wrap_err("Could not parse postDataJSON as a String")?;
+    let gallery: ImgurGallery =
+        serde_json::from_str(&data).wrap_err("Could not parse inner postDataJSON as a gallery")?;
+    trace!(?gallery.media, "parsed imgur gallery");
+
+    // Skip videos up front rather than downloading them only to find out they aren't images
+    let (media, videos): (Vec<_>, Vec<_>) = gallery.media.into_iter().partition(ImgurMedia::is_image);
+    Ok((media, videos.len()))
 }
 
 impl<'client> Fetcher<'client> {
     #[tracing::instrument(skip(self, body))]
     #[async_recursion(?Send)]
-    pub(super) async fn parse_imgur_gallery(&self, url: &str, body: Bytes) -> Result<()> {
-        // Parse HTML and ensure there were no errors
-        let html = scraper::Html::parse_document(std::str::from_utf8(&body).wrap_err("Body was not valid UTF-8.")?);
-        ensure!(html.errors.is_empty(), "html.errors was not empty");
-
-        // Extract a script tag containing the text "postDataJSON"
-        let script = html
-            .select(
-                &scraper::Selector::parse("script")
-                    .map_err(|_| format_err!("Could not parse `script` selector. In other news, 1 = 2."))?,
-            )
-            .find(|tag| tag.text().any(|text| text.contains("postDataJSON")))
-            .ok_or_else(|| format_err!("Could not find postDataJSON in body."))?;
-        let text = script.text().collect::<String>();
-
-        // That script that will be of the format `window.postDataJSON = "..."`. We're
-        // interested in just the "..." bit, so extract that.
-        let start = text
-            .find(&['\'', '"'][..])
-            .ok_or_else(|| format_err!("Could not find starting quote"))?;
-        let end = text
-            .rfind(&['\'', '"'][..])
-            .ok_or_else(|| format_err!("Could not find ending quote"))?;
-        let code = &text[start..=end];
-
-        // Parse the javascript string as a String and then parse its contents as a gallery
-        let data: String = serde_json::from_str(code).wrap_err("Could not parse postDataJSON as a String")?;
-        let gallery: ImgurGallery =
-            serde_json::from_str(&data).wrap_err("Could not parse inner postDataJSON as a gallery")?;
-        trace!(?gallery.media, "parsed imgur gallery");
-
-        // Make an iterator over the gallery's images
-        let url_amount = gallery.media.len();
-        let urls = gallery.media.into_iter().map(|media| media.url);
+    pub(super) async fn parse_imgur_gallery(&self, post: &Post, body: Bytes, ancestors: &[String]) -> Result<()> {
+        let (media, videos) = gallery_media(&body)?;
+        for _ in 0..videos {
+            self.rejections.record(Rejection::NotImage);
+        }
+
+        // Make an iterator over the gallery's images, which come from the same place as the gallery itself
+        let url_amount = media.len();
+        let posts = media.into_iter().map(|media| Post {
+            id: post.id.clone(),
+            url: media.url,
+            subreddit: post.subreddit.clone(),
+            title: post.title.clone(),
+            permalink: post.permalink.clone(),
+            score: post.score,
+            variants: Vec::new(),
+            ratio: post.ratio,
+        });
 
         // Fetch as many as we need
-        let touched = self.fetch_multiple(stream::iter(urls)).await?;
+        let mut chain = ancestors.to_vec();
+        chain.push(post.url.clone());
+        // Only download about as many images as we still need, instead of starting on the whole gallery at once
+        let concurrency = self.remaining().min(super::CONCURRENCY);
+        let touched = self.fetch_multiple(stream::iter(posts), &chain, concurrency).await?;
 
         // If we've touched all the images in the gallery, we've exhausted it and can therefore consider it "invalid"
         if touched >= url_amount {
-            debug!(%url, "exhausted imgur gallery");
-            self.invalid.insert(url.to_string()).await?;
+            debug!(url = %post.url, "exhausted imgur gallery");
+            self.invalid.insert(post.url.clone()).await?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_images_are_downloaded_out_of_imgur_galleries() {
+        // Trimmed down from a real album page: the gallery is a JSON string inside a script, next to other scripts
+        let page = r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Mountains - Album on Imgur</title>
+<script>window.__INITIAL_STATE__ = {"page":"gallery"};</script>
+<script>window.postDataJSON="{\"id\":\"Xy7Qa\",\"title\":\"Mountains\",\"is_album\":true,\"image_count\":3,\"media\":[{\"id\":\"a1B2c3\",\"type\":\"image\",\"mime_type\":\"image/jpeg\",\"url\":\"https://i.imgur.com/a1B2c3.jpeg\",\"width\":3840,\"height\":2160},{\"id\":\"d4E5f6\",\"type\":\"video\",\"mime_type\":\"video/mp4\",\"url\":\"https://i.imgur.com/d4E5f6.mp4\",\"width\":1920,\"height\":1080},{\"id\":\"g7H8i9\",\"url\":\"https://i.imgur.com/g7H8i9.png\",\"width\":2560,\"height\":1440}]}"</script>
+</head>
+<body><div id="root"></div></body>
+</html>"#;
+
+        let (images, videos) = gallery_media(page.as_bytes()).unwrap();
+        let urls = images.iter().map(|media| media.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            ["https://i.imgur.com/a1B2c3.jpeg", "https://i.imgur.com/g7H8i9.png"]
+        );
+        assert_eq!(videos, 1);
+    }
+
+    #[test]
+    fn pages_without_a_gallery_are_not_galleries() {
+        let page = "<!doctype html><html><head><title>Imgur</title></head><body></body></html>";
+        assert!(gallery_media(page.as_bytes()).is_err());
+    }
+}
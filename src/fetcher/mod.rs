@@ -1,7 +1,8 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsStr,
-    path::PathBuf,
-    sync::atomic::{AtomicUsize, Ordering},
+    path::{Path, PathBuf},
+    sync::Mutex,
 };
 
 use async_recursion::async_recursion;
@@ -9,144 +10,694 @@ use base64::prelude::*;
 use bytes::Bytes;
 use eyre::{bail, Result, WrapErr};
 use futures::prelude::*;
-use image::{imageops::FilterType::Lanczos3, ImageFormat};
+use image::{imageops::FilterType::Lanczos3, DynamicImage, ImageError, ImageFormat};
 use reqwest::Client;
 use tokio::fs;
-use tokio_stream::wrappers::ReadDirStream;
-use tracing::{debug, trace, trace_span};
+use tracing::{debug, trace, trace_span, warn};
 
 use crate::{
-    platform,
-    utils::{with_backoff, PersistentSet},
-    DIRS,
+    config::{Config, Mode, DEFAULT_PROFILE},
+    db::{MetadataRepo, StoredFile},
+    policy::{Fit, ImagePolicy, Reject},
+    processing::{self, Orientation},
+    reddit::Post,
+    sources::RatioRule,
+    utils::{with_backoff, Bandwidth, DeadPosts, ImageMetadata, PersistentSet, TASKS},
 };
 
-// This value is kinda arbitrary but there are 25 potential images in one reddit page
-const MAX_CACHED: usize = 25;
+// The biggest image we're willing to download, as some posts link to absurdly big originals
+const MAX_IMAGE_BYTES: u64 = 50 * 1024 * 1024;
 
-// The accepted difference between the screen's aspect ratio and a potential image's aspect ratio
-const ASPECT_RATIO_EPSILON: f64 = 0.01;
+// The most memory we're willing to use while decoding an image, to protect against decompression bombs
+const MAX_DECODE_BYTES: u64 = 512 * 1024 * 1024;
 
-// Which format to utilize for storing the images in the directory.
-const STORAGE_FORMAT: ImageFormat = ImageFormat::Png;
+// How many downloads we run at once
+const CONCURRENCY: usize = 25;
 
+// How long an encoded url may get before we name the file after its hash instead, keeping well clear of MAX_PATH
+const MAX_ENCODED_URL_LEN: usize = 128;
+
+// How many hex digits of the url's hash we use when we do
+const HASHED_URL_LEN: usize = 32;
+
+// How much we sharpen images we've had to scale up, as the blur radius and the smallest difference we sharpen
+const UPSCALE_SHARPEN_SIGMA: f32 = 0.8;
+const UPSCALE_SHARPEN_THRESHOLD: i32 = 2;
+
+// How many galleries deep we're willing to go; at 1, only top-level posts may be galleries
+const MAX_GALLERY_DEPTH: usize = 1;
+
+// How old a temporary file has to be before we're sure it was left behind by a crash rather than still being written
+const STALE_TEMP_FILE_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(thiserror::Error, Debug)]
+#[error("Image too large")]
+struct ImageTooLarge;
+
+/// We already have as many images for the monitors this one fits as we need, which says nothing about the image
+#[derive(thiserror::Error, Debug)]
+#[error("Enough images of its orientation already")]
+struct OrientationStocked(Orientation);
+
+/// Reddit's image hosts answer like this for images whose post was deleted, which won't come back by trying again
+#[derive(thiserror::Error, Debug)]
+#[error("Image was deleted ({0})")]
+struct DeadImage(reqwest::StatusCode);
+
+/// What we downloaded is neither an image nor a gallery we know of
+#[derive(thiserror::Error, Debug)]
+#[error("Unable to parse as anything known")]
+struct Unrecognized;
+
+/// What we got back from downloading an image
+enum Downloaded {
+    Body(Bytes),
+    /// The server told us the body is more than `MAX_IMAGE_BYTES`, so we didn't download it
+    TooLarge,
+    Dead(reqwest::StatusCode),
+}
+
+/// Why we refused to expand a gallery
 #[derive(thiserror::Error, Debug)]
-#[error("Aspect ratio not within epsilon ({iw}:{ih} instead of {sw}:{sh})")]
-struct InvalidAspectRatio {
-    iw: u32,
-    ih: u32,
-    sw: u32,
-    sh: u32,
+enum ExpansionError {
+    #[error("Gallery nested more than {MAX_GALLERY_DEPTH} deep")]
+    TooDeep,
+
+    #[error("Gallery refers back to itself")]
+    Cycle,
+}
+
+/// Get the directory holding the given profile's slice of the image cache
+pub fn images_dir(profile: &str) -> PathBuf {
+    crate::paths::root().join("images").join(profile)
+}
+
+/// Move images cached before profiles existed into the default profile's directory.
+pub fn migrate_flat_cache() -> Result<()> {
+    let root = crate::paths::root().join("images");
+    let dst = images_dir(DEFAULT_PROFILE);
+    std::fs::create_dir_all(&dst)?;
+
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            trace!(path = %entry.path().display(), "migrating cached image to default profile");
+            std::fs::rename(entry.path(), dst.join(entry.file_name())).wrap_err("Could not migrate cached image")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the file at `path` is an image still being written, or one that was when we crashed or got killed.
+pub fn is_partial_write(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(".tmp"))
 }
 
-/// Append a generated filename for an url to the given path buffer
-fn make_filename(url: &str, image_format: ImageFormat) -> PathBuf {
+/// Delete the images left half-written in every profile's directory by fetches that never got to finish, returning how
+/// many there were.
+pub fn remove_partial_writes() -> Result<usize> {
+    remove_partial_writes_in(&crate::paths::root().join("images"), std::time::SystemTime::now())
+}
+
+fn remove_partial_writes_in(images: &Path, now: std::time::SystemTime) -> Result<usize> {
+    let mut removed = 0;
+    for profile in std::fs::read_dir(images)? {
+        let profile = profile?.path();
+        if !profile.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&profile)? {
+            let entry = entry?;
+            // Another instance of us, e.g. `redditbg why`, may be running alongside a fetch
+            let stale = entry
+                .metadata()?
+                .modified()
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() >= STALE_TEMP_FILE_AGE);
+            if is_partial_write(&entry.path()) && stale {
+                trace!(path = %entry.path().display(), "removing leftover temporary file");
+                std::fs::remove_file(entry.path()).wrap_err("Could not remove leftover temporary file")?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Append a generated filename for an url to the given directory
+///
+/// The filename is the url itself when that's short enough, or a hash of it otherwise; either way it's recorded in the
+/// database, which is what `url_for_file` goes by.
+fn make_filename(dir: &Path, url: &str, image_format: ImageFormat) -> PathBuf {
     let mut s = BASE64_URL_SAFE_NO_PAD.encode(url.as_bytes());
+    if s.len() > MAX_ENCODED_URL_LEN {
+        s = blake3::hash(url.as_bytes()).to_hex()[..HASHED_URL_LEN].to_owned();
+    }
     s.push('.');
     s.push_str(image_format.extensions_str().first().unwrap_or(&"dat"));
-    DIRS.data_local_dir().join("images").join(s)
+    dir.join(s)
+}
+
+/// Count how many bytes the images in a profile's slice of the cache take up.
+pub fn cache_size(dir: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        total += entry?.metadata()?.len();
+    }
+    Ok(total)
+}
+
+/// Whether `set` holds `url`, taking it not to if we can't tell, as that only costs us looking at it again.
+async fn contains(set: &PersistentSet, url: &str) -> bool {
+    set.contains(url.to_owned()).await.unwrap_or_else(|error| {
+        warn!(?error, %url, "could not check whether url was seen");
+        false
+    })
+}
+
+/// Check that `url` is something we could download at all, so that odd links don't cost us a round trip.
+fn is_fetchable(url: &str) -> bool {
+    match reqwest::Url::parse(url) {
+        Ok(url) => matches!(url.scheme(), "http" | "https") && url.host_str().is_some_and(|host| !host.is_empty()),
+        Err(_) => false,
+    }
+}
+
+/// Recover the url the cached image at `path` was downloaded from
+pub fn url_for_file(metadata: &MetadataRepo, path: &Path) -> rusqlite::Result<Option<String>> {
+    let Some(filename) = path.file_name().and_then(OsStr::to_str) else {
+        return Ok(None);
+    };
+    if let Some(url) = metadata.url_for_file(filename)? {
+        return Ok(Some(url));
+    }
+
+    // Images cached before we recorded filenames have their url encoded in them
+    Ok(path
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .and_then(|s| BASE64_URL_SAFE_NO_PAD.decode(s.as_bytes()).ok())
+        .and_then(|buf| String::from_utf8(buf).ok()))
+}
+
+/// Whether a URL we got for a file means we downloaded it, rather than its name happening to decode to something.
+///
+/// Anything we downloaded has a record of it, even from before we recorded filenames, so a web URL alone isn't enough.
+pub fn is_downloaded(metadata: &MetadataRepo, url: &str) -> rusqlite::Result<bool> {
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        return Ok(false);
+    }
+    metadata.is_recorded(url)
+}
+
+/// Get the orientation of the monitor the cached image at `path` is meant for.
+///
+/// Images are stored cropped or blur-filled to their monitor's shape, so what we stored them as tells us best, then
+/// the shape of the original, and for images we never recorded anything about, the file's header.
+pub fn orientation_of_file(metadata: &MetadataRepo, path: &Path) -> rusqlite::Result<Option<Orientation>> {
+    let stored = match path.file_name().and_then(OsStr::to_str) {
+        Some(filename) => metadata.stored(filename)?,
+        None => None,
+    };
+    if let Some(stored) = stored {
+        return Ok(Some(Orientation::of(stored.width, stored.height)));
+    }
+    if let Some(url) = url_for_file(metadata, path)? {
+        if let Some(orientation) = metadata.orientation(&url)? {
+            return Ok(Some(orientation));
+        }
+    }
+    Ok(image::image_dimensions(path)
+        .ok()
+        .map(|(width, height)| Orientation::of(width, height)))
 }
 
-/// Count how many images we've got cached.
-async fn count_downloaded() -> Result<usize> {
-    let path = DIRS.data_local_dir().join("images");
-    Ok(ReadDirStream::new(fs::read_dir(path).await?)
-        .fold(0usize, |acc, _| future::ready(acc + 1))
-        .await)
+/// Count how many images of each orientation we've got cached in the given directory.
+///
+/// Files we can't make out the orientation of aren't counted, as the picker couldn't use them either.
+async fn count_downloaded(path: &Path, metadata: &ImageMetadata) -> Result<BTreeMap<Orientation, usize>> {
+    let mut counts = BTreeMap::new();
+    let mut dir = fs::read_dir(path).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if let Some(orientation) = metadata.orientation_of_file(entry.path()).await? {
+            *counts.entry(orientation).or_default() += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// An image that passed our checks, ready to be resized to the monitor it fits
+pub struct Evaluated {
+    pub image: DynamicImage,
+    /// The image's dimensions before being cropped to fit the monitor, or those of the canvas it was blur-filled onto
+    /// at its own scale
+    pub dimensions: (u32, u32),
+    /// The size of the monitor it fits
+    pub target: (u32, u32),
+    /// Whether it's meant to span several monitors, in which case no one monitor's size is right for it
+    pub span: bool,
+    /// Whether it's smaller than the monitor, and so has to be scaled up
+    pub upscaled: bool,
+    /// The format it was downloaded as
+    pub format: ImageFormat,
+}
+
+impl Evaluated {
+    /// Get the image as we store it: scaled to the monitor it fits, unless it's meant to span several.
+    pub fn scaled(&self) -> DynamicImage {
+        let (sw, sh) = self.target;
+        let mut scaled = if self.span {
+            self.image.clone()
+        } else {
+            self.image.resize(sw, sh, Lanczos3)
+        };
+        // Scaling up softens the image, which a little sharpening makes up for
+        if self.upscaled {
+            scaled = scaled.unsharpen(UPSCALE_SHARPEN_SIGMA, UPSCALE_SHARPEN_THRESHOLD);
+        }
+        scaled
+    }
+}
+
+/// Decode a downloaded body and check whether the policy accepts it as a background, without touching the cache.
+pub fn evaluate(config: &Config, policy: &ImagePolicy, rule: RatioRule, body: &[u8]) -> Result<Evaluated> {
+    // Try to guess the format from the body, returning early if it isn't an image.
+    let original_format = image::guess_format(body)?;
+    trace!(?original_format, "detected as image");
+
+    // Load the image, refusing to use too much memory doing so.
+    let mut reader = image::io::Reader::with_format(std::io::Cursor::new(body), original_format);
+    let mut limits = image::io::Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    reader.limits(limits);
+    let mut img = reader.decode().map_err(|error| match error {
+        ImageError::Limits(_) => eyre::Report::new(ImageTooLarge),
+        error => eyre::Report::new(error),
+    })?;
+
+    // Wide-gamut images would look washed out once stored without their color profile
+    #[cfg(feature = "color-management")]
+    if let Some(profile) = crate::color::icc_profile(body, original_format) {
+        match crate::color::to_srgb(img.clone(), &profile) {
+            Ok(converted) => img = converted,
+            Err(error) => debug!(?error, "could not convert to sRGB, keeping colors as they are"),
+        }
+    }
+
+    // Photos straight off a phone are often stored sideways, with a tag telling viewers how to rotate them
+    if let Some(orientation) = processing::exif_orientation(body) {
+        trace!(orientation, "applying EXIF orientation");
+        img = processing::apply_orientation(img, orientation);
+    }
+
+    // Get rid of any letterbox bars baked into the image, as they'd throw off the aspect ratio
+    let trimmed = processing::trim_borders(&img);
+    if (trimmed.width, trimmed.height) != (img.width(), img.height()) {
+        trace!(?trimmed, "trimming letterbox bars");
+        img = img.crop_imm(trimmed.x, trimmed.y, trimmed.width, trimmed.height);
+    }
+
+    // Ensure the aspect ratio of the image is similiar to the one of a monitor.
+    let (iw, ih) = (img.width(), img.height());
+    let ((sw, sh), fit) = policy.evaluate((iw, ih), Some(&img), rule).into_result()?;
+
+    let (dimensions, upscaled) = match fit {
+        // The bars don't count, so the image is as sharp as a canvas of the monitor's aspect ratio at its height
+        Fit::BlurFill => {
+            trace!("blur filling");
+            img = processing::blur_fill(&img, (sw, sh));
+            let width = (f64::from(ih) * f64::from(sw) / f64::from(sh)).round() as u32;
+            ((width, ih), ih < sh)
+        }
+
+        // Near misses are cropped around their subject if requested, and around their center otherwise. Images that
+        // already fit, or that span several monitors, are left whole.
+        Fit::Crop if config.smart_crop => {
+            let rect = processing::best_crop(&img, f64::from(sw) / f64::from(sh));
+            trace!(?rect, "smart cropping");
+            img = img.crop_imm(rect.x, rect.y, rect.width, rect.height);
+            ((iw, ih), img.width() < sw || img.height() < sh)
+        }
+        Fit::Crop => {
+            let rect = processing::center_crop(&img, f64::from(sw) / f64::from(sh));
+            trace!(?rect, "center cropping near miss");
+            img = img.crop_imm(rect.x, rect.y, rect.width, rect.height);
+            ((iw, ih), img.width() < sw || img.height() < sh)
+        }
+        Fit::AsIs => ((iw, ih), img.width() < sw || img.height() < sh),
+    };
+
+    // Images meant to span several monitors are kept at their own size, so there's nothing to scale up
+    let span = rule == RatioRule::Any;
+    Ok(Evaluated {
+        format: original_format,
+        upscaled: upscaled && !span,
+        span,
+        image: img,
+        dimensions,
+        target: (sw, sh),
+    })
 }
 
 struct Fetcher<'client> {
     downloaded: PersistentSet,
     invalid: PersistentSet,
-    gotten: AtomicUsize,
-    need: usize,
-    client: &'client Client,
+    dead: DeadPosts,
+    metadata: ImageMetadata,
+    bandwidth: Bandwidth,
+    /// What we accept, for the monitors we probe once per run so that a resolution change halfway through doesn't
+    /// leave us with images for two different screens
+    policy: ImagePolicy,
+    quota: QuotaTracker,
+    dir: PathBuf,
+    /// Our own copy of the client, so that we can swap it for an IPv4-only one halfway through
+    client: Mutex<Client>,
+    timeouts: TimeoutStreak,
+    rejections: Rejections,
+    config: &'client Config,
 }
 
 mod imgur;
+mod quota;
 mod reddit_gallery;
+mod report;
+mod timeouts;
+
+use quota::QuotaTracker;
+pub use report::{FetchReport, Rejection, Rejections};
+use timeouts::TimeoutStreak;
 
 impl<'client> Fetcher<'client> {
-    async fn new(client: &'client Client) -> Result<Fetcher<'client>> {
-        let downloaded = PersistentSet::new("downloaded").await?;
+    /// Get ready to fetch for `profile`, judging images by `policy` for the whole run.
+    async fn new(
+        client: &Client,
+        config: &'client Config,
+        profile: &str,
+        policy: ImagePolicy,
+    ) -> Result<Fetcher<'client>> {
+        // The default profile keeps the rows from before profiles existed
+        let downloaded = if profile == DEFAULT_PROFILE {
+            PersistentSet::new("downloaded").await?
+        } else {
+            PersistentSet::new(format!("downloaded/{profile}")).await?
+        };
         let invalid = PersistentSet::new("invalid").await?;
-        let need = MAX_CACHED.saturating_sub(count_downloaded().await?);
+        let dead = DeadPosts::new().await?;
+        let metadata = ImageMetadata::new().await?;
+        let bandwidth = Bandwidth::new().await?;
+        let dir = images_dir(profile);
+        fs::create_dir_all(&dir).await?;
+
+        // Keep each orientation stocked separately, so that a portrait monitor doesn't go without because the cache is
+        // full of landscape images
+        let cached = count_downloaded(&dir, &metadata).await?;
+        let need = policy
+            .monitors()
+            .iter()
+            .map(|&(sw, sh)| {
+                let orientation = Orientation::of(sw, sh);
+                let have = cached.get(&orientation).copied().unwrap_or_default();
+                (orientation, config.max_cached.saturating_sub(have))
+            })
+            .collect::<BTreeMap<_, _>>();
+        trace!(?cached, ?need, "counted cached images");
+
+        // The archive keeps everything, so there's no end to how much we'd download without a daily cap
+        let need = if config.mode == Mode::Archive {
+            let mut left = config
+                .archive_daily_images
+                .saturating_sub(downloaded.count_today().await?);
+            trace!(left, "capping downloads to what's left of today's quota");
+            need.into_iter()
+                .map(|(orientation, need)| {
+                    let capped = need.min(left);
+                    left -= capped;
+                    (orientation, capped)
+                })
+                .collect()
+        } else {
+            need
+        };
+
         Ok(Self {
             downloaded,
             invalid,
-            need,
-            gotten: AtomicUsize::new(0),
-            client,
+            dead,
+            metadata,
+            bandwidth,
+            policy,
+            quota: QuotaTracker::new(need),
+            dir,
+            client: Mutex::new(client.clone()),
+            timeouts: TimeoutStreak::new(config.prefer_ipv4),
+            rejections: Rejections::default(),
+            config,
         })
     }
 
     #[tracing::instrument(skip(self, body))]
-    async fn parse_raw_image(&self, url: &str, body: Bytes) -> Result<()> {
-        // Try to guess the format from the body, returning early if it isn't an image.
-        let original_format = image::guess_format(&body)?;
-        trace!(?original_format, "detected as image");
-
-        // Load the image and ensure the aspect ratio of the image is similiar to the one of the screen.
-        let img = image::load_from_memory_with_format(&body, original_format)?;
-        let (iw, ih) = (img.width(), img.height());
-        let (sw, sh) = platform::screen_size()?;
-        if (f64::from(iw) / f64::from(ih) - f64::from(sw) / f64::from(sh)).abs() > ASPECT_RATIO_EPSILON {
-            bail!(InvalidAspectRatio { iw, ih, sw, sh });
+    async fn parse_raw_image(&self, post: &Post, body: Bytes) -> Result<()> {
+        // Identical bytes often show up under different URLs, in which case we don't need to decode them to know
+        // whether they'll fit the screen
+        let body_hash = xxhash_rust::xxh3::xxh3_64(&body);
+        if let Some((iw, ih)) = self.metadata.probed(body_hash).await? {
+            trace!(body_hash, iw, ih, "seen these bytes before");
+            self.policy.evaluate((iw, ih), None, post.ratio).into_result()?;
+        }
+
+        let evaluated = evaluate(self.config, &self.policy, post.ratio, &body);
+        let probed = match evaluated {
+            Ok(Evaluated { dimensions, .. }) => Some(dimensions),
+            Err(ref error) => error.downcast_ref::<Reject>().map(|reject| reject.dimensions()),
+        };
+        if let Some((iw, ih)) = probed {
+            self.metadata.insert_probed(body_hash, iw, ih).await?;
         }
+        let evaluated = evaluated?;
+        let ((iw, ih), (sw, sh), upscaled) = (evaluated.dimensions, evaluated.target, evaluated.upscaled);
+        let format = processing::storage_format(evaluated.format, self.config.force_png);
 
+        // Claim a spot before writing anything, so that downloads finishing at once can't overfill an orientation, but
+        // give it back if we don't end up with a file the picker can use
+        let orientation = Orientation::of(sw, sh);
+        if !self.quota.try_reserve(orientation) {
+            bail!(OrientationStocked(orientation));
+        }
+        let result = self.persist(post, evaluated, format, (iw, ih), upscaled).await;
+        if result.is_err() {
+            self.quota.release(orientation);
+        }
+        result
+    }
+
+    /// Write an image we've accepted to the cache, and record everything we know about it.
+    async fn persist(
+        &self,
+        post: &Post,
+        evaluated: Evaluated,
+        format: ImageFormat,
+        (iw, ih): (u32, u32),
+        upscaled: bool,
+    ) -> Result<()> {
         // Now let's spawn a blocking task that resizes our image and persists it to a temporary
         // file. We do this in a separate task due to two advantages it has:
         // 1) the runtime isn't blocked on the CPU-heavy task of resizing the image;
         // 2) blocking tasks can not be canceled so we won't get half-written images.
-        let dst = make_filename(url, STORAGE_FORMAT);
-        tokio::task::spawn_blocking({
-            move || -> Result<()> {
-                use std::io::prelude::*;
-                let _span = trace_span!("writing fetched image", dst = %dst.display()).entered();
-                let mut file = tempfile::NamedTempFile::new()?;
-                trace!(tmp_path = %file.path().display(), "created temporary file");
-                img.resize(sw, sh, Lanczos3)
-                    .write_to(&mut file, STORAGE_FORMAT)
-                    .wrap_err("failed to write image")?;
-                trace!("flushing temporary file");
-                file.flush().wrap_err("failed to flush")?;
-                trace!("persisting temporary file");
-                file.persist(dst).wrap_err("failed to persist")?;
-                Ok(())
+        let dst = make_filename(&self.dir, &post.url, format);
+        let filename = dst.file_name().and_then(OsStr::to_str).map(str::to_owned);
+        if let Some(ref filename) = filename {
+            self.metadata.insert_file(filename.clone(), post.url.clone()).await?;
+        }
+        let stored = TASKS
+            .spawn_blocking("write image", {
+                move || -> Result<StoredFile> {
+                    use std::io::prelude::*;
+                    let _span = trace_span!("writing fetched image", dst = %dst.display()).entered();
+                    // The temporary file has to be on the same volume for persisting it to be a rename, so we put it
+                    // right next to where it's going instead of in the system's temporary directory
+                    let dir = dst
+                        .parent()
+                        .ok_or_else(|| eyre::format_err!("Destination has no parent"))?;
+                    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+                    trace!(tmp_path = %file.path().display(), "created temporary file");
+                    let resized = evaluated.scaled();
+                    processing::encode(&resized, &mut file, format).wrap_err("failed to write image")?;
+                    trace!("flushing temporary file");
+                    file.flush().wrap_err("failed to flush")?;
+                    let bytes = file.as_file().metadata()?.len();
+                    trace!("persisting temporary file");
+                    file.persist(dst).wrap_err("failed to persist")?;
+                    Ok(StoredFile {
+                        format,
+                        width: resized.width(),
+                        height: resized.height(),
+                        bytes,
+                        upscaled,
+                    })
+                }
+            })
+            .await??;
+        // The picker would have no use for a file we can't record the source of
+        if let Err(error) = self.record_persisted(post, filename, stored, (iw, ih)).await {
+            if let Err(error) = fs::remove_file(make_filename(&self.dir, &post.url, format)).await {
+                warn!(?error, url = %post.url, "could not remove image we failed to record");
             }
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Record everything we know about a file we've just persisted.
+    async fn record_persisted(
+        &self,
+        post: &Post,
+        filename: Option<String>,
+        stored: StoredFile,
+        (iw, ih): (u32, u32),
+    ) -> Result<()> {
+        // Saves the picker from guessing the format, and anything else from decoding the file just to learn its size
+        if let Some(filename) = filename {
+            self.metadata.insert_stored(filename, post.url.clone(), stored).await?;
+        }
+
+        // Remember how big the image originally was, so that the picker can prefer sharper images and match it to
+        // a monitor's orientation, and where it came from.
+        self.metadata.insert_dimensions(post.url.clone(), iw, ih).await?;
+        self.metadata
+            .insert_source(
+                post.url.clone(),
+                post.subreddit.clone(),
+                post.title.clone(),
+                post.permalink.clone(),
+                post.score,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// How many more images we need to download, across every orientation that still needs some.
+    fn remaining(&self) -> usize {
+        self.quota.remaining()
+    }
+
+    /// Download the body at `url`, refusing to download more than `MAX_IMAGE_BYTES`.
+    async fn download(&self, url: &str) -> Result<Bytes> {
+        let client = self.client.lock().unwrap().clone();
+        let result = with_backoff(|| {
+            client
+                .get(url)
+                .header("Accept", "image/*")
+                .send()
+                .and_then(|response| async move {
+                    let status = response.status();
+                    let from_reddit = matches!(response.url().host_str(), Some("i.redd.it" | "preview.redd.it"));
+                    if from_reddit && matches!(status.as_u16(), 403 | 404) {
+                        return Ok(Downloaded::Dead(status));
+                    }
+
+                    // Don't even start downloading bodies we know are too big
+                    match response.content_length() {
+                        Some(length) if length > MAX_IMAGE_BYTES => Ok(Downloaded::TooLarge),
+                        _ => response.bytes().await.map(Downloaded::Body),
+                    }
+                })
         })
-        .await??;
+        .await;
+        match result {
+            Err(ref error) if error.is_timeout() => self.record_timeout()?,
+            Err(_) => {}
+            Ok(_) => self.timeouts.succeeded(),
+        }
+        let body = result.wrap_err_with(|| format!("Failed to fetch {url:?}"))?;
 
-        // If we get here, we've successfully persisted an image to disk and we can add it to the `gotten` count.
-        self.gotten.fetch_add(1, Ordering::AcqRel);
+        // The server may not have told us the length up front, or it may have lied
+        let body = match body {
+            Downloaded::Body(body) if body.len() as u64 <= MAX_IMAGE_BYTES => body,
+            Downloaded::Body(_) | Downloaded::TooLarge => bail!(ImageTooLarge),
+            Downloaded::Dead(status) => bail!(DeadImage(status)),
+        };
+        trace!(size = body.len(), "got body");
+        self.bandwidth.record(body.len() as u64).await?;
+        Ok(body)
+    }
 
+    /// Count a download that timed out, switching to IPv4 for the rest of the run if they keep doing so.
+    ///
+    /// Happy eyeballs doesn't help when IPv6 connects fine but then stalls, which some ISPs manage.
+    fn record_timeout(&self) -> Result<()> {
+        if let Some(timeouts) = self.timeouts.timed_out() {
+            warn!(
+                timeouts,
+                "downloads keep timing out, switching to IPv4 for the rest of this run"
+            );
+            *self.client.lock().unwrap() = crate::build_client(self.config, true)?;
+        }
         Ok(())
     }
 
+    /// Fetch the largest of the post's resized variants that still covers a monitor, in place of the original.
+    async fn fetch_variant(&self, post: &Post) -> Result<()> {
+        let mut variants = post
+            .variants
+            .iter()
+            .filter(|variant| self.policy.covers((variant.width, variant.height), post.ratio))
+            .collect::<Vec<_>>();
+        variants.sort_by_key(|variant| std::cmp::Reverse(u64::from(variant.width) * u64::from(variant.height)));
+
+        for variant in variants {
+            trace!(?variant, "trying variant");
+            // We save the variant under the post's URL, so that it counts as downloading the post itself
+            let result = match self.download(&variant.url).await {
+                Ok(body) => self.parse_raw_image(post, body).await,
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(error) => trace!(?error, "variant failed"),
+            }
+        }
+
+        bail!(ImageTooLarge);
+    }
+
     /// Download one image into its place
+    ///
+    /// `ancestors` holds the URLs of the galleries we're expanding to get to this post, outermost first.
     #[tracing::instrument(skip(self))]
     #[async_recursion(?Send)]
-    async fn fetch_one(&self, url: String) -> Result<()> {
-        // We create a closure as a pseudo-try block.
-        let result = (|| async {
-            // Fetch the url's body
-            let body: Bytes = with_backoff(|| {
-                self.client
-                    .get(&url)
-                    .header("Accept", "image/*")
-                    .send()
-                    .and_then(reqwest::Response::bytes)
-            })
-            .await
-            .wrap_err_with(|| format!("Failed to fetch {url:?}"))?;
-            trace!(size = body.len(), "got body");
+    async fn fetch_one(&self, post: Post, ancestors: &[String]) -> Result<()> {
+        let url = &post.url;
+
+        // An async block serves as a pseudo-try block.
+        let result = async {
+            // Make sure galleries linking to galleries can't send us around in circles
+            if ancestors.contains(url) {
+                bail!(ExpansionError::Cycle);
+            }
+
+            // Fetch the url's body, falling back to Reddit's resized versions if it's too big
+            let body = match self.download(url).await {
+                Ok(body) => body,
+                Err(error) if error.is::<ImageTooLarge>() => return self.fetch_variant(&post).await,
+                Err(error) => return Err(error),
+            };
 
             // Try to parse it as a raw image.
-            match self.parse_raw_image(&url, body.clone()).await {
+            match self.parse_raw_image(&post, body.clone()).await {
                 Ok(()) => return Ok(()),
                 Err(error) => {
-                    if let Some(InvalidAspectRatio { .. }) = error.downcast_ref() {
-                        trace!(%error, "failed direct image check due to aspect ratio, bailing");
+                    // Reddit's resized versions of images with more pixels than we'll take may have few enough
+                    if error.is::<ImageTooLarge>() || matches!(error.downcast_ref(), Some(Reject::TooManyPixels { .. }))
+                    {
+                        trace!(%error, "image too large, trying variants");
+                        return self.fetch_variant(&post).await;
+                    }
+
+                    if error.is::<Reject>() {
+                        trace!(%error, "failed direct image check due to its dimensions, bailing");
                         return Err(error);
                     }
 
@@ -154,8 +705,13 @@ impl<'client> Fetcher<'client> {
                 }
             }
 
+            // Only go as deep as we're willing to when it comes to galleries.
+            if ancestors.len() >= MAX_GALLERY_DEPTH {
+                bail!(ExpansionError::TooDeep);
+            }
+
             // Try to parse it as an imgur gallery.
-            match self.parse_imgur_gallery(&url, body.clone()).await {
+            match self.parse_imgur_gallery(&post, body.clone(), ancestors).await {
                 Ok(..) => return Ok(()),
                 Err(error) => {
                     trace!(?error, "failed imgur gallery check");
@@ -163,7 +719,7 @@ impl<'client> Fetcher<'client> {
             }
 
             // Try to parse it as a reddit gallery.
-            match self.parse_reddit_gallery(&url, body.clone()).await {
+            match self.parse_reddit_gallery(&post, body.clone(), ancestors).await {
                 Ok(..) => return Ok(()),
                 Err(error) => {
                     trace!(?error, "failed reddit gallery check");
@@ -171,14 +727,22 @@ impl<'client> Fetcher<'client> {
             }
 
             // If we get here, we've no idea what this URL is.
-            bail!("Unable to parse as anything known");
-        })()
+            bail!(Unrecognized);
+        }
         .await;
 
-        // Having collected the result, if we got an error log it and mark this URL as invalid.
+        // Having collected the result, if we got an error log it and mark this URL as invalid, unless it was only
+        // turned down for now as we had enough like it.
         if let Err(ref error) = result {
             debug!(%url, ?error, "failed fetching");
-            self.invalid.insert(url).await?;
+            self.rejections.record(Rejection::of(error));
+            if !error.is::<OrientationStocked>() {
+                self.invalid.insert(url.clone()).await?;
+            }
+            // Only the post's own link tells us the post is gone, as a gallery can lose some of its images
+            if error.is::<DeadImage>() && ancestors.is_empty() && !post.id.is_empty() {
+                self.dead.insert(post.id.clone()).await?;
+            }
         }
 
         result
@@ -186,35 +750,65 @@ impl<'client> Fetcher<'client> {
 
     #[tracing::instrument(skip_all)]
     #[async_recursion(?Send)]
-    async fn fetch_multiple<Urls>(&self, urls: Urls) -> Result<usize>
+    async fn fetch_multiple<Posts>(&self, posts: Posts, ancestors: &[String], concurrency: usize) -> Result<usize>
     where
-        Urls: Stream<Item = String> + Unpin,
+        Posts: Stream<Item = Post> + Unpin,
     {
-        // Iterate over the given URLs, counting how many we "touch".
+        // Iterate over the given posts, counting how many we "touch".
         let mut touched = 0;
         {
-            let mut futures = std::pin::pin!(urls
+            let mut futures = std::pin::pin!(posts
                 .inspect(|_| touched += 1)
+                // Skip over links that aren't web URLs, without remembering them as invalid as they'd never be fetched
+                .filter(|post| {
+                    let fetchable = is_fetchable(&post.url);
+                    if !fetchable {
+                        trace!(url = %post.url, "skipping url we can't fetch");
+                        self.rejections.record(Rejection::Unfetchable);
+                    }
+                    future::ready(fetchable)
+                })
+                // Skip over posts we know were deleted, whatever their link is now
+                .filter(|post| {
+                    let id = post.id.clone();
+                    let check = ancestors.is_empty() && !id.is_empty();
+                    async move {
+                        // Not knowing only costs us a download, which tells us again if it's gone
+                        let dead = check
+                            && self.dead.contains(id.clone()).await.unwrap_or_else(|error| {
+                                warn!(?error, %id, "could not check whether post was deleted");
+                                false
+                            });
+                        if dead {
+                            trace!(%id, "skipping deleted post");
+                            self.rejections.record(Rejection::Deleted);
+                        }
+                        !dead
+                    }
+                })
                 // Skip over URLs we've already examined
-                .filter(|url| {
-                    let url = url.clone();
+                .filter(|post| {
+                    let url = post.url.clone();
                     async move {
-                        let downloaded = self.downloaded.contains(url.clone()).await.unwrap();
-                        let invalid = self.invalid.contains(url.clone()).await.unwrap();
+                        let downloaded = contains(&self.downloaded, &url).await;
+                        let invalid = contains(&self.invalid, &url).await;
                         trace!(%url, downloaded, invalid, "url status");
+                        if downloaded || invalid {
+                            self.rejections.record(Rejection::AlreadySeen);
+                        }
                         !(downloaded || invalid)
                     }
                 })
                 // Start fetching the specfic URLs themselves
-                .map(|url| self.fetch_one(url))
-                // Instead of polling in order, take a block of 25 and poll them all at once
-                .buffer_unordered(25));
+                .map(|post| self.fetch_one(post, ancestors))
+                // Instead of polling in order, take a block of them and poll them all at once
+                .buffer_unordered(concurrency.max(1)));
 
             // Iterate over the futures as they complete and stop once we've gotten enough.
             while let Some(res) = futures.next().await {
-                let gotten = self.gotten.load(Ordering::Acquire);
-                trace!(gotten, success = res.is_ok(), "future completed");
-                if gotten >= self.need {
+                let remaining = self.remaining();
+                trace!(remaining, success = res.is_ok(), "future completed");
+                if remaining == 0 {
                     break;
                 }
             }
@@ -222,41 +816,442 @@ impl<'client> Fetcher<'client> {
         Ok(touched)
     }
 
+    /// Fetch as many images as we need, reporting how many we got and what we turned down.
     #[tracing::instrument(skip_all)]
-    async fn fetch_toplevel<Urls>(self, urls: Urls) -> Result<()>
+    async fn fetch_toplevel<Posts>(self, posts: Posts) -> Result<FetchReport>
     where
-        Urls: Stream<Item = String> + Unpin,
+        Posts: Stream<Item = Post> + Unpin,
     {
         // If we don't need anything, bail!
-        if self.need == 0 {
-            return Ok(());
+        if self.remaining() == 0 {
+            return Ok(FetchReport::default());
         }
 
         // Offload actual fetching to `fetch_multiple`.
-        self.fetch_multiple(urls).await?;
+        self.fetch_multiple(posts, &[], CONCURRENCY).await?;
 
-        // Add that which we've downloaded to our database
-        let mut dir = tokio::fs::read_dir(DIRS.data_local_dir().join("images")).await?;
+        // Add that which we've downloaded to our database, all at once so that we only hit the disk once
+        let mut urls = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
         while let Some(entry) = dir.next_entry().await? {
-            if let Some(url) = entry
-                .path()
-                .file_stem()
-                .and_then(OsStr::to_str)
-                .and_then(|s| BASE64_URL_SAFE_NO_PAD.decode(s.as_bytes()).ok())
-                .and_then(|buf| String::from_utf8(buf).ok())
-            {
-                self.downloaded.insert(url).await?;
+            if let Some(url) = self.metadata.url_for_file(entry.path()).await? {
+                urls.push(url);
             }
         }
+        self.downloaded.insert_many(urls).await?;
 
-        Ok(())
+        Ok(FetchReport {
+            fetched: self.quota.gotten(),
+            rejections: self.rejections.into_histogram(),
+        })
     }
 }
 
 #[tracing::instrument(skip_all)]
-pub async fn fetch<Urls>(client: &Client, urls: Urls) -> Result<()>
+pub async fn fetch<Posts>(client: &Client, config: &Config, profile: &str, posts: Posts) -> Result<FetchReport>
 where
-    Urls: Stream<Item = String> + Unpin,
+    Posts: Stream<Item = Post> + Unpin,
 {
-    Fetcher::new(client).await?.fetch_toplevel(urls).await
+    let policy = ImagePolicy::current()?.configure(config);
+    Fetcher::new(client, config, profile, policy)
+        .await?
+        .fetch_toplevel(posts)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a gradient of the given size as a PNG, as if we'd downloaded it, with nothing that looks like a border.
+    fn fixture((width, height): (u32, u32)) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }));
+        let mut body = Vec::new();
+        image
+            .write_to(&mut std::io::Cursor::new(&mut body), image::ImageOutputFormat::Png)
+            .unwrap();
+        body
+    }
+
+    #[test]
+    fn only_stale_temporary_files_are_cleaned_up() {
+        let images = tempfile::tempdir().unwrap();
+        let profile = images.path().join(DEFAULT_PROFILE);
+        std::fs::create_dir_all(&profile).unwrap();
+        let now = std::time::SystemTime::now();
+        for (name, age) in [
+            (".tmpCrashed", STALE_TEMP_FILE_AGE),
+            (".tmpWriting", std::time::Duration::from_secs(1)),
+            ("https%3A%2F%2Fi.redd.it%2Fa.png", STALE_TEMP_FILE_AGE),
+        ] {
+            let file = std::fs::File::create(profile.join(name)).unwrap();
+            file.set_modified(now - age).unwrap();
+        }
+        std::fs::write(images.path().join(".tmpNotInAProfile"), "").unwrap();
+
+        assert_eq!(remove_partial_writes_in(images.path(), now).unwrap(), 1);
+        assert!(!profile.join(".tmpCrashed").exists());
+        assert!(profile.join(".tmpWriting").exists());
+        assert!(profile.join("https%3A%2F%2Fi.redd.it%2Fa.png").exists());
+    }
+
+    #[test]
+    fn only_web_urls_with_a_host_are_fetched() {
+        for url in [
+            "https://i.redd.it/a.png",
+            "http://i.imgur.com/a.jpg",
+            // Surrounding whitespace is dropped by the parser, and so by reqwest too
+            " https://i.redd.it/a.png\n",
+        ] {
+            assert!(is_fetchable(url), "{}", url);
+        }
+        for url in [
+            "javascript:alert(1)",
+            "data:image/png;base64,iVBORw0KGgo=",
+            "ftp://ftp.example.com/a.png",
+            "mailto:someone@example.com",
+            "http://",
+            "https://:443/a.png",
+            "/r/wallpapers/comments/abc/",
+            "a.png",
+            "",
+            "   ",
+            "https://i.redd .it/a.png",
+        ] {
+            assert!(!is_fetchable(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn very_long_urls_get_hashed_filenames_that_map_back_to_them() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        let metadata = MetadataRepo::new(&conn);
+        // About as deep as the cache gets on Windows
+        let dir = Path::new(r"C:\Users\a.long.user.name\AppData\Local\PurpleMyst\redditbg\data\images\default");
+        let url = format!("https://i.redd.it/{}.png", "a".repeat(1500));
+
+        let path = make_filename(dir, &url, ImageFormat::Png);
+        assert_eq!(path, make_filename(dir, &url, ImageFormat::Png));
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(filename.len(), HASHED_URL_LEN + ".png".len());
+        assert!(path.as_os_str().len() < 260, "{}", path.display());
+
+        // Nothing can be recovered from the hash itself, only from what we recorded
+        assert_eq!(url_for_file(&metadata, &path).unwrap(), None);
+        metadata.insert_file(filename, &url).unwrap();
+        assert_eq!(url_for_file(&metadata, &path).unwrap(), Some(url));
+
+        // Short ones are still named after the URL itself
+        let short = make_filename(dir, "https://i.redd.it/a.png", ImageFormat::Png);
+        assert_eq!(
+            url_for_file(&metadata, &short).unwrap().as_deref(),
+            Some("https://i.redd.it/a.png")
+        );
+    }
+
+    #[test]
+    fn cached_images_go_by_their_own_shape_rather_than_the_primary_monitor() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        let metadata = MetadataRepo::new(&conn);
+        let dir = tempfile::tempdir().unwrap();
+
+        // What we stored wins, as a portrait image may have been blur-filled for a landscape monitor
+        let stored = make_filename(dir.path(), "https://i.redd.it/stored.png", ImageFormat::Png);
+        std::fs::write(&stored, fixture((160, 90))).unwrap();
+        metadata
+            .insert_dimensions("https://i.redd.it/stored.png", 90, 160)
+            .unwrap();
+        let dims = StoredFile {
+            format: ImageFormat::Png,
+            width: 160,
+            height: 90,
+            bytes: 0,
+            upscaled: false,
+        };
+        let filename = stored.file_name().unwrap().to_str().unwrap();
+        metadata
+            .insert_stored(filename, "https://i.redd.it/stored.png", dims)
+            .unwrap();
+        // Then the original's shape, then the file itself
+        let recorded = make_filename(dir.path(), "https://i.redd.it/recorded.png", ImageFormat::Png);
+        std::fs::write(&recorded, fixture((160, 90))).unwrap();
+        metadata
+            .insert_dimensions("https://i.redd.it/recorded.png", 90, 160)
+            .unwrap();
+        let legacy = make_filename(dir.path(), "https://i.redd.it/legacy.png", ImageFormat::Png);
+        std::fs::write(&legacy, fixture((90, 160))).unwrap();
+
+        let of = |path: &Path| orientation_of_file(&metadata, path).unwrap();
+        assert_eq!(of(&stored), Some(Orientation::Landscape));
+        assert_eq!(of(&recorded), Some(Orientation::Portrait));
+        assert_eq!(of(&legacy), Some(Orientation::Portrait));
+        assert_eq!(of(&dir.path().join("missing.png")), None);
+    }
+
+    #[test]
+    fn ultra_wide_images_only_pass_for_sources_that_allow_any_ratio() {
+        let config = Config::default();
+        let policy = ImagePolicy::new(vec![(160, 90)]).configure(&config);
+        let body = fixture((640, 90));
+
+        let error = evaluate(&config, &policy, RatioRule::Strict, &body).err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(Reject::AspectRatio { .. })));
+
+        let evaluated = evaluate(&config, &policy, RatioRule::Any, &body).unwrap();
+        assert!(evaluated.span);
+        assert!(!evaluated.upscaled);
+        assert_eq!(evaluated.target, (160, 90));
+    }
+
+    #[test]
+    fn undersized_images_are_marked_as_upscaled() {
+        let config = Config {
+            allow_upscale_below: Some(0.9),
+            ..Config::default()
+        };
+        let policy = ImagePolicy::new(vec![(160, 90)]).configure(&config);
+
+        let full_size = evaluate(&config, &policy, RatioRule::Strict, &fixture((160, 90))).unwrap();
+        assert!(!full_size.upscaled);
+        let undersized = evaluate(&config, &policy, RatioRule::Strict, &fixture((144, 81))).unwrap();
+        assert!(undersized.upscaled);
+        let scaled = undersized.scaled();
+        assert_eq!((scaled.width(), scaled.height()), (160, 90));
+
+        let error = evaluate(&config, &policy, RatioRule::Strict, &fixture((128, 72)))
+            .err()
+            .unwrap();
+        assert!(matches!(error.downcast_ref(), Some(Reject::TooSmall { .. })));
+    }
+
+    #[test]
+    fn images_that_span_monitors_are_stored_at_their_own_size() {
+        let config = Config::default();
+        let policy = ImagePolicy::new(vec![(160, 90)]).configure(&config);
+
+        let spanning = evaluate(&config, &policy, RatioRule::Any, &fixture((640, 90))).unwrap();
+        let scaled = spanning.scaled();
+        assert_eq!((scaled.width(), scaled.height()), (640, 90));
+
+        let fitting = evaluate(&config, &policy, RatioRule::Strict, &fixture((320, 180))).unwrap();
+        let scaled = fitting.scaled();
+        assert_eq!((scaled.width(), scaled.height()), (160, 90));
+    }
+
+    #[test]
+    fn smart_cropping_leaves_images_that_already_fit_alone() {
+        let config = Config {
+            smart_crop: true,
+            ..Config::default()
+        };
+        let policy = ImagePolicy::new(vec![(160, 90)]).configure(&config);
+
+        let spanning = evaluate(&config, &policy, RatioRule::Any, &fixture((640, 90))).unwrap();
+        assert_eq!((spanning.image.width(), spanning.image.height()), (640, 90));
+        let fitting = evaluate(&config, &policy, RatioRule::Strict, &fixture((320, 180))).unwrap();
+        assert_eq!((fitting.image.width(), fitting.image.height()), (320, 180));
+
+        // Near misses still get cropped down to the monitor's aspect ratio
+        let near_miss = evaluate(&config, &policy, RatioRule::Strict, &fixture((360, 180))).unwrap();
+        assert_eq!((near_miss.image.width(), near_miss.image.height()), (320, 180));
+    }
+
+    #[test]
+    fn deleted_images_are_asked_for_once_and_their_posts_skipped_from_then_on() {
+        use crate::fake_server::{FakeServer, Response};
+
+        std::fs::create_dir_all(crate::DIRS.data_local_dir()).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let config = Config::default();
+        let profile = "dead-images";
+
+        // Reddit's hosts only say an image was deleted with the status, so the server stands in for them as a proxy
+        let server = FakeServer::builder()
+            .route("/deleted.png", Response::status(403))
+            .route("/missing.png", Response::status(404))
+            .route("/reposted.png", Response::png((1920, 1080)))
+            .start();
+        let client = Client::builder().proxy(server.proxy()).build().unwrap();
+        let post = |id: &str, url: &str| Post {
+            id: id.to_owned(),
+            url: url.to_owned(),
+            subreddit: "wallpapers".to_owned(),
+            title: String::new(),
+            permalink: None,
+            score: 0,
+            variants: Vec::new(),
+            ratio: RatioRule::default(),
+        };
+        let fetch = |posts: Vec<Post>| {
+            runtime
+                .block_on(fetch(&client, &config, profile, stream::iter(posts)))
+                .unwrap()
+        };
+
+        let report = fetch(vec![
+            post("deleted", "http://i.redd.it/deleted.png"),
+            post("missing", "http://preview.redd.it/missing.png"),
+        ]);
+        assert_eq!(report.fetched, 0);
+        assert_eq!(report.rejections, BTreeMap::from([(Rejection::Deleted, 2)]));
+        assert_eq!(server.hits("/deleted.png"), 1);
+        assert_eq!(server.hits("/missing.png"), 1);
+
+        let db = crate::db::open().unwrap();
+        let dead = crate::db::DeadPostsRepo::new(&db);
+        assert!(dead.contains("deleted").unwrap());
+        assert!(dead.contains("missing").unwrap());
+
+        // The posts are skipped whatever they link to now, without asking for anything
+        let report = fetch(vec![post("deleted", "http://i.redd.it/reposted.png")]);
+        assert_eq!(report.fetched, 0);
+        assert_eq!(report.rejections, BTreeMap::from([(Rejection::Deleted, 1)]));
+        assert_eq!(server.hits("/reposted.png"), 0);
+    }
+
+    #[test]
+    fn bytes_seen_before_are_judged_without_being_decoded() {
+        std::fs::create_dir_all(crate::DIRS.data_local_dir()).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let config = Config::default();
+        let client = Client::new();
+        let policy = ImagePolicy::current().unwrap().configure(&config);
+        let fetcher = runtime
+            .block_on(Fetcher::new(&client, &config, "probed-bytes", policy))
+            .unwrap();
+        let post = |url: &str| Post {
+            id: String::new(),
+            url: url.to_owned(),
+            subreddit: "wallpapers".to_owned(),
+            title: String::new(),
+            permalink: None,
+            score: 0,
+            variants: Vec::new(),
+            ratio: RatioRule::default(),
+        };
+        let parse = |url: &str, body: &[u8]| {
+            runtime.block_on(fetcher.parse_raw_image(&post(url), Bytes::copy_from_slice(body)))
+        };
+        let is_square = |error: eyre::Report| {
+            matches!(
+                error.downcast_ref::<Reject>(),
+                Some(Reject::AspectRatio { iw: 1080, ih: 1080, .. })
+            )
+        };
+
+        // Decoding an image once remembers what size its bytes were
+        let square = fixture((1080, 1080));
+        assert!(is_square(parse("https://i.redd.it/square.png", &square).unwrap_err()));
+        let hash = xxhash_rust::xxh3::xxh3_64(&square);
+        assert_eq!(
+            runtime.block_on(fetcher.metadata.probed(hash)).unwrap(),
+            Some((1080, 1080))
+        );
+        assert!(is_square(parse("https://i.imgur.com/repost.png", &square).unwrap_err()));
+
+        // Which is all it takes to reject them again, even if they wouldn't decode
+        let undecodable = b"these bytes were a square image last time";
+        let hash = xxhash_rust::xxh3::xxh3_64(undecodable);
+        assert!(!is_square(
+            parse("https://i.redd.it/broken.png", undecodable).unwrap_err()
+        ));
+        runtime
+            .block_on(fetcher.metadata.insert_probed(hash, 1080, 1080))
+            .unwrap();
+        assert!(is_square(
+            parse("https://i.redd.it/broken.png", undecodable).unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn monitors_are_probed_once_a_run_and_again_once_rearranged() {
+        use crate::{
+            fake_server::{FakeServer, Response},
+            policy::{Geometry, MonitorCache},
+        };
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Monitors that can be rearranged, counting how often they're looked at
+        struct Counted {
+            sizes: std::sync::Mutex<Vec<(u32, u32)>>,
+            layouts: AtomicUsize,
+            probes: AtomicUsize,
+        }
+
+        impl Geometry for Counted {
+            fn layout(&self) -> Result<u64> {
+                self.layouts.fetch_add(1, Ordering::SeqCst);
+                Ok(xxhash_rust::xxh3::xxh3_64(
+                    format!("{:?}", self.sizes.lock().unwrap()).as_bytes(),
+                ))
+            }
+
+            fn sizes(&self) -> Result<Vec<(u32, u32)>> {
+                self.probes.fetch_add(1, Ordering::SeqCst);
+                Ok(self.sizes.lock().unwrap().clone())
+            }
+        }
+
+        std::fs::create_dir_all(crate::DIRS.data_local_dir()).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let config = Config::default();
+        let client = Client::builder().no_proxy().build().unwrap();
+        let server = FakeServer::builder()
+            .route("/landscape.png", Response::png((1920, 1080)))
+            .route("/portrait.png", Response::png((1080, 1920)))
+            .start();
+        let post = |url: String| Post {
+            id: String::new(),
+            url,
+            subreddit: "wallpapers".to_owned(),
+            title: String::new(),
+            permalink: None,
+            score: 0,
+            variants: Vec::new(),
+            ratio: RatioRule::default(),
+        };
+
+        let geometry = Counted {
+            sizes: std::sync::Mutex::new(vec![(1920, 1080)]),
+            layouts: AtomicUsize::new(0),
+            probes: AtomicUsize::new(0),
+        };
+        let cache = MonitorCache::new();
+        let run = |profile: &str, paths: &[&str]| {
+            let policy = ImagePolicy::new(cache.sizes(&geometry).unwrap()).configure(&config);
+            let posts = paths.iter().map(|path| post(server.url(path))).collect::<Vec<_>>();
+            runtime
+                .block_on(async {
+                    Fetcher::new(&client, &config, profile, policy)
+                        .await?
+                        .fetch_toplevel(stream::iter(posts))
+                        .await
+                })
+                .unwrap()
+        };
+
+        // However many images a run looks at, the monitors are only probed the once
+        let report = run("geometry-landscape", &["/landscape.png", "/portrait.png"]);
+        assert_eq!(report.fetched, 1);
+        assert_eq!(report.rejections, BTreeMap::from([(Rejection::AspectRatio, 1)]));
+        assert_eq!(geometry.probes.load(Ordering::SeqCst), 1);
+
+        // The next run only checks they haven't moved
+        run("geometry-unmoved", &[]);
+        assert_eq!(geometry.layouts.load(Ordering::SeqCst), 2);
+        assert_eq!(geometry.probes.load(Ordering::SeqCst), 1);
+
+        // But once they have, it's their new sizes that images are judged by, with the URLs told apart as the ones
+        // turned down before would be skipped
+        *geometry.sizes.lock().unwrap() = vec![(1080, 1920)];
+        let report = run("geometry-portrait", &["/landscape.png?again", "/portrait.png?again"]);
+        assert_eq!(geometry.probes.load(Ordering::SeqCst), 2);
+        assert_eq!(report.fetched, 1);
+        assert_eq!(report.rejections, BTreeMap::from([(Rejection::AspectRatio, 1)]));
+        assert_eq!(server.hits("/portrait.png"), 2);
+    }
 }
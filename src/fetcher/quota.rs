@@ -0,0 +1,141 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::processing::Orientation;
+
+/// How many images of each orientation we still need, counting only the files that are actually on disk
+pub struct QuotaTracker {
+    need: BTreeMap<Orientation, usize>,
+    gotten: Mutex<BTreeMap<Orientation, usize>>,
+}
+
+impl QuotaTracker {
+    pub fn new(need: BTreeMap<Orientation, usize>) -> Self {
+        Self {
+            need,
+            gotten: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count a file we're about to persist, unless we already have as many of its orientation as we need.
+    pub fn try_reserve(&self, orientation: Orientation) -> bool {
+        let mut gotten = self.gotten.lock().unwrap();
+        let need = self.need.get(&orientation).copied().unwrap_or_default();
+        let gotten = gotten.entry(orientation).or_default();
+        if *gotten >= need {
+            return false;
+        }
+        *gotten += 1;
+        true
+    }
+
+    /// Stop counting a file we've reserved a spot for, because it didn't make it to the cache after all.
+    pub fn release(&self, orientation: Orientation) {
+        if let Some(gotten) = self.gotten.lock().unwrap().get_mut(&orientation) {
+            *gotten = gotten.saturating_sub(1);
+        }
+    }
+
+    /// How many images we've got so far, of any orientation.
+    pub fn gotten(&self) -> usize {
+        self.gotten.lock().unwrap().values().sum()
+    }
+
+    /// How many more images we need, across every orientation that still needs some.
+    pub fn remaining(&self) -> usize {
+        let gotten = self.gotten.lock().unwrap();
+        self.need
+            .iter()
+            .map(|(orientation, need)| need.saturating_sub(gotten.get(orientation).copied().unwrap_or_default()))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orientations_are_stocked_separately() {
+        let quota = QuotaTracker::new(BTreeMap::from([
+            (Orientation::Landscape, 2),
+            (Orientation::Portrait, 1),
+        ]));
+
+        assert!(quota.try_reserve(Orientation::Portrait));
+        assert!(!quota.try_reserve(Orientation::Portrait));
+        assert_eq!(quota.remaining(), 2);
+        assert!(quota.try_reserve(Orientation::Landscape));
+        assert!(quota.try_reserve(Orientation::Landscape));
+        assert!(!quota.try_reserve(Orientation::Landscape));
+        assert_eq!(quota.remaining(), 0);
+        assert_eq!(quota.gotten(), 3);
+    }
+
+    #[test]
+    fn orientations_we_need_none_of_are_never_reserved() {
+        let quota = QuotaTracker::new(BTreeMap::from([(Orientation::Landscape, 1)]));
+        assert!(!quota.try_reserve(Orientation::Portrait));
+        assert_eq!(quota.remaining(), 1);
+    }
+
+    #[test]
+    fn downloads_finishing_out_of_order_never_overfill() {
+        // However the downloads racing for the last spots interleave, only as many as are needed get them
+        let quota = QuotaTracker::new(BTreeMap::from([
+            (Orientation::Landscape, 3),
+            (Orientation::Portrait, 2),
+        ]));
+        let finished = [
+            Orientation::Portrait,
+            Orientation::Landscape,
+            Orientation::Portrait,
+            Orientation::Portrait,
+            Orientation::Landscape,
+            Orientation::Portrait,
+            Orientation::Landscape,
+            Orientation::Landscape,
+        ];
+        let reserved = finished
+            .iter()
+            .filter(|&&orientation| quota.try_reserve(orientation))
+            .count();
+
+        assert_eq!(reserved, 5);
+        assert_eq!(quota.remaining(), 0);
+        let gotten = quota.gotten.lock().unwrap();
+        assert_eq!(gotten[&Orientation::Landscape], 3);
+        assert_eq!(gotten[&Orientation::Portrait], 2);
+    }
+
+    #[test]
+    fn files_that_never_made_it_to_disk_give_their_spot_back() {
+        let quota = QuotaTracker::new(BTreeMap::from([(Orientation::Landscape, 1)]));
+        assert!(quota.try_reserve(Orientation::Landscape));
+        assert!(!quota.try_reserve(Orientation::Landscape));
+
+        quota.release(Orientation::Landscape);
+        assert_eq!((quota.gotten(), quota.remaining()), (0, 1));
+        assert!(quota.try_reserve(Orientation::Landscape));
+
+        // Releasing what was never reserved doesn't make room for more than we need
+        quota.release(Orientation::Portrait);
+        quota.release(Orientation::Landscape);
+        quota.release(Orientation::Landscape);
+        assert_eq!(quota.remaining(), 1);
+        assert!(quota.try_reserve(Orientation::Landscape));
+        assert!(!quota.try_reserve(Orientation::Landscape));
+    }
+
+    #[test]
+    fn concurrent_downloads_only_reserve_what_is_needed() {
+        let quota = QuotaTracker::new(BTreeMap::from([(Orientation::Landscape, 10)]));
+        let reserved = std::thread::scope(|scope| {
+            let workers = (0..8)
+                .map(|_| scope.spawn(|| (0..5).filter(|_| quota.try_reserve(Orientation::Landscape)).count()))
+                .collect::<Vec<_>>();
+            workers.into_iter().map(|worker| worker.join().unwrap()).sum::<usize>()
+        });
+        assert_eq!(reserved, 10);
+        assert_eq!(quota.remaining(), 0);
+    }
+}
@@ -7,7 +7,8 @@ use futures::stream;
 use serde::Deserialize;
 use tracing::{debug, trace};
 
-use super::Fetcher;
+use super::{Fetcher, Rejection};
+use crate::reddit::Post;
 
 #[derive(Deserialize)]
 struct RedditGallery {
@@ -28,22 +29,92 @@ struct Model {
 struct Media {
     #[serde(rename(deserialize = "mediaMetadata"))]
     media_metadata: HashMap<String, MediaMetadata>,
+    /// The order the images are shown in, which `media_metadata` doesn't keep
+    #[serde(default)]
+    gallery: Option<Gallery>,
+}
+
+#[derive(Deserialize)]
+struct Gallery {
+    items: Vec<GalleryItem>,
+}
+
+#[derive(Deserialize)]
+struct GalleryItem {
+    #[serde(rename(deserialize = "mediaId"))]
+    media_id: String,
+    #[serde(default)]
+    caption: Option<String>,
+}
+
+impl Media {
+    /// Get the URL of every image along with its caption, in the order the gallery shows them, and how many items
+    /// we skipped for being videos or animations.
+    ///
+    /// Items whose image has been deleted are missing from `media_metadata`, so we skip them too.
+    fn images(self) -> (Vec<(String, Option<String>)>, usize) {
+        let Self {
+            mut media_metadata,
+            gallery,
+        } = self;
+        let items = match gallery {
+            Some(gallery) => gallery
+                .items
+                .into_iter()
+                .filter_map(|item| {
+                    let metadata = media_metadata.remove(&item.media_id)?;
+                    Some((metadata, item.caption.filter(|caption| !caption.trim().is_empty())))
+                })
+                .collect(),
+            None => {
+                let mut items = media_metadata.into_iter().collect::<Vec<_>>();
+                items.sort_by(|(a, _), (b, _)| a.cmp(b));
+                items
+                    .into_iter()
+                    .map(|(_, metadata)| (metadata, None))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let total = items.len();
+        let images = items
+            .into_iter()
+            .filter_map(|(metadata, caption)| Some((metadata.image_url()?, caption)))
+            .collect::<Vec<_>>();
+        let skipped = total - images.len();
+        (images, skipped)
+    }
 }
 
 #[derive(Deserialize)]
 struct MediaMetadata {
+    /// The item's MIME type, e.g. `image/jpg` or `video/mp4`
+    #[serde(default)]
+    m: Option<String>,
     s: S,
 }
 
+impl MediaMetadata {
+    /// Get the URL of the item if it's a still image; GIFs would only get their first frame shown.
+    fn image_url(self) -> Option<String> {
+        match self.m {
+            Some(ref mime) if !mime.starts_with("image/") || mime == "image/gif" => None,
+            _ => self.s.u,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct S {
-    u: String,
+    /// Only present for still images, as animations have `gif` and `mp4` instead
+    #[serde(default)]
+    u: Option<String>,
 }
 
 impl<'client> Fetcher<'client> {
     #[tracing::instrument(skip(self, body))]
     #[async_recursion(?Send)]
-    pub(super) async fn parse_reddit_gallery(&self, url: &str, body: Bytes) -> Result<()> {
+    pub(super) async fn parse_reddit_gallery(&self, post: &Post, body: Bytes, ancestors: &[String]) -> Result<()> {
         // Parse HTML and ensure there were no errors
         let html = scraper::Html::parse_document(std::str::from_utf8(&body).wrap_err("Body was not valid UTF-8.")?);
         ensure!(html.errors.is_empty(), "html.errors was not empty");
@@ -71,27 +142,106 @@ impl<'client> Fetcher<'client> {
 
         // Dig out the URLs from the extremely nested structure.
         let gallery: RedditGallery = serde_json::from_str(code)?;
-        let gallery = gallery
-            .posts
-            .models
-            .into_iter()
-            .flat_map(|(_, model)| model.media.media_metadata.into_values().map(|metadata| metadata.s.u))
-            .collect::<Vec<String>>();
+        let mut models = gallery.posts.models.into_iter().collect::<Vec<_>>();
+        models.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut gallery = Vec::new();
+        for (_, model) in models {
+            let (images, skipped) = model.media.images();
+            gallery.extend(images);
+            for _ in 0..skipped {
+                self.rejections.record(Rejection::NotImage);
+            }
+        }
         trace!(?gallery, "parsed reddit gallery");
 
         // Count how many we've got.
         let contained = gallery.len();
 
-        // Fetch as many as we need.
-        let touched = self.fetch_multiple(stream::iter(gallery)).await?;
+        // Fetch as many as we need, remembering that they come from the same place as the gallery itself and
+        // keeping each image's caption along with the post's title.
+        let posts = gallery.into_iter().map(|(url, caption)| Post {
+            id: post.id.clone(),
+            url,
+            subreddit: post.subreddit.clone(),
+            title: match caption {
+                Some(caption) => format!("{} ({})", post.title, caption.trim()),
+                None => post.title.clone(),
+            },
+            permalink: post.permalink.clone(),
+            score: post.score,
+            variants: Vec::new(),
+            ratio: post.ratio,
+        });
+        let mut chain = ancestors.to_vec();
+        chain.push(post.url.clone());
+        // Only download about as many images as we still need, instead of starting on the whole gallery at once
+        let concurrency = self.remaining().min(super::CONCURRENCY);
+        let touched = self.fetch_multiple(stream::iter(posts), &chain, concurrency).await?;
 
         // If we've touched all the images in the gallery, we've exhausted it and can
         // therefore consider it "invalid".
         if touched >= contained {
-            debug!(%url, "exhausted reddit gallery");
-            self.invalid.insert(url.to_string()).await?;
+            debug!(url = %post.url, "exhausted reddit gallery");
+            self.invalid.insert(post.url.clone()).await?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn videos_animations_and_deleted_items_are_skipped_in_gallery_order() {
+        let media: Media = serde_json::from_str(
+            r#"{
+                "mediaMetadata": {
+                    "a": {"m": "image/jpg", "s": {"u": "https://preview.redd.it/a.jpg"}},
+                    "b": {"m": "image/gif", "s": {"gif": "https://i.redd.it/b.gif"}},
+                    "c": {"m": "video/mp4", "s": {"mp4": "https://v.redd.it/c.mp4"}},
+                    "d": {"s": {"u": "https://preview.redd.it/d.png"}}
+                },
+                "gallery": {"items": [
+                    {"mediaId": "d", "caption": "The last one"},
+                    {"mediaId": "c"},
+                    {"mediaId": "deleted"},
+                    {"mediaId": "b"},
+                    {"mediaId": "a", "caption": "  "}
+                ]}
+            }"#,
+        )
+        .unwrap();
+
+        let (images, skipped) = media.images();
+        assert_eq!(
+            images,
+            [
+                (
+                    "https://preview.redd.it/d.png".to_owned(),
+                    Some("The last one".to_owned())
+                ),
+                ("https://preview.redd.it/a.jpg".to_owned(), None),
+            ]
+        );
+        // The deleted one was never there as far as we're concerned
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn galleries_without_an_order_go_by_media_id() {
+        let media: Media = serde_json::from_str(
+            r#"{"mediaMetadata": {
+                "z": {"m": "image/png", "s": {"u": "https://preview.redd.it/z.png"}},
+                "a": {"m": "image/png", "s": {"u": "https://preview.redd.it/a.png"}}
+            }}"#,
+        )
+        .unwrap();
+
+        let (images, skipped) = media.images();
+        let urls = images.iter().map(|(url, _)| url.as_str()).collect::<Vec<_>>();
+        assert_eq!(urls, ["https://preview.redd.it/a.png", "https://preview.redd.it/z.png"]);
+        assert_eq!(skipped, 0);
+    }
+}
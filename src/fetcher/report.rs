@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, fmt, sync::Mutex};
+
+use image::ImageError;
+
+use crate::policy::Reject;
+
+use super::{DeadImage, ExpansionError, ImageTooLarge, OrientationStocked, Unrecognized};
+
+/// Why a post didn't make it into the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rejection {
+    /// It's marked NSFW
+    Nsfw,
+    /// It's pinned by the moderators
+    Stickied,
+    /// It's marked as a spoiler
+    Spoiler,
+    /// It didn't get enough upvotes
+    LowScore,
+    /// Its link isn't a web URL
+    Unfetchable,
+    /// We've already downloaded it or found it invalid
+    AlreadySeen,
+    /// It doesn't fit any of our monitors
+    AspectRatio,
+    /// It'd have to be scaled up too much, or it's below the minimum resolution
+    TooSmall,
+    /// It's too dark or too bright
+    Brightness,
+    /// We already had enough images of its orientation
+    Stocked,
+    /// It's too big, and none of its resized versions would do
+    Size,
+    /// It's a gallery we wouldn't expand
+    Gallery,
+    /// It's a gallery item that's a video or an animation
+    NotImage,
+    /// Its image was deleted
+    Deleted,
+    /// We couldn't download it
+    Download,
+    /// We couldn't make sense of what we downloaded
+    Unrecognized,
+    /// Something else went wrong
+    Other,
+}
+
+impl Rejection {
+    /// Figure out which of our checks turned a post down from the error it failed with.
+    pub fn of(error: &eyre::Report) -> Self {
+        if let Some(reject) = error.downcast_ref::<Reject>() {
+            match reject {
+                Reject::AspectRatio { .. } => Self::AspectRatio,
+                Reject::TooSmall { .. } | Reject::LowResolution { .. } => Self::TooSmall,
+                Reject::TooManyPixels { .. } => Self::Size,
+                Reject::Brightness { .. } => Self::Brightness,
+            }
+        } else if error.is::<OrientationStocked>() {
+            Self::Stocked
+        } else if error.is::<ImageTooLarge>() {
+            Self::Size
+        } else if error.is::<ExpansionError>() {
+            Self::Gallery
+        } else if error.is::<DeadImage>() {
+            Self::Deleted
+        } else if error.chain().any(|cause| cause.is::<reqwest::Error>()) {
+            Self::Download
+        } else if error.is::<Unrecognized>() || error.chain().any(|cause| cause.is::<ImageError>()) {
+            Self::Unrecognized
+        } else {
+            Self::Other
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Nsfw => "being NSFW",
+            Self::Stickied => "being stickied",
+            Self::Spoiler => "being a spoiler",
+            Self::LowScore => "low score",
+            Self::Unfetchable => "unfetchable link",
+            Self::AlreadySeen => "already seen",
+            Self::AspectRatio => "aspect ratio",
+            Self::TooSmall => "being too small",
+            Self::Brightness => "brightness",
+            Self::Stocked => "enough of its orientation",
+            Self::Size => "size",
+            Self::Gallery => "gallery depth",
+            Self::NotImage => "not being an image",
+            Self::Deleted => "deleted image",
+            Self::Download => "failed download",
+            Self::Unrecognized => "unrecognized format",
+            Self::Other => "other errors",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// How many posts each check turned down, shared between the downloads running at once
+#[derive(Debug, Default)]
+pub struct Rejections(Mutex<BTreeMap<Rejection, usize>>);
+
+impl Rejections {
+    pub fn record(&self, rejection: Rejection) {
+        *self.0.lock().unwrap().entry(rejection).or_default() += 1;
+    }
+
+    pub fn into_histogram(self) -> BTreeMap<Rejection, usize> {
+        self.0.into_inner().unwrap()
+    }
+}
+
+/// What came of a fetch
+#[derive(Debug, Default)]
+pub struct FetchReport {
+    /// How many images we downloaded
+    pub fetched: usize,
+    /// How many posts each check turned down
+    pub rejections: BTreeMap<Rejection, usize>,
+}
+
+impl FetchReport {
+    /// Add posts turned down elsewhere, e.g. while listing them, to those the fetch itself did.
+    pub fn merge(&mut self, rejections: BTreeMap<Rejection, usize>) {
+        for (rejection, count) in rejections {
+            *self.rejections.entry(rejection).or_default() += count;
+        }
+    }
+
+    /// The check that turned down the most posts, ignoring ones we'd already seen as those are expected.
+    pub fn top_rejection(&self) -> Option<(Rejection, usize)> {
+        self.rejections
+            .iter()
+            .filter(|&(&rejection, _)| rejection != Rejection::AlreadySeen)
+            .max_by_key(|&(_, &count)| count)
+            .map(|(&rejection, &count)| (rejection, count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_told_apart_by_what_turned_the_post_down() {
+        let reject = Reject::AspectRatio {
+            iw: 1000,
+            ih: 1000,
+            sw: 1920,
+            sh: 1080,
+        };
+        assert_eq!(Rejection::of(&eyre::Report::new(reject)), Rejection::AspectRatio);
+        assert_eq!(
+            Rejection::of(&eyre::Report::new(ImageTooLarge).wrap_err("Could not fetch variant")),
+            Rejection::Size
+        );
+        assert_eq!(Rejection::of(&eyre::Report::new(Unrecognized)), Rejection::Unrecognized);
+        assert_eq!(
+            Rejection::of(&eyre::Report::new(ImageError::IoError(
+                std::io::ErrorKind::UnexpectedEof.into()
+            ))),
+            Rejection::Unrecognized
+        );
+        // Anything we don't know of isn't blamed on the image's format
+        assert_eq!(Rejection::of(&eyre::eyre!("database is locked")), Rejection::Other);
+    }
+
+    #[test]
+    fn rejections_add_up_across_the_listing_and_the_fetch() {
+        let fetched = Rejections::default();
+        fetched.record(Rejection::AlreadySeen);
+        fetched.record(Rejection::AlreadySeen);
+        fetched.record(Rejection::AlreadySeen);
+        fetched.record(Rejection::AspectRatio);
+        fetched.record(Rejection::Nsfw);
+        let mut report = FetchReport {
+            fetched: 0,
+            rejections: fetched.into_histogram(),
+        };
+
+        let listed = Rejections::default();
+        listed.record(Rejection::Nsfw);
+        listed.record(Rejection::LowScore);
+        report.merge(listed.into_histogram());
+
+        assert_eq!(
+            report.rejections,
+            BTreeMap::from([
+                (Rejection::Nsfw, 2),
+                (Rejection::LowScore, 1),
+                (Rejection::AlreadySeen, 3),
+                (Rejection::AspectRatio, 1),
+            ])
+        );
+        // Posts we'd already seen are expected, so they're never the one to blame
+        assert_eq!(report.top_rejection(), Some((Rejection::Nsfw, 2)));
+        assert_eq!(FetchReport::default().top_rejection(), None);
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// How many downloads in a row may time out before we suspect IPv6 is broken and stick to IPv4
+const MAX_CONSECUTIVE_TIMEOUTS: usize = 3;
+
+/// Downloads that timed out in a row, to tell when IPv6 is broken and we're better off sticking to IPv4
+pub struct TimeoutStreak {
+    timeouts: AtomicUsize,
+    ipv4_only: AtomicBool,
+}
+
+impl TimeoutStreak {
+    /// Start counting, unless we're connecting over IPv4 only already.
+    pub fn new(ipv4_only: bool) -> Self {
+        Self {
+            timeouts: AtomicUsize::new(0),
+            ipv4_only: AtomicBool::new(ipv4_only),
+        }
+    }
+
+    /// Count a download that timed out, returning how many did in a row if it's time to switch to IPv4.
+    ///
+    /// Only the first download to reach the limit is told to switch, however many race past it.
+    pub fn timed_out(&self) -> Option<usize> {
+        let timeouts = self.timeouts.fetch_add(1, Ordering::AcqRel) + 1;
+        (timeouts >= MAX_CONSECUTIVE_TIMEOUTS && !self.ipv4_only.swap(true, Ordering::AcqRel)).then_some(timeouts)
+    }
+
+    /// Start over after a download that went through.
+    pub fn succeeded(&self) {
+        self.timeouts.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_timeouts_in_a_row_switch_to_ipv4() {
+        let streak = TimeoutStreak::new(false);
+        assert_eq!(streak.timed_out(), None);
+        assert_eq!(streak.timed_out(), None);
+        streak.succeeded();
+        assert_eq!(streak.timed_out(), None);
+        assert_eq!(streak.timed_out(), None);
+        assert_eq!(streak.timed_out(), Some(MAX_CONSECUTIVE_TIMEOUTS));
+
+        // Once is enough
+        assert_eq!(streak.timed_out(), None);
+        streak.succeeded();
+        for _ in 0..2 * MAX_CONSECUTIVE_TIMEOUTS {
+            assert_eq!(streak.timed_out(), None);
+        }
+    }
+
+    #[test]
+    fn nothing_switches_when_we_were_on_ipv4_from_the_start() {
+        let streak = TimeoutStreak::new(true);
+        for _ in 0..2 * MAX_CONSECUTIVE_TIMEOUTS {
+            assert_eq!(streak.timed_out(), None);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+//! Leaving the user's own background alone on first launch until they've asked for one of ours.
+
+use eyre::Result;
+
+use crate::db::{self, AppStateRepo};
+
+const KEY: &str = "first_run_complete";
+
+/// Whether we've ever applied a background.
+pub fn is_complete() -> Result<bool> {
+    let db = db::open()?;
+    Ok(AppStateRepo::new(&db).get(KEY)?.is_some())
+}
+
+/// Remember that we've applied a background, so that later launches change it right away.
+pub fn complete() -> Result<()> {
+    let db = db::open()?;
+    AppStateRepo::new(&db).set(KEY, "1")?;
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! A heartbeat file for watchdog scripts, so that they can tell whether we're still alive without talking to us.
+
+use std::{
+    fmt,
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::DIRS;
+
+// How often we update the heartbeat
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+// How old a heartbeat may be before `check-health` considers us wedged, unless told otherwise
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+// How long a cycle may run before `check-health` considers it stuck, unless told otherwise
+const DEFAULT_MAX_CYCLE: Duration = Duration::from_secs(30 * 60);
+
+/// When the cycle that's running started, in seconds since the Unix epoch
+static CYCLE_STARTED: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Marks a cycle as running for as long as it's kept around, so that the heartbeat can tell how long it's been going.
+pub struct Cycle(());
+
+impl Cycle {
+    pub fn start() -> Self {
+        *CYCLE_STARTED.lock().unwrap() = Some(now());
+        Self(())
+    }
+}
+
+impl Drop for Cycle {
+    fn drop(&mut self) {
+        *CYCLE_STARTED.lock().unwrap() = None;
+    }
+}
+
+/// What we last told the watchdog about ourselves
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Health {
+    /// When we wrote this, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub pid: u32,
+    /// How the last cycle went, or `None` if none has finished yet
+    pub last_cycle: Option<String>,
+    pub cycle_running: bool,
+    /// When the running cycle started, in seconds since the Unix epoch
+    #[serde(default)]
+    pub cycle_started: Option<u64>,
+    /// Why we stopped, if we did so cleanly
+    pub shutdown: Option<String>,
+}
+
+impl Health {
+    pub fn new(last_cycle: Option<String>, cycle_running: bool, shutdown: Option<String>) -> Self {
+        Self {
+            timestamp: now(),
+            pid: std::process::id(),
+            last_cycle,
+            cycle_running,
+            cycle_started: *CYCLE_STARTED.lock().unwrap(),
+            shutdown,
+        }
+    }
+}
+
+/// What `check-health` makes of the heartbeat
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Healthy,
+    Stale {
+        age: Duration,
+    },
+    /// Still alive, but a cycle has been running for longer than any should
+    Stuck {
+        running: Duration,
+    },
+    ShutDown {
+        reason: String,
+    },
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Healthy => write!(f, "healthy"),
+            Self::Stale { age } => write!(f, "stale, last heartbeat {}s ago", age.as_secs()),
+            Self::Stuck { running } => write!(f, "stuck, cycle running for {}s", running.as_secs()),
+            Self::ShutDown { reason } => write!(f, "shut down ({reason})"),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+/// Get the path to the heartbeat file
+pub fn path() -> PathBuf {
+    DIRS.data_local_dir().join("health.json")
+}
+
+/// Replace the heartbeat file, atomically so that the watchdog never reads half of it.
+pub fn write(health: &Health) -> Result<()> {
+    let path = path();
+    let dir = path.parent().unwrap_or(&path);
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut file, health)?;
+    file.flush()?;
+    file.persist(path).wrap_err("Could not persist health file")?;
+    Ok(())
+}
+
+/// Judge a heartbeat written at `health.timestamp` as of `now`.
+///
+/// A heartbeat from the future means the clock was moved back since it was written, which says nothing about whether
+/// we're wedged, so it counts as fresh, and the same goes for a cycle that started in the future.
+pub fn evaluate(health: &Health, now: u64, max_age: Duration, max_cycle: Duration) -> Verdict {
+    if let Some(ref reason) = health.shutdown {
+        return Verdict::ShutDown { reason: reason.clone() };
+    }
+
+    let age = Duration::from_secs(now.saturating_sub(health.timestamp));
+    if age > max_age {
+        return Verdict::Stale { age };
+    }
+
+    match health.cycle_started {
+        Some(started) if Duration::from_secs(now.saturating_sub(started)) > max_cycle => Verdict::Stuck {
+            running: Duration::from_secs(now.saturating_sub(started)),
+        },
+        _ => Verdict::Healthy,
+    }
+}
+
+/// Check the heartbeat for `redditbg check-health [max age in seconds] [max cycle length in seconds]`, returning
+/// whether we're healthy.
+pub fn check(max_age: Option<&str>, max_cycle: Option<&str>) -> Result<bool> {
+    let max_age = match max_age {
+        Some(secs) => Duration::from_secs(secs.parse().wrap_err("Invalid max age")?),
+        None => DEFAULT_MAX_AGE,
+    };
+    let max_cycle = match max_cycle {
+        Some(secs) => Duration::from_secs(secs.parse().wrap_err("Invalid max cycle length")?),
+        None => DEFAULT_MAX_CYCLE,
+    };
+    let contents = std::fs::read_to_string(path()).wrap_err("Could not read health file")?;
+    let health: Health = serde_json::from_str(&contents).wrap_err("Could not parse health file")?;
+
+    let verdict = evaluate(&health, now(), max_age, max_cycle);
+    println!("{verdict}");
+    Ok(verdict == Verdict::Healthy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(300);
+    const MAX_CYCLE: Duration = Duration::from_secs(1800);
+
+    fn health(timestamp: u64, cycle_started: Option<u64>, shutdown: Option<&str>) -> Health {
+        Health {
+            timestamp,
+            pid: 1,
+            last_cycle: None,
+            cycle_running: cycle_started.is_some(),
+            cycle_started,
+            shutdown: shutdown.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn fresh_heartbeats_are_healthy() {
+        assert_eq!(
+            evaluate(&health(1000, None, None), 1000, MAX_AGE, MAX_CYCLE),
+            Verdict::Healthy
+        );
+        assert_eq!(
+            evaluate(&health(1000, None, None), 1300, MAX_AGE, MAX_CYCLE),
+            Verdict::Healthy
+        );
+    }
+
+    #[test]
+    fn old_heartbeats_are_stale() {
+        assert_eq!(
+            evaluate(&health(1000, None, None), 1301, MAX_AGE, MAX_CYCLE),
+            Verdict::Stale {
+                age: Duration::from_secs(301)
+            }
+        );
+    }
+
+    #[test]
+    fn heartbeats_from_the_future_are_fresh() {
+        assert_eq!(
+            evaluate(&health(2000, Some(2000), None), 1000, MAX_AGE, MAX_CYCLE),
+            Verdict::Healthy
+        );
+    }
+
+    #[test]
+    fn shutting_down_trumps_everything_else() {
+        assert_eq!(
+            evaluate(&health(0, Some(0), Some("quit")), 10_000, MAX_AGE, MAX_CYCLE),
+            Verdict::ShutDown {
+                reason: "quit".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn cycles_running_too_long_are_stuck() {
+        assert_eq!(
+            evaluate(&health(5000, Some(3200), None), 5000, MAX_AGE, MAX_CYCLE),
+            Verdict::Healthy
+        );
+        assert_eq!(
+            evaluate(&health(5000, Some(3199), None), 5000, MAX_AGE, MAX_CYCLE),
+            Verdict::Stuck {
+                running: Duration::from_secs(1801)
+            }
+        );
+    }
+
+    #[test]
+    fn heartbeats_from_before_cycle_times_were_recorded_still_parse() {
+        let health: Health = serde_json::from_str(
+            r#"{"timestamp":1000,"pid":1,"last_cycle":"ok","cycle_running":true,"shutdown":null}"#,
+        )
+        .unwrap();
+        assert_eq!(evaluate(&health, 1000, MAX_AGE, MAX_CYCLE), Verdict::Healthy);
+    }
+}
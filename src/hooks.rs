@@ -0,0 +1,67 @@
+use std::{path::Path, time::Duration};
+
+use eyre::{ensure, format_err, Result, WrapErr};
+use tokio::{process::Command, runtime::Handle};
+use tracing::{info, warn};
+
+use crate::{picker::Picked, utils::TASKS};
+
+// How long the user's command may run before we kill it
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Run `command` through the shell with the given extra environment, logging its output.
+#[tracing::instrument(skip(env))]
+async fn run(command: String, env: Vec<(&'static str, String)>) -> Result<()> {
+    let mut cmd = std::process::Command::new("cmd");
+    cmd.arg("/C").envs(env);
+
+    // cmd doesn't unquote its arguments like other programs do, so the command has to reach it as the user wrote it
+    // rather than quoted as a single argument
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        cmd.raw_arg(&command);
+        // We don't have a console of our own, so don't let the command pop one up
+        cmd.creation_flags(winapi::um::winbase::CREATE_NO_WINDOW);
+    }
+    #[cfg(not(windows))]
+    cmd.arg(&command);
+
+    let mut cmd = Command::from(cmd);
+    cmd.kill_on_drop(true);
+
+    let output = tokio::time::timeout(HOOK_TIMEOUT, cmd.output())
+        .await
+        .map_err(|_| format_err!("Command timed out after {}s", HOOK_TIMEOUT.as_secs()))?
+        .wrap_err("Could not run command")?;
+    info!(
+        status = %output.status,
+        stdout = %String::from_utf8_lossy(&output.stdout),
+        stderr = %String::from_utf8_lossy(&output.stderr),
+        "on change command finished"
+    );
+    ensure!(output.status.success(), "Command exited with {}", output.status);
+    Ok(())
+}
+
+/// Run the user's `on_change_command` in the background, telling it about the background at `path`.
+///
+/// `picked` is `None` when we didn't pick a new image, e.g. when reapplying the current one.
+pub fn spawn_on_change(runtime: &Handle, command: String, path: &Path, picked: Option<&Picked>) {
+    let field = |get: fn(&Picked) -> &Option<String>| picked.and_then(|picked| get(picked).clone()).unwrap_or_default();
+    let env = vec![
+        ("REDDITBG_PATH", path.display().to_string()),
+        ("REDDITBG_URL", field(|picked| &picked.url)),
+        ("REDDITBG_SUBREDDIT", field(|picked| &picked.subreddit)),
+        ("REDDITBG_TITLE", field(|picked| &picked.title)),
+        ("REDDITBG_PERMALINK", field(|picked| &picked.permalink)),
+    ];
+
+    let _guard = runtime.enter();
+    TASKS.spawn("on change command", async move {
+        // Whatever the user's command does, it's none of the background's business
+        if let Err(error) = run(command, env).await {
+            warn!(?error, "on change command failed");
+        }
+    });
+}
@@ -0,0 +1,177 @@
+//! Our icon, built into the executable, and the variants the tray switches between to show what we're up to.
+//!
+//! Windows only loads icons from files or resources, so the variants are made at startup and written to the cache.
+
+use std::{fs, path::PathBuf};
+
+use eyre::{Result, WrapErr};
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+use crate::DIRS;
+
+const BASE: &[u8] = include_bytes!("icon.ico");
+
+/// How faded the paused icon is
+const PAUSED_OPACITY: f32 = 0.6;
+
+/// The badge's diameter, as a fraction of the icon's size
+const BADGE_SIZE: f32 = 0.45;
+
+/// What the icon in the tray says about us
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Normal,
+    /// Grayed out
+    Paused,
+    /// With a blue badge
+    Fetching,
+    /// With a red dot
+    Error,
+}
+
+impl State {
+    pub const ALL: [State; 4] = [State::Normal, State::Paused, State::Fetching, State::Error];
+
+    /// Pick the icon for the given state of things, the most pressing first.
+    pub fn of(paused: bool, running: bool, failed: bool) -> Self {
+        if running {
+            State::Fetching
+        } else if failed {
+            State::Error
+        } else if paused {
+            State::Paused
+        } else {
+            State::Normal
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            State::Normal => "normal",
+            State::Paused => "paused",
+            State::Fetching => "fetching",
+            State::Error => "error",
+        }
+    }
+
+    fn badge(self) -> Option<Rgba<u8>> {
+        match self {
+            State::Fetching => Some(Rgba([0x29, 0x79, 0xff, 0xff])),
+            State::Error => Some(Rgba([0xe8, 0x1e, 0x25, 0xff])),
+            State::Normal | State::Paused => None,
+        }
+    }
+}
+
+/// Get the directory our icons are written to.
+fn dir() -> PathBuf {
+    DIRS.cache_dir().join("icons")
+}
+
+/// Get the path the icon for `state` is written to.
+pub fn path(state: State) -> PathBuf {
+    dir().join(format!("{}.ico", state.name()))
+}
+
+/// Write every variant of our icon to the cache, replacing whatever an older version of us left there.
+pub fn generate() -> Result<()> {
+    fs::create_dir_all(dir()).wrap_err("Could not create icons directory")?;
+
+    // The normal one goes first and as it is, so that it's there even if the others can't be made
+    fs::write(path(State::Normal), BASE).wrap_err("Could not write icon")?;
+
+    let base = image::load_from_memory_with_format(BASE, ImageFormat::Ico)
+        .wrap_err("Could not decode icon")?
+        .into_rgba8();
+    for &state in State::ALL.iter().filter(|&&state| state != State::Normal) {
+        DynamicImage::ImageRgba8(variant(&base, state))
+            .save_with_format(path(state), ImageFormat::Ico)
+            .wrap_err_with(|| format!("Could not write {} icon", state.name()))?;
+    }
+    Ok(())
+}
+
+/// Make the icon for `state` out of the normal one.
+fn variant(base: &RgbaImage, state: State) -> RgbaImage {
+    let mut icon = base.clone();
+
+    if state == State::Paused {
+        for pixel in icon.pixels_mut() {
+            let [r, g, b, a] = pixel.0;
+            // Rec. 601 luma, as the icon is small enough that it doesn't matter which
+            let luma = (0.299 * f32::from(r) + 0.587 * f32::from(g) + 0.114 * f32::from(b)) as u8;
+            *pixel = Rgba([luma, luma, luma, (f32::from(a) * PAUSED_OPACITY) as u8]);
+        }
+    }
+
+    if let Some(color) = state.badge() {
+        // In the bottom right corner, where it covers the least of the icon
+        let (width, height) = icon.dimensions();
+        let radius = width.min(height) as f32 * BADGE_SIZE / 2.;
+        let (cx, cy) = (width as f32 - radius, height as f32 - radius);
+        for (x, y, pixel) in icon.enumerate_pixels_mut() {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            if dx.hypot(dy) <= radius {
+                *pixel = color;
+            }
+        }
+    }
+
+    icon
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Our icon.ico may not have been checked out, so the tests draw one of their own
+    fn base() -> RgbaImage {
+        RgbaImage::from_pixel(32, 32, Rgba([0xff, 0x80, 0x00, 0xff]))
+    }
+
+    // Round trip a variant through the ICO format, as Windows would load it
+    fn encoded(icon: RgbaImage) -> RgbaImage {
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(icon)
+            .write_to(&mut bytes, ImageFormat::Ico)
+            .unwrap();
+        image::load_from_memory_with_format(bytes.get_ref(), ImageFormat::Ico)
+            .unwrap()
+            .into_rgba8()
+    }
+
+    #[test]
+    fn every_variant_decodes_as_an_icon_of_the_same_size() {
+        for state in State::ALL {
+            let icon = encoded(variant(&base(), state));
+            assert_eq!(icon.dimensions(), (32, 32), "{:?}", state);
+        }
+    }
+
+    #[test]
+    fn the_paused_icon_is_gray_and_faded() {
+        let icon = encoded(variant(&base(), State::Paused));
+        let Rgba([r, g, b, a]) = *icon.get_pixel(0, 0);
+        assert!(r == g && g == b, "{:?}", (r, g, b));
+        assert_eq!(a, (255. * PAUSED_OPACITY) as u8);
+    }
+
+    #[test]
+    fn badges_go_in_the_bottom_right_corner() {
+        for state in [State::Fetching, State::Error] {
+            let icon = encoded(variant(&base(), state));
+            assert_eq!(icon.get_pixel(0, 0), base().get_pixel(0, 0), "{:?}", state);
+            assert_eq!(Some(*icon.get_pixel(24, 24)), state.badge(), "{:?}", state);
+            // A circle, so the very corner is left alone
+            assert_eq!(icon.get_pixel(31, 31), base().get_pixel(0, 0), "{:?}", state);
+        }
+    }
+
+    #[test]
+    fn the_most_pressing_state_wins() {
+        assert_eq!(State::of(true, true, true), State::Fetching);
+        assert_eq!(State::of(true, false, true), State::Error);
+        assert_eq!(State::of(true, false, false), State::Paused);
+        assert_eq!(State::of(false, false, false), State::Normal);
+    }
+}
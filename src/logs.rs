@@ -0,0 +1,109 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use eyre::{Result, WrapErr};
+use tracing::{debug, level_filters::LevelFilter};
+
+use crate::config::Config;
+
+/// Get the directory the rotating log files live in
+pub fn dir() -> PathBuf {
+    crate::paths::root().join("logs")
+}
+
+/// Turn the configured log level into a filter, falling back to everything if it isn't set or is left blank.
+///
+/// Returns the setting back as an error if it isn't a level we know, so that the caller can warn about it once
+/// logging is up.
+pub fn level_filter(setting: Option<&str>) -> Result<LevelFilter, String> {
+    // Tracing would take a blank level to mean errors only
+    match setting {
+        None => Ok(tracing::level_filters::STATIC_MAX_LEVEL),
+        Some(setting) if setting.trim().is_empty() => Ok(tracing::level_filters::STATIC_MAX_LEVEL),
+        Some(setting) => LevelFilter::from_str(setting.trim()).map_err(|_| setting.to_owned()),
+    }
+}
+
+/// Delete the oldest log files until they're within the configured age and size.
+///
+/// The newest file is always kept, as that's the one being written to.
+pub fn prune(config: &Config) -> Result<()> {
+    if config.log_retention_days.is_none() && config.log_max_mb.is_none() {
+        return Ok(());
+    }
+
+    let mut files = list(&dir())?;
+    files.sort_by_key(|&(_, modified, _)| std::cmp::Reverse(modified));
+
+    let max_age = config
+        .log_retention_days
+        .map(|days| Duration::from_secs(days.saturating_mul(60 * 60 * 24)));
+    let budget = config.log_max_mb.map(|mb| mb.saturating_mul(1_000_000));
+    let now = SystemTime::now();
+
+    let mut total = 0u64;
+    for (idx, (path, modified, bytes)) in files.into_iter().enumerate() {
+        total = total.saturating_add(bytes);
+        if idx == 0 {
+            continue;
+        }
+
+        let too_old = max_age.is_some_and(|max_age| now.duration_since(modified).unwrap_or_default() > max_age);
+        let over_budget = budget.is_some_and(|budget| total > budget);
+        if too_old || over_budget {
+            debug!(path = %path.display(), too_old, over_budget, "deleting old log file");
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error).wrap_err_with(|| format!("Could not delete log file {path:?}")),
+            }
+            total = total.saturating_sub(bytes);
+        }
+    }
+
+    Ok(())
+}
+
+/// List every file in `dir` along with when it was last written to and how big it is.
+fn list(dir: &Path) -> Result<Vec<(PathBuf, SystemTime, u64)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).wrap_err("Could not read logs directory")? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((entry.path(), metadata.modified()?, metadata.len()));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_levels_are_read_from_the_config_and_unknown_ones_handed_back() {
+        for setting in [None, Some(""), Some("  ")] {
+            assert_eq!(level_filter(setting), Ok(tracing::level_filters::STATIC_MAX_LEVEL));
+        }
+        for (setting, level) in [
+            ("trace", LevelFilter::TRACE),
+            ("debug", LevelFilter::DEBUG),
+            ("info", LevelFilter::INFO),
+            (" WARN ", LevelFilter::WARN),
+            ("Error", LevelFilter::ERROR),
+            ("off", LevelFilter::OFF),
+        ] {
+            assert_eq!(level_filter(Some(setting)), Ok(level), "{:?}", setting);
+        }
+
+        // Which the caller falls back to info for, after warning about what it couldn't make sense of
+        for setting in ["verbose", "info,debug", " loud "] {
+            assert_eq!(level_filter(Some(setting)), Err(setting.to_owned()));
+        }
+    }
+}
@@ -2,105 +2,883 @@
 #![cfg_attr(all(not(debug_assertions), windows), windows_subsystem = "windows")]
 
 use std::{
-    convert::Infallible,
-    fs,
-    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError},
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+    time::{Duration, Instant},
 };
 
 use directories::ProjectDirs;
 use eyre::{bail, Result, WrapErr};
-use reqwest::Client;
-use tokio::runtime::Runtime;
-use tracing::{debug, error, info, trace, Level};
+use futures::{future, StreamExt};
+use reqwest::{header::HeaderValue, Client};
+use tokio::runtime::{Handle, Runtime};
+use tracing::{debug, error, field::Empty, info, trace, warn, Level};
 
 static DIRS: once_cell::sync::Lazy<ProjectDirs> = once_cell::sync::Lazy::new(|| {
+    // Tests keep to a temporary directory of their own: joining an absolute path onto the per-user ones replaces them
+    if cfg!(test) {
+        let dir = tempfile::tempdir()
+            .expect("could not create test directory")
+            .into_path();
+        return ProjectDirs::from_path(dir).expect("could not create ProjectDirs");
+    }
     ProjectDirs::from("it", "PurpleMyst", env!("CARGO_PKG_NAME")).expect("could not create ProjectDirs")
 });
 
 mod utils;
 
+mod db;
+
+mod config;
+
+mod schedule;
+
+mod sources;
+
+mod status;
+
+mod processing;
+
+#[cfg(feature = "color-management")]
+mod color;
+
 mod reddit;
 
 mod fetcher;
 
+mod policy;
+
 mod picker;
 
 mod platform;
 
-#[tracing::instrument(skip_all)]
-fn find_new_background(runtime: &mut Runtime, client: &Client) -> Result<()> {
-    let subreddits_txt =
-        fs::read_to_string(DIRS.config_dir().join("subreddits.txt")).wrap_err("Could not read subreddits.txt")?;
+mod report;
 
-    let subreddits = subreddits_txt.trim().lines().collect::<Vec<&str>>();
-    info!(?subreddits, "using subreddits");
+mod digest;
 
-    // Make a closure that tells fetches our images
-    let mut already_fetched = false;
-    let do_fetch = || -> Result<()> {
-        runtime.block_on(async {
-            // Create a stream of URLs from Reddit
-            let posts = reddit::Posts::new(client, &subreddits);
+mod health;
 
-            // Fetch them
-            fetcher::fetch(client, posts).await
-        })
+mod hooks;
+
+mod logs;
+
+mod export;
+
+mod watch;
+
+mod update;
+
+mod tooltip;
+
+mod tray;
+
+mod why;
+
+mod bench;
+
+mod first_run;
+
+mod sources_report;
+
+mod snooze;
+
+mod paths;
+
+mod thumbnails;
+
+mod icons;
+
+#[cfg(test)]
+mod fake_server;
+
+#[cfg(test)]
+mod end_to_end;
+
+// How long to wait before the first change when started at login, unless configured otherwise
+const AUTOSTART_DELAY_SECS: u64 = 60;
+
+/// When the next change is due, give or take a few minutes so that instances started together drift apart.
+fn change_deadline(interval: Duration) -> Instant {
+    Instant::now() + schedule::jittered(interval, schedule::jitter_seed())
+}
+
+// How long after a change we check whether a group policy has put its own background back up
+const POLICY_CHECK_DELAY: Duration = Duration::from_secs(30);
+
+// How often we check whether another program has changed the background
+const WALLPAPER_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// How many candidates we try to apply per cycle before giving up
+const MAX_APPLY_ATTEMPTS: usize = 3;
+
+// How long we wait for background tasks to finish when quitting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What caused us to look for a new background
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Trigger {
+    Timer,
+    Manual,
+}
+
+/// What a click on Change now does, depending on the cycle that's running
+#[derive(Debug, PartialEq, Eq)]
+enum ChangeNow {
+    Start,
+    /// Once the running cycle is done
+    Queue,
+    /// The running cycle is already doing what was asked
+    Ignore,
+}
+
+impl ChangeNow {
+    fn of(running: Option<Trigger>) -> Self {
+        match running {
+            None => ChangeNow::Start,
+            Some(Trigger::Timer) => ChangeNow::Queue,
+            // Clicking again while we're on it shouldn't burn through several images
+            Some(Trigger::Manual) => ChangeNow::Ignore,
+        }
+    }
+}
+
+/// How long to wait before the first change: the configured delay, or a minute when started at login, unless the
+/// user snoozed us for longer.
+fn startup_delay(configured_secs: Option<u64>, autostart: bool, snoozed: Duration) -> Duration {
+    let default = if autostart { AUTOSTART_DELAY_SECS } else { 0 };
+    Duration::from_secs(configured_secs.unwrap_or(default)).max(snoozed)
+}
+
+/// Describe an error in terms the user can act on
+fn error_category(error: &eyre::Report) -> &'static str {
+    if error.is::<utils::NoInternet>() {
+        "no internet connection"
+    } else if error.is::<utils::BudgetExhausted>() {
+        "daily download budget used up"
+    } else if error.is::<utils::LowDiskSpace>() {
+        "not enough free disk space"
+    } else if error.is::<utils::StorageUnavailable>() {
+        "the drive holding the image cache is unavailable"
+    } else if error.is::<picker::NoValidImage>() {
+        "no suitable image found"
+    } else {
+        "unexpected error, see the logs for details"
+    }
+}
+
+/// What a cycle put up
+#[derive(Debug)]
+struct Change {
+    /// The subreddit the background came from, if we know it
+    subreddit: Option<String>,
+
+    /// Whether it's one we've shown before, as we were offline and had nothing new left
+    reapplied: bool,
+}
+
+/// Describe a successful change for the user
+fn describe_change(change: &Change) -> String {
+    match change {
+        Change { reapplied: true, .. } => "Offline with no new images, reapplied an earlier wallpaper".to_owned(),
+        Change {
+            subreddit: Some(subreddit),
+            ..
+        } => format!("New wallpaper from r/{subreddit}"),
+        Change { subreddit: None, .. } => "New wallpaper set".to_owned(),
+    }
+}
+
+/// Stop keeping the current background up, as the user asked for a new one.
+fn unsnooze(state: &mut State, tray: &tray::TrayHandle) {
+    if state.snoozed_until.take().is_some() {
+        if let Err(error) = snooze::clear() {
+            warn!(?error, "could not clear snooze");
+        }
+        if let Err(error) = tray.set_tooltip_now(&state.tooltip()) {
+            error!(?error, "could not set tooltip");
+        }
+    }
+}
+
+/// Decides what to tell the user about the outcome of each cycle
+#[derive(Debug, Default)]
+struct CycleNotices {
+    /// Whether we've told the user their storage is unavailable since the last successful cycle
+    storage_unavailable: bool,
+}
+
+impl CycleNotices {
+    /// Decide what to tell the user about the outcome of a cycle, if anything.
+    ///
+    /// When the user explicitly asked for a change they always get an answer, while timed cycles only speak up when
+    /// they fail. Storage being unavailable tends to last a while, so timed cycles only point it out the first time.
+    fn notification(&mut self, trigger: Trigger, result: &Result<Change>) -> Option<String> {
+        if result.is_ok() {
+            self.storage_unavailable = false;
+        }
+        match (trigger, result) {
+            (Trigger::Timer, Ok(_)) => None,
+            (Trigger::Manual, Ok(change)) => Some(describe_change(change)),
+            (Trigger::Timer, Err(error)) if error.is::<utils::StorageUnavailable>() => {
+                (!std::mem::replace(&mut self.storage_unavailable, true))
+                    .then(|| "The drive holding the image cache is unavailable, trying again next time".to_owned())
+            }
+            (_, Err(error)) => Some(format!("Could not change wallpaper: {}", error_category(error))),
+        }
+    }
+}
+
+/// What the user has chosen from the tray, which shapes every cycle, along with what we show them in the tooltip
+#[derive(Clone, Debug)]
+struct State {
+    /// In offline mode we keep rotating through the cache but never touch the network
+    offline: bool,
+
+    /// Which profile feeds the rotation
+    profile: String,
+
+    /// How many bytes we've downloaded today
+    downloaded_today: u64,
+
+    /// Until when the current background stays up, if the user asked us to keep it
+    snoozed_until: Option<time::OffsetDateTime>,
+
+    /// Whether the user paused timed changes, keeping the current background up until they resume them
+    paused: bool,
+}
+
+impl State {
+    fn tooltip(&self) -> String {
+        let mut tooltip = TOOLTIP.to_owned();
+        if self.profile != config::DEFAULT_PROFILE {
+            tooltip.push_str(&format!(" [{}]", self.profile));
+        }
+        if self.offline {
+            tooltip.push_str(" (offline)");
+        }
+        if self.paused {
+            tooltip.push_str("\nPaused");
+        } else if let Some(until) = self.snoozed_until {
+            tooltip.push_str(&format!(
+                "\nKeeping this background until {:02}:{:02}",
+                until.hour(),
+                until.minute()
+            ));
+        }
+        tooltip.push_str(&format!(
+            "\n{} downloaded today",
+            utils::format_bytes(self.downloaded_today)
+        ));
+        tooltip
+    }
+}
+
+/// The last day we told the user they'd used up their download budget
+const BUDGET_NOTICE_KEY: &str = "budget_notice";
+
+/// Whether the user is yet to be told about `key` on `day`, noting that they have been if so.
+fn first_notice_of_day(state: &db::AppStateRepo, key: &str, day: &str) -> rusqlite::Result<bool> {
+    if state.get(key)?.as_deref() == Some(day) {
+        return Ok(false);
+    }
+    state.set(key, day)?;
+    Ok(true)
+}
+
+/// Where fetching stands against one of the daily limits
+#[derive(Debug, PartialEq, Eq)]
+enum Limit {
+    Within,
+    /// Reached, and `notify` if it's the first time today
+    Reached {
+        notify: bool,
+    },
+}
+
+/// Where the downloads of `day` stand against a budget of `budget_mb` megabytes.
+fn budget_limit(db: &rusqlite::Connection, day: &str, budget_mb: u64) -> rusqlite::Result<Limit> {
+    if db::BandwidthRepo::new(db).on(day)? < budget_mb.saturating_mul(1_000_000) {
+        return Ok(Limit::Within);
+    }
+    let notify = first_notice_of_day(&db::AppStateRepo::new(db), BUDGET_NOTICE_KEY, day)?;
+    Ok(Limit::Reached { notify })
+}
+
+/// Refuse to fetch once we've downloaded more than the daily budget, telling the user the first time it happens.
+fn enforce_budget(config: &config::Config) -> Result<()> {
+    let Some(budget_mb) = config.daily_budget_mb else {
+        return Ok(());
     };
 
-    // Try to pick an image from the ones we've already fetched, so that we don't make
-    // our user wait too long in the case that they don't have internet access at the
-    // present moment.
-    let picked = match picker::pick() {
-        // If that succeeds, just return it
-        Ok(img) => img,
-
-        Err(err) => {
-            if let Some(picker::NoValidImage) = err.downcast_ref() {
-                // Otherwise, if we found no valid image, try to fetch them and pick again
-                debug!("found no valid image on first try");
-                do_fetch()?;
-                already_fetched = true;
-                picker::pick()?
-            } else {
-                // If we got any other error, bail and return it to the caller
-                bail!(err);
+    let db = db::open()?;
+    let (day, _) = db::BandwidthRepo::new(&db).today()?;
+    match budget_limit(&db, &day, budget_mb)? {
+        Limit::Within => return Ok(()),
+        Limit::Reached { notify: false } => {}
+        Limit::Reached { notify: true } => info!(
+            target: "notification",
+            "Used up today's download budget of {budget_mb} MB, not downloading until midnight"
+        ),
+    }
+
+    bail!(utils::BudgetExhausted);
+}
+
+/// Whether `free` bytes are below a floor of `floor_mb` megabytes.
+fn below_disk_floor(free: u64, floor_mb: u64) -> bool {
+    free < floor_mb.saturating_mul(1_000_000)
+}
+
+/// The last day we told the user the disk was nearly full
+const DISK_NOTICE_KEY: &str = "disk_notice";
+
+/// Where `free` bytes on `day` stand against a floor of `floor_mb` megabytes.
+fn disk_limit(db: &rusqlite::Connection, day: &str, free: u64, floor_mb: u64) -> rusqlite::Result<Limit> {
+    if !below_disk_floor(free, floor_mb) {
+        return Ok(Limit::Within);
+    }
+    let notify = first_notice_of_day(&db::AppStateRepo::new(db), DISK_NOTICE_KEY, day)?;
+    Ok(Limit::Reached { notify })
+}
+
+/// Refuse to fetch when the disk holding the cache is nearly full, telling the user once a day.
+fn enforce_disk_floor(config: &config::Config) -> Result<()> {
+    let free = platform::free_disk_space(paths::root())?;
+    let db = db::open()?;
+    let (day, _) = db::BandwidthRepo::new(&db).today()?;
+    match disk_limit(&db, &day, free, config.min_free_disk_mb)? {
+        Limit::Within => return Ok(()),
+        Limit::Reached { notify: false } => {}
+        Limit::Reached { notify: true } => warn!(
+            target: "notification",
+            "Only {} free on the disk holding the cache, not downloading new images",
+            utils::format_bytes(free)
+        ),
+    }
+
+    bail!(utils::LowDiskSpace);
+}
+
+/// Pick which of the sources to fetch from this cycle, favoring those we haven't fetched from in a while.
+fn sample_sources(sources: Vec<sources::SourceSpec>, count: usize) -> Result<Vec<sources::SourceSpec>> {
+    if sources.len() <= count {
+        return Ok(sources);
+    }
+
+    let db = db::open()?;
+    let last_sampled = db::SourceSamplesRepo::new(&db).all()?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    Ok(sources::sample(
+        sources,
+        count,
+        &last_sampled,
+        now,
+        schedule::jitter_seed(),
+    ))
+}
+
+/// Remember that we fetched from the given sources, so that the others get their turn first.
+fn record_samples(sources: &[sources::SourceSpec]) -> Result<()> {
+    let db = db::open()?;
+    let names = sources.iter().map(|source| source.name.as_str()).collect::<Vec<_>>();
+    db::SourceSamplesRepo::new(&db).record(&names, time::OffsetDateTime::now_utc().unix_timestamp())?;
+    Ok(())
+}
+
+/// What each source asks of its posts, by subreddit
+struct SourceRules<'a> {
+    rules: std::collections::HashMap<&'a str, (sources::RatioRule, i64)>,
+    /// The score posts of sources that don't set their own need
+    min_score: i64,
+}
+
+impl<'a> SourceRules<'a> {
+    fn new(sources: &'a [sources::SourceSpec], min_score: i64) -> Self {
+        let rules = sources
+            .iter()
+            .map(|source| {
+                let min_score = source.min_score().unwrap_or(min_score);
+                (source.name.as_str(), (source.ratio_rule(), min_score))
+            })
+            .collect();
+        Self { rules, min_score }
+    }
+
+    /// Carry the options of the post's source over to it, or turn it down if it scores too low for its source.
+    fn apply(&self, mut post: reddit::Post) -> Result<reddit::Post, fetcher::Rejection> {
+        let (rule, min_score) = self
+            .rules
+            .get(post.subreddit.to_ascii_lowercase().as_str())
+            .copied()
+            .unwrap_or((post.ratio, self.min_score));
+        if post.score < min_score {
+            debug!(url = %post.url, score = post.score, min_score, "skipping low scoring post");
+            return Err(fetcher::Rejection::LowScore);
+        }
+        post.ratio = rule;
+        Ok(post)
+    }
+}
+
+/// Fetch new images into the given profile's cache, returning how many we got.
+fn fetch_images(runtime: &Handle, client: &Client, config: &config::Config, profile: &str) -> Result<usize> {
+    let mut sources = config.sources(profile)?;
+    if let Some(count) = config.sources_per_cycle {
+        sources = sample_sources(sources, count)?;
+    }
+    let subreddits = sources
+        .iter()
+        .map(|source| (source.name.as_str(), source.sort()))
+        .collect::<Vec<_>>();
+    info!(?subreddits, "using subreddits");
+
+    let fetched = runtime.block_on(async {
+        // Don't bother if we can't reach Reddit at all
+        if !utils::is_online(client).await {
+            bail!(utils::NoInternet);
+        }
+
+        enforce_budget(config)?;
+        enforce_disk_floor(config)?;
+
+        // Create a stream of URLs from Reddit, carrying over any per-source options and skipping low scoring posts
+        let rules = SourceRules::new(&sources, config.min_score);
+        let filter = reddit::Filter {
+            include_nsfw: config.include_nsfw,
+            include_stickied: config.include_stickied,
+            include_spoilers: config.include_spoilers,
+        };
+        // Posts turned down before they reach the fetcher still count towards what it reports
+        let listing_rejections = fetcher::Rejections::default();
+        let posts = reddit::Posts::new(
+            client,
+            &subreddits,
+            filter,
+            config.allow_quarantined,
+            &listing_rejections,
+        )
+        .filter_map(|post| {
+            future::ready(match rules.apply(post) {
+                Ok(post) => Some(post),
+                Err(rejection) => {
+                    listing_rejections.record(rejection);
+                    None
+                }
+            })
+        });
+
+        // Fetch them
+        let mut report = fetcher::fetch(client, config, profile, posts).await?;
+        report.merge(listing_rejections.into_histogram());
+        debug!(fetched = report.fetched, rejections = ?report.rejections, "fetch finished");
+        if report.fetched == 0 {
+            if let Some((rejection, count)) = report.top_rejection() {
+                info!(target: "notification", "0 new images — {count} rejected by {rejection}");
             }
         }
+        Ok(report.fetched)
+    })?;
+
+    // Sources only had their turn if we got to fetch from them
+    if config.sources_per_cycle.is_some() {
+        if let Err(error) = record_samples(&sources) {
+            warn!(?error, "could not record which sources we fetched from");
+        }
+    }
+    Ok(fetched)
+}
+
+/// Resolve the background that's up right now, which is the one we expect unless it's gone missing, e.g. before our
+/// first cycle on a fresh install or after adopting another program's background that has since been deleted.
+fn current_background(expected: &Path) -> Option<&Path> {
+    expected.is_file().then_some(expected)
+}
+
+/// Get the link to the post the primary monitor's background came from, if we know it.
+fn last_permalink() -> Result<Option<String>> {
+    let Some(url) = picker::primary_url()? else {
+        return Ok(None);
     };
+    let db = db::open()?;
+    Ok(db::MetadataRepo::new(&db).permalink(&url)?)
+}
 
-    // Save it to the filesystem so that we can set it
-    let path = DIRS.cache_dir().join("background.png");
-    trace!(path = %path.display(), "saving background");
-    picked.save(&path)?;
+// The formats the background we save for Windows may be in
+const BACKGROUND_FORMATS: &[image::ImageFormat] = &[image::ImageFormat::Png, image::ImageFormat::Jpeg];
+
+/// Where we save the background for Windows to display when it's in the given format.
+fn background_path_for(format: image::ImageFormat) -> PathBuf {
+    monitor_background_path_for(0, format)
+}
+
+/// Where we save the background for the given monitor, counting from the primary one, when it's in the given format.
+fn monitor_background_path_for(monitor: usize, format: image::ImageFormat) -> PathBuf {
+    let extension = format.extensions_str().first().copied().unwrap_or("png");
+    let name = match monitor {
+        0 => "background".to_owned(),
+        monitor => format!("background-{monitor}"),
+    };
+    DIRS.cache_dir().join(name).with_extension(extension)
+}
+
+/// Where the background we last saved is, whichever format it's in.
+pub fn background_path() -> PathBuf {
+    BACKGROUND_FORMATS
+        .iter()
+        .map(|&format| background_path_for(format))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| background_path_for(image::ImageFormat::Png))
+}
+
+/// Where the background we last saved for the given monitor other than the primary one is, if we ever saved one.
+fn monitor_background_path(monitor: usize) -> Option<PathBuf> {
+    BACKGROUND_FORMATS
+        .iter()
+        .map(|&format| monitor_background_path_for(monitor, format))
+        .find(|path| path.is_file())
+}
+
+/// Put our backgrounds back up, e.g. after another program replaced them or to show them in another style.
+///
+/// The primary monitor gets `primary`, and with per-monitor backgrounds the others get their own back, as setting a
+/// single background would replace theirs.
+fn reapply_background(primary: &Path, style: platform::WallpaperStyle, per_monitor: bool) -> Result<()> {
+    let monitors = if per_monitor {
+        platform::wallpaper_monitors()?
+    } else {
+        Vec::new()
+    };
+    let Some((primary_monitor, others)) = monitors.split_first().filter(|(_, others)| !others.is_empty()) else {
+        return platform::set_background(primary, style);
+    };
+
+    let paths = (1..=others.len())
+        .map(|monitor| monitor_background_path(monitor).unwrap_or_else(|| primary.to_owned()))
+        .collect::<Vec<_>>();
+    // The primary monitor goes last, as the last background set is the one Windows reports as current
+    let backgrounds = others
+        .iter()
+        .zip(&paths)
+        .map(|(monitor, path)| (monitor, path.as_path()))
+        .chain(std::iter::once((primary_monitor, primary)))
+        .collect::<Vec<_>>();
+    platform::set_monitor_backgrounds(&backgrounds, style)
+}
+
+/// Save the picked image where Windows can get to it, in the format it's stored in, and set it as the background.
+fn apply_background(
+    picked: &picker::Picked,
+    filters: &[processing::Filter],
+    style: platform::WallpaperStyle,
+) -> Result<PathBuf> {
+    let path = save_background(picked, filters, 0)?;
 
-    // Set it as a background
     trace!("setting background");
-    platform::set_background(&path)?;
+    platform::set_background(&path, style)?;
+    if let Err(error) = picker::mark_primary(picked) {
+        warn!(?error, "could not record the primary monitor's background");
+    }
+    Ok(path)
+}
 
-    // If we didn't fetch while picking the image, do so after setting the background
-    if !already_fetched {
-        do_fetch()?;
+/// Save the picked image as the background of the given monitor, in the format it's stored in, returning where it went.
+fn save_background(picked: &picker::Picked, filters: &[processing::Filter], monitor: usize) -> Result<PathBuf> {
+    let format = processing::storage_format(picked.format, false);
+    let path = monitor_background_path_for(monitor, format);
+    trace!(path = %path.display(), "saving background");
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    if filters.is_empty() {
+        processing::encode(&picked.image, &mut file, format)?;
+    } else {
+        let filtered = filters.iter().fold(picked.image.clone(), |image, &filter| {
+            trace!(?filter, "applying filter");
+            processing::apply_filter(&image, filter)
+        });
+        processing::encode(&filtered, &mut file, format)?;
+    }
+    drop(file);
+
+    // Only the one we've just saved should be left, so that it's the one we find next time
+    for &other in BACKGROUND_FORMATS.iter().filter(|&&other| other != format) {
+        let _ = std::fs::remove_file(monitor_background_path_for(monitor, other));
+    }
+    Ok(path)
+}
+
+/// Give every monitor but the primary one, which just got `primary`, a background of its own picked to fit it.
+///
+/// Monitors nothing in the cache fits keep the background they had, or share the primary's if they never had one of
+/// their own. With a single monitor this does nothing, so that it keeps the background everything else already handles.
+fn apply_other_monitors(primary: &Path, profile: &str, config: &config::Config, exclude: &[PathBuf]) -> Result<()> {
+    let monitors = platform::wallpaper_monitors()?;
+    let Some((primary_monitor, others)) = monitors.split_first() else {
+        return Ok(());
+    };
+    if others.is_empty() {
+        return Ok(());
     }
 
+    let policy = policy::ImagePolicy::new(monitors.iter().map(|monitor| monitor.size).collect()).configure(config);
+    let screens = &policy.monitors()[1..];
+    let picked = picker::pick_many(profile, config, exclude, &policy, screens)?;
+    let paths = picked
+        .iter()
+        .enumerate()
+        .map(|(index, picked)| match picked {
+            Some(picked) => save_background(picked, &config.filters, index + 1),
+            None => Ok(monitor_background_path(index + 1).unwrap_or_else(|| primary.to_owned())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // The primary monitor goes last, as the last background set is the one Windows reports as current
+    let backgrounds = others
+        .iter()
+        .zip(&paths)
+        .map(|(monitor, path)| (monitor, path.as_path()))
+        .chain(std::iter::once((primary_monitor, primary)))
+        .collect::<Vec<_>>();
+    platform::set_monitor_backgrounds(&backgrounds, config.wallpaper_style)?;
+
+    for picked in picked.iter().flatten() {
+        picker::mark_applied(picked, profile, config.mode)?;
+    }
     Ok(())
 }
 
+/// Where the time went in a cycle
+#[derive(Debug, Default)]
+struct CycleTimings {
+    pick: Duration,
+    fetch: Duration,
+    apply: Duration,
+    fetched_images: usize,
+}
+
+impl CycleTimings {
+    /// Run `f`, adding how long it took to `phase`.
+    fn time<T>(phase: &mut Duration, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        *phase += start.elapsed();
+        result
+    }
+
+    /// Fill in the fields of the cycle's span.
+    fn record(&self, span: &tracing::Span) {
+        span.record("pick_secs", self.pick.as_secs_f64())
+            .record("fetch_secs", self.fetch.as_secs_f64())
+            .record("apply_secs", self.apply.as_secs_f64())
+            .record("fetched_images", self.fetched_images);
+    }
+}
+
+impl std::fmt::Display for CycleTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pick {:.1}s, fetch {:.1}s ({} images), apply {:.1}s",
+            self.pick.as_secs_f64(),
+            self.fetch.as_secs_f64(),
+            self.fetched_images,
+            self.apply.as_secs_f64()
+        )
+    }
+}
+
+/// Find and apply a new background, returning what we put up.
+#[tracing::instrument(
+    skip(runtime, client),
+    fields(pick_secs = Empty, fetch_secs = Empty, apply_secs = Empty, fetched_images = Empty)
+)]
+fn find_new_background(runtime: &Handle, client: &Client, state: &State, trigger: Trigger) -> Result<Change> {
+    let mut timings = CycleTimings::default();
+    let result = find_new_background_timed(runtime, client, state, &mut timings);
+
+    timings.record(&tracing::Span::current());
+    info!("cycle done: {timings}");
+
+    result
+}
+
+fn find_new_background_timed(
+    runtime: &Handle,
+    client: &Client,
+    state: &State,
+    timings: &mut CycleTimings,
+) -> Result<Change> {
+    let config = config::Config::load()?;
+    let (offline, profile) = (state.offline, state.profile.as_str());
+
+    // If the drive holding our data has gone away, there's nothing we can do until it comes back
+    utils::check_storage(paths::root())?;
+
+    // Nothing rotates through the archive in consume mode, so point out the space it's still taking up
+    match picker::switch_mode(config.mode) {
+        Ok(Some(archived)) if archived > 0 => info!(
+            target: "notification",
+            "Switched to consume mode, the {} archived images are kept in {} and only put back up while offline; delete them to free up space",
+            archived,
+            paths::root().join("archive").display(),
+        ),
+        Ok(_) => {}
+        Err(error) => warn!(?error, "could not record which mode we're in"),
+    }
+
+    // Make a closure that tells fetches our images
+    let mut already_fetched = false;
+    // Whether we had to fall back to an image we've shown before
+    let mut reapplied = false;
+    let do_fetch = |timings: &mut CycleTimings| {
+        let fetched = CycleTimings::time(&mut timings.fetch, || fetch_images(runtime, client, &config, profile))?;
+        timings.fetched_images += fetched;
+        Ok::<_, eyre::Report>(())
+    };
+
+    // Where the current background is, for putting it back up if we can't pick a new one
+    let path = background_path();
+
+    // The monitors' sizes are only probed again once they've been rearranged
+    let policy = policy::ImagePolicy::current()?.configure(&config);
+
+    // Candidates that failed to apply this cycle, which we skip in favor of the next ones
+    let mut failed = Vec::new();
+
+    let (picked, path) = loop {
+        // Try to pick an image from the ones we've already fetched, so that we don't make
+        // our user wait too long in the case that they don't have internet access at the
+        // present moment.
+        let picked = match CycleTimings::time(&mut timings.pick, || picker::pick(profile, &config, &failed, &policy)) {
+            // If that succeeds, just return it
+            Ok(img) => img,
+
+            Err(err) => {
+                if let (Some(picker::NoValidImage), false) = (err.downcast_ref(), already_fetched) {
+                    if offline {
+                        // If we're offline we can't fetch anything, so go back to what we haven't shown for longest
+                        match picker::pick_archived(profile, &config, &failed, &policy) {
+                            Ok(picked) => {
+                                info!("cache ran dry while offline, reapplying the least recently applied background");
+                                reapplied = true;
+                                picked
+                            }
+                            // And if there's nothing we've kept, put the current background back up
+                            Err(error) if error.is::<picker::NoValidImage>() => {
+                                if !path.exists() {
+                                    bail!(err);
+                                }
+                                info!(
+                                    "cache ran dry while offline with nothing archived, reapplying current background"
+                                );
+                                platform::set_background(&path, config.wallpaper_style)?;
+                                if let (Some(command), true) =
+                                    (config.on_change_command, config.on_change_command_on_reapply)
+                                {
+                                    hooks::spawn_on_change(runtime, command, &path, None);
+                                }
+                                return Ok(Change {
+                                    subreddit: None,
+                                    reapplied: true,
+                                });
+                            }
+                            Err(error) => return Err(error),
+                        }
+                    } else {
+                        // Otherwise, if we found no valid image, try to fetch them and pick again
+                        debug!("found no valid image on first try");
+                        do_fetch(timings)?;
+                        already_fetched = true;
+                        CycleTimings::time(&mut timings.pick, || picker::pick(profile, &config, &failed, &policy))?
+                    }
+                } else {
+                    // If we got any other error, bail and return it to the caller
+                    bail!(err);
+                }
+            }
+        };
+
+        match CycleTimings::time(&mut timings.apply, || {
+            apply_background(&picked, &config.filters, config.wallpaper_style)
+        }) {
+            Ok(path) => {
+                picker::mark_applied(&picked, profile, config.mode)?;
+                break (picked, path);
+            }
+
+            // Some images just won't apply, so rather than bothering the user move on to the next one
+            Err(error) => {
+                picker::record_failure(&picked, &error)?;
+                failed.push(picked.path);
+                if failed.len() >= MAX_APPLY_ATTEMPTS {
+                    return Err(error);
+                }
+                warn!(?error, "could not apply background, trying the next candidate");
+            }
+        }
+    };
+
+    // The other monitors only get their own backgrounds once the primary one has one, and they keep showing it if
+    // that fails
+    if config.per_monitor_backgrounds {
+        if let Err(error) = apply_other_monitors(&path, profile, &config, &failed) {
+            warn!(?error, "could not give the other monitors backgrounds of their own");
+        }
+    }
+
+    if let Some(ref command) = config.on_change_command {
+        hooks::spawn_on_change(runtime, command.clone(), &path, Some(&picked));
+    }
+
+    // Exporting means downloading the original again, which we can't do offline, and which counts against the same
+    // limits as any other download
+    if let (Some(export), Some(url), false) = (&config.export, &picked.url, offline) {
+        let allowed = enforce_budget(&config).and_then(|()| enforce_disk_floor(&config));
+        match allowed {
+            Ok(()) => export::spawn(runtime, client, export.clone(), url.clone(), picked.title.clone()),
+            Err(error) => info!(%error, "not exporting the original"),
+        }
+    }
+
+    // If we didn't fetch while picking the image, do so after setting the background, unless fetching happens on
+    // its own schedule
+    if !already_fetched && !offline && config.fetch_schedule.is_none() {
+        match do_fetch(timings) {
+            Err(error) if error.is::<utils::NoInternet>() => info!("no internet connection, relying on cached images"),
+            Err(error) if error.is::<utils::BudgetExhausted>() => {
+                info!("download budget used up, relying on cached images")
+            }
+            Err(error) if error.is::<utils::LowDiskSpace>() => info!("low on disk space, relying on cached images"),
+            result => result?,
+        }
+    }
+
+    Ok(Change {
+        subreddit: picked.subreddit,
+        reapplied,
+    })
+}
+
 fn setup_dirs() -> Result<()> {
     use std::fs::create_dir_all;
     create_dir_all(DIRS.cache_dir())?;
-    create_dir_all(DIRS.data_local_dir().join("images"))?;
-    create_dir_all(DIRS.data_local_dir().join("logs"))?;
+    create_dir_all(DIRS.data_local_dir())?;
     create_dir_all(DIRS.config_dir())?;
     Ok(())
 }
 
-fn setup_tracing() {
+/// Create the directories under the data root, which can only be found once the config is loaded.
+fn setup_data_dirs(root: &std::path::Path) -> std::io::Result<()> {
+    use std::fs::create_dir_all;
+    create_dir_all(root.join("images"))?;
+    create_dir_all(root.join("logs"))?;
+    Ok(())
+}
+
+fn setup_tracing(level: tracing::level_filters::LevelFilter, app_id: &str) {
     use tracing_subscriber::prelude::*;
 
     let file = std::sync::Mutex::new(file_rotator::RotatingFile::new(
         env!("CARGO_PKG_NAME"),
-        DIRS.data_local_dir().join("logs"),
+        logs::dir(),
         file_rotator::RotationPeriod::Interval(std::time::Duration::from_secs(60 * 60 * 24)),
         std::num::NonZeroUsize::new(128).unwrap(),
         file_rotator::Compression::Zstd { level: 0 },
@@ -108,15 +886,17 @@ fn setup_tracing() {
 
     let notifier = platform::Notifier {
         title: env!("CARGO_PKG_NAME").into(),
-        icon: ICON_PATH.into(),
+        app_id: app_id.into(),
+        icon: icons::path(icons::State::Normal),
     }
     .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
         metadata.is_event() && (*metadata.level() == Level::ERROR || metadata.target().ends_with("notification"))
     }));
 
+    // Our dependencies are chatty, so they never get to log more than we do
     let filter = tracing_subscriber::filter::Targets::new()
-        .with_default(tracing::Level::INFO)
-        .with_target("redditbg", tracing::level_filters::STATIC_MAX_LEVEL);
+        .with_default(level.min(tracing::level_filters::LevelFilter::INFO))
+        .with_target("redditbg", level);
 
     let fmt = tracing_subscriber::fmt::layer().event_format(tracing_subscriber::fmt::format().pretty());
 
@@ -130,138 +910,1258 @@ fn setup_tracing() {
         .init();
 }
 
-fn setup_client() -> Result<Client> {
-    Client::builder()
-        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+/// Build the user agent we send, which Reddit asks to be descriptive and to include contact info.
+///
+/// A user agent we can't send falls back to our own, as it's not worth refusing to start over.
+fn user_agent(config: &config::Config) -> HeaderValue {
+    const DEFAULT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+    let user_agent = match (&config.user_agent, &config.user_agent_suffix) {
+        (Some(custom), _) => custom.clone(),
+        (None, Some(suffix)) => format!("{DEFAULT} {suffix}"),
+        (None, None) => return HeaderValue::from_static(DEFAULT),
+    };
+
+    HeaderValue::from_str(&user_agent).unwrap_or_else(|error| {
+        warn!(
+            target: "notification",
+            ?error,
+            "Invalid characters in user agent {user_agent:?}, using the default one"
+        );
+        HeaderValue::from_static(DEFAULT)
+    })
+}
+
+fn setup_client(config: &config::Config) -> Result<Client> {
+    build_client(config, config.prefer_ipv4)
+}
+
+/// Build our HTTP client, optionally only connecting over IPv4 for networks whose IPv6 is broken.
+fn build_client(config: &config::Config, ipv4_only: bool) -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(user_agent(config))
         .timeout(Duration::from_secs(60))
-        .connect_timeout(Duration::from_secs(10))
-        .build()
-        .wrap_err("Failed to create client")
+        .connect_timeout(Duration::from_secs(10));
+    if ipv4_only {
+        // Binding to an IPv4 address means we can only ever connect to IPv4 ones
+        builder = builder.local_address(std::net::IpAddr::from(std::net::Ipv4Addr::UNSPECIFIED));
+    }
+    builder.build().wrap_err("Failed to create client")
+}
+
+/// A folder of ours the user can open from the tray
+#[derive(Clone, Copy, Debug)]
+enum FolderKind {
+    /// Where the current profile's images are cached
+    Images,
+    Logs,
 }
 
 enum Message {
     ChangeNow,
     CopyImage,
+    OpenSource,
+    SetOffline(bool),
+    SetNsfw(bool),
+    SetWallpaperStyle(platform::WallpaperStyle),
+    SwitchProfile(String),
+    PreviewCandidates,
+    OpenFolder(FolderKind),
+    Snooze(Duration),
+    Pause,
+    Resume,
+    CycleDone(Trigger, Result<Change>),
+    FirstFetchDone(Result<usize>),
+    FetchDone(Result<usize>),
     Quit,
 }
 
-const ICON_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/icon.ico");
+const TOOLTIP: &str = "Reddit Background Setter";
+
+/// Run `cycle` on a thread of its own, telling the main loop once it's done.
+fn spawn_cycle(
+    runtime: &Runtime,
+    tx: SyncSender<Message>,
+    trigger: Trigger,
+    cycle: impl FnOnce() -> Result<Change> + Send + 'static,
+) {
+    runtime.spawn_blocking(move || {
+        let running = health::Cycle::start();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(cycle))
+            .unwrap_or_else(|_| Err(eyre::eyre!("Cycle panicked")));
+        // The next cycle may start as soon as we say we're done
+        drop(running);
+        send_message(&tx, "cycle done", Message::CycleDone(trigger, result));
+    });
+}
+
+fn send_message(tx: &SyncSender<Message>, payload: &str, message: Message) {
+    info!(payload, "sending message");
 
-fn setup_systray() -> Result<(utils::JoinOnDrop, Receiver<Message>)> {
-    let mut app = systray::Application::new()?;
+    if let Err(error) = tx.send(message) {
+        let error = eyre::Report::from(error);
+        error!(?error, "could not send message");
+    }
+}
 
+fn setup_systray(
+    config: &config::Config,
+    paused: bool,
+) -> Result<(
+    tray::TrayHandle,
+    utils::JoinOnDrop,
+    SyncSender<Message>,
+    Receiver<Message>,
+)> {
     let (tx, rx) = sync_channel(10);
+    let mut menu = tray::Menu::new();
 
-    app.set_tooltip("Reddit Background Setter")?;
+    {
+        let tx = tx.clone();
+        menu.item("Change now", move |_| {
+            send_message(&tx, "change now", Message::ChangeNow)
+        });
+    }
+
+    {
+        let mut submenu = tray::Menu::new();
+        for (label, duration) in [
+            ("For an hour", Some(Duration::from_secs(60 * 60))),
+            ("For 4 hours", Some(Duration::from_secs(4 * 60 * 60))),
+            ("Until tomorrow", None),
+        ] {
+            let tx = tx.clone();
+            submenu.item(label, move |_| {
+                let duration = duration.unwrap_or_else(snooze::until_tomorrow);
+                send_message(&tx, "snooze", Message::Snooze(duration));
+            });
+        }
+        menu.submenu("Keep this wallpaper", submenu);
+    }
 
     {
         let tx = tx.clone();
-        app.add_menu_item("Change now", move |_app| -> Result<(), Infallible> {
-            info!(payload = "change now", "sending message");
+        menu.check_item("Pause", paused, move |_, paused| {
+            let message = if paused { Message::Pause } else { Message::Resume };
+            send_message(&tx, "pause", message);
+        });
+    }
 
-            if let Err(error) = tx.send(Message::ChangeNow) {
-                let error = eyre::Report::from(error);
-                error!(?error, "could not send message");
-            }
+    {
+        let tx = tx.clone();
+        menu.item("Copy background to clipboard", move |_| {
+            send_message(&tx, "copy image", Message::CopyImage);
+        });
+    }
 
-            Ok(())
-        })?;
+    {
+        let tx = tx.clone();
+        menu.item("Open current background on reddit", move |_| {
+            send_message(&tx, "open source", Message::OpenSource);
+        });
     }
 
     {
         let tx = tx.clone();
-        app.add_menu_item("Copy background to clipboard", move |_app| -> Result<(), Infallible> {
-            info!(payload = "copy image", "sending message");
+        menu.item("Preview next candidates", move |_| {
+            send_message(&tx, "preview candidates", Message::PreviewCandidates);
+        });
+    }
 
-            if let Err(error) = tx.send(Message::CopyImage) {
-                let error = eyre::Report::from(error);
-                error!(?error, "could not send message");
-            }
+    for (label, kind) in [
+        ("Open images folder", FolderKind::Images),
+        ("Open logs folder", FolderKind::Logs),
+    ] {
+        let tx = tx.clone();
+        menu.item(label, move |_| {
+            send_message(&tx, "open folder", Message::OpenFolder(kind));
+        });
+    }
 
-            Ok(())
-        })?;
+    {
+        let tx = tx.clone();
+        menu.check_item("Offline mode", false, move |_, offline| {
+            send_message(&tx, "set offline", Message::SetOffline(offline));
+        });
     }
 
-    app.add_menu_item("Quit", move |app| -> Result<(), Infallible> {
-        info!(payload = "quit", "sending message");
+    {
+        let tx = tx.clone();
+        menu.check_item("Include NSFW posts", config.include_nsfw, move |_, include| {
+            send_message(&tx, "set nsfw", Message::SetNsfw(include));
+        });
+    }
 
-        // at this point i'm praying this works
-        if let Err(error) = app.shutdown() {
-            let error = eyre::Report::from(error);
-            error!(?error, "shutdown failed");
+    {
+        let mut submenu = tray::Menu::new();
+        for &style in platform::WallpaperStyle::ALL {
+            let tx = tx.clone();
+            submenu.radio_item(style.label(), 1, style == config.wallpaper_style, move |_| {
+                send_message(&tx, "set wallpaper style", Message::SetWallpaperStyle(style));
+            });
         }
-        app.quit();
+        menu.submenu("Wallpaper style", submenu);
+    }
 
-        if let Err(error) = tx.send(Message::Quit) {
-            let error = eyre::Report::from(error);
-            error!(?error, "could not send message");
+    // Only bother with a submenu when there's something to choose from
+    let profiles = config.profile_names();
+    if profiles.len() > 1 {
+        let mut submenu = tray::Menu::new();
+        for profile in profiles {
+            let tx = tx.clone();
+            let label = profile.clone();
+            let checked = profile == config::DEFAULT_PROFILE;
+            submenu.radio_item(&label, 0, checked, move |_| {
+                send_message(&tx, "switch profile", Message::SwitchProfile(profile.clone()));
+            });
         }
+        menu.submenu("Profile", submenu);
+    }
 
-        Ok(())
-    })?;
+    menu.separator();
+
+    {
+        let tx = tx.clone();
+        menu.item("Quit", move |tray| {
+            if let Err(error) = tray.quit() {
+                error!(?error, "shutdown failed");
+            }
 
-    // This should really support Path.. grumble grumble..
-    app.set_icon_from_file(ICON_PATH)?;
+            send_message(&tx, "quit", Message::Quit);
+        });
+    }
 
-    let handle = std::thread::Builder::new()
-        .name("systray".to_owned())
-        .spawn(move || app.wait_for_message().map_err(eyre::Error::from))?;
+    let (handle, thread) = tray::spawn(TOOLTIP, &icons::path(icons::State::Normal), menu)?;
 
-    Ok((utils::JoinOnDrop::new(handle), rx))
+    Ok((handle, utils::JoinOnDrop::new(thread), tx, rx))
 }
 
 fn main() -> Result<()> {
     setup_dirs()?;
-    setup_tracing();
-    let (_guard, messages) = setup_systray()?;
-    let client = setup_client()?;
+    // Both the tray and notifications load our icon from a file, so it has to be written out before either is up
+    let icons = icons::generate();
+    // The config decides how much we log, so it's loaded before logging is up and any error is reported after
+    let (config, config_reset) = match config::Config::load_quietly() {
+        Ok((config, reset)) => (Ok(config), reset),
+        Err(error) => (Err(error), None),
+    };
+    let level = logs::level_filter(config.as_ref().ok().and_then(|config| config.log_level.as_deref()));
+    // The logs are kept under the data root, so it has to be known before logging is up too; if it's on a drive
+    // that isn't there right now, we make do with the default one until the next launch
+    let cache_root = config.as_ref().ok().and_then(|config| config.cache_root.clone());
+    let cache_root_error = match cache_root {
+        Some(ref root) => setup_data_dirs(root).err(),
+        None => None,
+    };
+    paths::set_root(cache_root.clone().filter(|_| cache_root_error.is_none()));
+    setup_data_dirs(paths::root())?;
+    // Notifications need to know whose name to show under before logging is up, too
+    let register_app_id = matches!(&config, Ok(config) if config.register_app_id);
+    let registered = if register_app_id {
+        platform::register_app_id()
+    } else {
+        platform::unregister_app_id()
+    };
+    let app_id = match registered {
+        Ok(()) if register_app_id => platform::APP_ID,
+        _ => winrt_notification::Toast::POWERSHELL_APP_ID,
+    };
+    setup_tracing(
+        level.clone().unwrap_or(tracing::level_filters::LevelFilter::INFO),
+        app_id,
+    );
+    if let Err(setting) = level {
+        warn!(?setting, "unknown log level, using info");
+    }
+    if let Err(error) = icons {
+        warn!(?error, "could not write icons");
+    }
+    if let Some(reset) = config_reset {
+        reset.notify();
+    }
+    if let (Some(root), Some(error)) = (cache_root, cache_root_error) {
+        warn!(
+            target: "notification",
+            ?error,
+            root = %root.display(),
+            "The drive holding the image cache is unavailable, using the default location until the next launch"
+        );
+    }
+    if let Err(error) = registered {
+        if register_app_id {
+            warn!(
+                ?error,
+                "could not register our app identity, notifications will show up as PowerShell's"
+            );
+        } else {
+            warn!(?error, "could not remove our Start Menu shortcut");
+        }
+    }
+    platform::set_dpi_aware();
+    // Rather than delete the user's files, we don't start at all
+    if let Ok(ref config) = config {
+        if let Err(error) = paths::check_overlaps(config) {
+            error!(target: "notification", "Refusing to start, as files would be deleted: {error}");
+            return Err(error);
+        }
+    }
+    // Bring the database up to date before anything else gets to it
+    db::open()?;
+    // A move that fails halfway is picked up again on the next launch, as the new root isn't recorded until it's done
+    if let Err(error) = paths::migrate() {
+        error!(?error, "could not move data to its new location");
+    }
+    fetcher::migrate_flat_cache()?;
+    match fetcher::remove_partial_writes() {
+        Ok(0) => {}
+        Ok(removed) => info!(removed, "removed images left half-written by an earlier run"),
+        Err(error) => warn!(?error, "could not remove images left half-written by an earlier run"),
+    }
+    let config = config.unwrap_or_else(|error| {
+        error!(?error, "could not load config, using defaults");
+        config::Config::default()
+    });
 
-    let mut runtime = Runtime::new()?;
+    // `redditbg why <url>` is a one-shot diagnostic for posts that never show up
+    let mut args = std::env::args().skip(1);
+    let mut autostart = false;
+    let command = args.next();
+    // Commands print their results, which would go nowhere without a console
+    if command.as_deref().is_some_and(|command| !command.starts_with("--")) {
+        platform::attach_console();
+    }
+    match command.as_deref() {
+        Some("why") => {
+            let Some(url) = args.next() else {
+                bail!("usage: redditbg why <url>")
+            };
+            return why::run(&config, &setup_client(&config)?, &url);
+        }
+        // `redditbg check-health [max age in seconds] [max cycle length in seconds]` is for watchdog scripts
+        Some("check-health") => {
+            if !health::check(args.next().as_deref(), args.next().as_deref())? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        // `redditbg bench --images <dir>` times the image pipeline without touching the network or the cache
+        Some("bench") => return bench::run(&config, args),
+        // `redditbg sources-report` shows which subreddits are worth keeping
+        Some("sources-report") => return sources_report::run(),
+        // Startup shortcuts pass `--autostart`, so that we stay out of the way while everything else starts up
+        Some("--autostart") => autostart = true,
+        Some(command) => bail!("unknown command {command:?}"),
+        None => {}
+    }
 
-    'mainloop: loop {
-        match find_new_background(&mut runtime, &client) {
-            Ok(()) => info!("set background successfully"),
-            Err(error) => {
-                error!(?error, "error while finding new background");
+    config.warn_deprecated();
+
+    // A pause lasts until the user resumes, even across restarts
+    let paused = snooze::is_paused().unwrap_or_else(|error| {
+        warn!(?error, "could not load pause");
+        false
+    });
+    let (tray, _guard, tx, messages) = setup_systray(&config, paused)?;
+
+    let client = setup_client(&config)?;
+
+    // Windows re-encodes backgrounds as JPEGs, which undoes all the care we take to store them losslessly
+    let _jpeg_quality = if config.max_jpeg_quality {
+        platform::max_jpeg_import_quality().unwrap_or_else(|error| {
+            warn!(?error, "could not raise JPEG import quality");
+            None
+        })
+    } else {
+        None
+    };
+
+    if config.notify_on_start {
+        // We're about to change the background, so the next change is one interval away
+        match status::Status::gather(config::DEFAULT_PROFILE, Some(config.change_interval())) {
+            Ok(status) => info!(target: "notification", "{} started: {status}", env!("CARGO_PKG_NAME")),
+            Err(error) => warn!(?error, "could not gather startup status"),
+        }
+    }
+
+    let runtime = Runtime::new()?;
+
+    // Get lazily initialized things out of the way now, so that the first cycle starts right away and anything wrong
+    // with them shows up as soon as we start
+    if let Err(error) = runtime.block_on(utils::warm_up_db()) {
+        error!(?error, "could not open database");
+    }
+    match policy::monitors() {
+        Ok(monitors) => debug!(?monitors, "probed monitors"),
+        Err(error) => error!(?error, "could not get screen size"),
+    }
+
+    let mut state = State {
+        offline: false,
+        profile: config::DEFAULT_PROFILE.to_owned(),
+        downloaded_today: 0,
+        snoozed_until: snooze::load().unwrap_or_else(|error| {
+            warn!(?error, "could not load snooze");
+            None
+        }),
+        paused,
+    };
+
+    // Fetching may happen on its own schedule, in which case we keep track of when it's next due. While the background
+    // is kept as it is, fetching carries on without one at the pace we'd otherwise change it at, so that there's plenty
+    // to pick from once we do.
+    let fetch_deadline = |state: &State| {
+        let scheduled = config
+            .fetch_schedule
+            .as_ref()
+            .and_then(config::FetchSchedule::until_next)
+            .map(|until| Instant::now() + until);
+        let kept = state.paused || state.snoozed_until.is_some();
+        scheduled.or_else(|| kept.then(|| change_deadline(config.change_interval())))
+    };
+    let mut next_fetch = fetch_deadline(&state);
+
+    // We only offer an update once, there's no point in piling up menu items
+    let mut update_offered = false;
+
+    let mut notices = CycleNotices::default();
+
+    // Cycles run in the background so that we keep handling the tray while they do; the first one happens on its
+    // own, just like the timed ones
+    let start_cycle = |state: &State, trigger: Trigger| {
+        let (handle, client) = (runtime.handle().clone(), client.clone());
+        let state = state.clone();
+        spawn_cycle(&runtime, tx.clone(), trigger, move || {
+            find_new_background(&handle, &client, &state, trigger)
+        });
+    };
+
+    // On first launch we may only fill the cache, leaving the user's background up until they ask for one of ours
+    let mut first_run_complete = first_run::is_complete().unwrap_or_else(|error| {
+        warn!(?error, "could not check for first run, assuming it's done");
+        true
+    });
+    let awaiting_first_change =
+        |first_run_complete: bool| !first_run_complete && config.first_run != config::FirstRun::Apply;
+
+    // When started at login, we wait a bit so as not to compete with everything else starting up, and a snooze from
+    // before we were restarted still holds
+    let startup_delay = startup_delay(
+        config.startup_delay_seconds,
+        autostart,
+        state.snoozed_until.map(snooze::remaining).unwrap_or_default(),
+    );
+
+    // What started the cycle that's running, if any; the first fetch counts as a timed cycle, so that clicking
+    // "Change now" during it waits for it to finish
+    let mut running = None;
+    let mut next_change = change_deadline(config.change_interval());
+    if awaiting_first_change(first_run_complete) {
+        info!("first run, fetching without changing the background");
+        let (handle, client, tx, state) = (runtime.handle().clone(), client.clone(), tx.clone(), state.clone());
+        runtime.spawn_blocking(move || {
+            std::thread::sleep(startup_delay);
+            let cycle = health::Cycle::start();
+            let result =
+                config::Config::load().and_then(|config| fetch_images(&handle, &client, &config, &state.profile));
+            drop(cycle);
+            send_message(&tx, "first fetch done", Message::FirstFetchDone(result));
+        });
+        running = Some(Trigger::Timer);
+    } else if state.paused {
+        info!("paused, leaving the background alone");
+    } else if startup_delay.is_zero() {
+        start_cycle(&state, Trigger::Timer);
+        running = Some(Trigger::Timer);
+    } else {
+        info!(?startup_delay, "delaying the first change");
+        next_change = Instant::now() + startup_delay;
+    }
+
+    // A cycle requested while another was running, which we start as soon as that one's done
+    let mut pending_trigger = None;
+
+    let mut expected_background = background_path();
+    let mut next_check = None;
+
+    // Once a group policy has been caught putting its own background back, we stop changing ours until we're restarted
+    let mut policy_check = None;
+    let mut policy_blocked = false;
+
+    // The style can be changed from the tray while we're running, and reapplying has to use the new one
+    let mut wallpaper_style = config.wallpaper_style;
+
+    // What we tell watchdog scripts about ourselves
+    let mut next_heartbeat = Instant::now();
+    let mut last_cycle = None;
+    let mut shutdown_reason = "quit";
+
+    // The tray starts out with the normal icon, and switches whenever what it should show changes
+    let mut last_cycle_failed = false;
+    let mut shown_icon = icons::State::Normal;
+
+    loop {
+        let icon = icons::State::of(state.paused, running.is_some(), last_cycle_failed);
+        if icon != shown_icon {
+            if let Err(error) = tray.set_icon(&icons::path(icon)) {
+                error!(?error, "could not set icon");
             }
+            shown_icon = icon;
         }
 
-        loop {
-            match messages.recv_timeout(Duration::from_secs(60 * 60)) {
-                Ok(Message::Quit) => {
-                    info!("got quit message");
-                    break 'mainloop;
+        if next_heartbeat <= Instant::now() {
+            if let Err(error) = health::write(&health::Health::new(last_cycle.clone(), running.is_some(), None)) {
+                warn!(?error, "could not write health file");
+            }
+            next_heartbeat = Instant::now() + health::HEARTBEAT_INTERVAL;
+        }
+
+        // While a cycle is running, nothing but the tray and the heartbeat can wake us up
+        let deadline = if running.is_some() {
+            next_heartbeat
+        } else {
+            [next_fetch, next_check, policy_check]
+                .iter()
+                .flatten()
+                .copied()
+                .fold(next_change.min(next_heartbeat), Instant::min)
+        };
+        match messages.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Message::Quit) => {
+                info!("got quit message");
+                break;
+            }
+
+            Ok(Message::ChangeNow) => {
+                info!("got change now message");
+                match ChangeNow::of(running) {
+                    ChangeNow::Ignore => info!(target: "notification", "Already changing the wallpaper"),
+                    ChangeNow::Queue => {
+                        unsnooze(&mut state, &tray);
+                        pending_trigger = Some(Trigger::Manual);
+                    }
+                    ChangeNow::Start => {
+                        unsnooze(&mut state, &tray);
+                        start_cycle(&state, Trigger::Manual);
+                        running = Some(Trigger::Manual);
+                    }
+                }
+            }
+
+            Ok(Message::CycleDone(trigger, result)) => {
+                running = None;
+                last_cycle_failed = result.is_err();
+                last_cycle = Some(match result {
+                    Ok(_) => "ok".to_owned(),
+                    Err(ref error) => format!("error: {}", error_category(error)),
+                });
+                match result {
+                    Ok(_) => {
+                        info!("set background successfully");
+                        policy_check = Some(Instant::now() + POLICY_CHECK_DELAY);
+                        if !first_run_complete {
+                            match first_run::complete() {
+                                Ok(()) => first_run_complete = true,
+                                Err(error) => warn!(?error, "could not record first run"),
+                            }
+                        }
+                    }
+                    // The user gets told about it below, so don't show the raw error too
+                    Err(ref error) => warn!(?error, "error while finding new background"),
+                }
+                if let Some(message) = notices.notification(trigger, &result) {
+                    info!(target: "notification", "{message}");
+                }
+
+                // Manual changes already got an answer above, but timed ones happen while the user isn't looking.
+                // Putting an old background back up while offline isn't worth pointing out though, and nothing is while
+                // the user doesn't want to be disturbed.
+                if let (Trigger::Timer, Ok(change @ Change { reapplied: false, .. })) = (trigger, &result) {
+                    // The user may have paused changes while this one was underway
+                    let quiet = state.paused
+                        || platform::do_not_disturb().unwrap_or_else(|error| {
+                            warn!(?error, "could not tell whether the user wants to be left alone");
+                            false
+                        });
+                    if quiet {
+                        debug!("not cueing the change, as the user doesn't want to be disturbed");
+                    } else {
+                        if config.on_change_cue.sound() {
+                            if let Err(error) = platform::play_cue() {
+                                warn!(?error, "could not play change cue");
+                            }
+                        }
+                        if config.on_change_cue.toast() {
+                            info!(target: "notification", "{}", describe_change(change));
+                        }
+                    }
+                }
+
+                match runtime.block_on(async { utils::Bandwidth::new().await?.today().await }) {
+                    Ok((_, bytes)) => {
+                        state.downloaded_today = bytes;
+                        if let Err(error) = tray.set_tooltip(&state.tooltip()) {
+                            error!(?error, "could not set tooltip");
+                        }
+                    }
+                    Err(error) => warn!(?error, "could not get bandwidth usage"),
+                }
+
+                if config.check_for_updates && !update_offered && !state.offline {
+                    if let Some(update) = runtime.block_on(update::check(&client)) {
+                        info!(target: "notification", "Version {} is available", update.version);
+                        let result = tray.prepend_item("Download update", move |_| {
+                            if let Err(error) = platform::open(Path::new(&update.url)) {
+                                error!(?error, "could not open release page");
+                            }
+                        });
+                        match result {
+                            Ok(_) => update_offered = true,
+                            Err(error) => error!(?error, "could not add update menu item"),
+                        }
+                    }
+                }
+
+                if config.weekly_digest {
+                    if let Err(error) = digest::maybe_notify() {
+                        warn!(?error, "could not show weekly digest");
+                    }
+                }
+
+                if let Err(error) = logs::prune(&config) {
+                    warn!(?error, "could not prune log files");
+                }
+
+                // A cycle that was already running when the user snoozed doesn't cut the snooze short
+                next_change = change_deadline(config.change_interval())
+                    .max(Instant::now() + state.snoozed_until.map(snooze::remaining).unwrap_or_default());
+
+                // Having just gone through a cycle, the background that's up should be ours
+                expected_background = background_path();
+                next_check = (config.foreign_wallpaper != config::ForeignWallpaperPolicy::Ignore)
+                    .then(|| Instant::now() + WALLPAPER_CHECK_INTERVAL);
+
+                if let Some(trigger) = pending_trigger.take() {
+                    start_cycle(&state, trigger);
+                    running = Some(trigger);
+                }
+            }
+
+            Ok(Message::SetOffline(value)) => {
+                info!(offline = value, "got set offline message");
+                state.offline = value;
+                if let Err(error) = tray.set_tooltip_now(&state.tooltip()) {
+                    error!(?error, "could not set tooltip");
+                }
+            }
+
+            Ok(Message::SetNsfw(value)) => {
+                info!(include_nsfw = value, "got set nsfw message");
+                // The config is reloaded before every fetch, so this takes effect on the next one
+                if let Err(error) = config::set("include_nsfw", value) {
+                    error!(?error, "could not save NSFW setting");
+                }
+            }
+
+            Ok(Message::SetWallpaperStyle(style)) => {
+                info!(?style, "got set wallpaper style message");
+                if let Err(error) = config::set("wallpaper_style", style.name()) {
+                    error!(?error, "could not save wallpaper style");
+                }
+
+                // Show the new style right away rather than on the next change
+                wallpaper_style = style;
+                if let Some(current) = current_background(&expected_background) {
+                    if let Err(error) = reapply_background(current, style, config.per_monitor_backgrounds) {
+                        error!(?error, "could not reapply background");
+                    }
+                }
+            }
+
+            Ok(Message::SwitchProfile(profile)) => {
+                info!(?profile, "got switch profile message");
+                state.profile = profile;
+                if let Err(error) = tray.set_tooltip_now(&state.tooltip()) {
+                    error!(?error, "could not set tooltip");
+                }
+
+                // Show something from the new profile right away
+                if running.is_some() {
+                    pending_trigger = Some(Trigger::Manual);
+                } else {
+                    start_cycle(&state, Trigger::Manual);
+                    running = Some(Trigger::Manual);
+                }
+            }
+
+            Ok(Message::Snooze(duration)) => {
+                info!(?duration, "got snooze message");
+                match snooze::start(duration) {
+                    Ok(until) => state.snoozed_until = Some(until),
+                    Err(error) => error!(?error, "could not save snooze"),
                 }
+                // Snoozing again replaces the previous snooze rather than adding to it
+                next_change = Instant::now() + duration;
+                next_fetch = fetch_deadline(&state);
+                if let Err(error) = tray.set_tooltip_now(&state.tooltip()) {
+                    error!(?error, "could not set tooltip");
+                }
+            }
 
-                Ok(Message::ChangeNow) => {
-                    info!("got change now message");
-                    continue 'mainloop;
+            Ok(message @ (Message::Pause | Message::Resume)) => {
+                let paused = matches!(message, Message::Pause);
+                info!(paused, "got pause message");
+                state.paused = paused;
+                if let Err(error) = snooze::set_paused(paused) {
+                    error!(?error, "could not save pause");
                 }
+                // Resuming starts the interval over rather than changing the background right away
+                if !paused {
+                    next_change = change_deadline(config.change_interval());
+                }
+                next_fetch = fetch_deadline(&state);
+                if let Err(error) = tray.set_tooltip_now(&state.tooltip()) {
+                    error!(?error, "could not set tooltip");
+                }
+            }
 
-                Ok(Message::CopyImage) => {
-                    match image::io::Reader::open(&DIRS.cache_dir().join("background.png"))
-                        .map_err(eyre::Error::from)
-                        .and_then(|reader| platform::copy_image(&reader.with_guessed_format()?.decode()?))
+            Ok(Message::OpenFolder(kind)) => {
+                info!(?kind, "got open folder message");
+                let dir = match kind {
+                    FolderKind::Images => fetcher::images_dir(&state.profile),
+                    FolderKind::Logs => logs::dir(),
+                };
+                // A profile we've just switched to may not have a folder yet
+                if let Err(error) = std::fs::create_dir_all(&dir)
+                    .map_err(eyre::Report::from)
+                    .and_then(|()| platform::open(&dir))
+                {
+                    error!(?error, "could not open folder");
+                }
+            }
+
+            Ok(Message::PreviewCandidates) => {
+                info!("got preview candidates message");
+                // Making thumbnails for a full cache takes a while, so the tray keeps going while it happens
+                let profile = state.profile.clone();
+                runtime.spawn_blocking(move || {
+                    if let Err(error) = report::preview_candidates(&profile).and_then(|page| platform::open(&page)) {
+                        error!(?error, "preview candidates error");
+                    }
+                });
+            }
+
+            Ok(Message::CopyImage) => {
+                let Some(current) = current_background(&expected_background) else {
+                    warn!(target: "notification", "There's no background to copy yet");
+                    continue;
+                };
+                match image::io::Reader::open(current)
+                    .map_err(eyre::Error::from)
+                    .and_then(|reader| platform::copy_image(&reader.with_guessed_format()?.decode()?))
+                {
+                    Ok(()) => info!(target: "notification", "copied image"),
+
+                    Err(error) => {
+                        error!(?error, "copy image error");
+                    }
+                }
+            }
+
+            Ok(Message::OpenSource) => {
+                info!("got open source message");
+                // An adopted background isn't one of ours, so the last one we applied isn't what's up
+                let permalink = if expected_background == background_path() {
+                    last_permalink()
+                } else {
+                    Ok(None)
+                };
+                match permalink {
+                    Ok(Some(permalink)) => {
+                        if let Err(error) = platform::open(Path::new(&permalink)) {
+                            error!(?error, "could not open post");
+                        }
+                    }
+                    Ok(None) => {
+                        warn!(target: "notification", "We don't know which post the current background came from")
+                    }
+                    Err(error) => error!(?error, "could not look up the current background's post"),
+                }
+            }
+
+            Ok(Message::FirstFetchDone(result)) => {
+                running = None;
+                match result {
+                    Ok(fetched) => {
+                        info!(fetched, "first fetch done");
+                        info!(
+                            target: "notification",
+                            "New backgrounds are ready, click \"Change now\" to apply one"
+                        );
+                    }
+                    Err(error) => warn!(?error, "error during first fetch"),
+                }
+                next_change = change_deadline(config.change_interval());
+
+                if let Some(trigger) = pending_trigger.take() {
+                    start_cycle(&state, trigger);
+                    running = Some(trigger);
+                }
+            }
+
+            Ok(Message::FetchDone(result)) => {
+                running = None;
+                match result {
+                    Ok(fetched) => info!(fetched, "fetched images successfully"),
+                    Err(error)
+                        if error.is::<utils::NoInternet>()
+                            || error.is::<utils::BudgetExhausted>()
+                            || error.is::<utils::LowDiskSpace>() =>
+                    {
+                        info!(?error, "skipped scheduled fetch")
+                    }
+                    Err(error) => error!(?error, "error while fetching images"),
+                }
+
+                if let Some(trigger) = pending_trigger.take() {
+                    start_cycle(&state, trigger);
+                    running = Some(trigger);
+                }
+            }
+
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("sys tray hung up");
+                shutdown_reason = "tray hung up";
+                break;
+            }
+
+            // Nothing else is due while a cycle is running
+            Err(RecvTimeoutError::Timeout) if running.is_some() => {}
+
+            Err(RecvTimeoutError::Timeout)
+                if policy_check.is_some_and(|policy_check| policy_check <= Instant::now()) =>
+            {
+                policy_check = None;
+                let enforced = platform::policy_wallpaper();
+                match (platform::get_background(), enforced) {
+                    (Ok(observed), Ok(enforced))
+                        if !policy_blocked
+                            && watch::is_policy_conflict(&observed, &expected_background, enforced.as_deref()) =>
                     {
-                        Ok(()) => info!(target: "notification", "copied image"),
+                        warn!(
+                            target: "notification",
+                            "A group policy keeps your background set to {}, so it won't be changed until {} is restarted",
+                            observed.display(),
+                            env!("CARGO_PKG_NAME")
+                        );
+                        policy_blocked = true;
+                    }
+                    (Err(error), _) | (_, Err(error)) => warn!(?error, "could not check for a wallpaper policy"),
+                    _ => {}
+                }
+            }
+
+            Err(RecvTimeoutError::Timeout) if next_check.is_some_and(|next_check| next_check <= Instant::now()) => {
+                next_check = Some(Instant::now() + WALLPAPER_CHECK_INTERVAL);
+                let observed = match platform::get_background() {
+                    Ok(observed) => observed,
+                    Err(error) => {
+                        warn!(?error, "could not get current background");
+                        continue;
+                    }
+                };
+
+                match watch::decide(config.foreign_wallpaper, &observed, &expected_background) {
+                    watch::Action::Nothing => trace!(observed = %observed.display(), "background unchanged"),
 
-                        Err(error) => {
-                            error!(?error, "copy image error");
+                    // Windows would only put the policy's background right back
+                    watch::Action::Reapply if policy_blocked => {
+                        debug!(observed = %observed.display(), "background changed by a group policy, leaving it");
+                    }
+
+                    watch::Action::Reapply => {
+                        info!(observed = %observed.display(), "background changed by another program, reapplying");
+                        if let Err(error) =
+                            reapply_background(&expected_background, wallpaper_style, config.per_monitor_backgrounds)
+                        {
+                            error!(?error, "could not reapply background");
                         }
                     }
+
+                    watch::Action::Adopt => {
+                        info!(observed = %observed.display(), "background changed by another program, adopting");
+                        expected_background = observed;
+                        next_change = change_deadline(config.change_interval());
+                    }
                 }
+            }
 
-                Err(RecvTimeoutError::Disconnected) => {
-                    error!("sys tray hung up");
-                    break 'mainloop;
+            Err(RecvTimeoutError::Timeout) if next_fetch.is_some_and(|next_fetch| next_fetch <= Instant::now()) => {
+                info!("scheduled fetch");
+                next_fetch = fetch_deadline(&state);
+                if state.offline {
+                    continue;
                 }
 
-                Err(RecvTimeoutError::Timeout) => {
-                    continue 'mainloop;
+                // Fetching takes a while, so it happens in the background just like cycles do, and holds them off
+                // until it's done
+                let (handle, client, tx, profile) = (
+                    runtime.handle().clone(),
+                    client.clone(),
+                    tx.clone(),
+                    state.profile.clone(),
+                );
+                runtime.spawn_blocking(move || {
+                    let cycle = health::Cycle::start();
+                    let result =
+                        config::Config::load().and_then(|config| fetch_images(&handle, &client, &config, &profile));
+                    drop(cycle);
+                    send_message(&tx, "fetch done", Message::FetchDone(result));
+                });
+                running = Some(Trigger::Timer);
+            }
+
+            // Changing the background would only be undone by the group policy, or the user asked us not to
+            Err(RecvTimeoutError::Timeout) if next_change <= Instant::now() && (policy_blocked || state.paused) => {
+                next_change = change_deadline(config.change_interval());
+            }
+
+            // Until the user has asked for their first background, the timer leaves theirs alone
+            Err(RecvTimeoutError::Timeout)
+                if next_change <= Instant::now() && awaiting_first_change(first_run_complete) =>
+            {
+                next_change = change_deadline(config.change_interval());
+            }
+
+            Err(RecvTimeoutError::Timeout) if next_change <= Instant::now() => {
+                if state.snoozed_until.take().is_some() {
+                    if let Err(error) = snooze::clear() {
+                        warn!(?error, "could not clear snooze");
+                    }
                 }
+                start_cycle(&state, Trigger::Timer);
+                running = Some(Trigger::Timer);
             }
+
+            // Only the heartbeat was due, which we take care of at the top of the loop
+            Err(RecvTimeoutError::Timeout) => {}
         }
     }
 
+    let health = health::Health::new(last_cycle, running.is_some(), Some(shutdown_reason.to_owned()));
+    if let Err(error) = health::write(&health) {
+        warn!(?error, "could not write health file");
+    }
+
+    runtime.block_on(utils::TASKS.shutdown(SHUTDOWN_TIMEOUT));
+    if running.is_some() {
+        // Don't keep the user waiting on a cycle that may be stuck on a slow download
+        runtime.shutdown_background();
+    } else {
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clicking_change_now_again_does_nothing_until_the_change_is_done() {
+        assert_eq!(ChangeNow::of(None), ChangeNow::Start);
+        assert_eq!(ChangeNow::of(Some(Trigger::Manual)), ChangeNow::Ignore);
+        // A scheduled change isn't the one that was asked for, so ours still goes through after it
+        assert_eq!(ChangeNow::of(Some(Trigger::Timer)), ChangeNow::Queue);
+    }
+
+    #[test]
+    fn only_autostarts_are_delayed_unless_configured_otherwise() {
+        let none = Duration::ZERO;
+        assert_eq!(startup_delay(None, false, none), Duration::ZERO);
+        assert_eq!(
+            startup_delay(None, true, none),
+            Duration::from_secs(AUTOSTART_DELAY_SECS)
+        );
+        assert_eq!(startup_delay(Some(5), false, none), Duration::from_secs(5));
+        assert_eq!(startup_delay(Some(0), true, none), Duration::ZERO);
+
+        // A snooze that outlasts the delay wins
+        let snoozed = Duration::from_secs(10 * 60);
+        assert_eq!(startup_delay(None, true, snoozed), snoozed);
+        assert_eq!(startup_delay(Some(3600), true, snoozed), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn posts_have_to_score_as_high_as_their_source_asks() {
+        let sources = sources::parse("EarthPorn min_score=100 ratio=any\nwallpapers").unwrap();
+        let rules = SourceRules::new(&sources, 10);
+        let post = |subreddit: &str, score| reddit::Post {
+            id: "t3_post".to_owned(),
+            url: "https://i.redd.it/post.png".to_owned(),
+            subreddit: subreddit.to_owned(),
+            title: String::new(),
+            permalink: None,
+            score,
+            variants: Vec::new(),
+            ratio: sources::RatioRule::Strict,
+        };
+
+        // Reddit spells the subreddit's name its own way
+        let Ok(accepted) = rules.apply(post("EarthPorn", 100)) else {
+            panic!("turned down a post scoring just enough");
+        };
+        assert_eq!(accepted.ratio, sources::RatioRule::Any);
+        assert!(matches!(
+            rules.apply(post("EarthPorn", 99)),
+            Err(fetcher::Rejection::LowScore)
+        ));
+
+        // The rest go by the configured minimum, even the ones from no source we know of
+        assert!(rules.apply(post("wallpapers", 10)).is_ok());
+        assert!(rules.apply(post("wallpapers", 9)).is_err());
+        let Ok(unknown) = rules.apply(post("SkyPorn", 10)) else {
+            panic!("turned down a post from another source");
+        };
+        assert_eq!(unknown.ratio, sources::RatioRule::Strict);
+        assert!(rules.apply(post("SkyPorn", -1)).is_err());
+    }
+
+    #[test]
+    fn there_is_no_current_background_until_one_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected = dir.path().join("background.png");
+        assert_eq!(current_background(&expected), None);
+        // Nor when something else took its place
+        std::fs::create_dir(&expected).unwrap();
+        assert_eq!(current_background(&expected), None);
+
+        std::fs::remove_dir(&expected).unwrap();
+        std::fs::write(&expected, "").unwrap();
+        assert_eq!(current_background(&expected), Some(expected.as_path()));
+    }
+
+    #[test]
+    fn the_user_agent_is_ours_unless_configured_otherwise() {
+        let ours = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+        let sent = |custom: Option<&str>, suffix: Option<&str>| {
+            let config = config::Config {
+                user_agent: custom.map(str::to_owned),
+                user_agent_suffix: suffix.map(str::to_owned),
+                ..config::Config::default()
+            };
+            user_agent(&config).to_str().unwrap().to_owned()
+        };
+
+        assert_eq!(sent(None, None), ours);
+        assert_eq!(sent(None, Some("(by /u/someone)")), format!("{ours} (by /u/someone)"));
+        assert_eq!(sent(Some("my-wallpapers/1.0"), None), "my-wallpapers/1.0");
+        // A user agent of their own replaces ours altogether, contact and all
+        assert_eq!(
+            sent(Some("my-wallpapers/1.0"), Some("(by /u/someone)")),
+            "my-wallpapers/1.0"
+        );
+        assert_eq!(sent(Some("my-wallpapers\r\nX-Injected: 1"), None), ours);
+        assert_eq!(sent(None, Some("(by /u/someone)\n")), ours);
+    }
+
+    #[test]
+    fn changes_asked_for_are_always_answered_and_timed_ones_only_when_they_fail() {
+        let changed = || {
+            Ok(Change {
+                subreddit: Some("EarthPorn".to_owned()),
+                reapplied: false,
+            })
+        };
+        let failed = || Err(eyre::Report::new(utils::NoInternet));
+        let failure = "Could not change wallpaper: no internet connection";
+
+        for (trigger, result, expected) in [
+            (Trigger::Manual, changed(), Some("New wallpaper from r/EarthPorn")),
+            (Trigger::Manual, failed(), Some(failure)),
+            (Trigger::Timer, changed(), None),
+            (Trigger::Timer, failed(), Some(failure)),
+        ] {
+            let notification = CycleNotices::default().notification(trigger, &result);
+            assert_eq!(notification.as_deref(), expected, "{:?} {:?}", trigger, result);
+        }
+    }
+
+    #[test]
+    fn the_budget_is_noticed_once_a_day_and_starts_over_at_midnight() {
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut db).unwrap();
+        let bandwidth = db::BandwidthRepo::new(&db);
+
+        // Half the budget late at night, then the rest just before midnight
+        let (evening, morning) = ("2024-01-31", "2024-02-01");
+        bandwidth.record_on(evening, 500_000).unwrap();
+        assert_eq!(budget_limit(&db, evening, 1).unwrap(), Limit::Within);
+        bandwidth.record_on(evening, 500_000).unwrap();
+        assert_eq!(budget_limit(&db, evening, 1).unwrap(), Limit::Reached { notify: true });
+        assert_eq!(budget_limit(&db, evening, 1).unwrap(), Limit::Reached { notify: false });
+
+        // Past midnight nothing's been downloaded yet, and using it up again is noticed again
+        assert_eq!(budget_limit(&db, morning, 1).unwrap(), Limit::Within);
+        bandwidth.record_on(morning, 1_000_000).unwrap();
+        assert_eq!(budget_limit(&db, morning, 1).unwrap(), Limit::Reached { notify: true });
+        assert_eq!(budget_limit(&db, morning, 1).unwrap(), Limit::Reached { notify: false });
+        assert_eq!(bandwidth.on(evening).unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn fetching_stops_below_the_disk_floor_with_one_notice_a_day() {
+        let floor = 100_000_000;
+        assert!(below_disk_floor(floor - 1, 100));
+        assert!(!below_disk_floor(floor, 100));
+        assert!(!below_disk_floor(floor + 1, 100));
+        assert!(!below_disk_floor(0, 0));
+        assert!(below_disk_floor(u64::MAX - 1, u64::MAX));
+
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut db).unwrap();
+        assert_eq!(disk_limit(&db, "2024-01-31", floor, 100).unwrap(), Limit::Within);
+        assert_eq!(
+            disk_limit(&db, "2024-01-31", floor - 1, 100).unwrap(),
+            Limit::Reached { notify: true }
+        );
+        assert_eq!(
+            disk_limit(&db, "2024-01-31", floor - 1, 100).unwrap(),
+            Limit::Reached { notify: false }
+        );
+        assert_eq!(
+            disk_limit(&db, "2024-01-31", 0, 100).unwrap(),
+            Limit::Reached { notify: false }
+        );
+        assert_eq!(
+            disk_limit(&db, "2024-02-01", 0, 100).unwrap(),
+            Limit::Reached { notify: true }
+        );
+    }
+
+    #[test]
+    fn cycle_timings_fill_in_the_span_and_summary() {
+        use std::{
+            collections::BTreeMap,
+            sync::{Arc, Mutex},
+        };
+        use tracing_subscriber::prelude::*;
+
+        /// Every field recorded on a span after it was created, by name
+        #[derive(Default)]
+        struct Recorded(BTreeMap<&'static str, String>);
+
+        impl tracing::field::Visit for Recorded {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0.insert(field.name(), format!("{value:?}"));
+            }
+        }
+
+        struct Capture(Arc<Mutex<Recorded>>);
+
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Capture {
+            fn on_record(
+                &self,
+                _span: &tracing::span::Id,
+                values: &tracing::span::Record<'_>,
+                _ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                values.record(&mut *self.0.lock().unwrap());
+            }
+        }
+
+        let mut timings = CycleTimings::default();
+        CycleTimings::time(&mut timings.pick, || std::thread::sleep(Duration::from_millis(10)));
+        CycleTimings::time(&mut timings.fetch, || std::thread::sleep(Duration::from_millis(20)));
+        CycleTimings::time(&mut timings.fetch, || std::thread::sleep(Duration::from_millis(20)));
+        CycleTimings::time(&mut timings.apply, || std::thread::sleep(Duration::from_millis(10)));
+        timings.fetched_images = 7;
+        assert!(timings.pick >= Duration::from_millis(10));
+        assert!(timings.fetch >= Duration::from_millis(40));
+        assert!(timings.apply >= Duration::from_millis(10));
+
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(Capture(recorded.clone())), || {
+            let span = tracing::info_span!(
+                "find_new_background",
+                pick_secs = Empty,
+                fetch_secs = Empty,
+                apply_secs = Empty,
+                fetched_images = Empty
+            );
+            timings.record(&span);
+        });
+        let recorded = std::mem::take(&mut recorded.lock().unwrap().0);
+        assert_eq!(
+            recorded.keys().copied().collect::<Vec<_>>(),
+            ["apply_secs", "fetch_secs", "fetched_images", "pick_secs"]
+        );
+        for field in ["pick_secs", "fetch_secs", "apply_secs"] {
+            assert!(recorded[field].parse::<f64>().unwrap() > 0.0, "{}", field);
+        }
+        assert_eq!(recorded["fetched_images"], "7");
+
+        let summary = timings.to_string();
+        assert!(summary.starts_with("pick 0."), "{}", summary);
+        assert!(summary.contains("s (7 images), apply "), "{}", summary);
+    }
+
+    #[test]
+    fn a_cache_drive_going_away_mid_run_is_noticed_once_until_it_comes_back() {
+        let root = tempfile::tempdir().unwrap();
+        let images = root.path().join("images");
+        std::fs::create_dir_all(&images).unwrap();
+        let mut notices = CycleNotices::default();
+        let cycle = || {
+            utils::check_storage(&images).map(|()| Change {
+                subreddit: None,
+                reapplied: false,
+            })
+        };
+
+        assert!(cycle().is_ok());
+        assert_eq!(notices.notification(Trigger::Timer, &cycle()), None);
+
+        // Every cycle fails while it's gone, but only the first one says why
+        std::fs::remove_dir_all(&images).unwrap();
+        let error = cycle().unwrap_err();
+        assert!(error.is::<utils::StorageUnavailable>(), "{:?}", error);
+        assert_eq!(
+            notices.notification(Trigger::Timer, &cycle()).as_deref(),
+            Some("The drive holding the image cache is unavailable, trying again next time")
+        );
+        assert_eq!(notices.notification(Trigger::Timer, &cycle()), None);
+        assert_eq!(notices.notification(Trigger::Timer, &cycle()), None);
+
+        // Once a cycle gets through, going away again is worth another notice
+        std::fs::create_dir_all(&images).unwrap();
+        assert_eq!(notices.notification(Trigger::Timer, &cycle()), None);
+        std::fs::remove_dir_all(&images).unwrap();
+        assert!(notices.notification(Trigger::Timer, &cycle()).is_some());
+    }
+
+    #[test]
+    fn quitting_does_not_wait_for_a_cycle_that_never_finishes() {
+        let runtime = Runtime::new().unwrap();
+        let (tx, messages) = sync_channel(10);
+        let (release, stuck) = std::sync::mpsc::channel::<()>();
+
+        // A cycle stuck on a download that never completes doesn't hold up the tray
+        let start = Instant::now();
+        spawn_cycle(&runtime, tx.clone(), Trigger::Timer, move || {
+            let _ = stuck.recv();
+            bail!("released")
+        });
+        send_message(&tx, "quit", Message::Quit);
+        assert!(matches!(
+            messages.recv_timeout(Duration::from_secs(5)),
+            Ok(Message::Quit)
+        ));
+
+        // Nor does shutting down afterwards
+        runtime.shutdown_background();
+        assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+        assert!(messages.try_recv().is_err());
+        drop(release);
+    }
+}
@@ -0,0 +1,285 @@
+//! Where our bulky data lives, which may be somewhere other than the local data directory if configured.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use eyre::{bail, ensure, Result, WrapErr};
+use tracing::{debug, info};
+
+use crate::{
+    config::Config,
+    db::{self, AppStateRepo},
+    DIRS,
+};
+
+/// The directories that can grow large, as opposed to the database and the config, which stay where they are
+const BULKY: &[&str] = &["images", "archive", "quarantine", "logs"];
+
+/// The directories whose files we delete as we see fit
+const MANAGED: &[&str] = &["images", "archive", "quarantine"];
+
+/// A directory of the user's that's inside one we manage, where their files would end up deleted
+#[derive(thiserror::Error, Debug)]
+#[error("The {what} directory {} is inside {}, whose files {} deletes", .theirs.display(), .ours.display(), env!("CARGO_PKG_NAME"))]
+pub struct Overlap {
+    what: &'static str,
+    theirs: PathBuf,
+    ours: PathBuf,
+}
+
+const KEY: &str = "data_root";
+
+static ROOT: once_cell::sync::OnceCell<PathBuf> = once_cell::sync::OnceCell::new();
+
+/// Use `root` for our bulky data instead of the local data directory.
+///
+/// This has to happen before anything asks for [`root`], and only the first call counts.
+pub fn set_root(root: Option<PathBuf>) {
+    let _ = ROOT.set(root.unwrap_or_else(|| DIRS.data_local_dir().to_owned()));
+}
+
+/// Get the directory our bulky data lives in.
+pub fn root() -> &'static Path {
+    ROOT.get_or_init(|| DIRS.data_local_dir().to_owned())
+}
+
+/// Refuse configurations that point a directory of the user's inside one we manage.
+///
+/// The other way around is fine, as we only ever delete files we've recorded writing outside of them.
+pub fn check_overlaps(config: &Config) -> Result<()> {
+    let Some(ref export) = config.export else {
+        return Ok(());
+    };
+    check_overlap("export", &export.dir, root())
+}
+
+/// Refuse `dir` if it's inside one of the directories we manage under `root`.
+fn check_overlap(what: &'static str, dir: &Path, root: &Path) -> Result<()> {
+    let theirs = normalize(dir);
+    for name in MANAGED {
+        let ours = normalize(&root.join(name));
+        if theirs.starts_with(&ours) {
+            bail!(Overlap { what, theirs, ours });
+        }
+    }
+    Ok(())
+}
+
+/// Resolve `path` as far as it exists, so that links and `..` can't hide an overlap.
+fn normalize(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return rest.iter().rev().fold(canonical, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return path.to_owned(),
+        }
+    }
+}
+
+/// Move our bulky data over from wherever it was last time, if the root has changed since.
+pub fn migrate() -> Result<()> {
+    let db = db::open()?;
+    let state = AppStateRepo::new(&db);
+    let new = root();
+    let old = state
+        .get(KEY)?
+        .map_or_else(|| DIRS.data_local_dir().to_owned(), PathBuf::from);
+
+    move_root(&old, new)?;
+
+    let new = new
+        .to_str()
+        .ok_or_else(|| eyre::format_err!("Data root is not valid UTF-8"))?;
+    state.set(KEY, new)?;
+    Ok(())
+}
+
+/// Move the bulky directories under `old` to under `new`.
+fn move_root(old: &Path, new: &Path) -> Result<()> {
+    // Moving a directory into itself would never finish
+    let nested = BULKY
+        .iter()
+        .find(|name| normalize(new).starts_with(normalize(&old.join(name))));
+    ensure!(
+        nested.is_none(),
+        "The new data root {} is inside the old {} directory",
+        new.display(),
+        nested.copied().unwrap_or_default()
+    );
+
+    if old != new && old.exists() {
+        info!(old = %old.display(), new = %new.display(), "moving data to its new location");
+        for name in BULKY {
+            let src = old.join(name);
+            if !src.exists() {
+                continue;
+            }
+            let moved = move_entry(&src, &new.join(name)).wrap_err_with(|| format!("Could not move {name}"))?;
+            info!(name, moved, "moved directory");
+        }
+    }
+    Ok(())
+}
+
+/// Move a file or a directory, merging it into what's already at `dst`, and return how many files were moved.
+///
+/// Renaming only works within a volume, so we fall back to copying when the new root is on another drive.
+fn move_entry(src: &Path, dst: &Path) -> io::Result<usize> {
+    if src.is_dir() {
+        if !dst.exists() && fs::rename(src, dst).is_ok() {
+            return Ok(fs::read_dir(dst)?.count());
+        }
+
+        fs::create_dir_all(dst)?;
+        let mut moved = 0;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            moved += move_entry(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        fs::remove_dir(src)?;
+        Ok(moved)
+    } else {
+        if let Err(error) = fs::rename(src, dst) {
+            debug!(?error, src = %src.display(), "could not rename, copying instead");
+            fs::copy(src, dst)?;
+            fs::remove_file(src)?;
+        }
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn directories_inside_ours_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("images")).unwrap();
+
+        for inside in [
+            root.join("images"),
+            root.join("images").join("export"),
+            root.join("archive").join("a"),
+        ] {
+            let error = check_overlap("export", &inside, &root).unwrap_err();
+            assert!(error.is::<Overlap>(), "{:?} should overlap", inside);
+        }
+    }
+
+    #[test]
+    fn links_and_dots_dont_hide_an_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("images")).unwrap();
+        fs::create_dir_all(dir.path().join("elsewhere")).unwrap();
+
+        let dotted = dir
+            .path()
+            .join("elsewhere")
+            .join("..")
+            .join("root")
+            .join("images")
+            .join("export");
+        assert!(check_overlap("export", &dotted, &root).is_err());
+    }
+
+    #[test]
+    fn directories_beside_or_around_ours_dont_overlap() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("images")).unwrap();
+
+        // Ours being inside theirs is fine, as we only delete files we've recorded writing there
+        for outside in [
+            dir.path().to_owned(),
+            root.clone(),
+            root.join("logs"),
+            dir.path().join("images"),
+        ] {
+            check_overlap("export", &outside, &root).unwrap();
+        }
+    }
+
+    #[test]
+    fn moves_a_directory_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        write(&src.join("a"), "a");
+        write(&src.join("b"), "b");
+
+        assert_eq!(move_entry(&src, &dst).unwrap(), 2);
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(dst.join("a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dst.join("b")).unwrap(), "b");
+    }
+
+    #[test]
+    fn merges_into_an_existing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        write(&src.join("nested").join("a"), "new a");
+        write(&src.join("b"), "b");
+        write(&dst.join("nested").join("a"), "old a");
+        write(&dst.join("c"), "c");
+
+        assert_eq!(move_entry(&src, &dst).unwrap(), 2);
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(dst.join("nested").join("a")).unwrap(), "new a");
+        assert_eq!(fs::read_to_string(dst.join("b")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dst.join("c")).unwrap(), "c");
+    }
+
+    #[test]
+    fn moves_only_bulky_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        write(&old.join("images").join("a.png"), "a");
+        write(&old.join("logs").join("redditbg.log"), "log");
+        write(&old.join("db.sqlite3"), "db");
+
+        move_root(&old, &new).unwrap();
+        assert!(new.join("images").join("a.png").exists());
+        assert!(new.join("logs").join("redditbg.log").exists());
+        assert!(!new.join("archive").exists());
+        assert!(old.join("db.sqlite3").exists());
+        assert!(!new.join("db.sqlite3").exists());
+    }
+
+    #[test]
+    fn does_nothing_when_the_root_is_unchanged_or_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        write(&root.join("images").join("a.png"), "a");
+
+        move_root(&root, &root).unwrap();
+        assert!(root.join("images").join("a.png").exists());
+        move_root(&dir.path().join("unplugged"), &root).unwrap();
+        assert!(root.join("images").join("a.png").exists());
+    }
+
+    #[test]
+    fn refuses_to_move_into_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("old");
+        write(&old.join("images").join("a.png"), "a");
+
+        assert!(move_root(&old, &old.join("images").join("elsewhere")).is_err());
+        assert!(old.join("images").join("a.png").exists());
+    }
+}
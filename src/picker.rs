@@ -1,57 +1,468 @@
-use std::fs;
+use std::{
+    cell::Cell,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use eyre::{bail, Result, WrapErr};
-use image::DynamicImage;
-use tracing::{debug, info, trace_span, warn};
+use image::{DynamicImage, ImageFormat};
+use image_hasher::ImageHash;
+use tracing::{debug, info, trace, trace_span, warn};
 
-use crate::DIRS;
+use crate::{
+    config::{Config, Mode},
+    db::{self, AppStateRepo, AppliedImagesRepo, MetadataRepo, StoredFile},
+    fetcher, platform,
+    policy::ImagePolicy,
+    utils,
+};
 
 #[derive(thiserror::Error, Debug)]
 #[error("No valid image")]
 pub struct NoValidImage;
 
-#[tracing::instrument]
-pub fn pick() -> Result<DynamicImage> {
+/// Get the directory holding the images of the given profile we've applied, in archive mode
+pub fn archive_dir(profile: &str) -> PathBuf {
+    crate::paths::root().join("archive").join(profile)
+}
+
+// How many times applying an image may fail before we quarantine it
+const MAX_APPLY_FAILURES: u32 = 2;
+
+// How many images the quarantine directory may hold before we start deleting the oldest ones
+const MAX_QUARANTINED: usize = 50;
+
+// The URL of the image on the primary monitor, which the history can't tell us as the other monitors' come after it
+const PRIMARY_URL_KEY: &str = "primary_url";
+
+// The mode we last ran in, to tell when the user switches from one to the other
+const MODE_KEY: &str = "mode";
+
+/// The image we picked, along with what we know about where it came from
+pub struct Picked {
+    pub image: DynamicImage,
+    pub image_hash: ImageHash,
+    pub path: PathBuf,
+    pub url: Option<String>,
+    pub subreddit: Option<String>,
+    pub title: Option<String>,
+    /// Where to see the post it came from on Reddit
+    pub permalink: Option<String>,
+    /// Whether it came from the archive rather than the cache
+    pub archived: bool,
+    /// The format it's stored in
+    pub format: ImageFormat,
+}
+
+/// Score a candidate by how well its original dimensions cover the screen; higher is better.
+///
+/// Images at least as big as the screen in both axes are pixel-perfect, while smaller ones were upscaled and look
+/// soft. Images we don't know the dimensions of land in the middle.
+pub fn size_score(dimensions: Option<(u32, u32)>, screen: (u32, u32)) -> u8 {
+    match dimensions {
+        Some((width, height)) if width >= screen.0 && height >= screen.1 => 2,
+        Some(_) => 0,
+        None => 1,
+    }
+}
+
+/// A cached image we could pick, along with what we know about it
+#[derive(Debug)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub url: Option<String>,
+    pub subreddit: Option<String>,
+    pub size_score: u8,
+    /// When we last applied it, for archived images
+    pub last_applied: Option<String>,
+    /// Whether it was meant for a monitor of another orientation
+    pub other_orientation: bool,
+    /// Whether it had to be scaled up to fit
+    pub upscaled: bool,
+    /// What we recorded about the file when we stored it, if we did
+    pub stored: Option<StoredFile>,
+    /// Whether it's an archived image we've shown within the repeat window
+    pub repeat: bool,
+}
+
+/// How many of our preferences a candidate has to give up on to be picked, from none to the most.
+///
+/// Rather than fail with [`NoValidImage`] when candidates only miss our preferences, we give up on them in this order:
+/// first avoiding the subreddits of the last few backgrounds, then keeping images meant for other monitors for them,
+/// then not showing archived images again within the repeat window. Decoding, not having been applied before outside
+/// of the archive and not being quarantined are never given up on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tier {
+    All,
+    AnySubreddit,
+    AnyOrientation,
+    AnyRepeat,
+}
+
+impl Tier {
+    /// The first tier willing to pick the candidate.
+    pub fn of(candidate: &Candidate, recent_subreddits: &[String]) -> Self {
+        let repeated = candidate
+            .subreddit
+            .as_ref()
+            .is_some_and(|subreddit| recent_subreddits.contains(subreddit));
+        if candidate.repeat {
+            Self::AnyRepeat
+        } else if candidate.other_orientation {
+            Self::AnyOrientation
+        } else if repeated {
+            Self::AnySubreddit
+        } else {
+            Self::All
+        }
+    }
+}
+
+/// Order candidates by preference: those that meet all of them come first, then those we didn't have to scale up,
+/// then archived images we haven't shown for the longest, then the sharpest.
+pub fn rank(candidates: &mut [Candidate], recent_subreddits: &[String]) {
+    candidates.sort_by_cached_key(|candidate| {
+        (
+            Tier::of(candidate, recent_subreddits),
+            candidate.upscaled,
+            candidate.last_applied.clone(),
+            std::cmp::Reverse(candidate.size_score),
+        )
+    });
+}
+
+/// Everything that stays the same while we look through the candidates
+struct Context<'a> {
+    hasher: image_hasher::Hasher,
+    applied: AppliedImagesRepo<'a>,
+    metadata: MetadataRepo<'a>,
+    recent_subreddits: Vec<String>,
+    /// The rules for the monitor we're picking for alone
+    policy: ImagePolicy,
+    screen: (u32, u32),
+    /// Whether to skip images that don't fit the monitor, rather than fall back to them when nothing else is left
+    strict: bool,
+    exclude: &'a [PathBuf],
+    /// How many days archived images have to go unshown to be shown again without giving up on that
+    repeat_days: u32,
+    /// How many images we've decoded so far, which is one per pick unless the cache holds broken or repeated images
+    decodes: Cell<usize>,
+}
+
+/// Pick the next background out of the given profile's cache, avoiding the subreddits of the last `variety` ones.
+///
+/// In archive mode we fall back to the images we've already applied once the cache runs dry, least recently applied
+/// first, and those not shown within the last `archive_repeat_days` before the others.
+///
+/// Nothing is recorded until the image is passed to `mark_applied`, and candidates in `exclude` are skipped, so that
+/// the caller can move on to the next one if applying this one fails.
+pub fn pick(profile: &str, config: &Config, exclude: &[PathBuf], policy: &ImagePolicy) -> Result<Picked> {
+    let screen = primary_screen(policy)?;
+    pick_for(
+        profile,
+        config,
+        Sources::of(config.mode),
+        exclude,
+        policy,
+        screen,
+        false,
+    )
+}
+
+/// Pick the least recently applied image out of the given profile's archive, whichever mode we're in.
+///
+/// This is what we fall back to once the cache runs dry while offline, as we can't fetch any more.
+pub fn pick_archived(profile: &str, config: &Config, exclude: &[PathBuf], policy: &ImagePolicy) -> Result<Picked> {
+    let screen = primary_screen(policy)?;
+    pick_for(profile, config, Sources::Archive, exclude, policy, screen, false)
+}
+
+/// Get the size of the first monitor `policy` is for, or of the primary monitor if it's for none in particular.
+fn primary_screen(policy: &ImagePolicy) -> Result<(u32, u32)> {
+    match policy.monitors().first() {
+        Some(&screen) => Ok(screen),
+        None => Ok(*crate::policy::monitors()?
+            .first()
+            .ok_or_else(|| eyre::format_err!("No monitors attached"))?),
+    }
+}
+
+/// Where to look for candidates, in order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Sources {
+    Cache,
+    CacheThenArchive,
+    Archive,
+}
+
+impl Sources {
+    fn of(mode: Mode) -> Self {
+        match mode {
+            Mode::Consume => Self::Cache,
+            Mode::Archive => Self::CacheThenArchive,
+        }
+    }
+}
+
+/// Pick a background for each of the given screens, out of the monitors `policy` is for, never the same image twice.
+///
+/// Unlike [`pick`], only images that fit each screen are picked for it, and screens nothing fits get `None`.
+pub fn pick_many(
+    profile: &str,
+    config: &Config,
+    exclude: &[PathBuf],
+    policy: &ImagePolicy,
+    screens: &[(u32, u32)],
+) -> Result<Vec<Option<Picked>>> {
+    let mut exclude = exclude.to_vec();
+    let mut picked = Vec::with_capacity(screens.len());
+    for &screen in screens {
+        match pick_for(
+            profile,
+            config,
+            Sources::of(config.mode),
+            &exclude,
+            policy,
+            screen,
+            true,
+        ) {
+            Ok(next) => {
+                exclude.push(next.path.clone());
+                picked.push(Some(next));
+            }
+            Err(error) if error.is::<NoValidImage>() => {
+                debug!(?screen, "nothing fits this monitor");
+                picked.push(None);
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(picked)
+}
+
+/// Pick the next background for a screen of the given size.
+#[tracing::instrument(skip(config, policy))]
+fn pick_for(
+    profile: &str,
+    config: &Config,
+    sources: Sources,
+    exclude: &[PathBuf],
+    policy: &ImagePolicy,
+    screen: (u32, u32),
+    strict: bool,
+) -> Result<Picked> {
+    // Don't mistake a drive that's gone away for an empty cache
+    utils::check_storage(crate::paths::root())?;
+
     // Create our hasher and our database connection
-    let hasher = image_hasher::HasherConfig::new().to_hasher();
-    let db = rusqlite::Connection::open(DIRS.data_local_dir().join("db.sqlite3"))?;
-    db.execute_batch(include_str!("picker.sql"))?;
-
-    // For every file in the images/ directory...
-    for entry in DIRS.data_local_dir().join("images").read_dir()? {
-        // Create a span and pick out the path (which is what we actually care about).
-        let _span = trace_span!("picking", ?entry).entered();
+    let db = db::open()?;
+    let applied = AppliedImagesRepo::new(&db);
+    let recent_subreddits = applied.recent_subreddits(config.variety, current_layout())?;
+    trace!(?recent_subreddits, "avoiding recent subreddits");
+
+    let ctx = Context {
+        hasher: image_hasher::HasherConfig::new().to_hasher(),
+        applied,
+        metadata: MetadataRepo::new(&db),
+        recent_subreddits,
+        policy: policy.only(screen),
+        screen,
+        strict,
+        exclude,
+        repeat_days: config.archive_repeat_days,
+        decodes: Cell::new(0),
+    };
+
+    match sources {
+        Sources::Cache => pick_from(&ctx, &fetcher::images_dir(profile), false),
+        Sources::CacheThenArchive => match pick_from(&ctx, &fetcher::images_dir(profile), false) {
+            Err(error) if error.is::<NoValidImage>() => {
+                debug!("cache ran dry, picking from the archive");
+                pick_from(&ctx, &archive_dir(profile), true)
+            }
+            result => result,
+        },
+        Sources::Archive => pick_from(&ctx, &archive_dir(profile), true),
+    }
+}
+
+/// Pick the best candidate out of `dir`, which holds images we've already applied if `archived` is set.
+fn pick_from(ctx: &Context, dir: &Path, archived: bool) -> Result<Picked> {
+    let Context {
+        ref hasher,
+        ref applied,
+        ref metadata,
+        ..
+    } = *ctx;
+
+    // Gather every file in the directory, sorting them so that the sharpest images fitting the screen's orientation
+    // from subreddits we haven't just seen come first.
+    let mut candidates = Vec::new();
+    // A profile we've just switched to may not have any images yet
+    fs::create_dir_all(dir)?;
+    for entry in dir.read_dir()? {
         let path = entry?.path();
+        if ctx.exclude.contains(&path) {
+            continue;
+        }
+        // Images still being written by the fetcher live next to the finished ones until they're done
+        if fetcher::is_partial_write(&path) {
+            continue;
+        }
+        let url = fetcher::url_for_file(metadata, &path)?;
+        // Whatever we don't know the source of isn't ours, so it's neither picked nor deleted
+        let downloaded = match url {
+            Some(ref url) => fetcher::is_downloaded(metadata, url)?,
+            None => false,
+        };
+        if !downloaded {
+            debug!(path = %path.display(), "leaving alone a file we didn't download");
+            continue;
+        }
+
+        // What we recorded when we stored the file tells us enough to rank it, or skip it, without decoding it
+        let stored = match path.file_name().and_then(OsStr::to_str) {
+            Some(filename) => metadata.stored(filename)?,
+            None => None,
+        };
+        if let Some(stored) = stored {
+            let bytes = fs::metadata(&path)?.len();
+            if bytes != stored.bytes {
+                debug!(path = %path.display(), bytes, expected = stored.bytes, "deleting file that's been cut short or overwritten");
+                fs::remove_file(&path)?;
+                continue;
+            }
+        }
+
+        // Images meant for another monitor are best kept in the cache for when they fit
+        let other_orientation = fetcher::orientation_of_file(metadata, &path)?
+            .is_some_and(|orientation| !ctx.policy.accepts_orientation(orientation));
+        // How big the image originally was says how sharp it is, but how we stored it will do if that's all we know
+        let (dimensions, subreddit) = match url {
+            Some(ref url) => (metadata.dimensions(url)?, metadata.subreddit(url)?),
+            None => (None, None),
+        };
+        let dimensions = dimensions.or_else(|| stored.map(|stored| (stored.width, stored.height)));
+        // Each of several monitors only gets images that fit it, going by how we stored them if we know
+        if ctx.strict {
+            let fits = match stored {
+                Some(stored) => ctx.policy.fits_stored((stored.width, stored.height)),
+                None => !other_orientation,
+            };
+            if !fits {
+                trace!(path = %path.display(), "doesn't fit this monitor");
+                continue;
+            }
+        }
+        let (last_applied, repeat) = match url {
+            Some(ref url) if archived => (
+                applied.last_applied_url(url)?,
+                applied.applied_within(url, ctx.repeat_days)?,
+            ),
+            _ => (None, false),
+        };
+        let upscaled = stored.is_some_and(|stored| stored.upscaled);
+        trace!(
+            path = %path.display(),
+            ?dimensions,
+            ?subreddit,
+            ?last_applied,
+            repeat,
+            other_orientation,
+            upscaled,
+            "found candidate"
+        );
+        candidates.push(Candidate {
+            path,
+            url,
+            subreddit,
+            size_score: size_score(dimensions, ctx.screen),
+            last_applied,
+            other_orientation,
+            upscaled,
+            stored,
+            repeat,
+        });
+    }
+    rank(&mut candidates, &ctx.recent_subreddits);
+
+    // For every candidate...
+    for candidate in candidates {
+        let tier = Tier::of(&candidate, &ctx.recent_subreddits);
+        let Candidate {
+            path,
+            url,
+            subreddit,
+            size_score: score,
+            stored,
+            ..
+        } = candidate;
+
+        // Create a span for it.
+        let _span = trace_span!("picking", path = %path.display(), score, ?tier).entered();
 
         // Try to read this path as an image
-        let maybe_image = (image::io::Reader::open(&path).wrap_err("failed to open path"))
-            .and_then(|r| r.with_guessed_format().wrap_err("failed to guess format"))
-            .and_then(|r| r.decode().wrap_err("failed to decode"));
+        let filename = path.file_name().and_then(OsStr::to_str).map(str::to_owned);
+        ctx.decodes.set(ctx.decodes.get() + 1);
+        let maybe_image = decode(&path, stored.map(|stored| stored.format));
 
         match maybe_image {
-            Ok(image) => {
-                // If this actually is an image, make sure we haven't already applied anything with the same image hash.
+            Ok((image, format)) => {
+                // Files cached before we recorded what they hold get recorded the first time we decode them
+                if let (None, Some(filename), Some(url)) = (stored, &filename, &url) {
+                    let stored = StoredFile {
+                        format,
+                        width: image.width(),
+                        height: image.height(),
+                        bytes: fs::metadata(&path)?.len(),
+                        upscaled: false,
+                    };
+                    metadata.insert_stored(filename, url, stored)?;
+                }
+
+                // If this actually is an image, make sure we haven't already applied anything with the same image hash,
+                // which is the whole point of the archive.
                 let image_hash = hasher.hash_image(&image);
-                let already_applied = db.query_row(
-                    "SELECT COUNT(*) FROM AppliedImages WHERE image_hash = ?",
-                    [image_hash.as_bytes()],
-                    |row| Ok(row.get::<_, usize>(0)? != 0),
-                )?;
-                if already_applied {
+                if !archived && applied.contains(image_hash.as_bytes())? {
                     debug!("skipping image that's already been applied");
                     fs::remove_file(path)?;
                     continue;
                 }
 
-                // If we haven't, add the image hash to the database, remove the original file and return our image.
-                db.execute(
-                    "INSERT INTO AppliedImages(image_hash) VALUES (?)",
-                    [image_hash.as_bytes()],
-                )?;
-                info!(?image_hash, "picked next background!");
-                fs::remove_file(path)?;
+                // Images that keep failing to apply should have been quarantined already, but there may be copies
+                if applied.failures(image_hash.as_bytes())? >= MAX_APPLY_FAILURES {
+                    debug!("skipping image that's been quarantined");
+                    fs::remove_file(path)?;
+                    continue;
+                }
+
+                let (title, permalink) = match url {
+                    Some(ref url) => (metadata.title(url)?, metadata.permalink(url)?),
+                    None => (None, None),
+                };
+                info!(
+                    ?image_hash,
+                    archived,
+                    ?tier,
+                    decodes = ctx.decodes.get(),
+                    "picked next background!"
+                );
+                if tier != Tier::All {
+                    info!(?tier, "nothing met all of our preferences, had to relax them");
+                }
 
-                return Ok(image);
+                return Ok(Picked {
+                    image,
+                    image_hash,
+                    path,
+                    url,
+                    subreddit,
+                    title,
+                    permalink,
+                    archived,
+                    format,
+                });
             }
 
             Err(error) => {
@@ -64,3 +475,504 @@ pub fn pick() -> Result<DynamicImage> {
 
     bail!(NoValidImage);
 }
+
+/// Decode the cached image at `path`, trusting the format we recorded for it if there is one rather than guessing.
+fn decode(path: &Path, format: Option<ImageFormat>) -> Result<(DynamicImage, ImageFormat)> {
+    let mut reader = image::io::Reader::open(path).wrap_err("failed to open path")?;
+    match format {
+        Some(format) => reader.set_format(format),
+        None => reader = reader.with_guessed_format().wrap_err("failed to guess format")?,
+    }
+    let format = reader.format().ok_or_else(|| eyre::eyre!("unknown format"))?;
+    Ok((reader.decode().wrap_err("failed to decode")?, format))
+}
+
+/// Get the current monitor layout's hash, which is only used to tell history apart so its absence isn't an error.
+fn current_layout() -> Option<u64> {
+    platform::monitor_layout()
+        .map_err(|error| debug!(?error, "could not get monitor layout"))
+        .ok()
+}
+
+/// Record that we've applied the picked image, removing it from the cache or, in archive mode, moving it to the
+/// archive.
+pub fn mark_applied(picked: &Picked, profile: &str, mode: Mode) -> Result<()> {
+    let db = db::open()?;
+    AppliedImagesRepo::new(&db).insert(
+        picked.image_hash.as_bytes(),
+        picked.url.as_deref(),
+        picked.subreddit.as_deref(),
+        current_layout(),
+    )?;
+
+    match (mode, picked.archived) {
+        (_, true) => {}
+        (Mode::Consume, false) => fs::remove_file(&picked.path)?,
+        (Mode::Archive, false) => {
+            let dir = archive_dir(profile);
+            fs::create_dir_all(&dir)?;
+            if let Some(filename) = picked.path.file_name() {
+                fs::rename(&picked.path, dir.join(filename)).wrap_err("Could not archive image")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Record the mode we're running in, returning how many images are archived if we've just switched from archive mode
+/// to consume mode, as they're only ever shown again while offline from then on.
+pub fn switch_mode(mode: Mode) -> Result<Option<usize>> {
+    let db = db::open()?;
+    switch_mode_in(&AppStateRepo::new(&db), mode, &crate::paths::root().join("archive"))
+}
+
+fn switch_mode_in(state: &AppStateRepo, mode: Mode, archive: &Path) -> Result<Option<usize>> {
+    let name = match mode {
+        Mode::Consume => "consume",
+        Mode::Archive => "archive",
+    };
+    let previous = state.get(MODE_KEY)?;
+    if previous.as_deref() == Some(name) {
+        return Ok(None);
+    }
+    state.set(MODE_KEY, name)?;
+    if (previous.as_deref(), mode) != (Some("archive"), Mode::Consume) {
+        return Ok(None);
+    }
+
+    // Every profile has its own archive
+    let profiles = match archive.read_dir() {
+        Ok(profiles) => profiles,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Some(0)),
+        Err(error) => return Err(error).wrap_err("Could not read archive"),
+    };
+    let mut archived = 0;
+    for profile in profiles {
+        let profile = profile?.path();
+        if profile.is_dir() {
+            archived += profile.read_dir()?.count();
+        }
+    }
+    Ok(Some(archived))
+}
+
+/// Record that the picked image went up on the primary monitor, so that the tray can open the post it came from.
+pub fn mark_primary(picked: &Picked) -> Result<()> {
+    let db = db::open()?;
+    set_primary_url(&AppStateRepo::new(&db), picked.url.as_deref())?;
+    Ok(())
+}
+
+fn set_primary_url(state: &AppStateRepo, url: Option<&str>) -> rusqlite::Result<()> {
+    match url {
+        Some(url) => state.set(PRIMARY_URL_KEY, url),
+        None => state.remove(PRIMARY_URL_KEY),
+    }
+}
+
+/// Get the URL of the image on the primary monitor, if we know where it came from.
+pub fn primary_url() -> Result<Option<String>> {
+    let db = db::open()?;
+    Ok(AppStateRepo::new(&db).get(PRIMARY_URL_KEY)?)
+}
+
+/// Record that applying the picked image failed, quarantining it if it keeps happening.
+pub fn record_failure(picked: &Picked, error: &eyre::Report) -> Result<()> {
+    // Not being able to write the background out says nothing about the image itself
+    if is_io_error(error) {
+        debug!(?error, "not counting an IO error against the image");
+        return Ok(());
+    }
+
+    let db = db::open()?;
+    let failures = AppliedImagesRepo::new(&db).record_failure(picked.image_hash.as_bytes())?;
+    if failures < MAX_APPLY_FAILURES {
+        return Ok(());
+    }
+
+    // Keep the file around instead of deleting it outright, in case the user wants to know what went wrong
+    warn!(path = %picked.path.display(), failures, "quarantining image that keeps failing to apply");
+    let dir = crate::paths::root().join("quarantine");
+    fs::create_dir_all(&dir)?;
+    if let Some(filename) = picked.path.file_name() {
+        let quarantined = dir.join(filename);
+        fs::rename(&picked.path, &quarantined).wrap_err("Could not quarantine image")?;
+        // Date the file by when it was quarantined, so that the cap goes by that rather than when it was downloaded
+        if let Err(error) = fs::File::options()
+            .write(true)
+            .open(&quarantined)
+            .and_then(|file| file.set_modified(std::time::SystemTime::now()))
+        {
+            debug!(?error, "could not date quarantined image");
+        }
+        cap_quarantine(&dir, &quarantined, MAX_QUARANTINED)?;
+    }
+    Ok(())
+}
+
+/// Whether the error came from the filesystem rather than from decoding or applying the image.
+fn is_io_error(error: &eyre::Report) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<std::io::Error>() || matches!(cause.downcast_ref(), Some(image::ImageError::IoError(_)))
+    })
+}
+
+/// Delete the oldest images in the quarantine directory until there are at most `max`, never deleting `keep`.
+fn cap_quarantine(dir: &Path, keep: &Path, max: usize) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .wrap_err("Could not read quarantine directory")?
+        .map(|entry| {
+            let entry = entry?;
+            Ok((entry.path(), entry.metadata()?.modified()?))
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if entries.len() <= max {
+        return Ok(());
+    }
+
+    entries.sort_by_key(|&(_, modified)| modified);
+    let excess = entries.len() - max;
+    for (path, _) in entries.into_iter().filter(|(path, _)| path != keep).take(excess) {
+        debug!(path = %path.display(), "deleting old quarantined image");
+        fs::remove_file(&path).wrap_err("Could not delete quarantined image")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+
+    use super::*;
+
+    fn context<'a>(conn: &'a rusqlite::Connection, exclude: &'a [PathBuf]) -> Context<'a> {
+        Context {
+            hasher: image_hasher::HasherConfig::new().to_hasher(),
+            applied: AppliedImagesRepo::new(conn),
+            metadata: MetadataRepo::new(conn),
+            recent_subreddits: Vec::new(),
+            policy: ImagePolicy::new(vec![(1920, 1080)]),
+            screen: (1920, 1080),
+            strict: false,
+            exclude,
+            repeat_days: 7,
+            decodes: Cell::new(0),
+        }
+    }
+
+    /// Write an image of the given size into `dir` as if we'd downloaded it from `url`, recording how we stored it.
+    fn store(
+        conn: &rusqlite::Connection,
+        dir: &Path,
+        url: &str,
+        (width, height): (u32, u32),
+        upscaled: bool,
+    ) -> PathBuf {
+        let filename = format!("{}.png", BASE64_URL_SAFE_NO_PAD.encode(url));
+        let path = dir.join(&filename);
+        image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, url.len() as u8])
+        })
+        .save(&path)
+        .unwrap();
+        let metadata = MetadataRepo::new(conn);
+        metadata.insert_dimensions(url, width, height).unwrap();
+        let stored = StoredFile {
+            format: ImageFormat::Png,
+            width,
+            height,
+            bytes: fs::metadata(&path).unwrap().len(),
+            upscaled,
+        };
+        metadata.insert_stored(&filename, url, stored).unwrap();
+        path
+    }
+
+    #[test]
+    fn files_we_didnt_download_are_left_alone() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // Neither a name that decodes to a url we never fetched nor one that decodes to nothing makes a file ours
+        let url = "https://example.com/theirs.png";
+        let theirs = dir.path().join(format!("{}.png", BASE64_URL_SAFE_NO_PAD.encode(url)));
+        let notes = dir.path().join("notes.txt");
+        fs::write(&theirs, "not an image").unwrap();
+        fs::write(&notes, "not an image either").unwrap();
+
+        let Err(error) = pick_from(&context(&conn, &[]), dir.path(), false) else {
+            panic!("picked a file that isn't an image");
+        };
+        assert!(error.is::<NoValidImage>());
+        assert!(theirs.exists());
+        assert!(notes.exists());
+    }
+
+    #[test]
+    fn broken_files_we_downloaded_are_deleted() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let url = "https://i.redd.it/ours.png";
+        let ours = dir.path().join(format!("{}.png", BASE64_URL_SAFE_NO_PAD.encode(url)));
+        fs::write(&ours, "not an image").unwrap();
+        MetadataRepo::new(&conn).insert_dimensions(url, 1920, 1080).unwrap();
+
+        let Err(error) = pick_from(&context(&conn, &[]), dir.path(), false) else {
+            panic!("picked a file that isn't an image");
+        };
+        assert!(error.is::<NoValidImage>());
+        assert!(!ours.exists());
+    }
+
+    #[test]
+    fn the_primary_url_outlives_the_other_monitors() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let (applied, state) = (AppliedImagesRepo::new(&conn), AppStateRepo::new(&conn));
+
+        applied
+            .insert(&[1], Some("https://i.redd.it/primary.png"), None, None)
+            .unwrap();
+        set_primary_url(&state, Some("https://i.redd.it/primary.png")).unwrap();
+        applied
+            .insert(&[2], Some("https://i.redd.it/secondary.png"), None, None)
+            .unwrap();
+        assert_eq!(
+            state.get(PRIMARY_URL_KEY).unwrap().as_deref(),
+            Some("https://i.redd.it/primary.png")
+        );
+
+        // An image we don't know the source of mustn't leave the last one's behind
+        applied.insert(&[3], None, None, None).unwrap();
+        set_primary_url(&state, None).unwrap();
+        assert_eq!(state.get(PRIMARY_URL_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn only_the_oldest_quarantined_images_go_past_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        for (name, secs_ago) in [("a", 30), ("b", 20), ("c", 10), ("d", 40)] {
+            let file = fs::File::create(dir.path().join(name)).unwrap();
+            file.set_modified(now - std::time::Duration::from_secs(secs_ago))
+                .unwrap();
+        }
+
+        // The image that was just quarantined stays even if it's the oldest
+        cap_quarantine(dir.path(), &dir.path().join("d"), 2).unwrap();
+        let mut left = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["c", "d"]);
+
+        cap_quarantine(dir.path(), &dir.path().join("d"), 2).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn io_errors_are_not_the_images_fault() {
+        let io = || std::io::Error::new(std::io::ErrorKind::StorageFull, "disk full");
+        assert!(is_io_error(
+            &eyre::Report::new(io()).wrap_err("Could not save background")
+        ));
+        assert!(is_io_error(&eyre::Report::new(image::ImageError::IoError(io()))));
+
+        let decoding = image::ImageError::Unsupported(image::error::UnsupportedError::from_format_and_kind(
+            image::error::ImageFormatHint::Unknown,
+            image::error::UnsupportedErrorKind::GenericFeature("test".to_owned()),
+        ));
+        assert!(!is_io_error(&eyre::Report::new(decoding)));
+        assert!(!is_io_error(&eyre::eyre!("Could not set the background")));
+    }
+
+    #[test]
+    fn the_archive_goes_least_recently_applied_first() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut paths = Vec::new();
+        for (seed, applied_at) in [(0, "2026-10-02 12:00:00"), (1, "2026-10-01 12:00:00")] {
+            let url = format!("https://i.redd.it/{seed}.png");
+            let path = dir.path().join(format!("{}.png", BASE64_URL_SAFE_NO_PAD.encode(&url)));
+            image::RgbImage::from_fn(1920, 1080, |x, y| {
+                image::Rgb([((x * (seed + 1)) % 256) as u8, (y % 256) as u8, 0])
+            })
+            .save(&path)
+            .unwrap();
+            MetadataRepo::new(&conn).insert_dimensions(&url, 1920, 1080).unwrap();
+            conn.execute(
+                "INSERT INTO AppliedHistory(image_hash, url, timestamp) VALUES (?, ?, ?)",
+                rusqlite::params![[seed as u8], url, applied_at],
+            )
+            .unwrap();
+            paths.push(path);
+        }
+
+        let Ok(picked) = pick_from(&context(&conn, &[]), dir.path(), true) else {
+            panic!("picked nothing out of the archive");
+        };
+        assert_eq!(picked.path, paths[1]);
+        assert!(picked.archived);
+
+        // Archived images stay where they are, to be picked again some other time
+        assert!(paths.iter().all(|path| path.exists()));
+        let Ok(picked) = pick_from(&context(&conn, &paths[1..]), dir.path(), true) else {
+            panic!("picked nothing out of the archive");
+        };
+        assert_eq!(picked.path, paths[0]);
+    }
+
+    #[test]
+    fn only_the_image_we_pick_gets_decoded() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // What we stored says the first would be best and the last worst, but the first has been cut short since
+        let cut = store(&conn, dir.path(), "https://i.redd.it/cut.png", (1920, 1080), false);
+        let contents = fs::read(&cut).unwrap();
+        fs::write(&cut, &contents[..contents.len() / 2]).unwrap();
+        let upscaled = store(&conn, dir.path(), "https://i.redd.it/upscaled.png", (1920, 1080), true);
+        let portrait = store(&conn, dir.path(), "https://i.redd.it/portrait.png", (1080, 1920), false);
+
+        let ctx = context(&conn, &[]);
+        let Ok(picked) = pick_from(&ctx, dir.path(), false) else {
+            panic!("picked nothing");
+        };
+        assert_eq!(picked.path, upscaled);
+        assert_eq!(ctx.decodes.get(), 1);
+        assert!(!cut.exists());
+        assert!(portrait.exists());
+    }
+
+    #[test]
+    fn switching_to_consume_mode_counts_what_is_left_in_the_archive() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let state = AppStateRepo::new(&conn);
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive");
+
+        // Starting out, or switching the other way, isn't worth telling anyone about
+        assert_eq!(switch_mode_in(&state, Mode::Consume, &archive).unwrap(), None);
+        assert_eq!(switch_mode_in(&state, Mode::Archive, &archive).unwrap(), None);
+        assert_eq!(switch_mode_in(&state, Mode::Archive, &archive).unwrap(), None);
+
+        for (profile, count) in [("default", 2), ("work", 1)] {
+            fs::create_dir_all(archive.join(profile)).unwrap();
+            for idx in 0..count {
+                fs::write(archive.join(profile).join(format!("{idx}.png")), "").unwrap();
+            }
+        }
+        assert_eq!(switch_mode_in(&state, Mode::Consume, &archive).unwrap(), Some(3));
+        assert_eq!(switch_mode_in(&state, Mode::Consume, &archive).unwrap(), None);
+    }
+
+    #[test]
+    fn archived_images_shown_recently_go_last() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::migrate(&mut conn).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        // Shown yesterday, so within the window, even though it's been longer for the other
+        let recent = store(&conn, dir.path(), "https://i.redd.it/recent.png", (1920, 1080), false);
+        let older = store(&conn, dir.path(), "https://i.redd.it/older.png", (1920, 1080), false);
+        for (url, days_ago) in [("https://i.redd.it/recent.png", 1), ("https://i.redd.it/older.png", 30)] {
+            conn.execute(
+                "INSERT INTO AppliedHistory(image_hash, url, subreddit, timestamp)
+                 VALUES (x'00', ?, 'wallpapers', datetime('now', ?))",
+                rusqlite::params![url, format!("-{days_ago} days")],
+            )
+            .unwrap();
+        }
+
+        // The variety we'd like is given up on before the repeat window is
+        let ctx = Context {
+            recent_subreddits: vec!["wallpapers".to_owned()],
+            ..context(&conn, &[])
+        };
+        let Ok(picked) = pick_from(&ctx, dir.path(), true) else {
+            panic!("picked nothing out of the archive");
+        };
+        assert_eq!(picked.path, older);
+
+        // But once everything's been shown within it, the least recently shown still goes back up
+        let exclude = [older];
+        let Ok(picked) = pick_from(&context(&conn, &exclude), dir.path(), true) else {
+            panic!("picked nothing out of the archive");
+        };
+        assert_eq!(picked.path, recent);
+    }
+
+    fn candidate(name: &str, subreddit: &str) -> Candidate {
+        Candidate {
+            path: PathBuf::from(name),
+            url: None,
+            subreddit: Some(subreddit.to_owned()),
+            size_score: 0,
+            last_applied: None,
+            other_orientation: false,
+            upscaled: false,
+            stored: None,
+            repeat: false,
+        }
+    }
+
+    #[test]
+    fn candidates_go_in_the_tier_of_the_last_preference_they_miss() {
+        let recent = ["pics".to_owned()];
+        assert_eq!(Tier::of(&candidate("a", "wallpapers"), &recent), Tier::All);
+        assert_eq!(Tier::of(&candidate("a", "pics"), &recent), Tier::AnySubreddit);
+
+        let other_orientation = Candidate {
+            other_orientation: true,
+            ..candidate("a", "pics")
+        };
+        assert_eq!(Tier::of(&other_orientation, &recent), Tier::AnyOrientation);
+        let repeat = Candidate {
+            repeat: true,
+            ..other_orientation
+        };
+        assert_eq!(Tier::of(&repeat, &recent), Tier::AnyRepeat);
+        // Images we know nothing of the source of are never from a recent subreddit
+        let unknown = Candidate {
+            subreddit: None,
+            ..candidate("a", "pics")
+        };
+        assert_eq!(Tier::of(&unknown, &recent), Tier::All);
+    }
+
+    #[test]
+    fn tiers_outrank_upscaling_and_sharpness() {
+        let mut candidates = vec![
+            Candidate {
+                size_score: 9,
+                ..candidate("recent", "pics")
+            },
+            Candidate {
+                upscaled: true,
+                ..candidate("upscaled", "wallpapers")
+            },
+            Candidate {
+                size_score: 1,
+                ..candidate("blurry", "wallpapers")
+            },
+            Candidate {
+                size_score: 5,
+                ..candidate("sharp", "wallpapers")
+            },
+        ];
+        rank(&mut candidates, &["pics".to_owned()]);
+        let order = candidates
+            .iter()
+            .map(|candidate| candidate.path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(order, ["sharp", "blurry", "upscaled", "recent"]);
+    }
+}
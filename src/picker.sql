@@ -1,3 +0,0 @@
-CREATE TABLE IF NOT EXISTS AppliedImages (
-    image_hash BLOB NOT NULL PRIMARY KEY
-);
@@ -16,9 +16,68 @@ macro_rules! wintry {
     };
 }
 
+/// Send what we print to the console we were run from, as being a GUI program we don't get one of our own.
+///
+/// Output that's been redirected already goes where it was sent, so it's left alone.
+#[cfg(windows)]
+pub fn attach_console() {
+    use winapi::um::{
+        processenv::GetStdHandle,
+        winbase::STD_OUTPUT_HANDLE,
+        wincon::{AttachConsole, ATTACH_PARENT_PROCESS},
+    };
+
+    if unsafe { GetStdHandle(STD_OUTPUT_HANDLE) }.is_null() {
+        // There's no console to attach to when we weren't run from one, and nothing to be done about it
+        let _ = wintry!(unsafe { AttachConsole(ATTACH_PARENT_PROCESS) });
+    }
+}
+
+/// Opt into per-monitor DPI awareness, so that Windows tells us about physical pixels instead of scaled ones.
+///
+/// This has to happen before we create any window.
+#[cfg(windows)]
+pub fn set_dpi_aware() {
+    use winapi::{
+        shared::windef::{DPI_AWARENESS_CONTEXT, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2},
+        um::{
+            libloaderapi::{GetModuleHandleA, GetProcAddress},
+            winuser::SetProcessDPIAware,
+        },
+    };
+
+    // SetProcessDpiAwarenessContext only exists since Windows 10 1703, so we can't link to it directly
+    let set_context = unsafe {
+        let user32 = GetModuleHandleA(b"user32.dll\0".as_ptr().cast());
+        if user32.is_null() {
+            std::ptr::null_mut()
+        } else {
+            GetProcAddress(user32, b"SetProcessDpiAwarenessContext\0".as_ptr().cast())
+        }
+    };
+
+    if !set_context.is_null() {
+        let set_context: unsafe extern "system" fn(DPI_AWARENESS_CONTEXT) -> i32 =
+            unsafe { std::mem::transmute(set_context) };
+        if unsafe { set_context(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) } != 0 {
+            tracing::debug!("enabled per-monitor DPI awareness");
+            return;
+        }
+        let error = io::Error::last_os_error();
+        tracing::debug!(?error, "could not enable per-monitor DPI awareness");
+    }
+
+    if let Err(error) = wintry!(unsafe { SetProcessDPIAware() }) {
+        tracing::warn!(?error, "could not enable DPI awareness, screen size may be scaled");
+    }
+}
+
 #[cfg(windows)]
 pub fn screen_size() -> Result<(u32, u32)> {
-    use winapi::um::winuser::{GetSystemMetrics, SM_CXSCREEN, SM_CYSCREEN};
+    use winapi::um::{
+        wingdi::{GetDeviceCaps, DESKTOPHORZRES, DESKTOPVERTRES},
+        winuser::{GetDC, GetSystemMetrics, ReleaseDC, SM_CXSCREEN, SM_CYSCREEN},
+    };
 
     let (width, height) = unsafe { (GetSystemMetrics(SM_CXSCREEN), GetSystemMetrics(SM_CYSCREEN)) };
 
@@ -26,22 +85,696 @@ pub fn screen_size() -> Result<(u32, u32)> {
     ensure!(width != 0, "GetSystemMetrics's returned width was zero");
     ensure!(height != 0, "GetSystemMetrics's returned height was zero");
 
-    Ok((u32::try_from(width)?, u32::try_from(height)?))
+    // Under DPI scaling the metrics above may be scaled down if we're not DPI aware, while the device caps are always
+    // in physical pixels.
+    let (physical_width, physical_height) = unsafe {
+        let dc = GetDC(std::ptr::null_mut());
+        if dc.is_null() {
+            (0, 0)
+        } else {
+            let caps = (GetDeviceCaps(dc, DESKTOPHORZRES), GetDeviceCaps(dc, DESKTOPVERTRES));
+            ReleaseDC(std::ptr::null_mut(), dc);
+            caps
+        }
+    };
+    tracing::debug!(width, height, physical_width, physical_height, "got screen size");
+
+    if physical_width > 0 && physical_height > 0 {
+        Ok((u32::try_from(physical_width)?, u32::try_from(physical_height)?))
+    } else {
+        Ok((u32::try_from(width)?, u32::try_from(height)?))
+    }
+}
+
+/// A monitor's position and size: x, y, width and height
+pub type Geometry = (i32, i32, u32, u32);
+
+/// Hash the arrangement of monitors given by their geometry, in whatever order.
+pub fn layout_hash(geometry: &[Geometry]) -> u64 {
+    let mut geometry = geometry.to_vec();
+    geometry.sort_unstable();
+    let bytes = geometry
+        .iter()
+        .flat_map(|&(x, y, width, height)| {
+            [
+                x.to_le_bytes(),
+                y.to_le_bytes(),
+                width.to_le_bytes(),
+                height.to_le_bytes(),
+            ]
+        })
+        .flatten()
+        .collect::<Vec<u8>>();
+    xxhash_rust::xxh3::xxh3_64(&bytes)
 }
 
+/// Get a hash of how the attached monitors are arranged, which changes whenever one is added, removed, moved or
+/// resized.
 #[cfg(windows)]
-pub fn set_background(path: &Path) -> Result<()> {
-    use std::os::windows::ffi::OsStrExt;
-    use winapi::um::winuser::{SystemParametersInfoW, SPI_SETDESKWALLPAPER};
+pub fn monitor_layout() -> Result<u64> {
+    let geometry = monitors()?
+        .into_iter()
+        .map(|(_, geometry)| geometry)
+        .collect::<Vec<_>>();
+    Ok(layout_hash(&geometry))
+}
+
+/// Get whether every attached monitor is the primary one along with its geometry.
+#[cfg(windows)]
+fn monitors() -> Result<Vec<(bool, Geometry)>> {
+    use winapi::{
+        shared::{
+            minwindef::{BOOL, LPARAM, TRUE},
+            windef::{HDC, HMONITOR, LPRECT},
+        },
+        um::winuser::{EnumDisplayMonitors, GetMonitorInfoW, MONITORINFO, MONITORINFOF_PRIMARY},
+    };
+
+    unsafe extern "system" fn callback(monitor: HMONITOR, _: HDC, _: LPRECT, data: LPARAM) -> BOOL {
+        let monitors = &mut *(data as *mut Vec<(bool, Geometry)>);
+        let mut info: MONITORINFO = std::mem::zeroed();
+        info.cbSize = std::mem::size_of::<MONITORINFO>() as u32;
+        if GetMonitorInfoW(monitor, &mut info) != 0 {
+            let rect = info.rcMonitor;
+            monitors.push((
+                info.dwFlags & MONITORINFOF_PRIMARY != 0,
+                (
+                    rect.left,
+                    rect.top,
+                    (rect.right - rect.left).unsigned_abs(),
+                    (rect.bottom - rect.top).unsigned_abs(),
+                ),
+            ));
+        }
+        TRUE
+    }
+
+    let mut monitors = Vec::<(bool, Geometry)>::new();
+    wintry!(unsafe {
+        EnumDisplayMonitors(
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            Some(callback),
+            &mut monitors as *mut _ as LPARAM,
+        )
+    })
+    .wrap_err("Failed to enumerate monitors")?;
+    tracing::debug!(?monitors, "got monitors");
+    Ok(monitors)
+}
+
+/// Get the size of every attached monitor in physical pixels, starting with the primary one.
+#[cfg(windows)]
+pub fn monitor_sizes() -> Result<Vec<(u32, u32)>> {
+    let mut monitors = monitors()?;
+
+    // Without per-monitor DPI awareness the sizes above may be scaled, so fall back to what we know works
+    if monitors.len() <= 1 {
+        return Ok(vec![screen_size()?]);
+    }
+
+    monitors.sort_by_key(|&(primary, _)| !primary);
+    Ok(monitors
+        .into_iter()
+        .map(|(_, (_, _, width, height))| (width, height))
+        .collect())
+}
+
+macro_rules! hrtry {
+    ($expr:expr) => {{
+        let hr = $expr;
+        if winapi::shared::winerror::SUCCEEDED(hr) {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(hr))
+        }
+    }};
+}
+
+/// Initializes COM for the current thread for as long as it's alive
+#[cfg(windows)]
+struct ComGuard;
+
+#[cfg(windows)]
+impl ComGuard {
+    fn new() -> Result<Self> {
+        use winapi::um::{combaseapi::CoInitializeEx, objbase::COINIT_APARTMENTTHREADED};
+
+        // S_FALSE means COM was already initialized on this thread, which still needs balancing
+        hrtry!(unsafe { CoInitializeEx(std::ptr::null_mut(), COINIT_APARTMENTTHREADED) })
+            .wrap_err("Failed to initialize COM")?;
+        Ok(Self)
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ComGuard {
+    fn drop(&mut self) {
+        unsafe { winapi::um::combaseapi::CoUninitialize() };
+    }
+}
+
+/// How Windows places a background that doesn't exactly match the screen
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WallpaperStyle {
+    /// Scaled to cover the screen, cropping what doesn't fit
+    #[default]
+    Fill,
+    /// Scaled to fit the screen, leaving bars where it doesn't cover it
+    Fit,
+    /// Scaled to exactly the screen's size, distorting it
+    Stretch,
+    /// Kept at its size in the middle of the screen
+    Center,
+    /// Repeated at its size across the screen
+    Tile,
+    /// Scaled to cover every monitor at once
+    Span,
+}
+
+impl WallpaperStyle {
+    pub const ALL: &'static [Self] = &[
+        Self::Fill,
+        Self::Fit,
+        Self::Stretch,
+        Self::Center,
+        Self::Tile,
+        Self::Span,
+    ];
+
+    /// What the style is called in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Fill => "fill",
+            Self::Fit => "fit",
+            Self::Stretch => "stretch",
+            Self::Center => "center",
+            Self::Tile => "tile",
+            Self::Span => "span",
+        }
+    }
+
+    /// What the style is called in the tray menu.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Fill => "Fill",
+            Self::Fit => "Fit",
+            Self::Stretch => "Stretch",
+            Self::Center => "Center",
+            Self::Tile => "Tile",
+            Self::Span => "Span",
+        }
+    }
+
+    /// The `WallpaperStyle` and `TileWallpaper` values Settings writes for the style.
+    #[cfg(windows)]
+    fn registry_values(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Fill => ("10", "0"),
+            Self::Fit => ("6", "0"),
+            Self::Stretch => ("2", "0"),
+            Self::Center => ("0", "0"),
+            Self::Tile => ("0", "1"),
+            Self::Span => ("22", "0"),
+        }
+    }
+
+    #[cfg(windows)]
+    fn position(self) -> winapi::um::shobjidl_core::DESKTOP_WALLPAPER_POSITION {
+        use winapi::um::shobjidl_core::{DWPOS_CENTER, DWPOS_FILL, DWPOS_FIT, DWPOS_SPAN, DWPOS_STRETCH, DWPOS_TILE};
+
+        match self {
+            Self::Fill => DWPOS_FILL,
+            Self::Fit => DWPOS_FIT,
+            Self::Stretch => DWPOS_STRETCH,
+            Self::Center => DWPOS_CENTER,
+            Self::Tile => DWPOS_TILE,
+            Self::Span => DWPOS_SPAN,
+        }
+    }
+}
 
+/// Write the style for the legacy API, which reads it from the registry when the background is set.
+#[cfg(windows)]
+fn set_wallpaper_style(style: WallpaperStyle) -> Result<()> {
+    use winapi::{
+        shared::winerror::ERROR_SUCCESS,
+        um::{
+            winnt::REG_SZ,
+            winreg::{RegSetKeyValueW, HKEY_CURRENT_USER},
+        },
+    };
+
+    let (wallpaper_style, tile_wallpaper) = style.registry_values();
+    let key = to_wide(DESKTOP_KEY);
+    for (name, data) in [("WallpaperStyle", wallpaper_style), ("TileWallpaper", tile_wallpaper)] {
+        let (name, data) = (to_wide(name), to_wide(data));
+        let status = unsafe {
+            RegSetKeyValueW(
+                HKEY_CURRENT_USER,
+                key.as_ptr(),
+                name.as_ptr(),
+                REG_SZ,
+                data.as_ptr().cast(),
+                (data.len() * std::mem::size_of::<u16>()) as u32,
+            )
+        };
+        if status as u32 != ERROR_SUCCESS {
+            return Err(io::Error::from_raw_os_error(status)).wrap_err("Failed to write wallpaper style");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn set_background(path: &Path, style: WallpaperStyle) -> Result<()> {
     ensure!(path.is_absolute(), "SystemParametersInfoW requires an absolute path");
 
+    // Try the legacy API first, as it's the only one available on Windows 7, checking that it actually took.
+    let legacy_result = set_wallpaper_style(style)
+        .and_then(|()| set_background_legacy(path))
+        .and_then(|()| {
+            let current = get_background()?;
+            ensure!(
+                current.as_os_str().eq_ignore_ascii_case(path.as_os_str()),
+                "Background is {current:?} instead of {path:?} after setting it"
+            );
+            Ok(())
+        });
+
+    match legacy_result {
+        Ok(()) => Ok(()),
+
+        Err(legacy_error) => {
+            tracing::warn!(?legacy_error, "legacy set_background failed, trying IDesktopWallpaper");
+            set_background_com(path, style).wrap_err_with(|| format!("Legacy API also failed: {legacy_error:?}"))
+        }
+    }
+}
+
+#[cfg(windows)]
+fn set_background_legacy(path: &Path) -> Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::um::winuser::{SystemParametersInfoW, SPI_SETDESKWALLPAPER};
+
     let path_utf16 = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
 
     wintry!(unsafe { SystemParametersInfoW(SPI_SETDESKWALLPAPER, 0, path_utf16.as_ptr() as *mut _, 0) })
         .wrap_err(format!("Failed to set background to {path:?}"))
 }
 
+/// An `IDesktopWallpaper` that's released once we're done with it
+#[cfg(windows)]
+struct DesktopWallpaper(*mut winapi::um::shobjidl_core::IDesktopWallpaper);
+
+#[cfg(windows)]
+impl DesktopWallpaper {
+    fn new() -> Result<Self> {
+        use winapi::{
+            um::{
+                combaseapi::{CoCreateInstance, CLSCTX_ALL},
+                shobjidl_core::{CLSID_DesktopWallpaper, IDesktopWallpaper},
+            },
+            Interface,
+        };
+
+        let mut wallpaper: *mut IDesktopWallpaper = std::ptr::null_mut();
+        hrtry!(unsafe {
+            CoCreateInstance(
+                &CLSID_DesktopWallpaper,
+                std::ptr::null_mut(),
+                CLSCTX_ALL,
+                &IDesktopWallpaper::uuidof(),
+                std::ptr::addr_of_mut!(wallpaper).cast(),
+            )
+        })
+        .wrap_err("Failed to create IDesktopWallpaper")?;
+        Ok(Self(wallpaper))
+    }
+
+    /// Set the background of the monitor with the given NUL-terminated ID, or of every monitor if it's null.
+    fn set(&self, monitor: *const u16, path: &Path) -> Result<()> {
+        use std::os::windows::ffi::OsStrExt;
+
+        let path_utf16 = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+        hrtry!(unsafe { (*self.0).SetWallpaper(monitor, path_utf16.as_ptr()) })
+            .wrap_err(format!("IDesktopWallpaper failed to set background to {path:?}"))
+    }
+
+    fn set_position(&self, style: WallpaperStyle) -> Result<()> {
+        hrtry!(unsafe { (*self.0).SetPosition(style.position()) }).wrap_err("Failed to set wallpaper position")
+    }
+
+    /// We're taking over, so make sure a slideshow doesn't override us. Not all versions accept an empty slideshow, in
+    /// which case there's probably no slideshow to disable anyway.
+    fn disable_slideshow(&self) {
+        if let Err(error) = hrtry!(unsafe { (*self.0).SetSlideshow(std::ptr::null_mut()) }) {
+            tracing::debug!(?error, "could not disable slideshow");
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for DesktopWallpaper {
+    fn drop(&mut self) {
+        unsafe { (*self.0).Release() };
+    }
+}
+
+#[cfg(windows)]
+fn set_background_com(path: &Path, style: WallpaperStyle) -> Result<()> {
+    let _com = ComGuard::new()?;
+    let wallpaper = DesktopWallpaper::new()?;
+    wallpaper.disable_slideshow();
+    wallpaper.set_position(style)?;
+
+    // A null monitor ID means every monitor
+    wallpaper.set(std::ptr::null(), path)
+}
+
+/// A monitor we can set a background of its own on
+#[cfg(windows)]
+#[derive(Clone, Debug)]
+pub struct Monitor {
+    /// Its NUL-terminated device path
+    id: Vec<u16>,
+    /// Its size in physical pixels
+    pub size: (u32, u32),
+}
+
+/// Get every attached monitor `IDesktopWallpaper` can set a background on, starting with the primary one.
+#[cfg(windows)]
+pub fn wallpaper_monitors() -> Result<Vec<Monitor>> {
+    use winapi::{shared::windef::RECT, um::combaseapi::CoTaskMemFree};
+
+    let _com = ComGuard::new()?;
+    let wallpaper = DesktopWallpaper::new()?;
+
+    let mut count = 0;
+    hrtry!(unsafe { (*wallpaper.0).GetMonitorDevicePathCount(&mut count) }).wrap_err("Failed to count monitors")?;
+
+    let mut monitors = Vec::new();
+    for index in 0..count {
+        let mut path = std::ptr::null_mut();
+        hrtry!(unsafe { (*wallpaper.0).GetMonitorDevicePathAt(index, &mut path) })
+            .wrap_err("Failed to get monitor device path")?;
+        let id = unsafe {
+            let len = (0..).take_while(|&i| *path.add(i) != 0).count();
+            let id = std::slice::from_raw_parts(path, len + 1).to_vec();
+            CoTaskMemFree(path.cast());
+            id
+        };
+
+        // Monitors that were attached once are still listed, they just don't have a rectangle anymore
+        let mut rect: RECT = unsafe { std::mem::zeroed() };
+        if hrtry!(unsafe { (*wallpaper.0).GetMonitorRECT(id.as_ptr(), &mut rect) }).is_err() {
+            continue;
+        }
+        let (width, height) = (
+            (rect.right - rect.left).unsigned_abs(),
+            (rect.bottom - rect.top).unsigned_abs(),
+        );
+        if width == 0 || height == 0 {
+            continue;
+        }
+
+        // The primary monitor is the one at the origin of the virtual screen
+        let primary = rect.left == 0 && rect.top == 0;
+        monitors.push((
+            primary,
+            Monitor {
+                id,
+                size: (width, height),
+            },
+        ));
+    }
+    tracing::debug!(?monitors, "got wallpaper monitors");
+
+    monitors.sort_by_key(|&(primary, _)| !primary);
+    Ok(monitors.into_iter().map(|(_, monitor)| monitor).collect())
+}
+
+/// Give each monitor its own background, in order.
+#[cfg(windows)]
+pub fn set_monitor_backgrounds(backgrounds: &[(&Monitor, &Path)], style: WallpaperStyle) -> Result<()> {
+    ensure!(
+        backgrounds.iter().all(|(_, path)| path.is_absolute()),
+        "IDesktopWallpaper requires absolute paths"
+    );
+
+    let _com = ComGuard::new()?;
+    let wallpaper = DesktopWallpaper::new()?;
+    wallpaper.disable_slideshow();
+    wallpaper.set_position(style)?;
+    for (monitor, path) in backgrounds {
+        wallpaper.set(monitor.id.as_ptr(), path)?;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn to_wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+#[cfg(windows)]
+const DESKTOP_KEY: &str = "Control Panel\\Desktop";
+
+#[cfg(windows)]
+const JPEG_IMPORT_QUALITY: &str = "JPEGImportQuality";
+
+/// Read the JPEG quality Windows re-encodes wallpapers at, if it's been set at all.
+#[cfg(windows)]
+fn get_jpeg_import_quality() -> Result<Option<u32>> {
+    use winapi::{
+        shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS},
+        um::winreg::{RegGetValueW, HKEY_CURRENT_USER, RRF_RT_REG_DWORD},
+    };
+
+    let (key, value) = (to_wide(DESKTOP_KEY), to_wide(JPEG_IMPORT_QUALITY));
+    let mut data = 0u32;
+    let mut size = std::mem::size_of::<u32>() as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            key.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_DWORD,
+            std::ptr::null_mut(),
+            (&mut data as *mut u32).cast(),
+            &mut size,
+        )
+    };
+    match status as u32 {
+        ERROR_SUCCESS => Ok(Some(data)),
+        ERROR_FILE_NOT_FOUND => Ok(None),
+        _ => Err(io::Error::from_raw_os_error(status)).wrap_err("Failed to read JPEGImportQuality"),
+    }
+}
+
+/// Set the JPEG quality Windows re-encodes wallpapers at, or remove the setting entirely if `quality` is `None`.
+#[cfg(windows)]
+fn set_jpeg_import_quality(quality: Option<u32>) -> Result<()> {
+    use winapi::{
+        shared::winerror::ERROR_SUCCESS,
+        um::{
+            winnt::REG_DWORD,
+            winreg::{RegDeleteKeyValueW, RegSetKeyValueW, HKEY_CURRENT_USER},
+        },
+    };
+
+    let (key, value) = (to_wide(DESKTOP_KEY), to_wide(JPEG_IMPORT_QUALITY));
+    let status = match quality {
+        Some(quality) => unsafe {
+            RegSetKeyValueW(
+                HKEY_CURRENT_USER,
+                key.as_ptr(),
+                value.as_ptr(),
+                REG_DWORD,
+                (&quality as *const u32).cast(),
+                std::mem::size_of::<u32>() as u32,
+            )
+        },
+        None => unsafe { RegDeleteKeyValueW(HKEY_CURRENT_USER, key.as_ptr(), value.as_ptr()) },
+    };
+    if status as u32 != ERROR_SUCCESS {
+        return Err(io::Error::from_raw_os_error(status)).wrap_err("Failed to write JPEGImportQuality");
+    }
+    Ok(())
+}
+
+/// Puts the JPEG import quality back the way it was when dropped
+#[cfg(windows)]
+pub struct JpegQualityGuard {
+    previous: Option<u32>,
+}
+
+#[cfg(windows)]
+impl Drop for JpegQualityGuard {
+    fn drop(&mut self) {
+        tracing::debug!(previous = ?self.previous, "restoring JPEG import quality");
+        if let Err(error) = set_jpeg_import_quality(self.previous) {
+            tracing::warn!(?error, "could not restore JPEG import quality");
+        }
+    }
+}
+
+/// Stop Windows from re-encoding our wallpapers as ~85% quality JPEGs, until the returned guard is dropped.
+///
+/// Returns `None` if the quality was already at its maximum, as then there's nothing to restore.
+#[cfg(windows)]
+pub fn max_jpeg_import_quality() -> Result<Option<JpegQualityGuard>> {
+    const MAX_QUALITY: u32 = 100;
+
+    let previous = get_jpeg_import_quality()?;
+    if previous == Some(MAX_QUALITY) {
+        return Ok(None);
+    }
+
+    tracing::debug!(?previous, "raising JPEG import quality");
+    set_jpeg_import_quality(Some(MAX_QUALITY))?;
+    Ok(Some(JpegQualityGuard { previous }))
+}
+
+/// Get the wallpaper a group policy forces on the user, if one does.
+///
+/// Windows quietly puts this one back up whenever anything else sets a background.
+#[cfg(windows)]
+pub fn policy_wallpaper() -> Result<Option<PathBuf>> {
+    use winapi::um::winreg::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE};
+
+    // Machine policies win over user ones
+    read_path_value(
+        &[HKEY_LOCAL_MACHINE, HKEY_CURRENT_USER],
+        "Software\\Microsoft\\Windows\\CurrentVersion\\Policies\\System",
+        "Wallpaper",
+    )
+    .wrap_err("Failed to read wallpaper policy")
+}
+
+/// Read the path stored as `value` under `key` in the first of `roots` that has a non-empty one.
+#[cfg(windows)]
+fn read_path_value(roots: &[winapi::shared::minwindef::HKEY], key: &str, value: &str) -> io::Result<Option<PathBuf>> {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+    use winapi::{
+        shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS},
+        um::winreg::{RegGetValueW, RRF_RT_REG_EXPAND_SZ, RRF_RT_REG_SZ},
+    };
+
+    let (key, value) = (to_wide(key), to_wide(value));
+    for &root in roots {
+        let mut buf = [0u16; 1024];
+        let mut size = std::mem::size_of_val(&buf) as u32;
+        let status = unsafe {
+            RegGetValueW(
+                root,
+                key.as_ptr(),
+                value.as_ptr(),
+                RRF_RT_REG_SZ | RRF_RT_REG_EXPAND_SZ,
+                std::ptr::null_mut(),
+                buf.as_mut_ptr().cast(),
+                &mut size,
+            )
+        };
+        match status as u32 {
+            ERROR_SUCCESS => {
+                let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+                if len > 0 {
+                    return Ok(Some(OsString::from_wide(&buf[..len]).into()));
+                }
+            }
+            ERROR_FILE_NOT_FOUND => {}
+            _ => return Err(io::Error::from_raw_os_error(status)),
+        }
+    }
+    Ok(None)
+}
+
+/// Get the path of the current background
+#[cfg(windows)]
+pub fn get_background() -> Result<PathBuf> {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+    use winapi::{
+        shared::minwindef::MAX_PATH,
+        um::winuser::{SystemParametersInfoW, SPI_GETDESKWALLPAPER},
+    };
+
+    let mut buf = [0u16; MAX_PATH];
+    wintry!(unsafe { SystemParametersInfoW(SPI_GETDESKWALLPAPER, buf.len() as u32, buf.as_mut_ptr().cast(), 0) })
+        .wrap_err("Failed to get background")?;
+
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Ok(OsString::from_wide(&buf[..len]).into())
+}
+
+/// Play the system's default sound, as a subtle cue that something happened
+#[cfg(windows)]
+pub fn play_cue() -> Result<()> {
+    use winapi::um::winuser::{MessageBeep, MB_OK};
+
+    wintry!(unsafe { MessageBeep(MB_OK) }).wrap_err("Failed to play sound")?;
+    Ok(())
+}
+
+/// Whether the user doesn't want to be disturbed right now, be it quiet hours, a presentation or a full screen game
+#[cfg(windows)]
+pub fn do_not_disturb() -> Result<bool> {
+    use winapi::um::shellapi::{
+        SHQueryUserNotificationState, QUNS_BUSY, QUNS_PRESENTATION_MODE, QUNS_QUIET_TIME, QUNS_RUNNING_D3D_FULL_SCREEN,
+    };
+
+    let mut state = 0;
+    hrtry!(unsafe { SHQueryUserNotificationState(&mut state) }).wrap_err("Failed to query notification state")?;
+    Ok(matches!(
+        state,
+        QUNS_BUSY | QUNS_RUNNING_D3D_FULL_SCREEN | QUNS_PRESENTATION_MODE | QUNS_QUIET_TIME
+    ))
+}
+
+/// Get how many bytes are free on the volume holding `path`, as far as we're allowed to use them
+#[cfg(windows)]
+pub fn free_disk_space(path: &Path) -> Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::um::{fileapi::GetDiskFreeSpaceExW, winnt::ULARGE_INTEGER};
+
+    let wide_path = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let mut available: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+    wintry!(unsafe {
+        GetDiskFreeSpaceExW(
+            wide_path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    })
+    .wrap_err("Failed to get free disk space")?;
+    Ok(unsafe { *available.QuadPart() })
+}
+
+#[cfg(windows)]
+pub fn open(path: &Path) -> Result<()> {
+    use std::{os::windows::ffi::OsStrExt, ptr};
+    use winapi::um::{shellapi::ShellExecuteW, winuser::SW_SHOWNORMAL};
+
+    let operation = "open\0".encode_utf16().collect::<Vec<u16>>();
+    let path_utf16 = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+
+    // ShellExecuteW returns a fake HINSTANCE which is greater than 32 on success
+    let result = unsafe {
+        ShellExecuteW(
+            ptr::null_mut(),
+            operation.as_ptr(),
+            path_utf16.as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            SW_SHOWNORMAL,
+        )
+    } as usize;
+    ensure!(result > 32, "Failed to open {path:?} (code {result})");
+    Ok(())
+}
+
 #[cfg(windows)]
 pub fn copy_image(img: &image::DynamicImage) -> Result<()> {
     use std::convert::TryInto;
@@ -86,30 +819,290 @@ pub fn copy_image(img: &image::DynamicImage) -> Result<()> {
     set_result.and(delete_result).and(close_result)
 }
 
+/// The AppUserModelID our notifications are shown under once we've registered it
+#[cfg(windows)]
+pub const APP_ID: &str = "PurpleMyst.redditbg";
+
+/// Tell Windows which AppUserModelID this process belongs to, so that its notifications are grouped under it.
+#[cfg(windows)]
+fn set_app_id() -> Result<()> {
+    use winapi::{
+        shared::winerror::HRESULT,
+        um::libloaderapi::{GetProcAddress, LoadLibraryA},
+    };
+
+    // winapi doesn't bind SetCurrentProcessExplicitAppUserModelID, so look it up like set_dpi_aware does
+    let set_app_id = unsafe {
+        let shell32 = LoadLibraryA(b"shell32.dll\0".as_ptr().cast());
+        ensure!(!shell32.is_null(), "Failed to load shell32.dll");
+        GetProcAddress(shell32, b"SetCurrentProcessExplicitAppUserModelID\0".as_ptr().cast())
+    };
+    ensure!(
+        !set_app_id.is_null(),
+        "SetCurrentProcessExplicitAppUserModelID is not available"
+    );
+    let set_app_id: unsafe extern "system" fn(*const u16) -> HRESULT = unsafe { std::mem::transmute(set_app_id) };
+
+    let app_id = to_wide(APP_ID);
+    hrtry!(unsafe { set_app_id(app_id.as_ptr()) }).wrap_err("Failed to set the process's AppUserModelID")?;
+    Ok(())
+}
+
+/// Get where our Start Menu shortcut lives; toasts only show up under our name if such a shortcut carries our ID.
+#[cfg(windows)]
+fn shortcut_path() -> Result<PathBuf> {
+    let appdata = std::env::var_os("APPDATA").ok_or_else(|| format_err!("APPDATA is not set"))?;
+    Ok(PathBuf::from(appdata).join("Microsoft\\Windows\\Start Menu\\Programs\\redditbg.lnk"))
+}
+
+/// Create a Start Menu shortcut to ourselves carrying [`APP_ID`] and adopt that ID for this process.
+///
+/// The shortcut is rewritten every time, so that it keeps pointing at the current executable if it moved.
+#[cfg(windows)]
+pub fn register_app_id() -> Result<()> {
+    let shortcut = shortcut_path()?;
+    let exe = std::env::current_exe().wrap_err("Failed to get our own path")?;
+    let _com = ComGuard::new()?;
+
+    let link = create_shell_link()?;
+    let result = unsafe { save_shortcut(link, &exe, &shortcut) };
+    unsafe { (*link).Release() };
+    result?;
+
+    set_app_id()
+}
+
+/// Create an `IShellLinkW`, which the caller has to release.
+#[cfg(windows)]
+fn create_shell_link() -> Result<*mut winapi::um::shobjidl_core::IShellLinkW> {
+    use std::ptr;
+    use winapi::{
+        shared::{guiddef::GUID, wtypesbase::CLSCTX_INPROC_SERVER},
+        um::{combaseapi::CoCreateInstance, shobjidl_core::IShellLinkW},
+        Interface,
+    };
+
+    // winapi doesn't define CLSID_ShellLink either
+    const CLSID_SHELL_LINK: GUID = GUID {
+        Data1: 0x0002_1401,
+        Data2: 0x0000,
+        Data3: 0x0000,
+        Data4: [0xC0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x46],
+    };
+
+    let mut link: *mut IShellLinkW = ptr::null_mut();
+    hrtry!(unsafe {
+        CoCreateInstance(
+            &CLSID_SHELL_LINK,
+            ptr::null_mut(),
+            CLSCTX_INPROC_SERVER,
+            &IShellLinkW::uuidof(),
+            ptr::addr_of_mut!(link).cast(),
+        )
+    })
+    .wrap_err("Failed to create IShellLinkW")?;
+    Ok(link)
+}
+
+/// Point the shortcut `link` at `exe`, tag it with [`APP_ID`] and save it to `shortcut`.
+///
+/// # Safety
+///
+/// `link` must be a valid `IShellLinkW`.
+#[cfg(windows)]
+unsafe fn save_shortcut(link: *mut winapi::um::shobjidl_core::IShellLinkW, exe: &Path, shortcut: &Path) -> Result<()> {
+    use std::{os::windows::ffi::OsStrExt, ptr};
+    use winapi::{
+        shared::wtypes::VT_LPWSTR,
+        um::{objidl::IPersistFile, propidl::PROPVARIANT, propkey::PKEY_AppUserModel_ID, propsys::IPropertyStore},
+        Interface,
+    };
+
+    let exe_utf16 = exe.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    hrtry!((*link).SetPath(exe_utf16.as_ptr())).wrap_err("Failed to set the shortcut's target")?;
+
+    let mut store: *mut IPropertyStore = ptr::null_mut();
+    hrtry!((*link).QueryInterface(&IPropertyStore::uuidof(), ptr::addr_of_mut!(store).cast()))
+        .wrap_err("Failed to get the shortcut's IPropertyStore")?;
+    // The store copies the value, so it can point at our own buffer
+    let mut app_id = to_wide(APP_ID);
+    let mut value: PROPVARIANT = std::mem::zeroed();
+    value.vt = VT_LPWSTR as u16;
+    *value.data.pwszVal_mut() = app_id.as_mut_ptr();
+    let stored = hrtry!((*store).SetValue(&PKEY_AppUserModel_ID, &value))
+        .and_then(|()| hrtry!((*store).Commit()))
+        .wrap_err("Failed to set the shortcut's AppUserModelID");
+    (*store).Release();
+    stored?;
+
+    let mut file: *mut IPersistFile = ptr::null_mut();
+    hrtry!((*link).QueryInterface(&IPersistFile::uuidof(), ptr::addr_of_mut!(file).cast()))
+        .wrap_err("Failed to get the shortcut's IPersistFile")?;
+    let shortcut_utf16 = shortcut.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let saved = hrtry!((*file).Save(shortcut_utf16.as_ptr(), 1))
+        .wrap_err(format!("Failed to save the shortcut to {shortcut:?}"));
+    (*file).Release();
+    saved
+}
+
+/// Read the AppUserModelID the shortcut saved at `shortcut` carries, if it carries one.
+///
+/// # Safety
+///
+/// `link` must be a valid `IShellLinkW`.
+#[cfg(windows)]
+unsafe fn shortcut_app_id(
+    link: *mut winapi::um::shobjidl_core::IShellLinkW,
+    shortcut: &Path,
+) -> Result<Option<String>> {
+    use std::{os::windows::ffi::OsStrExt, ptr};
+    use winapi::{
+        shared::wtypes::VT_LPWSTR,
+        um::{
+            combaseapi::PropVariantClear, coml2api::STGM_READ, objidl::IPersistFile, propidl::PROPVARIANT,
+            propkey::PKEY_AppUserModel_ID, propsys::IPropertyStore,
+        },
+        Interface,
+    };
+
+    let mut file: *mut IPersistFile = ptr::null_mut();
+    hrtry!((*link).QueryInterface(&IPersistFile::uuidof(), ptr::addr_of_mut!(file).cast()))
+        .wrap_err("Failed to get the shortcut's IPersistFile")?;
+    let shortcut_utf16 = shortcut.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let loaded = hrtry!((*file).Load(shortcut_utf16.as_ptr(), STGM_READ))
+        .wrap_err(format!("Failed to load the shortcut at {shortcut:?}"));
+    (*file).Release();
+    loaded?;
+
+    let mut store: *mut IPropertyStore = ptr::null_mut();
+    hrtry!((*link).QueryInterface(&IPropertyStore::uuidof(), ptr::addr_of_mut!(store).cast()))
+        .wrap_err("Failed to get the shortcut's IPropertyStore")?;
+    let mut value: PROPVARIANT = std::mem::zeroed();
+    let read = hrtry!((*store).GetValue(&PKEY_AppUserModel_ID, &mut value))
+        .wrap_err("Failed to get the shortcut's AppUserModelID");
+    (*store).Release();
+    read?;
+
+    let app_id = match *value.data.pwszVal() {
+        app_id if value.vt == VT_LPWSTR as u16 && !app_id.is_null() => {
+            let len = (0..).take_while(|&i| *app_id.add(i) != 0).count();
+            Some(String::from_utf16_lossy(std::slice::from_raw_parts(app_id, len)))
+        }
+        _ => None,
+    };
+    PropVariantClear(&mut value);
+    Ok(app_id)
+}
+
+/// Remove the Start Menu shortcut [`register_app_id`] created, if there is one.
+///
+/// A shortcut by the same name that doesn't carry [`APP_ID`] is the user's own, so it's left alone.
+#[cfg(windows)]
+pub fn unregister_app_id() -> Result<()> {
+    let shortcut = shortcut_path()?;
+    if !shortcut.exists() {
+        return Ok(());
+    }
+
+    let _com = ComGuard::new()?;
+    let link = create_shell_link()?;
+    let app_id = unsafe { shortcut_app_id(link, &shortcut) };
+    unsafe { (*link).Release() };
+    if app_id?.as_deref() != Some(APP_ID) {
+        tracing::debug!(shortcut = %shortcut.display(), "leaving alone a shortcut that isn't ours");
+        return Ok(());
+    }
+
+    match std::fs::remove_file(&shortcut) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error).wrap_err("Failed to remove our shortcut"),
+        _ => Ok(()),
+    }
+}
+
 pub struct Notifier {
     pub title: String,
+    /// The AppUserModelID to show notifications under
+    pub app_id: String,
     pub icon: PathBuf,
 }
 
+/// How many characters of the message a notification shows
+const MAX_MESSAGE_CHARS: usize = 200;
+
+/// How many characters of each field a notification shows
+const MAX_FIELD_CHARS: usize = 80;
+
+/// How many fields a notification shows, as the rest wouldn't fit anyway
+const MAX_FIELDS: usize = 3;
+
+/// Things that look like credentials, which notifications show as `[redacted]`, along with what to keep of them
+static SECRETS: once_cell::sync::Lazy<Vec<(regex::Regex, &str)>> = once_cell::sync::Lazy::new(|| {
+    [
+        // Query parameters and `key=value` pairs
+        (
+            r#"(?i)\b((?:access_token|refresh_token|token|api_?key|key|sig|signature|auth|password|secret|session)=)[^&\s'"]+"#,
+            "${1}[redacted]",
+        ),
+        // Headers, whether as `name: value` or as `"name": "value"` in debug output
+        (
+            r#"(?i)\b((?:set-)?cookie|authorization)("?\s*[:=]\s*)"[^"]*""#,
+            r#"${1}${2}"[redacted]""#,
+        ),
+        (r#"(?i)\b((?:set-)?cookie|authorization)(\s*[:=]\s*)[^",}|]+"#, "${1}${2}[redacted]"),
+        (r"(?i)\b(bearer\s+)[\w.~+/-]+=*", "${1}[redacted]"),
+    ]
+    .iter()
+    .map(|&(pattern, replacement)| (regex::Regex::new(pattern).unwrap(), replacement))
+    .collect()
+});
+
+/// Make `text` fit on a single line of at most `max_chars` characters, without anything that looks like a secret.
+fn sanitize(text: &str, max_chars: usize) -> String {
+    let mut text = text.replace(['\r', '\n'], " ");
+    for (secret, replacement) in SECRETS.iter() {
+        text = secret.replace_all(&text, *replacement).into_owned();
+    }
+
+    if text.chars().count() > max_chars {
+        let mut truncated = text.chars().take(max_chars - 1).collect::<String>();
+        truncated.push('…');
+        truncated
+    } else {
+        text
+    }
+}
+
 #[derive(Default)]
 struct NotifierVisit {
     message: Option<String>,
-    fields: String,
+    fields: Vec<String>,
+    dropped: usize,
+}
+
+impl NotifierVisit {
+    /// Get the fields to show below the message, mentioning how many didn't make it.
+    fn fields(&self) -> String {
+        let mut fields = self.fields.join(" | ");
+        if self.dropped > 0 {
+            fields.push_str(&format!(" (+{} more)", self.dropped));
+        }
+        fields
+    }
 }
 
 impl tracing::field::Visit for NotifierVisit {
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        use std::fmt::Write;
-
         if field.name() == "message" {
-            self.message = Some(format!("{value:?}"));
+            self.message = Some(sanitize(&format!("{value:?}"), MAX_MESSAGE_CHARS));
             return;
         }
 
-        if !self.fields.is_empty() {
-            let _ = write!(self.fields, " | ");
+        if self.fields.len() >= MAX_FIELDS {
+            self.dropped += 1;
+            return;
         }
-        let _ = write!(self.fields, "{}: {:?}", field.name(), value);
+        self.fields
+            .push(sanitize(&format!("{}: {:?}", field.name(), value), MAX_FIELD_CHARS));
     }
 }
 
@@ -122,7 +1115,7 @@ impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Notifier {
 
         let meta = event.metadata();
 
-        let _ = Toast::new(Toast::POWERSHELL_APP_ID)
+        let _ = Toast::new(&self.app_id)
             .title(&format!(
                 "{} ({}:{})",
                 self.title,
@@ -130,7 +1123,7 @@ impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Notifier {
                 meta.line().unwrap_or(0xCAFE_BABE),
             ))
             .text1(visitor.message.as_deref().unwrap_or("no message"))
-            .text2(&visitor.fields)
+            .text2(&visitor.fields())
             .duration(Duration::Short)
             .icon(
                 &self.icon,
@@ -148,3 +1141,164 @@ impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Notifier {
             });
     }
 }
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+    use winapi::um::{
+        winnt::REG_SZ,
+        winreg::{RegDeleteTreeW, RegSetKeyValueW, HKEY_CURRENT_USER},
+    };
+
+    /// A key of our own under the current user's, deleted again once the test is done
+    struct TestKey(String);
+
+    impl TestKey {
+        fn new(name: &str) -> Self {
+            Self(format!("Software\\redditbg-tests\\{name}-{}", std::process::id()))
+        }
+
+        fn set(&self, name: &str, data: &str) {
+            let (key, name, data) = (to_wide(&self.0), to_wide(name), to_wide(data));
+            let status = unsafe {
+                RegSetKeyValueW(
+                    HKEY_CURRENT_USER,
+                    key.as_ptr(),
+                    name.as_ptr(),
+                    REG_SZ,
+                    data.as_ptr().cast(),
+                    (data.len() * std::mem::size_of::<u16>()) as u32,
+                )
+            };
+            assert_eq!(status, 0);
+        }
+
+        fn read(&self, name: &str) -> Option<PathBuf> {
+            read_path_value(&[HKEY_CURRENT_USER], &self.0, name).unwrap()
+        }
+    }
+
+    impl Drop for TestKey {
+        fn drop(&mut self) {
+            let key = to_wide(&self.0);
+            unsafe { RegDeleteTreeW(HKEY_CURRENT_USER, key.as_ptr()) };
+        }
+    }
+
+    #[test]
+    fn reads_a_path_value() {
+        let key = TestKey::new("reads_a_path_value");
+        key.set("Wallpaper", "C:\\Windows\\Web\\Wallpaper\\corporate.jpg");
+        assert_eq!(
+            key.read("Wallpaper"),
+            Some(PathBuf::from("C:\\Windows\\Web\\Wallpaper\\corporate.jpg"))
+        );
+    }
+
+    #[test]
+    fn missing_and_empty_values_are_none() {
+        let key = TestKey::new("missing_and_empty_values_are_none");
+        assert_eq!(key.read("Wallpaper"), None);
+        key.set("Wallpaper", "");
+        assert_eq!(key.read("Wallpaper"), None);
+    }
+
+    /// Run the notifier's visitor over the event `log` logs.
+    fn visit(log: impl FnOnce()) -> NotifierVisit {
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::prelude::*;
+
+        struct Capture(Arc<Mutex<NotifierVisit>>);
+
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Capture {
+            fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+                event.record(&mut *self.0.lock().unwrap());
+            }
+        }
+
+        let visited = Arc::new(Mutex::new(NotifierVisit::default()));
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(Capture(visited.clone())), log);
+        let visited = std::mem::take(&mut *visited.lock().unwrap());
+        visited
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert_eq!(
+            sanitize("GET https://oauth.reddit.com/api?access_token=abc123&limit=5", 200),
+            "GET https://oauth.reddit.com/api?access_token=[redacted]&limit=5"
+        );
+        assert_eq!(
+            sanitize(
+                r#"headers: {"authorization": "Bearer abc.def", "cookie": "session=xyz"}"#,
+                200
+            ),
+            r#"headers: {"authorization": "[redacted]", "cookie": "[redacted]"}"#
+        );
+        assert_eq!(
+            sanitize("Authorization: Bearer abc.def", 200),
+            "Authorization: [redacted]"
+        );
+        assert_eq!(sanitize("sent Bearer abc.def-ghi", 200), "sent Bearer [redacted]");
+        assert_eq!(sanitize("keyboard=qwerty", 200), "keyboard=qwerty");
+    }
+
+    #[test]
+    fn notification_text_fits_on_one_bounded_line() {
+        assert_eq!(sanitize("first\r\nsecond", 200), "first  second");
+        assert_eq!(sanitize("abcdef", 6), "abcdef");
+        assert_eq!(sanitize("abcdefg", 6), "abcde…");
+        // Counting characters, not bytes
+        assert_eq!(sanitize("ééééééé", 6), "ééééé…");
+    }
+
+    #[test]
+    fn only_the_first_few_fields_are_shown() {
+        let visited = visit(|| {
+            tracing::warn!(
+                first = 1,
+                second = "two",
+                url = "https://example.com/?token=hunter2",
+                fourth = 4,
+                fifth = 5,
+                "could not fetch {}",
+                "https://example.com/?sig=abc"
+            )
+        });
+
+        assert_eq!(
+            visited.message.as_deref(),
+            Some("could not fetch https://example.com/?sig=[redacted]")
+        );
+        assert_eq!(
+            visited.fields(),
+            r#"first: 1 | second: "two" | url: "https://example.com/?token=[redacted]" (+2 more)"#
+        );
+    }
+
+    #[test]
+    fn long_fields_are_cut_short() {
+        let long = "x".repeat(2 * MAX_FIELD_CHARS);
+        let visited = visit(|| tracing::warn!(long = %long, "message"));
+        assert_eq!(visited.fields.len(), 1);
+        assert_eq!(visited.fields[0].chars().count(), MAX_FIELD_CHARS);
+        assert!(visited.fields[0].starts_with("long: xxx"));
+        assert!(visited.fields[0].ends_with('…'));
+    }
+
+    #[test]
+    fn the_layout_hash_ignores_the_order_of_monitors() {
+        let left = (0, 0, 1920, 1080);
+        let right = (1920, 0, 2560, 1440);
+        assert_eq!(layout_hash(&[left, right]), layout_hash(&[right, left]));
+
+        // Moving, resizing or unplugging one is a different layout
+        assert_ne!(
+            layout_hash(&[left, right]),
+            layout_hash(&[left, (1920, -180, 2560, 1440)])
+        );
+        assert_ne!(layout_hash(&[left, right]), layout_hash(&[left, (1920, 0, 1920, 1080)]));
+        assert_ne!(layout_hash(&[left, right]), layout_hash(&[left]));
+        assert_ne!(layout_hash(&[left, left]), layout_hash(&[left]));
+    }
+}
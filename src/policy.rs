@@ -0,0 +1,558 @@
+//! The rules deciding which images make for good backgrounds, in one place.
+
+use std::sync::Mutex;
+
+use eyre::Result;
+use image::DynamicImage;
+
+use crate::{
+    config::Config,
+    platform,
+    processing::{self, Orientation},
+    sources::RatioRule,
+};
+
+// The accepted difference between the screen's aspect ratio and a potential image's aspect ratio, unless configured
+const ASPECT_RATIO_EPSILON: f64 = 0.01;
+
+// How far past that an image's aspect ratio may be for it to be cropped to fit instead of turned down, unless
+// configured
+const CROP_EPSILON: f64 = 0.25;
+
+/// Where the monitors' sizes come from, which tests stand in for
+pub trait Geometry {
+    /// A hash of how the monitors are laid out, which changes whenever they're rearranged
+    fn layout(&self) -> Result<u64>;
+
+    /// The monitors' sizes, primary first
+    fn sizes(&self) -> Result<Vec<(u32, u32)>>;
+}
+
+/// The monitors actually attached to this machine
+pub struct Attached;
+
+impl Geometry for Attached {
+    fn layout(&self) -> Result<u64> {
+        platform::monitor_layout()
+    }
+
+    fn sizes(&self) -> Result<Vec<(u32, u32)>> {
+        platform::monitor_sizes()
+    }
+}
+
+/// The monitors' sizes as last probed, along with the layout they were probed for
+struct Probed {
+    layout: u64,
+    sizes: Vec<(u32, u32)>,
+}
+
+/// Remembers the monitors' sizes across runs, as probing them is much slower than checking whether they've moved
+pub struct MonitorCache(Mutex<Option<Probed>>);
+
+impl MonitorCache {
+    pub const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    /// Get the monitors' sizes, primary first, only probing them again once they've been rearranged.
+    pub fn sizes(&self, geometry: &dyn Geometry) -> Result<Vec<(u32, u32)>> {
+        let layout = geometry.layout()?;
+        let mut cached = self.0.lock().unwrap();
+        match *cached {
+            Some(ref probed) if probed.layout == layout => Ok(probed.sizes.clone()),
+            _ => {
+                let sizes = geometry.sizes()?;
+                *cached = Some(Probed {
+                    layout,
+                    sizes: sizes.clone(),
+                });
+                Ok(sizes)
+            }
+        }
+    }
+}
+
+static MONITORS: MonitorCache = MonitorCache::new();
+
+/// Get the attached monitors' sizes, primary first, only probing them again once they've been rearranged.
+pub fn monitors() -> Result<Vec<(u32, u32)>> {
+    // Tests shouldn't depend on the monitors of whoever runs them
+    if cfg!(test) {
+        return Ok(vec![(1920, 1080)]);
+    }
+
+    MONITORS.sizes(&Attached)
+}
+
+/// Why we turned an image down
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reject {
+    #[error("Aspect ratio not within epsilon ({iw}:{ih} instead of {sw}:{sh})")]
+    AspectRatio { iw: u32, ih: u32, sw: u32, sh: u32 },
+
+    #[error("Too small to scale up ({iw}x{ih} for {sw}x{sh})")]
+    TooSmall { iw: u32, ih: u32, sw: u32, sh: u32 },
+
+    #[error("Below the minimum resolution ({iw}x{ih} instead of at least {min_w}x{min_h})")]
+    LowResolution { iw: u32, ih: u32, min_w: u32, min_h: u32 },
+
+    #[error("Too many pixels ({iw}x{ih}, more than {max_pixels})")]
+    TooManyPixels { iw: u32, ih: u32, max_pixels: u64 },
+
+    #[error("Too dark or too bright ({percent}% brightness)")]
+    Brightness { iw: u32, ih: u32, percent: u8 },
+}
+
+impl Reject {
+    /// The dimensions of the image we turned down.
+    pub fn dimensions(self) -> (u32, u32) {
+        match self {
+            Self::AspectRatio { iw, ih, .. }
+            | Self::TooSmall { iw, ih, .. }
+            | Self::LowResolution { iw, ih, .. }
+            | Self::TooManyPixels { iw, ih, .. }
+            | Self::Brightness { iw, ih, .. } => (iw, ih),
+        }
+    }
+}
+
+/// How an image we accepted is made to fit its monitor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fit {
+    /// It's close enough to be scaled as it is
+    AsIs,
+    /// It's cropped to the monitor's aspect ratio
+    Crop,
+    /// It's scaled to fit the monitor's height, with a blurred copy of itself filling the bars on either side
+    BlurFill,
+}
+
+/// What we think of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// It'd make a good background for the monitor of the given size, once made to fit it
+    Accept {
+        target: (u32, u32),
+        fit: Fit,
+    },
+    Reject(Reject),
+}
+
+impl Verdict {
+    /// Turn the verdict into the size of the monitor the image is for and how it's made to fit, or the reason we turned
+    /// it down.
+    pub fn into_result(self) -> Result<((u32, u32), Fit), Reject> {
+        match self {
+            Self::Accept { target, fit } => Ok((target, fit)),
+            Self::Reject(reject) => Err(reject),
+        }
+    }
+}
+
+/// The rules an image has to pass to become a background on one of our monitors
+#[derive(Clone, Debug)]
+pub struct ImagePolicy {
+    /// The monitors' sizes, primary first
+    monitors: Vec<(u32, u32)>,
+    /// The accepted difference between a monitor's aspect ratio and an image's
+    epsilon: f64,
+    /// The difference past which an image is turned down rather than cropped
+    crop_epsilon: f64,
+    /// Whether portrait images too far off to be cropped are blur-filled onto landscape monitors instead
+    blur_fill: bool,
+    /// The smallest fraction of its monitor's size an image may be, if we turn smaller ones down at all
+    min_scale: Option<f64>,
+    /// The smallest an image may be regardless of our monitors, in either orientation
+    min_resolution: Option<(u32, u32)>,
+    /// The most pixels an image may have
+    max_pixels: Option<u64>,
+    /// The range an image's average brightness has to be in, from 0 for black to 1 for white
+    brightness: Option<(f64, f64)>,
+}
+
+impl ImagePolicy {
+    pub fn new(monitors: Vec<(u32, u32)>) -> Self {
+        Self {
+            monitors,
+            epsilon: ASPECT_RATIO_EPSILON,
+            crop_epsilon: CROP_EPSILON,
+            blur_fill: false,
+            min_scale: None,
+            min_resolution: None,
+            max_pixels: None,
+            brightness: None,
+        }
+    }
+
+    /// Apply the user's settings on how strict to be.
+    pub fn configure(self, config: &Config) -> Self {
+        Self {
+            epsilon: config.aspect_ratio_epsilon,
+            crop_epsilon: config.aspect_ratio_crop_epsilon.max(config.aspect_ratio_epsilon),
+            blur_fill: config.blur_fill,
+            min_scale: config.allow_upscale_below,
+            min_resolution: config.min_resolution,
+            max_pixels: config
+                .max_megapixels
+                .map(|megapixels| (megapixels * 1_000_000.0) as u64),
+            brightness: config.brightness_range,
+            ..self
+        }
+    }
+
+    /// Build a policy for the monitors attached right now.
+    pub fn current() -> Result<Self> {
+        Ok(Self::new(monitors()?))
+    }
+
+    pub fn monitors(&self) -> &[(u32, u32)] {
+        &self.monitors
+    }
+
+    /// The same rules, for just the given monitor.
+    pub fn only(&self, monitor: (u32, u32)) -> Self {
+        Self {
+            monitors: vec![monitor],
+            ..self.clone()
+        }
+    }
+
+    /// Whether an image we've already made fit a monitor, and stored as `dimensions`, can go on ours as it is.
+    ///
+    /// Blur-filling takes making a new image, so only images that are close enough to be scaled or cropped count.
+    pub fn fits_stored(&self, dimensions: (u32, u32)) -> bool {
+        matches!(
+            self.evaluate(dimensions, None, RatioRule::Strict),
+            Verdict::Accept {
+                fit: Fit::AsIs | Fit::Crop,
+                ..
+            }
+        )
+    }
+
+    /// Whether a resized version of an image, at `dimensions`, would do in place of the original: it has to pass our
+    /// rules and be big enough for the monitor it's for without scaling it up.
+    pub fn covers(&self, dimensions: (u32, u32), rule: RatioRule) -> bool {
+        let (iw, ih) = dimensions;
+        match self.evaluate(dimensions, None, rule) {
+            Verdict::Accept {
+                target: (_, sh),
+                fit: Fit::BlurFill,
+            } => ih >= sh,
+            Verdict::Accept { target: (sw, sh), .. } => iw >= sw && ih >= sh,
+            Verdict::Reject(_) => false,
+        }
+    }
+
+    /// Judge an image by its dimensions, and by its pixels if we have them, accepting it for the first monitor it'd fit
+    /// without too much distortion.
+    ///
+    /// Images that just miss every monitor are accepted for the closest one, to be cropped to fit it, and if enabled
+    /// portrait images that miss by more are accepted for the first landscape monitor, to be blur-filled. Sources that
+    /// don't care about the aspect ratio get the monitor whose aspect ratio is closest instead, as they are.
+    pub fn evaluate(&self, (iw, ih): (u32, u32), pixels: Option<&DynamicImage>, rule: RatioRule) -> Verdict {
+        if let Some((min_w, min_h)) = self.min_resolution {
+            let (long, short) = (iw.max(ih), iw.min(ih));
+            if long < min_w.max(min_h) || short < min_w.min(min_h) {
+                return Verdict::Reject(Reject::LowResolution { iw, ih, min_w, min_h });
+            }
+        }
+        if let Some(max_pixels) = self.max_pixels {
+            if u64::from(iw) * u64::from(ih) > max_pixels {
+                return Verdict::Reject(Reject::TooManyPixels { iw, ih, max_pixels });
+            }
+        }
+
+        let ratio = f64::from(iw) / f64::from(ih);
+        let distance = |&(sw, sh): &(u32, u32)| (ratio - f64::from(sw) / f64::from(sh)).abs();
+        let closest = || self.monitors.iter().min_by(|a, b| distance(a).total_cmp(&distance(b)));
+
+        let landscape = || {
+            self.monitors
+                .iter()
+                .find(|&&(sw, sh)| Orientation::of(sw, sh) == Orientation::Landscape)
+        };
+
+        let (target, fit) = if rule == RatioRule::Any {
+            (closest(), Fit::AsIs)
+        } else if let Some(monitor) = self.monitors.iter().find(|monitor| distance(monitor) <= self.epsilon) {
+            (Some(monitor), Fit::AsIs)
+        } else if let Some(monitor) = closest().filter(|monitor| distance(monitor) <= self.crop_epsilon) {
+            (Some(monitor), Fit::Crop)
+        } else if self.blur_fill && Orientation::of(iw, ih) == Orientation::Portrait {
+            (landscape(), Fit::BlurFill)
+        } else {
+            (None, Fit::Crop)
+        };
+        let Some(&(sw, sh)) = target else {
+            let (sw, sh) = self.monitors.first().copied().unwrap_or_default();
+            return Verdict::Reject(Reject::AspectRatio { iw, ih, sw, sh });
+        };
+
+        // How much of the monitor the image covers before scaling, going by its shortest side, or by its height when
+        // it's only going to fill that
+        let scale = match fit {
+            Fit::BlurFill => f64::from(ih) / f64::from(sh),
+            _ => (f64::from(iw) / f64::from(sw)).min(f64::from(ih) / f64::from(sh)),
+        };
+        if matches!(self.min_scale, Some(min_scale) if scale < min_scale) {
+            return Verdict::Reject(Reject::TooSmall { iw, ih, sw, sh });
+        }
+
+        // Looking at the pixels is the most expensive check, so it goes last
+        if let (Some((min, max)), Some(pixels)) = (self.brightness, pixels) {
+            let brightness = processing::brightness(pixels);
+            if !(min..=max).contains(&brightness) {
+                let percent = (brightness * 100.0).round() as u8;
+                return Verdict::Reject(Reject::Brightness { iw, ih, percent });
+            }
+        }
+        Verdict::Accept { target: (sw, sh), fit }
+    }
+
+    /// Whether images of the given orientation could fit any of our monitors.
+    pub fn accepts_orientation(&self, orientation: Orientation) -> bool {
+        self.monitors
+            .iter()
+            .any(|&(sw, sh)| Orientation::of(sw, sh) == orientation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_images_fit_only_monitors_of_their_shape() {
+        let policy = ImagePolicy::new(vec![(1920, 1080), (1080, 1920)]);
+        let (landscape, portrait) = (policy.only((1920, 1080)), policy.only((1080, 1920)));
+
+        assert!(landscape.fits_stored((1920, 1080)));
+        assert!(landscape.fits_stored((3840, 2160)));
+        assert!(!landscape.fits_stored((1080, 1920)));
+        assert!(portrait.fits_stored((1080, 1920)));
+        assert!(!portrait.fits_stored((1920, 1080)));
+    }
+
+    fn strict(policy: &ImagePolicy, dimensions: (u32, u32)) -> Verdict {
+        policy.evaluate(dimensions, None, RatioRule::Strict)
+    }
+
+    #[test]
+    fn the_minimum_resolution_applies_in_either_orientation() {
+        let config = Config {
+            min_resolution: Some((1920, 1080)),
+            aspect_ratio_epsilon: 1.0,
+            ..Config::default()
+        };
+        let policy = ImagePolicy::new(vec![(1280, 720), (720, 1280)]).configure(&config);
+
+        assert!(matches!(strict(&policy, (1920, 1080)), Verdict::Accept { .. }));
+        assert!(matches!(strict(&policy, (1080, 1920)), Verdict::Accept { .. }));
+        assert!(matches!(
+            strict(&policy, (1919, 1080)),
+            Verdict::Reject(Reject::LowResolution { .. })
+        ));
+        assert!(matches!(
+            strict(&policy, (1080, 1919)),
+            Verdict::Reject(Reject::LowResolution { .. })
+        ));
+    }
+
+    #[test]
+    fn images_with_too_many_pixels_are_turned_down() {
+        let config = Config {
+            max_megapixels: Some(8.3),
+            ..Config::default()
+        };
+        let policy = ImagePolicy::new(vec![(1920, 1080)]).configure(&config);
+
+        assert!(matches!(strict(&policy, (3840, 2160)), Verdict::Accept { .. }));
+        assert!(matches!(
+            strict(&policy, (3841, 2161)),
+            Verdict::Reject(Reject::TooManyPixels { .. })
+        ));
+    }
+
+    #[test]
+    fn brightness_is_only_judged_with_pixels() {
+        let config = Config {
+            brightness_range: Some((0.1, 0.9)),
+            ..Config::default()
+        };
+        let policy = ImagePolicy::new(vec![(1920, 1080)]).configure(&config);
+        let gray = |value| DynamicImage::ImageLuma8(image::GrayImage::from_pixel(192, 108, image::Luma([value])));
+
+        let dark = gray(10);
+        let verdict = policy.evaluate((1920, 1080), Some(&dark), RatioRule::Strict);
+        assert!(matches!(
+            verdict,
+            Verdict::Reject(Reject::Brightness { percent: 4, .. })
+        ));
+        assert!(matches!(
+            policy.evaluate((1920, 1080), Some(&gray(250)), RatioRule::Strict),
+            Verdict::Reject(Reject::Brightness { .. })
+        ));
+        assert!(matches!(
+            policy.evaluate((1920, 1080), Some(&gray(128)), RatioRule::Strict),
+            Verdict::Accept { .. }
+        ));
+        assert!(matches!(strict(&policy, (1920, 1080)), Verdict::Accept { .. }));
+    }
+
+    #[test]
+    fn variants_have_to_cover_the_monitor_they_are_for() {
+        let policy = ImagePolicy::new(vec![(1920, 1080), (1080, 1920)]);
+
+        assert!(policy.covers((1920, 1080), RatioRule::Strict));
+        assert!(policy.covers((1080, 1920), RatioRule::Strict));
+        assert!(!policy.covers((1280, 720), RatioRule::Strict));
+        // Big enough, but the wrong shape for either monitor
+        assert!(!policy.covers((4000, 1000), RatioRule::Strict));
+    }
+
+    #[test]
+    fn stored_images_are_never_blur_filled_onto_a_monitor() {
+        let config = Config {
+            blur_fill: true,
+            ..Config::default()
+        };
+        let policy = ImagePolicy::new(vec![(1920, 1080)]).configure(&config);
+
+        assert!(matches!(
+            policy.evaluate((1080, 1920), None, RatioRule::Strict),
+            Verdict::Accept { fit: Fit::BlurFill, .. }
+        ));
+        assert!(!policy.fits_stored((1080, 1920)));
+    }
+
+    #[test]
+    fn near_misses_are_cropped_and_far_misses_turned_down() {
+        let policy = ImagePolicy::new(vec![(1920, 1080)]).configure(&Config::default());
+        let fit = |dimensions| match strict(&policy, dimensions) {
+            Verdict::Accept { target, fit } => Some((target, fit)),
+            Verdict::Reject(_) => None,
+        };
+
+        // 1.778 against 1.786, within the epsilon
+        assert_eq!(fit((1920, 1080)), Some(((1920, 1080), Fit::AsIs)));
+        assert_eq!(fit((1929, 1080)), Some(((1920, 1080), Fit::AsIs)));
+        // 1.852 and 2.0, past the epsilon but within the crop band
+        assert_eq!(fit((2000, 1080)), Some(((1920, 1080), Fit::Crop)));
+        assert_eq!(fit((2160, 1080)), Some(((1920, 1080), Fit::Crop)));
+        assert_eq!(fit((1920, 1200)), Some(((1920, 1080), Fit::Crop)));
+        // 2.037, past it
+        assert!(matches!(
+            strict(&policy, (2200, 1080)),
+            Verdict::Reject(Reject::AspectRatio { sw: 1920, sh: 1080, .. })
+        ));
+        assert_eq!(fit((1080, 1920)), None);
+    }
+
+    #[test]
+    fn crops_are_for_the_closest_monitor() {
+        let policy = ImagePolicy::new(vec![(1920, 1080), (2560, 1080)]).configure(&Config::default());
+
+        assert!(matches!(
+            strict(&policy, (2400, 1080)),
+            Verdict::Accept {
+                target: (2560, 1080),
+                fit: Fit::Crop
+            }
+        ));
+        assert!(matches!(
+            strict(&policy, (2000, 1080)),
+            Verdict::Accept {
+                target: (1920, 1080),
+                fit: Fit::Crop
+            }
+        ));
+    }
+
+    #[test]
+    fn portrait_images_too_far_off_are_blur_filled_only_if_enabled() {
+        let monitors = vec![(1080, 1920), (1920, 1080)];
+        let blur_fill = |enabled| {
+            let config = Config {
+                blur_fill: enabled,
+                ..Config::default()
+            };
+            ImagePolicy::new(monitors.clone()).configure(&config)
+        };
+
+        // Onto the first landscape monitor, not the primary one
+        assert!(matches!(
+            strict(&blur_fill(true), (1000, 4000)),
+            Verdict::Accept {
+                target: (1920, 1080),
+                fit: Fit::BlurFill
+            }
+        ));
+        assert!(matches!(
+            strict(&blur_fill(false), (1000, 4000)),
+            Verdict::Reject(Reject::AspectRatio { .. })
+        ));
+
+        // Those that fit a portrait monitor, and landscape ones, are never blur-filled
+        assert!(matches!(
+            strict(&blur_fill(true), (1080, 1920)),
+            Verdict::Accept {
+                target: (1080, 1920),
+                fit: Fit::AsIs
+            }
+        ));
+        assert!(matches!(
+            strict(&blur_fill(true), (4000, 1000)),
+            Verdict::Reject(Reject::AspectRatio { .. })
+        ));
+
+        // Without a landscape monitor there's nothing to blur-fill onto
+        let config = Config {
+            blur_fill: true,
+            ..Config::default()
+        };
+        let portrait_only = ImagePolicy::new(vec![(1080, 1920)]).configure(&config);
+        assert!(matches!(
+            strict(&portrait_only, (1000, 4000)),
+            Verdict::Reject(Reject::AspectRatio { .. })
+        ));
+    }
+
+    #[test]
+    fn only_images_close_enough_to_their_monitors_size_are_scaled_up() {
+        let config = Config {
+            allow_upscale_below: Some(0.9),
+            blur_fill: true,
+            ..Config::default()
+        };
+        let policy = ImagePolicy::new(vec![(1920, 1080)]).configure(&config);
+
+        // 0.9 of the monitor either way is just enough
+        assert!(matches!(strict(&policy, (1728, 972)), Verdict::Accept { .. }));
+        assert!(matches!(
+            strict(&policy, (1727, 971)),
+            Verdict::Reject(Reject::TooSmall { sw: 1920, sh: 1080, .. })
+        ));
+        // Going by the shortest side once cropped
+        assert!(matches!(
+            strict(&policy, (1900, 971)),
+            Verdict::Reject(Reject::TooSmall { .. })
+        ));
+        assert!(matches!(strict(&policy, (3840, 2160)), Verdict::Accept { .. }));
+
+        // Blur-filled images only have to fill the height
+        assert!(matches!(
+            strict(&policy, (243, 972)),
+            Verdict::Accept { fit: Fit::BlurFill, .. }
+        ));
+        assert!(matches!(
+            strict(&policy, (243, 971)),
+            Verdict::Reject(Reject::TooSmall { .. })
+        ));
+
+        // Without a limit, any size goes
+        let policy = ImagePolicy::new(vec![(1920, 1080)]);
+        assert!(matches!(strict(&policy, (192, 108)), Verdict::Accept { .. }));
+    }
+}
@@ -0,0 +1,591 @@
+use image::{
+    imageops::{
+        self,
+        FilterType::{Lanczos3, Triangle},
+    },
+    DynamicImage, GenericImageView, GrayImage, ImageFormat, ImageOutputFormat,
+};
+
+// The quality we encode JPEGs at, high enough that resizing is the only loss anyone would notice
+const JPEG_QUALITY: u8 = 92;
+
+// How big the longest side of the copy we compute saliency on is
+const SALIENCY_SIZE: u32 = 128;
+
+// How much smaller than the screen the copy filling the bars around a blur-filled image is blurred at, which is much
+// cheaper than blurring at full size and looks the same once scaled back up
+const BLUR_FILL_DOWNSCALE: u32 = 8;
+
+// How heavily that copy is blurred, at its reduced size
+const BLUR_FILL_SIGMA: f32 = 6.0;
+
+// The most of each edge we're willing to trim as a letterbox bar
+const MAX_BORDER_FRACTION: f64 = 0.15;
+
+// How dark (or bright) and how uniform a row or column has to be to count as part of a bar
+const BLACK_MEAN: f64 = 10.0;
+const WHITE_MEAN: f64 = 245.0;
+const BORDER_VARIANCE: f64 = 9.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Which way round an image or a monitor is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Orientation {
+    Landscape,
+    Portrait,
+    Square,
+}
+
+impl Orientation {
+    pub fn of(width: u32, height: u32) -> Self {
+        match width.cmp(&height) {
+            std::cmp::Ordering::Greater => Self::Landscape,
+            std::cmp::Ordering::Less => Self::Portrait,
+            std::cmp::Ordering::Equal => Self::Square,
+        }
+    }
+
+    /// The name we store in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Landscape => "landscape",
+            Self::Portrait => "portrait",
+            Self::Square => "square",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "landscape" => Some(Self::Landscape),
+            "portrait" => Some(Self::Portrait),
+            "square" => Some(Self::Square),
+            _ => None,
+        }
+    }
+}
+
+/// Composite `img` onto a canvas of the given size, scaled to fit in its center, with a heavily blurred copy of itself
+/// stretched over the whole canvas filling the bars on either side.
+pub fn blur_fill(img: &DynamicImage, (width, height): (u32, u32)) -> DynamicImage {
+    let (small_width, small_height) = (
+        (width / BLUR_FILL_DOWNSCALE).max(1),
+        (height / BLUR_FILL_DOWNSCALE).max(1),
+    );
+    let mut canvas = img
+        .resize_exact(small_width, small_height, Triangle)
+        .blur(BLUR_FILL_SIGMA)
+        .resize_exact(width, height, Triangle)
+        .to_rgb8();
+
+    let foreground = img.resize(width, height, Lanczos3).to_rgb8();
+    let x = (width - foreground.width().min(width)) / 2;
+    let y = (height - foreground.height().min(height)) / 2;
+    imageops::overlay(&mut canvas, &foreground, i64::from(x), i64::from(y));
+    DynamicImage::ImageRgb8(canvas)
+}
+
+/// The size of the biggest crop of an image of the given size with the given aspect ratio (width / height), which
+/// always spans the whole image along one axis.
+fn crop_size(width: u32, height: u32, target_ratio: f64) -> (u32, u32) {
+    let (crop_width, crop_height) = if f64::from(width) / f64::from(height) > target_ratio {
+        ((f64::from(height) * target_ratio).round() as u32, height)
+    } else {
+        (width, (f64::from(width) / target_ratio).round() as u32)
+    };
+    (crop_width.clamp(1, width), crop_height.clamp(1, height))
+}
+
+/// Find the biggest crop of `img` with the given aspect ratio (width / height) around its center.
+pub fn center_crop(img: &DynamicImage, target_ratio: f64) -> Rect {
+    let (width, height) = img.dimensions();
+    let (crop_width, crop_height) = crop_size(width, height, target_ratio);
+    Rect {
+        x: (width - crop_width) / 2,
+        y: (height - crop_height) / 2,
+        width: crop_width,
+        height: crop_height,
+    }
+}
+
+/// Find the biggest crop of `img` with the given aspect ratio (width / height) which retains the most
+/// "interesting" part of the image.
+///
+/// Interest is measured as edge density over a downscaled copy of the image, which is cheap and does a
+/// decent job of finding the subject of a photo.
+pub fn best_crop(img: &DynamicImage, target_ratio: f64) -> Rect {
+    let (width, height) = img.dimensions();
+
+    // Figure out the size of the crop window; it always spans the whole image along one axis, so we only
+    // ever need to slide it along the other one.
+    let (crop_width, crop_height) = crop_size(width, height, target_ratio);
+    let horizontal = crop_width < width;
+
+    // Compute the edge density of each column (or row) of a small grayscale copy.
+    let small = img.resize(SALIENCY_SIZE, SALIENCY_SIZE, Triangle).to_luma8();
+    let (sw, sh) = small.dimensions();
+    let mut profile = vec![0u64; if horizontal { sw } else { sh } as usize];
+    for y in 1..sh {
+        for x in 1..sw {
+            let here = i32::from(small.get_pixel(x, y)[0]);
+            let left = i32::from(small.get_pixel(x - 1, y)[0]);
+            let up = i32::from(small.get_pixel(x, y - 1)[0]);
+            let edge = u64::from((here - left).unsigned_abs() + (here - up).unsigned_abs());
+            profile[if horizontal { x } else { y } as usize] += edge;
+        }
+    }
+
+    // Slide the window (scaled down to match the copy) along the profile, keeping the best offset.
+    let (full, crop) = if horizontal {
+        (width, crop_width)
+    } else {
+        (height, crop_height)
+    };
+    let window = ((u64::from(crop) * profile.len() as u64) / u64::from(full)).max(1) as usize;
+    let mut best = (0, 0);
+    let mut sum: u64 = profile.iter().take(window).sum();
+    // Prefer the centered window when there's nothing interesting anywhere
+    let centered = (profile.len() - window.min(profile.len())) / 2;
+    for start in 0..=profile.len().saturating_sub(window) {
+        if start > 0 {
+            sum = sum - profile[start - 1] + profile[start + window - 1];
+        }
+        if sum > best.1 || (sum == best.1 && start == centered) {
+            best = (start, sum);
+        }
+    }
+
+    // Scale the offset back up and make sure the crop stays within the image.
+    let offset = ((best.0 as u64 * u64::from(full)) / profile.len() as u64) as u32;
+    let offset = offset.min(full - crop);
+
+    if horizontal {
+        Rect {
+            x: offset,
+            y: 0,
+            width: crop_width,
+            height: crop_height,
+        }
+    } else {
+        Rect {
+            x: 0,
+            y: offset,
+            width: crop_width,
+            height: crop_height,
+        }
+    }
+}
+
+fn is_border(pixels: impl Iterator<Item = u8>) -> bool {
+    let (mut count, mut sum, mut sum_sq) = (0u64, 0u64, 0u64);
+    for p in pixels {
+        count += 1;
+        sum += u64::from(p);
+        sum_sq += u64::from(p) * u64::from(p);
+    }
+    if count == 0 {
+        return false;
+    }
+
+    let mean = sum as f64 / count as f64;
+    let variance = sum_sq as f64 / count as f64 - mean * mean;
+    (mean <= BLACK_MEAN || mean >= WHITE_MEAN) && variance <= BORDER_VARIANCE
+}
+
+/// Count how many of `lines` in a row are border, giving up after `max`.
+fn count_border(lines: impl Iterator<Item = u32>, max: u32, is_border_line: impl Fn(u32) -> bool) -> u32 {
+    lines
+        .take(max as usize)
+        .take_while(|&line| is_border_line(line))
+        .count() as u32
+}
+
+/// Get the average brightness of `img`, from 0 for black to 1 for white, going by a small copy of it.
+pub fn brightness(img: &DynamicImage) -> f64 {
+    let small = img.resize(SALIENCY_SIZE, SALIENCY_SIZE, Triangle).to_luma8();
+    let count = u64::from(small.width()) * u64::from(small.height());
+    let sum = small.pixels().map(|pixel| u64::from(pixel[0])).sum::<u64>();
+    sum as f64 / count.max(1) as f64 / 255.0
+}
+
+/// Find the part of `img` left after trimming letterbox bars, i.e. uniform near-black or near-white borders.
+///
+/// Bars only count when they're on both opposite edges, so that a photo with a dark sky doesn't get cropped, and we
+/// never trim more than `MAX_BORDER_FRACTION` of any edge.
+pub fn trim_borders(img: &DynamicImage) -> Rect {
+    let luma: GrayImage = img.to_luma8();
+    let (width, height) = luma.dimensions();
+    let row = |y: u32| is_border((0..width).map(|x| luma.get_pixel(x, y)[0]));
+    let column = |x: u32| is_border((0..height).map(|y| luma.get_pixel(x, y)[0]));
+
+    let max_rows = (f64::from(height) * MAX_BORDER_FRACTION) as u32;
+    let max_columns = (f64::from(width) * MAX_BORDER_FRACTION) as u32;
+
+    let (mut top, mut bottom) = (
+        count_border(0..height, max_rows, row),
+        count_border((0..height).rev(), max_rows, row),
+    );
+    if top == 0 || bottom == 0 {
+        (top, bottom) = (0, 0);
+    }
+
+    let (mut left, mut right) = (
+        count_border(0..width, max_columns, column),
+        count_border((0..width).rev(), max_columns, column),
+    );
+    if left == 0 || right == 0 {
+        (left, right) = (0, 0);
+    }
+
+    Rect {
+        x: left,
+        y: top,
+        width: width - left - right,
+        height: height - top - bottom,
+    }
+}
+
+/// Read the EXIF orientation tag out of an encoded image, if it has one.
+pub fn exif_orientation(body: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(body))
+        .ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Turn an image decoded in sensor orientation the right way up, given its EXIF orientation.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        // Transposed, i.e. mirrored along the top-left to bottom-right diagonal
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        // Transversed, i.e. mirrored along the top-right to bottom-left diagonal
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        // 1 is already upright, and anything else is bogus
+        _ => img,
+    }
+}
+
+/// A color filter run over the background before it's set, for a more uniform look
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Filter {
+    Grayscale,
+    Sepia,
+    /// Maps luminance onto a gradient from the first color (shadows) to the second (highlights)
+    Duotone([u8; 3], [u8; 3]),
+}
+
+/// Parse a `#rrggbb` color.
+fn parse_color(s: &str) -> Option<[u8; 3]> {
+    let hex = s.trim().strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |idx: usize| u8::from_str_radix(&hex[idx..idx + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+impl std::convert::TryFrom<String> for Filter {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let s = s.trim();
+        match s {
+            "grayscale" => return Ok(Self::Grayscale),
+            "sepia" => return Ok(Self::Sepia),
+            _ => {}
+        }
+
+        let args = s
+            .strip_prefix("duotone(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(|| format!("unknown filter {s:?}, expected grayscale, sepia or duotone(#rrggbb,#rrggbb)"))?;
+        match args.split(',').map(parse_color).collect::<Vec<_>>()[..] {
+            [Some(dark), Some(light)] => Ok(Self::Duotone(dark, light)),
+            _ => Err(format!("expected duotone(#rrggbb,#rrggbb), got {s:?}")),
+        }
+    }
+}
+
+/// Run a color filter over an image, keeping its alpha channel as it is.
+pub fn apply_filter(img: &DynamicImage, filter: Filter) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let (r, g, b) = (f64::from(r), f64::from(g), f64::from(b));
+        let clamp = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+
+        pixel.0 = match filter {
+            Filter::Grayscale => [clamp(luma), clamp(luma), clamp(luma), a],
+            Filter::Sepia => [
+                clamp(0.393 * r + 0.769 * g + 0.189 * b),
+                clamp(0.349 * r + 0.686 * g + 0.168 * b),
+                clamp(0.272 * r + 0.534 * g + 0.131 * b),
+                a,
+            ],
+            Filter::Duotone(dark, light) => {
+                let t = luma / 255.0;
+                let mix = |idx: usize| clamp(f64::from(dark[idx]) * (1.0 - t) + f64::from(light[idx]) * t);
+                [mix(0), mix(1), mix(2), a]
+            }
+        };
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Choose the format to store an image downloaded as `original` in.
+///
+/// JPEGs stay JPEGs, as storing them losslessly makes them several times bigger for no gain. Anything else becomes a
+/// PNG, as we either can't write it or would lose quality doing so, e.g. GIFs being limited to 256 colors.
+pub fn storage_format(original: ImageFormat, force_png: bool) -> ImageFormat {
+    match original {
+        ImageFormat::Jpeg if !force_png => ImageFormat::Jpeg,
+        _ => ImageFormat::Png,
+    }
+}
+
+/// Encode `img` as `format`, dropping its alpha channel if the format can't hold one.
+pub fn encode<W: std::io::Write + std::io::Seek>(
+    img: &DynamicImage,
+    writer: &mut W,
+    format: ImageFormat,
+) -> image::ImageResult<()> {
+    match format {
+        ImageFormat::Jpeg => {
+            DynamicImage::ImageRgb8(img.to_rgb8()).write_to(writer, ImageOutputFormat::Jpeg(JPEG_QUALITY))
+        }
+        format => img.write_to(writer, format),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use std::convert::TryFrom;
+
+    /// Something photo-like, which no row or column of looks like a bar.
+    fn textured(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let value = (64 + (x * 7 + y * 13) % 128) as u8;
+            Rgb([value, value, value])
+        })
+    }
+
+    /// Put `photo` in the middle of a `width` by `height` canvas of `bars`.
+    fn framed(photo: &RgbImage, (width, height): (u32, u32), bars: Rgb<u8>) -> DynamicImage {
+        let mut canvas = RgbImage::from_pixel(width, height, bars);
+        image::imageops::replace(
+            &mut canvas,
+            photo,
+            i64::from((width - photo.width()) / 2),
+            i64::from((height - photo.height()) / 2),
+        );
+        DynamicImage::ImageRgb8(canvas)
+    }
+
+    #[test]
+    fn smart_crops_keep_the_interesting_part() {
+        // A flat gray panorama with something going on near its right edge
+        let mut panorama = RgbImage::from_pixel(400, 100, Rgb([128, 128, 128]));
+        image::imageops::replace(&mut panorama, &textured(80, 100), 300, 0);
+        let rect = best_crop(&DynamicImage::ImageRgb8(panorama), 1.0);
+        assert_eq!((rect.y, rect.width, rect.height), (0, 100, 100));
+        assert!(rect.x <= 300 && rect.x + rect.width >= 380, "{:?}", rect);
+
+        // A mountain peak near the top of a tall image
+        let mut tall = RgbImage::from_pixel(100, 400, Rgb([128, 128, 128]));
+        image::imageops::replace(&mut tall, &textured(100, 60), 0, 20);
+        let rect = best_crop(&DynamicImage::ImageRgb8(tall), 1.0);
+        assert_eq!((rect.x, rect.width, rect.height), (0, 100, 100));
+        assert!(rect.y <= 20 && rect.y + rect.height >= 80, "{:?}", rect);
+    }
+
+    #[test]
+    fn smart_crops_of_uniform_images_are_centered() {
+        let gray = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 100, Rgb([128, 128, 128])));
+        assert_eq!(best_crop(&gray, 1.0), center_crop(&gray, 1.0));
+        assert_eq!(
+            best_crop(&gray, 1.0),
+            Rect {
+                x: 150,
+                y: 0,
+                width: 100,
+                height: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn letterbox_and_pillarbox_bars_are_trimmed() {
+        let letterboxed = framed(&textured(160, 90), (160, 120), Rgb([0, 0, 0]));
+        assert_eq!(
+            trim_borders(&letterboxed),
+            Rect {
+                x: 0,
+                y: 15,
+                width: 160,
+                height: 90,
+            }
+        );
+
+        let pillarboxed = framed(&textured(120, 90), (160, 90), Rgb([0xff, 0xff, 0xff]));
+        assert_eq!(
+            trim_borders(&pillarboxed),
+            Rect {
+                x: 20,
+                y: 0,
+                width: 120,
+                height: 90,
+            }
+        );
+
+        // Bars thicker than we'd ever trim are only trimmed so far
+        let boxed = framed(&textured(100, 50), (160, 90), Rgb([0, 0, 0]));
+        assert_eq!(
+            trim_borders(&boxed),
+            Rect {
+                x: 24,
+                y: 13,
+                width: 112,
+                height: 64,
+            }
+        );
+    }
+
+    #[test]
+    fn dark_photos_are_not_trimmed() {
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width: 160,
+            height: 90,
+        };
+
+        // A night sky is dark all over, but never as flat as a bar
+        let night = RgbImage::from_fn(160, 90, |x, y| {
+            let value = ((x * 7 + y * 13) % 20) as u8;
+            Rgb([value, value, value])
+        });
+        assert_eq!(trim_borders(&DynamicImage::ImageRgb8(night)), whole);
+
+        // A flat black sky is only on one edge, unlike letterbox bars
+        let mut dark_sky = textured(160, 90);
+        for (_, y, pixel) in dark_sky.enumerate_pixels_mut() {
+            if y < 20 {
+                *pixel = Rgb([0, 0, 0]);
+            }
+        }
+        assert_eq!(trim_borders(&DynamicImage::ImageRgb8(dark_sky)), whole);
+    }
+
+    #[test]
+    fn blur_filled_images_fill_the_canvas_around_the_original() {
+        let red = Rgb([0xff, 0, 0]);
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(100, 200, red));
+        let filled = blur_fill(&img, (400, 200));
+
+        assert_eq!(filled.dimensions(), (400, 200));
+        // The original goes in the center at its full height
+        let filled = filled.to_rgb8();
+        assert_eq!(*filled.get_pixel(200, 0), red);
+        assert_eq!(*filled.get_pixel(200, 199), red);
+    }
+
+    #[test]
+    fn blur_filling_onto_tiny_canvases_still_works() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(100, 200));
+        assert_eq!(blur_fill(&img, (1, 1)).dimensions(), (1, 1));
+        assert_eq!(blur_fill(&img, (7, 3)).dimensions(), (7, 3));
+    }
+
+    #[test]
+    fn filters_are_parsed_as_written_in_the_config() {
+        let parse = |s: &str| Filter::try_from(s.to_owned());
+        assert_eq!(parse("grayscale"), Ok(Filter::Grayscale));
+        assert_eq!(parse(" sepia "), Ok(Filter::Sepia));
+        assert_eq!(
+            parse("duotone(#1a2b3c, #FFFFFF)"),
+            Ok(Filter::Duotone([0x1a, 0x2b, 0x3c], [0xff, 0xff, 0xff]))
+        );
+        for invalid in [
+            "Grayscale",
+            "duotone(#000000)",
+            "duotone(#000000,#ffffff,#ff0000)",
+            "duotone(#00000,#ffffff)",
+            "duotone(000000,ffffff)",
+            "duotone(#000000,#ffffff",
+            "duotone(#ééé,#ffffff)",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn filters_keep_the_alpha_channel() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 2, image::Rgba([200, 100, 50, 128])));
+        let pixel = |filter| apply_filter(&img, filter).to_rgba8().get_pixel(0, 0).0;
+
+        // 0.299 * 200 + 0.587 * 100 + 0.114 * 50
+        assert_eq!(pixel(Filter::Grayscale), [124, 124, 124, 128]);
+        assert_eq!(pixel(Filter::Sepia), [165, 147, 114, 128]);
+        // Black and white are the ends of the gradient
+        let black = DynamicImage::ImageRgb8(RgbImage::new(1, 1));
+        let duotone = Filter::Duotone([10, 20, 30], [240, 230, 220]);
+        assert_eq!(
+            apply_filter(&black, duotone).to_rgba8().get_pixel(0, 0).0,
+            [10, 20, 30, 255]
+        );
+        let white = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([255, 255, 255])));
+        assert_eq!(
+            apply_filter(&white, duotone).to_rgba8().get_pixel(0, 0).0,
+            [240, 230, 220, 255]
+        );
+    }
+
+    #[test]
+    fn every_exif_orientation_turns_the_image_upright() {
+        // A 3x2 image, with a marker in the top left corner as it's stored
+        let marker = Rgb([0xff, 0, 0]);
+        let mut img = RgbImage::new(3, 2);
+        img.put_pixel(0, 0, marker);
+        let img = DynamicImage::ImageRgb8(img);
+
+        for (orientation, dimensions, corner) in [
+            (1, (3, 2), (0, 0)),
+            (2, (3, 2), (2, 0)),
+            (3, (3, 2), (2, 1)),
+            (4, (3, 2), (0, 1)),
+            (5, (2, 3), (0, 0)),
+            (6, (2, 3), (1, 0)),
+            (7, (2, 3), (1, 2)),
+            (8, (2, 3), (0, 2)),
+            // Bogus orientations leave the image as it is
+            (0, (3, 2), (0, 0)),
+            (9, (3, 2), (0, 0)),
+        ] {
+            let upright = apply_orientation(img.clone(), orientation).to_rgb8();
+            assert_eq!(upright.dimensions(), dimensions, "orientation {}", orientation);
+            let found = upright
+                .enumerate_pixels()
+                .filter(|&(_, _, &pixel)| pixel == marker)
+                .map(|(x, y, _)| (x, y))
+                .collect::<Vec<_>>();
+            assert_eq!(found, [corner], "orientation {}", orientation);
+        }
+    }
+}
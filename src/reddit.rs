@@ -1,108 +1,483 @@
-use std::{pin::Pin, task::Poll};
+use std::{collections::BTreeSet, pin::Pin, sync::Mutex, task::Poll};
 
-use eyre::{eyre, Result};
+use eyre::{Result, WrapErr};
 use futures::prelude::*;
 use reqwest::Client;
+use serde::Deserialize;
 use serde_json::Value;
-use tracing::{trace, warn};
+use tracing::{debug, trace, warn};
 
-use crate::utils::with_backoff;
+use crate::{
+    fetcher::{Rejection, Rejections},
+    sources::{RatioRule, Sort},
+    utils::{with_backoff, Bandwidth},
+};
 
-pub struct Posts<'a> {
+// Where the listings come from, unless something's standing in for Reddit
+const REDDIT: &str = "https://reddit.com";
+
+// The cookie telling Reddit we've agreed to see quarantined subreddits, URL-encoded
+const QUARANTINE_OPT_IN: &str = "_options=%7B%22pref_quarantine_optin%22%3A%20true%7D";
+
+/// Reddit refused to list the subreddits as (one of) them is quarantined
+#[derive(thiserror::Error, Debug)]
+#[error("r/{0} is quarantined")]
+pub struct Quarantined(pub String);
+
+// The quarantined subreddits we've told the user about since we started, as they'd otherwise hear of them every fetch
+static QUARANTINE_WARNED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Whether this is the first time we've come across the given quarantined subreddit since we started.
+fn first_quarantine(subreddit: &str) -> bool {
+    QUARANTINE_WARNED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .insert(subreddit.to_lowercase())
+}
+
+/// How many times in a row we try to get a page before giving up on the rest of the listing
+const PAGE_ATTEMPTS: usize = 3;
+
+/// Which kinds of posts we take, beyond the ones we always do
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Filter {
+    pub include_nsfw: bool,
+    /// Posts pinned by the moderators, which are hardly ever images
+    pub include_stickied: bool,
+    pub include_spoilers: bool,
+}
+
+impl Filter {
+    /// Why we won't take the post, if we won't.
+    fn rejection(self, post: &PostData) -> Option<Rejection> {
+        if !self.include_nsfw && post.over_18 {
+            Some(Rejection::Nsfw)
+        } else if !self.include_stickied && post.stickied {
+            Some(Rejection::Stickied)
+        } else if !self.include_spoilers && post.spoiler {
+            Some(Rejection::Spoiler)
+        } else {
+            None
+        }
+    }
+}
+
+/// The posts of a set of subreddits, taken from the listing each of them asked for
+pub struct Posts<'a>(stream::SelectAll<SortedPosts<'a>>);
+
+/// The posts of the subreddits that share a sort, from a single listing
+struct SortedPosts<'a> {
     client: &'a Client,
-    subreddits: &'a [&'a str],
+    /// Where Reddit is, which tests point at a fake of it
+    base_url: &'a str,
+    subreddits: Vec<&'a str>,
+    sort: Sort,
+    filter: Filter,
+    allow_quarantined: bool,
+    /// Where we count the posts the filter turned down
+    rejections: &'a Rejections,
     next_page_id: Option<String>,
+    /// How many times in a row getting the next page has failed
+    failures: usize,
     state: PostsState,
+    /// Gets the pages, from Reddit unless we're being tested
+    fetch_page: Box<dyn Fn(PageRequest) -> PageFuture + 'a>,
+}
+
+/// Everything we need to get a page of a listing
+struct PageRequest {
+    client: Client,
+    base_url: String,
+    subreddits: Vec<String>,
+    sort: Sort,
+    /// The page the last one pointed at, if this isn't the first
+    after: Option<String>,
+    filter: Filter,
+    allow_quarantined: bool,
+}
+
+type PageFuture = Pin<Box<dyn Future<Output = Result<Page>>>>;
+
+/// A link to a potential image, along with where it came from
+#[derive(Clone, Debug)]
+pub struct Post {
+    /// Reddit's id for the post the link came from, which the items of a gallery share
+    pub id: String,
+    pub url: String,
+    pub subreddit: String,
+    pub title: String,
+    /// Where to see the post on Reddit, if it told us
+    pub permalink: Option<String>,
+    /// How many upvotes the post has, net of downvotes
+    pub score: i64,
+    /// Smaller versions of the image that Reddit generated, to fall back on if the original is too big
+    pub variants: Vec<Variant>,
+    /// How closely the image has to match a monitor's aspect ratio, as configured for its source
+    pub ratio: RatioRule,
+}
+
+/// A resized version of a post's image hosted by Reddit
+#[derive(Clone, Debug, Deserialize)]
+pub struct Variant {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Deserialize)]
+struct Listing {
+    data: ListingData,
+}
+
+#[derive(Deserialize)]
+struct ListingData {
+    after: Option<String>,
+    // Kept untyped so that a single odd post doesn't make us throw away the whole page
+    children: Vec<Value>,
+}
+
+/// What Reddit sends instead of a listing when it won't give us one
+#[derive(Deserialize)]
+struct ErrorBody {
+    reason: Option<String>,
+}
+
+impl ErrorBody {
+    fn is_quarantine(body: &[u8]) -> bool {
+        serde_json::from_slice::<Self>(body).is_ok_and(|error| error.reason.as_deref() == Some("quarantined"))
+    }
+}
+
+/// What Reddit tells us about a subreddit, as far as we care
+#[derive(Deserialize)]
+struct About {
+    data: AboutData,
+}
+
+#[derive(Deserialize)]
+struct AboutData {
+    #[serde(default)]
+    quarantine: bool,
+}
+
+impl About {
+    /// Whether the body of a subreddit's about page says it's quarantined, either by refusing to show it or outright.
+    fn is_quarantined(body: &[u8]) -> bool {
+        ErrorBody::is_quarantine(body) || serde_json::from_slice::<Self>(body).is_ok_and(|about| about.data.quarantine)
+    }
+}
+
+/// Find out which of the given subreddits is quarantined, as Reddit refuses to list them together without saying.
+async fn find_quarantined(client: &Client, base_url: &str, subreddits: &[String]) -> Result<Quarantined> {
+    // There's no need to ask if there's only one it could be
+    if let [subreddit] = subreddits {
+        return Ok(Quarantined(subreddit.clone()));
+    }
+
+    for subreddit in subreddits {
+        let url = format!("{base_url}/r/{subreddit}/about.json");
+        let body = with_backoff(|| {
+            client
+                .get(&url)
+                .send()
+                .and_then(reqwest::Response::bytes)
+                .map_err(eyre::Error::from)
+        })
+        .await?;
+        Bandwidth::new().await?.record(body.len() as u64).await?;
+        if About::is_quarantined(&body) {
+            return Ok(Quarantined(subreddit.clone()));
+        }
+    }
+
+    // If none of them admits to it, there's nothing better to blame than all of them
+    Ok(Quarantined(subreddits.join("+")))
+}
+
+#[derive(Deserialize)]
+struct Child {
+    data: PostData,
+}
+
+#[derive(Deserialize)]
+struct PostData {
+    #[serde(default)]
+    id: String,
+    url: String,
+    subreddit: String,
+    title: String,
+    // A path on reddit.com
+    #[serde(default)]
+    permalink: Option<String>,
+    // Treated as 0 when missing rather than making us skip the post
+    #[serde(default)]
+    score: i64,
+    over_18: bool,
+    #[serde(default)]
+    stickied: bool,
+    #[serde(default)]
+    spoiler: bool,
+    #[serde(default)]
+    preview: Option<Preview>,
+}
+
+#[derive(Deserialize)]
+struct Preview {
+    images: Vec<PreviewImage>,
+}
+
+#[derive(Deserialize)]
+struct PreviewImage {
+    source: Variant,
+    resolutions: Vec<Variant>,
+}
+
+/// Undo the HTML escaping Reddit applies to some URLs, as requesting them as-is gets us a 403
+pub fn unescape_url(url: &str) -> String {
+    url.replace("&amp;", "&")
+}
+
+impl From<PostData> for Post {
+    fn from(data: PostData) -> Self {
+        let variants = data
+            .preview
+            .into_iter()
+            .flat_map(|preview| preview.images)
+            .flat_map(|image| std::iter::once(image.source).chain(image.resolutions))
+            .map(|variant| Variant {
+                url: unescape_url(&variant.url),
+                ..variant
+            })
+            .collect();
+
+        Self {
+            id: data.id,
+            url: data.url,
+            subreddit: data.subreddit,
+            title: data.title,
+            permalink: data
+                .permalink
+                .map(|permalink| format!("https://www.reddit.com{permalink}")),
+            score: data.score,
+            variants,
+            ratio: RatioRule::default(),
+        }
+    }
 }
 
 struct Page {
     next_page_id: Option<String>,
-    posts: Vec<String>,
+    posts: Vec<Post>,
+    /// Why each of the posts we skipped was
+    filtered: Vec<Rejection>,
 }
 
 enum PostsState {
     NeedMore,
-    Fetching(Pin<Box<dyn Future<Output = Result<Page>>>>),
-    Fetched(Vec<String>),
+    Fetching(PageFuture),
+    Fetched(Vec<Post>),
     Exhausted,
 }
 
 impl<'a> Posts<'a> {
-    pub fn new(client: &'a Client, subreddits: &'a [&'a str]) -> Self {
+    /// List the posts of every subreddit, each from the listing its sort picks.
+    ///
+    /// Subreddits sharing a sort are listed together, and each listing is paginated on its own so that running out of
+    /// one doesn't stop the others. The posts the filter turns down are counted in `rejections`.
+    pub fn new(
+        client: &'a Client,
+        subreddits: &[(&'a str, Sort)],
+        filter: Filter,
+        allow_quarantined: bool,
+        rejections: &'a Rejections,
+    ) -> Self {
+        let mut groups: Vec<(Sort, Vec<&'a str>)> = Vec::new();
+        for &(subreddit, sort) in subreddits {
+            match groups.iter_mut().find(|(group, _)| *group == sort) {
+                Some((_, group)) => group.push(subreddit),
+                None => groups.push((sort, vec![subreddit])),
+            }
+        }
+
+        Self(stream::select_all(groups.into_iter().map(|(sort, subreddits)| {
+            SortedPosts::new(client, subreddits, sort, filter, allow_quarantined, rejections)
+        })))
+    }
+
+    /// List from a stand-in for Reddit at `base_url` instead.
+    #[cfg(test)]
+    pub fn with_base_url(mut self, base_url: &'a str) -> Self {
+        for posts in self.0.iter_mut() {
+            posts.base_url = base_url;
+        }
+        self
+    }
+}
+
+impl<'a> Stream for Posts<'a> {
+    type Item = Post;
+
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(ctx)
+    }
+}
+
+impl<'a> SortedPosts<'a> {
+    fn new(
+        client: &'a Client,
+        subreddits: Vec<&'a str>,
+        sort: Sort,
+        filter: Filter,
+        allow_quarantined: bool,
+        rejections: &'a Rejections,
+    ) -> Self {
         Self {
             client,
+            base_url: REDDIT,
             subreddits,
+            sort,
+            filter,
+            allow_quarantined,
+            rejections,
             next_page_id: None,
+            failures: 0,
             state: PostsState::NeedMore,
+            fetch_page: Box::new(|request| request.fetch().boxed_local()),
+        }
+    }
+
+    /// Stop listing the quarantined subreddit, carrying on with the others if there are any.
+    fn skip_quarantined(&mut self, error: &Quarantined) {
+        let Quarantined(quarantined) = error;
+        if first_quarantine(quarantined) {
+            warn!(target: "notification", "{error}, set allow_quarantined to fetch from it anyway");
+        } else {
+            debug!(%quarantined, "skipping quarantined subreddit");
         }
+
+        // We may not have been able to tell which of them it is
+        let quarantined = quarantined.split('+').collect::<Vec<_>>();
+        self.subreddits
+            .retain(|subreddit| !quarantined.iter().any(|name| name.eq_ignore_ascii_case(subreddit)));
+        self.state = if self.subreddits.is_empty() {
+            PostsState::Exhausted
+        } else {
+            PostsState::NeedMore
+        };
+    }
+
+    /// Start getting the next page of the listing.
+    fn get_next_page(&self) -> PageFuture {
+        (self.fetch_page)(PageRequest {
+            client: self.client.clone(),
+            base_url: self.base_url.to_owned(),
+            subreddits: self.subreddits.iter().map(|&subreddit| subreddit.to_owned()).collect(),
+            sort: self.sort,
+            after: self.next_page_id.clone(),
+            filter: self.filter,
+            allow_quarantined: self.allow_quarantined,
+        })
     }
+}
+
+impl PageRequest {
+    #[tracing::instrument(skip_all)]
+    fn fetch(self) -> impl Future<Output = Result<Page>> {
+        let Self {
+            client,
+            base_url,
+            subreddits,
+            sort,
+            after,
+            filter,
+            allow_quarantined,
+        } = self;
 
-    #[tracing::instrument(skip(self))]
-    fn get_next_page(&mut self) -> impl Future<Output = Result<Page>> {
         // Spin up the request builder at the correct URL
-        let url = format!("https://reddit.com/r/{}/new.json", self.subreddits.join("+"));
-        let mut req_builder = self.client.get(&url);
+        let url = format!("{base_url}/r/{}/{}.json", subreddits.join("+"), sort.path());
+        let mut req_builder = client.get(&url);
+        if let Some(window) = sort.window() {
+            req_builder = req_builder.query(&[("t", window)]);
+        }
 
-        // Make sure we're getting the freshest posts
-        if let Some(after) = self.next_page_id.as_ref() {
+        // Pick up where the last page left off
+        if let Some(after) = after.as_ref() {
             req_builder = req_builder.query(&[("after", after)]);
         }
         trace!(
             url = url.as_str(),
-            next_page_id = ?self.next_page_id,
+            ?sort,
+            next_page_id = ?after,
             "posts request"
         );
 
         // *puts on sunglasses* Now it's time to enter the matrix
         async move {
             // Here we make our retryable future that just sends out the
-            // response and parses it as JSON. It's important that we parse the
-            // response into JSON inside the retryable future because RequestBuilder::send()
+            // response and reads its body. It's important that we read the
+            // body inside the retryable future because RequestBuilder::send()
             // does not actually consume the response
-            let mut listing: Value = with_backoff(move || {
-                req_builder
-                    .try_clone()
-                    .unwrap()
-                    .send()
-                    .and_then(reqwest::Response::json)
-                    .map_err(eyre::Error::from)
-            })
-            .await?;
-
-            let data = listing
-                .get_mut("data")
-                .ok_or_else(|| eyre!("Toplevel JSON did not have data"))?;
-
-            let next_page_id = data
-                .get("after")
-                .and_then(serde_json::Value::as_str)
-                .map(ToOwned::to_owned);
-
-            // Now let's navigate the tree that Reddit gives us to get what we want
-            Ok(Page {
-                next_page_id,
-                posts: data
-                    .get_mut("children")
-                    .ok_or_else(|| eyre!("Toplevel data did not contain children"))?
-                    .as_array()
-                    .ok_or_else(|| eyre!("Toplevel children were not an array"))?
-                    .iter()
-                    .filter_map(|child| {
-                        let data = child.get("data")?;
-                        if data.get("over_18")?.as_bool()? {
-                            // skip over NSFW wallpapers
-                            return None;
-                        }
-                        Some(data.get("url")?.as_str()?.to_owned())
-                    })
-                    .collect(),
-            })
+            let fetch_body = |req_builder: reqwest::RequestBuilder| {
+                with_backoff(move || {
+                    req_builder
+                        .try_clone()
+                        .unwrap()
+                        .send()
+                        .and_then(reqwest::Response::bytes)
+                        .map_err(eyre::Error::from)
+                })
+            };
+            let mut body = fetch_body(req_builder.try_clone().unwrap()).await?;
+            Bandwidth::new().await?.record(body.len() as u64).await?;
+
+            // Quarantined subreddits answer with an error instead of a listing unless we've opted in to seeing them
+            if ErrorBody::is_quarantine(&body) {
+                if !allow_quarantined {
+                    return Err(find_quarantined(&client, &base_url, &subreddits).await?.into());
+                }
+                debug!(?subreddits, "opting in to quarantined subreddit");
+                let req_builder = req_builder
+                    .query(&[("raw_json", "1")])
+                    .header(reqwest::header::COOKIE, QUARANTINE_OPT_IN);
+                body = fetch_body(req_builder).await?;
+                Bandwidth::new().await?.record(body.len() as u64).await?;
+                if ErrorBody::is_quarantine(&body) {
+                    return Err(find_quarantined(&client, &base_url, &subreddits).await?.into());
+                }
+            }
+            Page::parse(&body, filter)
         }
     }
 }
 
-impl<'a> Stream for Posts<'a> {
-    type Item = String;
+impl Page {
+    /// Navigate the tree that Reddit gives us to get what we want out of a listing.
+    fn parse(body: &[u8], filter: Filter) -> Result<Self> {
+        let listing: Listing = serde_json::from_slice(body).wrap_err("Could not parse listing")?;
+        let mut page = Self {
+            next_page_id: listing.data.after,
+            posts: Vec::new(),
+            filtered: Vec::new(),
+        };
+        for child in listing.data.children {
+            match serde_json::from_value::<Child>(child) {
+                // skip over NSFW, stickied and spoiler posts unless asked not to
+                Ok(Child { data }) => match filter.rejection(&data) {
+                    Some(rejection) => {
+                        trace!(url = %data.url, %rejection, "skipping filtered post");
+                        page.filtered.push(rejection);
+                    }
+                    None => page.posts.push(Post::from(data)),
+                },
+                Err(error) => trace!(?error, "skipping malformed post"),
+            }
+        }
+        Ok(page)
+    }
+}
+
+impl<'a> Stream for SortedPosts<'a> {
+    type Item = Post;
 
     fn poll_next(mut self: Pin<&mut Self>, ctx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         // Simple state-machine loop
@@ -118,13 +493,36 @@ impl<'a> Stream for Posts<'a> {
 
                     match posts {
                         // If we've got posts, move on to the next state
-                        Ok(Page { next_page_id, posts }) => {
+                        Ok(Page {
+                            next_page_id,
+                            posts,
+                            filtered,
+                        }) => {
+                            for rejection in filtered {
+                                self.rejections.record(rejection);
+                            }
                             self.next_page_id = next_page_id;
+                            self.failures = 0;
                             self.state = PostsState::Fetched(posts);
                         }
 
+                        Err(error) if error.is::<Quarantined>() => {
+                            self.skip_quarantined(error.downcast_ref().expect("checked above"))
+                        }
+
+                        // We've already got backoff baked into `get_next_page`, but a page that failed after all of
+                        // that may still come through on a later try, and `next_page_id` still points at it
+                        Err(error) if self.failures + 1 < PAGE_ATTEMPTS => {
+                            self.failures += 1;
+                            debug!(
+                                ?error,
+                                failures = self.failures,
+                                "error while fetching posts, trying again"
+                            );
+                            self.state = PostsState::NeedMore;
+                        }
+
                         Err(error) => {
-                            // We've already got backoff baked into `get_next_page`, we probably can't recover here
                             // It's best if we just stop giving out posts
                             warn!(?error, "error while fetching posts");
                             self.state = PostsState::Exhausted;
@@ -152,3 +550,312 @@ impl<'a> Stream for Posts<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantined_subreddits_are_told_apart() {
+        assert!(ErrorBody::is_quarantine(
+            br#"{"reason": "quarantined", "message": "Forbidden", "error": 403}"#
+        ));
+        assert!(!ErrorBody::is_quarantine(
+            br#"{"reason": "private", "message": "Forbidden", "error": 403}"#
+        ));
+        assert!(!ErrorBody::is_quarantine(br#"{"kind": "Listing", "data": {}}"#));
+
+        assert!(About::is_quarantined(
+            br#"{"kind": "t5", "data": {"quarantine": true}}"#
+        ));
+        assert!(About::is_quarantined(br#"{"reason": "quarantined"}"#));
+        assert!(!About::is_quarantined(
+            br#"{"kind": "t5", "data": {"quarantine": false}}"#
+        ));
+        assert!(!About::is_quarantined(br#"{"kind": "t5", "data": {}}"#));
+    }
+
+    fn listing(posts: &[(&str, bool, bool, bool)]) -> Vec<u8> {
+        let children = posts
+            .iter()
+            .map(|&(url, over_18, stickied, spoiler)| {
+                serde_json::json!({
+                    "kind": "t3",
+                    "data": {
+                        "url": url,
+                        "subreddit": "wallpapers",
+                        "title": "A title",
+                        "over_18": over_18,
+                        "stickied": stickied,
+                        "spoiler": spoiler,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_vec(&serde_json::json!({ "data": { "after": "t3_next", "children": children } })).unwrap()
+    }
+
+    #[test]
+    fn filtered_posts_are_counted_by_why() {
+        let body = listing(&[
+            ("https://i.redd.it/plain.png", false, false, false),
+            ("https://i.redd.it/nsfw.png", true, false, false),
+            ("https://i.redd.it/pinned.png", false, true, false),
+            ("https://i.redd.it/spoiler.png", false, false, true),
+            ("https://i.redd.it/everything.png", true, true, true),
+        ]);
+
+        let page = Page::parse(&body, Filter::default()).unwrap();
+        assert_eq!(page.next_page_id.as_deref(), Some("t3_next"));
+        assert_eq!(
+            page.posts.iter().map(|post| post.url.as_str()).collect::<Vec<_>>(),
+            ["https://i.redd.it/plain.png"]
+        );
+        assert_eq!(
+            page.filtered,
+            [
+                Rejection::Nsfw,
+                Rejection::Stickied,
+                Rejection::Spoiler,
+                Rejection::Nsfw
+            ]
+        );
+
+        let everything = Filter {
+            include_nsfw: true,
+            include_stickied: true,
+            include_spoilers: true,
+        };
+        let page = Page::parse(&body, everything).unwrap();
+        assert_eq!(page.posts.len(), 5);
+        assert!(page.filtered.is_empty());
+    }
+
+    #[test]
+    fn preview_urls_are_unescaped_to_be_requested() {
+        let escaped = "https://preview.redd.it/abc123.jpg?width=1080&amp;crop=smart&amp;auto=webp&amp;s=0f1e2d3c";
+        let unescaped = "https://preview.redd.it/abc123.jpg?width=1080&crop=smart&auto=webp&s=0f1e2d3c";
+        assert_eq!(unescape_url(escaped), unescaped);
+        assert_eq!(unescape_url(unescaped), unescaped);
+
+        let body = serde_json::json!({ "data": { "after": null, "children": [{
+            "kind": "t3",
+            "data": {
+                "url": "https://i.redd.it/abc123.jpg",
+                "subreddit": "wallpapers",
+                "title": "A title",
+                "over_18": false,
+                "preview": { "images": [{
+                    "source": { "url": escaped, "width": 3840, "height": 2160 },
+                    "resolutions": [{
+                        "url": "https://preview.redd.it/abc123.jpg?width=640&amp;crop=smart&amp;s=9a8b7c",
+                        "width": 640,
+                        "height": 360,
+                    }],
+                }]},
+            },
+        }]}});
+        let page = Page::parse(&serde_json::to_vec(&body).unwrap(), Filter::default()).unwrap();
+        assert_eq!(
+            page.posts[0]
+                .variants
+                .iter()
+                .map(|variant| variant.url.as_str())
+                .collect::<Vec<_>>(),
+            [
+                unescaped,
+                "https://preview.redd.it/abc123.jpg?width=640&crop=smart&s=9a8b7c"
+            ]
+        );
+    }
+
+    #[test]
+    fn the_other_subreddits_outlive_a_quarantined_one() {
+        let (client, rejections) = (Client::new(), Rejections::default());
+        let mut posts = SortedPosts::new(
+            &client,
+            vec!["wallpapers", "Quarantined", "EarthPorn"],
+            Sort::Hot,
+            Filter::default(),
+            false,
+            &rejections,
+        );
+
+        posts.skip_quarantined(&Quarantined("quarantined".to_owned()));
+        assert_eq!(posts.subreddits, ["wallpapers", "EarthPorn"]);
+        assert!(matches!(posts.state, PostsState::NeedMore));
+
+        // When we couldn't tell which one it was, all of them go
+        posts.skip_quarantined(&Quarantined("wallpapers+EarthPorn".to_owned()));
+        assert!(posts.subreddits.is_empty());
+        assert!(matches!(posts.state, PostsState::Exhausted));
+    }
+
+    #[test]
+    fn quarantined_subreddits_are_only_warned_about_once() {
+        assert!(first_quarantine("SomeQuarantinedSubreddit"));
+        assert!(!first_quarantine("SomeQuarantinedSubreddit"));
+        assert!(!first_quarantine("somequarantinedsubreddit"));
+        assert!(first_quarantine("AnotherQuarantinedSubreddit"));
+    }
+
+    /// List from pages handed out by `fetch_page` instead of Reddit, counting what was asked for in `requests`.
+    fn listed_from<'a>(
+        client: &'a Client,
+        rejections: &'a Rejections,
+        requests: &'a std::cell::RefCell<Vec<Option<String>>>,
+        fetch_page: impl Fn(usize) -> Result<Page> + 'a,
+    ) -> SortedPosts<'a> {
+        let mut posts = SortedPosts::new(
+            client,
+            vec!["wallpapers"],
+            Sort::Hot,
+            Filter::default(),
+            false,
+            rejections,
+        );
+        posts.fetch_page = Box::new(move |request| {
+            requests.borrow_mut().push(request.after);
+            let attempt = requests.borrow().len();
+            future::ready(fetch_page(attempt)).boxed_local()
+        });
+        posts
+    }
+
+    fn page(urls: &[&str], next_page_id: Option<&str>) -> Page {
+        Page {
+            next_page_id: next_page_id.map(str::to_owned),
+            posts: urls
+                .iter()
+                .map(|&url| Post {
+                    id: url.to_owned(),
+                    url: url.to_owned(),
+                    subreddit: "wallpapers".to_owned(),
+                    title: String::new(),
+                    permalink: None,
+                    score: 0,
+                    variants: Vec::new(),
+                    ratio: RatioRule::default(),
+                })
+                .collect(),
+            filtered: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn failed_pages_are_tried_again_from_where_we_left_off() {
+        let (client, rejections, requests) = (Client::new(), Rejections::default(), Default::default());
+        let posts = listed_from(&client, &rejections, &requests, |attempt| match attempt {
+            1 => Ok(page(&["a"], Some("t3_a"))),
+            // Fails one time short of giving up, then goes through and starts the count over
+            2 | 3 => Err(eyre::eyre!("connection reset")),
+            4 => Ok(page(&["b"], Some("t3_b"))),
+            5 | 6 => Err(eyre::eyre!("connection reset")),
+            7 => Ok(page(&["c"], None)),
+            _ => panic!("asked for a page after the last one"),
+        });
+
+        let urls = futures::executor::block_on(posts.map(|post| post.url).collect::<Vec<_>>());
+        assert_eq!(urls, ["a", "b", "c"]);
+        let requests = requests.into_inner();
+        let expected = [
+            None,
+            Some("t3_a"),
+            Some("t3_a"),
+            Some("t3_a"),
+            Some("t3_b"),
+            Some("t3_b"),
+            Some("t3_b"),
+        ];
+        assert_eq!(requests.iter().map(Option::as_deref).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn the_listing_is_given_up_on_after_too_many_failures_in_a_row() {
+        let (client, rejections, requests) = (Client::new(), Rejections::default(), Default::default());
+        let posts = listed_from(&client, &rejections, &requests, |attempt| match attempt {
+            1 => Ok(page(&["a"], Some("t3_a"))),
+            _ => Err(eyre::eyre!("connection reset")),
+        });
+
+        let urls = futures::executor::block_on(posts.map(|post| post.url).collect::<Vec<_>>());
+        assert_eq!(urls, ["a"]);
+        assert_eq!(requests.into_inner().len(), 1 + PAGE_ATTEMPTS);
+    }
+
+    #[test]
+    fn subreddits_sharing_a_sort_share_a_listing() {
+        let (client, rejections) = (Client::new(), Rejections::default());
+        let posts = Posts::new(
+            &client,
+            &[
+                ("wallpapers", Sort::Hot),
+                ("EarthPorn", Sort::Top(Some("week"))),
+                ("SkyPorn", Sort::Hot),
+                ("CityPorn", Sort::Top(Some("all"))),
+            ],
+            Filter::default(),
+            false,
+            &rejections,
+        );
+
+        // The listings are polled in whatever order
+        let mut listings = posts
+            .0
+            .iter()
+            .map(|listing| (listing.sort, listing.subreddits.clone()))
+            .collect::<Vec<_>>();
+        listings.sort_by_key(|&(sort, _)| (sort.path(), sort.window()));
+        assert_eq!(
+            listings,
+            [
+                (Sort::Hot, vec!["wallpapers", "SkyPorn"]),
+                (Sort::Top(Some("all")), vec!["CityPorn"]),
+                (Sort::Top(Some("week")), vec!["EarthPorn"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn each_kind_of_post_is_let_through_on_its_own() {
+        let post = |over_18, stickied, spoiler| -> PostData {
+            serde_json::from_value(serde_json::json!({
+                "url": "https://i.redd.it/post.png",
+                "subreddit": "wallpapers",
+                "title": "A title",
+                "over_18": over_18,
+                "stickied": stickied,
+                "spoiler": spoiler,
+            }))
+            .unwrap()
+        };
+        let only = |include_nsfw, include_stickied, include_spoilers| Filter {
+            include_nsfw,
+            include_stickied,
+            include_spoilers,
+        };
+
+        assert_eq!(only(true, false, false).rejection(&post(true, false, false)), None);
+        assert_eq!(only(false, true, false).rejection(&post(false, true, false)), None);
+        assert_eq!(only(false, false, true).rejection(&post(false, false, true)), None);
+        // Letting one kind through says nothing of the others
+        assert_eq!(
+            only(true, false, true).rejection(&post(true, true, true)),
+            Some(Rejection::Stickied)
+        );
+        assert_eq!(
+            only(true, true, false).rejection(&post(true, true, true)),
+            Some(Rejection::Spoiler)
+        );
+
+        // Posts from before Reddit had spoilers are neither
+        let old: PostData = serde_json::from_value(serde_json::json!({
+            "url": "https://i.redd.it/old.png",
+            "subreddit": "wallpapers",
+            "title": "A title",
+            "over_18": false,
+        }))
+        .unwrap();
+        assert_eq!(Filter::default().rejection(&old), None);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, WrapErr};
+use tracing::{debug, trace_span};
+
+use crate::{
+    db::{self, MetadataRepo},
+    fetcher, thumbnails, DIRS,
+};
+
+/// A cached image that could be picked as the next background
+#[derive(Debug)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub thumbnail: String,
+    pub url: Option<String>,
+    /// The subreddit it was posted in, if we know it
+    pub subreddit: Option<String>,
+    /// The post's score when we fetched it, if we know it
+    pub score: Option<i64>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Get thumbnails for every image cached for the given profile along with an HTML page showing them, returning the
+/// page's path.
+#[tracing::instrument]
+pub fn preview_candidates(profile: &str) -> Result<PathBuf> {
+    // Start from a clean slate so that we don't link to images that are long gone
+    let dir = DIRS.cache_dir().join("preview");
+    match fs::remove_dir_all(&dir) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error).wrap_err("Could not clear preview directory"),
+    }
+    fs::create_dir_all(&dir)?;
+
+    let db = db::open()?;
+    let mut candidates = Vec::new();
+    let mut thumbnails = HashSet::new();
+    for entry in fetcher::images_dir(profile).read_dir()? {
+        let path = entry?.path();
+        let _span = trace_span!("previewing", path = %path.display()).entered();
+
+        // What we recorded when we stored the file saves reading its header
+        let metadata = MetadataRepo::new(&db);
+        let stored = match path.file_name().and_then(OsStr::to_str) {
+            Some(filename) => metadata.stored(filename)?,
+            None => None,
+        };
+        let dimensions = match stored {
+            Some(stored) => Ok((stored.width, stored.height)),
+            None => image::image_dimensions(&path).map_err(eyre::Report::from),
+        };
+
+        // Unlike the picker we don't clean up after invalid images, we just don't show them
+        let (width, height, thumbnail) = match dimensions
+            .and_then(|(width, height)| Ok((width, height, thumbnails::get_or_create(profile, &path)?)))
+        {
+            Ok(candidate) => candidate,
+            Err(error) => {
+                debug!(?error, "could not decode candidate");
+                continue;
+            }
+        };
+
+        let url = fetcher::url_for_file(&metadata, &path)?;
+        let (subreddit, score) = match url {
+            Some(ref url) => (metadata.subreddit(url)?, metadata.score(url)?),
+            None => (None, None),
+        };
+        candidates.push(Candidate {
+            url,
+            subreddit,
+            score,
+            path,
+            thumbnail: file_url(&thumbnail),
+            width,
+            height,
+        });
+        thumbnails.insert(thumbnail);
+    }
+    thumbnails::retain(profile, &thumbnails)?;
+
+    let page = dir.join("index.html");
+    fs::write(&page, render_preview(&candidates)).wrap_err("Could not write preview page")?;
+    Ok(page)
+}
+
+/// Render the HTML page listing the given candidates.
+pub fn render_preview(candidates: &[Candidate]) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        concat!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{} candidates</title>\n",
+            "<style>body {{ font-family: sans-serif; }} figure {{ display: inline-block; margin: 8px; }}</style>\n",
+            "</head>\n<body>\n<h1>{} candidates</h1>\n"
+        ),
+        env!("CARGO_PKG_NAME"),
+        candidates.len()
+    );
+
+    if candidates.is_empty() {
+        html.push_str("<p>The cache is empty.</p>\n");
+    }
+
+    for candidate in candidates {
+        let _ = write!(
+            html,
+            "<figure>\n<a href=\"{}\"><img src=\"{}\"></a>\n<figcaption>{}&times;{}",
+            escape_html(&file_url(&candidate.path)),
+            escape_html(&candidate.thumbnail),
+            candidate.width,
+            candidate.height,
+        );
+        if let Some(ref subreddit) = candidate.subreddit {
+            let _ = write!(html, " from r/{}", escape_html(subreddit));
+        }
+        if let Some(score) = candidate.score {
+            let _ = write!(html, ", {score} points");
+        }
+        if let Some(ref url) = candidate.url {
+            let _ = write!(html, "<br><a href=\"{0}\">{0}</a>", escape_html(url));
+        }
+        html.push_str("</figcaption>\n</figure>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Link to a local file, escaping whatever in its path would otherwise be taken as part of the URL's syntax.
+fn file_url(path: &Path) -> String {
+    reqwest::Url::from_file_path(path).map_or_else(|()| path.display().to_string(), String::from)
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(path: PathBuf) -> Candidate {
+        Candidate {
+            thumbnail: file_url(&path.with_extension("thumb.png")),
+            path,
+            url: Some("https://i.redd.it/abc.png?width=1&height=2".to_owned()),
+            subreddit: Some("EarthPorn".to_owned()),
+            score: Some(1234),
+            width: 3840,
+            height: 2160,
+        }
+    }
+
+    #[test]
+    fn candidates_show_where_they_came_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let html = render_preview(&[candidate(dir.path().join("image.png"))]);
+        assert!(html.contains("<h1>1 candidates</h1>"), "{}", html);
+        assert!(
+            html.contains("3840&times;2160 from r/EarthPorn, 1234 points"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("https://i.redd.it/abc.png?width=1&amp;height=2"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn unknown_sources_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let html = render_preview(&[Candidate {
+            url: None,
+            subreddit: None,
+            score: None,
+            ..candidate(dir.path().join("image.png"))
+        }]);
+        assert!(html.contains("3840&times;2160</figcaption>"), "{}", html);
+    }
+
+    #[test]
+    fn an_empty_cache_says_so() {
+        assert!(render_preview(&[]).contains("<p>The cache is empty.</p>"));
+    }
+
+    #[test]
+    fn file_urls_escape_their_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = file_url(&dir.path().join("my images").join("#1.png"));
+        assert!(url.starts_with("file:///"), "{}", url);
+        assert!(url.ends_with("/my%20images/%231.png"), "{}", url);
+        assert_eq!(
+            reqwest::Url::parse(&url).unwrap().to_file_path().unwrap(),
+            dir.path().join("my images").join("#1.png")
+        );
+    }
+}
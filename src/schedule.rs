@@ -0,0 +1,123 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{ensure, format_err, Result};
+use serde::{Deserialize, Deserializer};
+use time::{OffsetDateTime, Time};
+
+// How far apart changes may stray from their interval, as a fraction of it either way
+const JITTER: f64 = 0.1;
+
+/// Stretch or shrink `interval` by up to `JITTER` of it, by an amount derived from `seed`.
+pub fn jittered(interval: Duration, seed: u64) -> Duration {
+    // Spread the seed evenly over [-1, 1], as seeds taken from the clock are anything but
+    let unit = xxhash_rust::xxh3::xxh3_64(&seed.to_le_bytes()) as f64 / u64::MAX as f64 * 2.0 - 1.0;
+    interval.mul_f64(1.0 + JITTER * unit)
+}
+
+/// A seed for `jittered` that differs between instances and between calls.
+pub fn jitter_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.subsec_nanos());
+    u64::from(nanos) ^ u64::from(std::process::id()) << 32
+}
+
+/// A time of day in the local timezone, written as `HH:MM`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeOfDay {
+    hour: u8,
+    minute: u8,
+}
+
+impl FromStr for TimeOfDay {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (hour, minute) = s
+            .split_once(':')
+            .ok_or_else(|| format_err!("Expected HH:MM, got {s:?}"))?;
+        let (hour, minute) = (hour.parse::<u8>()?, minute.parse::<u8>()?);
+        ensure!(hour < 24 && minute < 60, "Time of day {s:?} out of range");
+        Ok(Self { hour, minute })
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl TimeOfDay {
+    /// How long from `now` until this time of day next comes around.
+    fn until_next(self, now: OffsetDateTime) -> Duration {
+        let time = Time::from_hms(self.hour, self.minute, 0).expect("TimeOfDay is always in range");
+        let mut next = now.replace_time(time);
+        if next <= now {
+            next += time::Duration::DAY;
+        }
+        (next - now).unsigned_abs()
+    }
+}
+
+/// When to fetch new images, independently of when we change the background
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FetchSchedule {
+    /// Times of day at which to fetch, e.g. `["03:00"]`
+    pub times: Vec<TimeOfDay>,
+
+    /// How often to fetch, in minutes
+    pub interval_minutes: Option<u64>,
+}
+
+impl FetchSchedule {
+    /// How long until we should next fetch, or `None` if the schedule is empty.
+    pub fn until_next(&self) -> Option<Duration> {
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        self.times
+            .iter()
+            .map(|time| time.until_next(now))
+            .chain(self.interval_minutes.map(|minutes| Duration::from_secs(minutes * 60)))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_stays_within_its_fraction_of_the_interval() {
+        let interval = Duration::from_secs(60 * 60);
+        let (low, high) = (interval.mul_f64(1.0 - JITTER), interval.mul_f64(1.0 + JITTER));
+        let jittered = (0..1000).map(|seed| jittered(interval, seed)).collect::<Vec<_>>();
+
+        assert!(jittered.iter().all(|&jittered| (low..=high).contains(&jittered)));
+        // Consecutive seeds, as taken from the clock, still spread out over both sides
+        assert!(jittered
+            .iter()
+            .any(|&jittered| jittered < interval.mul_f64(1.0 - JITTER / 2.0)));
+        assert!(jittered
+            .iter()
+            .any(|&jittered| jittered > interval.mul_f64(1.0 + JITTER / 2.0)));
+    }
+
+    #[test]
+    fn the_same_seed_jitters_the_same() {
+        let interval = Duration::from_secs(30 * 60);
+        assert_eq!(jittered(interval, 42), jittered(interval, 42));
+        assert_eq!(jittered(Duration::ZERO, 42), Duration::ZERO);
+    }
+}
@@ -0,0 +1,75 @@
+//! Keeping the current background up for a while or until resumed, without pausing fetching.
+
+use std::time::Duration;
+
+use eyre::Result;
+use time::{OffsetDateTime, Time};
+
+use crate::db::{self, AppStateRepo};
+
+const KEY: &str = "snoozed_until";
+
+const PAUSED_KEY: &str = "paused";
+
+fn now() -> OffsetDateTime {
+    OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc())
+}
+
+/// How long until midnight, for snoozing for the rest of the day.
+pub fn until_tomorrow() -> Duration {
+    let now = now();
+    let midnight = (now + time::Duration::DAY).replace_time(Time::MIDNIGHT);
+    (midnight - now).unsigned_abs()
+}
+
+/// How long is left until `until`, if anything.
+pub fn remaining(until: OffsetDateTime) -> Duration {
+    let left = until - now();
+    if left.is_positive() {
+        left.unsigned_abs()
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// Snooze for `duration` from now, replacing any snooze that's already running, and return until when.
+pub fn start(duration: Duration) -> Result<OffsetDateTime> {
+    let until = now() + duration;
+    let db = db::open()?;
+    AppStateRepo::new(&db).set(KEY, &until.unix_timestamp().to_string())?;
+    Ok(until)
+}
+
+/// Get until when we're snoozed, if we are, as left by a previous run or this one.
+pub fn load() -> Result<Option<OffsetDateTime>> {
+    let db = db::open()?;
+    let Some(until) = AppStateRepo::new(&db).get(KEY)? else {
+        return Ok(None);
+    };
+    let until = OffsetDateTime::from_unix_timestamp(until.parse()?)?.to_offset(now().offset());
+    Ok(Some(until).filter(|&until| !remaining(until).is_zero()))
+}
+
+pub fn clear() -> Result<()> {
+    let db = db::open()?;
+    AppStateRepo::new(&db).remove(KEY)?;
+    Ok(())
+}
+
+/// Stop or resume changing the background on a timer, until told otherwise even across restarts.
+pub fn set_paused(paused: bool) -> Result<()> {
+    let db = db::open()?;
+    let state = AppStateRepo::new(&db);
+    if paused {
+        state.set(PAUSED_KEY, "1")?;
+    } else {
+        state.remove(PAUSED_KEY)?;
+    }
+    Ok(())
+}
+
+/// Get whether timed changes were paused, by a previous run or this one.
+pub fn is_paused() -> Result<bool> {
+    let db = db::open()?;
+    Ok(AppStateRepo::new(&db).get(PAUSED_KEY)?.is_some())
+}
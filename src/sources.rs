@@ -0,0 +1,652 @@
+use std::collections::{BTreeMap, HashMap};
+
+use tracing::warn;
+
+// The options we understand after a subreddit's name; anything else is warned about and ignored
+const KNOWN_KEYS: &[&str] = &["ratio", "sort", "min_score", "weight"];
+
+/// How closely a source's images have to match a monitor's aspect ratio, set with `ratio=`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RatioRule {
+    /// Within a small epsilon, so that images aren't visibly cropped or stretched
+    #[default]
+    Strict,
+    /// Not at all, for e.g. subreddits of ultra-wide images meant to span several monitors
+    Any,
+}
+
+impl RatioRule {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "strict" => Some(Self::Strict),
+            "any" => Some(Self::Any),
+            _ => None,
+        }
+    }
+}
+
+/// Which of a subreddit's listings to take posts from, set with `name:sort[:window]` or `sort=`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sort {
+    #[default]
+    New,
+    Hot,
+    /// The most upvoted posts within the given window, e.g. `week`
+    Top(Option<&'static str>),
+}
+
+impl Sort {
+    // The windows Reddit accepts for its top listings
+    const WINDOWS: &'static [&'static str] = &["hour", "day", "week", "month", "year", "all"];
+
+    /// Parse `new`, `hot`, `top` or `top:<window>`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.split_once(':') {
+            None if value == "new" => Some(Self::New),
+            None if value == "hot" => Some(Self::Hot),
+            None if value == "top" => Some(Self::Top(None)),
+            Some(("top", window)) => Self::WINDOWS
+                .iter()
+                .find(|&&known| known == window)
+                .map(|&window| Self::Top(Some(window))),
+            _ => None,
+        }
+    }
+
+    /// The last part of the listing's path, e.g. `top` for `/r/wallpapers/top.json`.
+    pub fn path(self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::Hot => "hot",
+            Self::Top(_) => "top",
+        }
+    }
+
+    /// The time window to ask for, if any.
+    pub fn window(self) -> Option<&'static str> {
+        match self {
+            Self::Top(window) => window,
+            Self::New | Self::Hot => None,
+        }
+    }
+}
+
+/// One line of `subreddits.txt`: a subreddit along with the options that apply to it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceSpec {
+    pub name: String,
+    pub options: BTreeMap<String, String>,
+}
+
+impl SourceSpec {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// How closely this source's images have to match a monitor's aspect ratio.
+    pub fn ratio_rule(&self) -> RatioRule {
+        self.options
+            .get("ratio")
+            .and_then(|value| RatioRule::parse(value))
+            .unwrap_or_default()
+    }
+
+    /// The lowest score this source's posts may have, if it overrides the configured one.
+    pub fn min_score(&self) -> Option<i64> {
+        self.options.get("min_score").and_then(|value| value.parse().ok())
+    }
+
+    /// How much more often than the others this source is fetched from when only some of them are each cycle.
+    pub fn weight(&self) -> f64 {
+        self.options
+            .get("weight")
+            .and_then(|value| parse_weight(value))
+            .unwrap_or(1.0)
+    }
+
+    /// Which of this source's listings to take posts from.
+    pub fn sort(&self) -> Sort {
+        self.options
+            .get("sort")
+            .and_then(|value| Sort::parse(value))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+// Reddit's limits on subreddit names, with some leeway for old two letter subreddits like r/de
+const NAME_LENGTH: std::ops::RangeInclusive<usize> = 2..=21;
+
+// Reddit's limits on usernames, whose profiles we take posts from as the subreddit `u_<name>`
+const USER_NAME_LENGTH: std::ops::RangeInclusive<usize> = 3..=20;
+
+// The prefixes reddit.com is reached under, e.g. `old.reddit.com`
+const REDDIT_HOST_PREFIXES: &[&str] = &["www.", "old.", "new.", "np.", "m."];
+
+// The last duplicates we warned about, so that reparsing the same file doesn't warn every cycle
+static WARNED_DUPLICATES: std::sync::Mutex<Vec<(usize, String)>> = std::sync::Mutex::new(Vec::new());
+
+/// Split a source as written by the user into its subreddit's name and the sort that may follow it, e.g.
+/// `wallpapers:top:week`.
+fn split_sort(source: &str) -> Result<(&str, Option<&str>), String> {
+    match source.split_once(':') {
+        None => Ok((source, None)),
+        Some((name, sort)) if Sort::parse(sort).is_some() => Ok((name, Some(sort))),
+        Some((_, sort)) => Err(format!(
+            "expected new, hot, top or top:<window> after the subreddit's name, got {sort:?}"
+        )),
+    }
+}
+
+/// Turn a link to a subreddit or a user's profile as pasted from the browser, e.g.
+/// `https://old.reddit.com/r/EarthPorn/top/?t=week`, into the source it stands for as we'd expect it written, e.g.
+/// `earthporn:top:week`.
+///
+/// Returns `None` if `source` isn't a link at all.
+fn from_url(source: &str) -> Option<Result<String, String>> {
+    let rest = source
+        .strip_prefix("https://")
+        .or_else(|| source.strip_prefix("http://"))?;
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+
+    let host = host.to_ascii_lowercase();
+    let host = REDDIT_HOST_PREFIXES
+        .iter()
+        .find_map(|prefix| host.strip_prefix(prefix))
+        .unwrap_or(&host);
+    if host != "reddit.com" {
+        return Some(Err(format!("{source:?} links to {host}, not to reddit.com")));
+    }
+
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    let name = match (segments.next(), segments.next()) {
+        (Some("r"), Some(name)) => name.to_owned(),
+        (Some("user" | "u"), Some(name)) => format!("u_{name}"),
+        _ => return Some(Err(format!("{source:?} isn't a link to a subreddit or a user"))),
+    };
+
+    // Links to a listing keep its sort, along with the window of top listings
+    let window = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("t="))
+        .filter(|window| Sort::WINDOWS.contains(window));
+    Some(Ok(match (segments.next(), window) {
+        (Some("top"), Some(window)) => format!("{name}:top:{window}"),
+        (Some(sort @ ("new" | "hot" | "top")), _) => format!("{name}:{sort}"),
+        _ => name,
+    }))
+}
+
+/// Build a source out of a subreddit's name, followed by its sort if any, or out of a link to it, as written by the
+/// user.
+fn spec(source: &str) -> Result<SourceSpec, String> {
+    let normalized;
+    let source = match from_url(source) {
+        Some(normalized_url) => {
+            normalized = normalized_url?;
+            normalized.as_str()
+        }
+        None => source,
+    };
+
+    let (name, sort) = split_sort(source)?;
+    let mut spec = SourceSpec::new(normalize_name(name)?);
+    if let Some(sort) = sort {
+        spec.options.insert("sort".to_owned(), sort.to_owned());
+    }
+    Ok(spec)
+}
+
+/// Normalize a subreddit name as written by the user, e.g. `/r/EarthPorn/` becomes `earthporn`.
+///
+/// Users' profiles are subreddits too, so `/u/Someone` becomes `u_someone`.
+fn normalize_name(name: &str) -> Result<String, String> {
+    let name = name.trim_end_matches('/');
+    let user = ["/user/", "user/", "/u/", "u/", "u_"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix));
+    if let Some(user) = user {
+        return normalize_user(user);
+    }
+
+    let name = name
+        .strip_prefix("/r/")
+        .or_else(|| name.strip_prefix("r/"))
+        .unwrap_or(name);
+
+    if !NAME_LENGTH.contains(&name.len()) {
+        return Err(format!(
+            "subreddit name {name:?} should be between {} and {} characters long",
+            NAME_LENGTH.start(),
+            NAME_LENGTH.end()
+        ));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("subreddit name {name:?} contains invalid characters"));
+    }
+
+    // Subreddit names are case insensitive
+    Ok(name.to_ascii_lowercase())
+}
+
+/// Normalize a username into the name of the subreddit of their profile.
+fn normalize_user(user: &str) -> Result<String, String> {
+    if !USER_NAME_LENGTH.contains(&user.len()) {
+        return Err(format!(
+            "username {user:?} should be between {} and {} characters long",
+            USER_NAME_LENGTH.start(),
+            USER_NAME_LENGTH.end()
+        ));
+    }
+    if !user.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(format!("username {user:?} contains invalid characters"));
+    }
+
+    Ok(format!("u_{}", user.to_ascii_lowercase()))
+}
+
+/// Drop any source whose subreddit is listed more than once, keeping the first one.
+///
+/// `specs` holds each source along with the line it came from.
+fn dedupe(specs: Vec<(usize, SourceSpec)>) -> Vec<SourceSpec> {
+    let mut seen = std::collections::HashMap::new();
+    let mut duplicates = Vec::new();
+    let mut deduped = Vec::new();
+    for (line, spec) in specs {
+        if let Some(first) = seen.get(&spec.name) {
+            duplicates.push((line, format!("{} (first listed on line {first})", spec.name)));
+        } else {
+            seen.insert(spec.name.clone(), line);
+            deduped.push(spec);
+        }
+    }
+
+    let mut warned = WARNED_DUPLICATES.lock().unwrap();
+    if !duplicates.is_empty() && *warned != duplicates {
+        warn!(?duplicates, "ignoring duplicate subreddits");
+    }
+    *warned = duplicates;
+
+    deduped
+}
+
+/// Build sources out of a plain list of subreddit names, e.g. from the config file.
+///
+/// Names are normalized the same way as in `subreddits.txt`, with invalid ones being skipped.
+pub fn from_names(names: &[String]) -> Vec<SourceSpec> {
+    let specs = names
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, name)| match spec(name) {
+            Ok(spec) => Some((idx + 1, spec)),
+            Err(message) => {
+                warn!(entry = idx + 1, %message, "skipping invalid subreddit");
+                None
+            }
+        })
+        .collect();
+    dedupe(specs)
+}
+
+/// Parse the contents of `subreddits.txt`.
+///
+/// Every line is either blank, a `# comment` or `name [key=value ...]`, optionally followed by a comment. Names are
+/// normalized so that e.g. `/r/EarthPorn`, `earthporn` and a link to the subreddit are recognized as duplicates, and
+/// lines with invalid names or links to other sites are skipped with a warning instead of breaking the whole listing.
+pub fn parse(contents: &str) -> Result<Vec<SourceSpec>, ParseError> {
+    // Editors on Windows love to put a BOM at the start of the file
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+
+    let mut specs = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line_no = idx + 1;
+        let error = |message: String| ParseError { line: line_no, message };
+
+        let line = match comment_start(line) {
+            Some(start) => &line[..start],
+            None => line,
+        };
+
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else { continue };
+        let mut spec = match spec(name) {
+            Ok(spec) => spec,
+            Err(message) => {
+                warn!(line = line_no, %message, "skipping invalid subreddit in subreddits.txt");
+                continue;
+            }
+        };
+
+        for word in words {
+            let Some((key, value)) = word.split_once('=') else {
+                return Err(error(format!("expected key=value, got {word:?}")));
+            };
+            if key.is_empty() {
+                return Err(error(format!("missing key in {word:?}")));
+            }
+            if !KNOWN_KEYS.contains(&key) {
+                warn!(line = line_no, key, "ignoring unknown option in subreddits.txt");
+                continue;
+            }
+            if key == "ratio" && RatioRule::parse(value).is_none() {
+                return Err(error(format!("expected ratio=strict or ratio=any, got {word:?}")));
+            }
+            if key == "min_score" && value.parse::<i64>().is_err() {
+                return Err(error(format!("expected min_score=<number>, got {word:?}")));
+            }
+            if key == "weight" && parse_weight(value).is_none() {
+                return Err(error(format!("expected weight=<positive number>, got {word:?}")));
+            }
+            if key == "sort" && Sort::parse(value).is_none() {
+                return Err(error(format!(
+                    "expected sort=new, sort=hot, sort=top or sort=top:<window>, got {word:?}"
+                )));
+            }
+            if spec.options.insert(key.to_owned(), value.to_owned()).is_some() {
+                return Err(error(format!("duplicate option {key:?}")));
+            }
+        }
+
+        specs.push((line_no, spec));
+    }
+
+    Ok(dedupe(specs))
+}
+
+/// Find where the comment on a line starts, if it has one; a `#` inside a link starts its fragment instead.
+fn comment_start(line: &str) -> Option<usize> {
+    line.match_indices('#').map(|(start, _)| start).find(|&start| {
+        let word = line[..start].rsplit(char::is_whitespace).next().unwrap_or_default();
+        !(word.starts_with("https://") || word.starts_with("http://"))
+    })
+}
+
+fn parse_weight(value: &str) -> Option<f64> {
+    value
+        .parse()
+        .ok()
+        .filter(|weight: &f64| weight.is_finite() && *weight > 0.0)
+}
+
+// How long a source has to go without being fetched from to get the most of a boost in its odds of being next
+const SAMPLE_STALENESS_CAP: i64 = 24 * 60 * 60;
+
+// How many times its weight a source left out for that long counts as, compared to one we've just fetched from
+const SAMPLE_STALENESS_BOOST: f64 = 24.0;
+
+/// Randomly pick `count` of the sources to fetch from this cycle, according to their weights.
+///
+/// `last_sampled` holds when each source was last fetched from, and the longer ago that was, up to a day, the likelier
+/// a source is to be picked; sources that never were are as likely as those left out for a day. The same `seed` always
+/// picks the same sources.
+pub fn sample(
+    mut sources: Vec<SourceSpec>,
+    count: usize,
+    last_sampled: &HashMap<String, i64>,
+    now: i64,
+    seed: u64,
+) -> Vec<SourceSpec> {
+    // Weighted sampling without replacement: each source draws a uniform number, raised to the inverse of its weight,
+    // and the highest draws win
+    sources.sort_by_cached_key(|source| {
+        let staleness = last_sampled
+            .get(&source.name)
+            .map_or(SAMPLE_STALENESS_CAP, |&at| (now - at).clamp(0, SAMPLE_STALENESS_CAP));
+        let boost = 1.0 + (SAMPLE_STALENESS_BOOST - 1.0) * staleness as f64 / SAMPLE_STALENESS_CAP as f64;
+        let weight = source.weight() * boost;
+        let draw = xxhash_rust::xxh3::xxh3_64_with_seed(source.name.as_bytes(), seed);
+        // Never quite 0, so that every weight tells the draws apart
+        let uniform = (draw as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+        std::cmp::Reverse(uniform.powf(1.0 / weight).to_bits())
+    });
+    sources.truncate(count);
+    sources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(names: &[&str]) -> Vec<SourceSpec> {
+        names.iter().map(|&name| SourceSpec::new(name)).collect()
+    }
+
+    fn names(sources: &[SourceSpec]) -> Vec<&str> {
+        sources.iter().map(|source| source.name.as_str()).collect()
+    }
+
+    #[test]
+    fn the_same_seed_samples_the_same_sources() {
+        let all = sources(&["a", "b", "c", "d", "e", "f"]);
+        let first = sample(all.clone(), 3, &HashMap::new(), 0, 42);
+        assert_eq!(first.len(), 3);
+        assert_eq!(sample(all.clone(), 3, &HashMap::new(), 0, 42), first);
+
+        // Other seeds don't all pick the same ones
+        assert!((0..20).any(|seed| sample(all.clone(), 3, &HashMap::new(), 0, seed) != first));
+    }
+
+    #[test]
+    fn sources_left_out_longer_and_weighed_more_come_up_more_often() {
+        let now = 10 * SAMPLE_STALENESS_CAP;
+        let mut all = sources(&["fresh", "stale", "heavy", "light"]);
+        all[2].options.insert("weight".to_owned(), "8".to_owned());
+        all[3].options.insert("weight".to_owned(), "0.125".to_owned());
+        let last_sampled = HashMap::from([
+            ("fresh".to_owned(), now),
+            ("stale".to_owned(), now - SAMPLE_STALENESS_CAP),
+            ("heavy".to_owned(), now),
+            ("light".to_owned(), now),
+        ]);
+
+        let mut counts = HashMap::new();
+        for seed in 0..1000 {
+            for source in sample(all.clone(), 1, &last_sampled, now, seed) {
+                *counts.entry(source.name).or_insert(0) += 1;
+            }
+        }
+        let count = |name: &str| counts.get(name).copied().unwrap_or(0);
+        assert!(count("stale") > 5 * count("fresh"), "{:?}", counts);
+        assert!(count("heavy") > 3 * count("fresh"), "{:?}", counts);
+        assert!(count("fresh") > count("light"), "{:?}", counts);
+    }
+
+    #[test]
+    fn sampling_keeps_every_source_when_there_are_few_enough() {
+        let sampled = sample(sources(&["a", "b"]), 5, &HashMap::new(), 0, 7);
+        let mut sampled = names(&sampled);
+        sampled.sort_unstable();
+        assert_eq!(sampled, ["a", "b"]);
+    }
+
+    #[test]
+    fn weights_have_to_be_positive_numbers() {
+        assert_eq!(parse("EarthPorn weight=2").unwrap()[0].weight(), 2.0);
+        assert_eq!(parse("EarthPorn").unwrap()[0].weight(), 1.0);
+        for weight in ["0", "-1", "heavy", "inf", "NaN"] {
+            assert!(parse(&format!("EarthPorn weight={weight}")).is_err(), "{}", weight);
+        }
+    }
+
+    #[test]
+    fn links_become_the_sources_they_stand_for() {
+        for (link, expected) in [
+            ("https://www.reddit.com/r/EarthPorn/", "EarthPorn"),
+            ("https://reddit.com/r/EarthPorn", "EarthPorn"),
+            ("http://old.reddit.com/r/EarthPorn/", "EarthPorn"),
+            ("https://np.reddit.com/r/wallpapers/#comments", "wallpapers"),
+            ("https://WWW.Reddit.com/r/EarthPorn/top/?t=week", "EarthPorn:top:week"),
+            (
+                "https://old.reddit.com/r/EarthPorn/top/?sort=top&t=all",
+                "EarthPorn:top:all",
+            ),
+            ("https://www.reddit.com/r/EarthPorn/top/", "EarthPorn:top"),
+            ("https://www.reddit.com/r/EarthPorn/top/?t=forever", "EarthPorn:top"),
+            ("https://m.reddit.com/r/EarthPorn/new/?t=week", "EarthPorn:new"),
+            (
+                "https://www.reddit.com/r/EarthPorn/comments/abc123/a_mountain/",
+                "EarthPorn",
+            ),
+            ("https://www.reddit.com/user/Someone/", "u_Someone"),
+            ("https://www.reddit.com/u/Someone/submitted/", "u_Someone"),
+        ] {
+            assert_eq!(from_url(link), Some(Ok(expected.to_owned())), "{}", link);
+        }
+    }
+
+    #[test]
+    fn only_links_to_subreddits_or_users_are_sources() {
+        assert_eq!(from_url("EarthPorn"), None);
+        assert_eq!(from_url("/r/EarthPorn"), None);
+        for link in [
+            "https://imgur.com/r/EarthPorn",
+            "https://notreddit.com/r/EarthPorn",
+            "https://www.reddit.com/",
+            "https://www.reddit.com/r/",
+            "https://www.reddit.com/comments/abc123/",
+        ] {
+            assert!(matches!(from_url(link), Some(Err(_))), "{}", link);
+        }
+
+        // What's left of a link still goes through the same checks as a name
+        assert_eq!(
+            parse("https://www.reddit.com/r/EarthPorn/top/?t=week").unwrap()[0].name,
+            "earthporn"
+        );
+        assert_eq!(
+            parse("https://www.reddit.com/r/a/\nhttps://imgur.com/r/EarthPorn").unwrap(),
+            []
+        );
+    }
+
+    #[test]
+    fn sorts_are_new_hot_or_top_over_a_known_window() {
+        assert_eq!(Sort::parse("new"), Some(Sort::New));
+        assert_eq!(Sort::parse("hot"), Some(Sort::Hot));
+        assert_eq!(Sort::parse("top"), Some(Sort::Top(None)));
+        assert_eq!(Sort::parse("top:week"), Some(Sort::Top(Some("week"))));
+        for invalid in ["", "Top", "rising", "top:", "top:decade", "new:week", "top:week:all"] {
+            assert_eq!(Sort::parse(invalid), None, "{}", invalid);
+        }
+
+        assert_eq!(
+            (Sort::Top(Some("all")).path(), Sort::Top(Some("all")).window()),
+            ("top", Some("all"))
+        );
+        assert_eq!((Sort::Hot.path(), Sort::Hot.window()), ("hot", None));
+    }
+
+    #[test]
+    fn sorts_follow_the_subreddits_name() {
+        assert_eq!(split_sort("wallpapers"), Ok(("wallpapers", None)));
+        assert_eq!(split_sort("wallpapers:new"), Ok(("wallpapers", Some("new"))));
+        assert_eq!(
+            split_sort("wallpapers:top:month"),
+            Ok(("wallpapers", Some("top:month")))
+        );
+        assert!(split_sort("wallpapers:top:decade").is_err());
+        assert!(split_sort("wallpapers:").is_err());
+
+        let sources = parse("wallpapers:top:week\nEarthPorn sort=new\nSkyPorn").unwrap();
+        let sorts = sources.iter().map(SourceSpec::sort).collect::<Vec<_>>();
+        assert_eq!(sorts, [Sort::Top(Some("week")), Sort::New, Sort::default()]);
+        assert!(parse("EarthPorn sort=rising").is_err());
+    }
+
+    #[test]
+    fn boms_line_endings_and_comments_are_looked_past() {
+        let contents =
+            "\u{feff}# My subreddits\r\nEarthPorn weight=2 # the best one\r\n\r\n  # SkyPorn\r\nwallpapers#mine\r\n";
+        let sources = parse(contents).unwrap();
+        assert_eq!(names(&sources), ["earthporn", "wallpapers"]);
+        assert_eq!(sources[0].weight(), 2.0);
+    }
+
+    #[test]
+    fn pasted_links_keep_their_fragment_out_of_the_comment() {
+        let sources = parse("https://www.reddit.com/r/EarthPorn/#top sort=new # comment").unwrap();
+        assert_eq!(names(&sources), ["earthporn"]);
+        assert_eq!(sources[0].sort(), Sort::New);
+    }
+
+    #[test]
+    fn errors_say_which_line_they_are_on() {
+        let error = |contents: &str| parse(contents).unwrap_err();
+        assert_eq!(
+            error("EarthPorn\r\n# comment\r\nwallpapers ratio"),
+            ParseError {
+                line: 3,
+                message: "expected key=value, got \"ratio\"".to_owned(),
+            }
+        );
+        assert_eq!(error("\u{feff}EarthPorn =2").line, 1);
+        assert_eq!(error("EarthPorn\n\nwallpapers min_score=lots").line, 3);
+        assert_eq!(error("EarthPorn\nwallpapers sort=new sort=hot").line, 2);
+        assert_eq!(
+            error("EarthPorn ratio=wide").to_string(),
+            "line 1: expected ratio=strict or ratio=any, got \"ratio=wide\""
+        );
+    }
+
+    #[test]
+    fn subreddit_and_user_names_are_normalized() {
+        for (name, expected) in [
+            ("EarthPorn", "earthporn"),
+            ("/r/EarthPorn/", "earthporn"),
+            ("r/EarthPorn", "earthporn"),
+            ("EarthPorn//", "earthporn"),
+            ("de", "de"),
+            ("/u/Some-One/", "u_some-one"),
+            ("user/Someone", "u_someone"),
+            ("u_Someone", "u_someone"),
+        ] {
+            assert_eq!(normalize_name(name), Ok(expected.to_owned()), "{}", name);
+        }
+
+        for (name, complaint) in [
+            ("a", "between 2 and 21 characters"),
+            ("/r/", "invalid characters"),
+            ("abcdefghijklmnopqrstuv", "between 2 and 21 characters"),
+            ("earth porn", "invalid characters"),
+            ("earth.porn", "invalid characters"),
+            ("earth-porn", "invalid characters"),
+            ("/u/ab", "between 3 and 20 characters"),
+            ("u_abcdefghijklmnopqrstu", "between 3 and 20 characters"),
+            ("/u/some.one", "invalid characters"),
+            ("user/some one", "invalid characters"),
+        ] {
+            let error = normalize_name(name).unwrap_err();
+            assert!(error.contains(complaint), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn the_same_subreddit_written_differently_is_only_kept_the_first_time() {
+        let sources = from_names(&[
+            "EarthPorn:top:week".to_owned(),
+            "wallpapers".to_owned(),
+            "/r/EarthPorn/".to_owned(),
+            "earthporn".to_owned(),
+            "https://www.reddit.com/r/EarthPorn/new/".to_owned(),
+            "/u/Someone".to_owned(),
+            "u_someone".to_owned(),
+        ]);
+        assert_eq!(names(&sources), ["earthporn", "wallpapers", "u_someone"]);
+        assert_eq!(sources[0].sort(), Sort::Top(Some("week")));
+
+        let sources = parse("wallpapers\n/r/EarthPorn/\nEarthPorn:hot\n").unwrap();
+        assert_eq!(names(&sources), ["wallpapers", "earthporn"]);
+        assert_eq!(sources[1].sort(), Sort::New);
+    }
+}
@@ -0,0 +1,183 @@
+//! Which subreddits actually end up as backgrounds, for `redditbg sources-report`.
+
+use std::fmt;
+
+use eyre::Result;
+use rusqlite::Connection;
+
+// How long a subreddit may go without any of its images being applied before we suggest dropping it
+const PRUNE_AFTER_DAYS: u32 = 30;
+
+/// What became of the images from one subreddit
+#[derive(Debug)]
+pub struct SourceStats {
+    pub subreddit: String,
+    pub downloaded: usize,
+    /// How many different images were applied, however many times each
+    pub applied: usize,
+    /// How many were applied in the last `PRUNE_AFTER_DAYS` days
+    pub applied_recently: usize,
+}
+
+impl SourceStats {
+    /// How many of the images we downloaded were applied, as a fraction.
+    pub fn ratio(&self) -> f64 {
+        if self.downloaded == 0 {
+            0.0
+        } else {
+            self.applied as f64 / self.downloaded as f64
+        }
+    }
+
+    pub fn prune_candidate(&self) -> bool {
+        self.applied_recently == 0
+    }
+}
+
+/// Every subreddit we've downloaded from or applied images of, best first
+#[derive(Debug)]
+pub struct SourcesReport(pub Vec<SourceStats>);
+
+impl SourcesReport {
+    /// Join the images' sources with the applied history, subreddit by subreddit.
+    pub fn query(db: &Connection) -> Result<Self> {
+        let mut stats = db
+            .prepare(
+                "WITH Downloaded AS (
+                    SELECT lower(subreddit) AS subreddit, COUNT(*) AS count FROM ImageSources GROUP BY 1
+                ), Applied AS (
+                    -- Archived images come back around, so each one only counts once
+                    SELECT lower(subreddit) AS subreddit, COUNT(DISTINCT image) AS count,
+                           COUNT(DISTINCT CASE WHEN timestamp >= datetime('now', ?) THEN image END) AS recent
+                    FROM (SELECT *, COALESCE(url, hex(image_hash)) AS image FROM AppliedHistory)
+                    WHERE subreddit IS NOT NULL GROUP BY 1
+                ), Subreddits AS (
+                    SELECT subreddit FROM Downloaded UNION SELECT subreddit FROM Applied
+                )
+                SELECT Subreddits.subreddit, COALESCE(Downloaded.count, 0),
+                       COALESCE(Applied.count, 0), COALESCE(Applied.recent, 0)
+                FROM Subreddits
+                LEFT JOIN Downloaded USING (subreddit)
+                LEFT JOIN Applied USING (subreddit)",
+            )?
+            .query_map([format!("-{PRUNE_AFTER_DAYS} days")], |row| {
+                Ok(SourceStats {
+                    subreddit: row.get(0)?,
+                    downloaded: row.get(1)?,
+                    applied: row.get(2)?,
+                    applied_recently: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        stats.sort_by(|a, b| {
+            b.ratio()
+                .total_cmp(&a.ratio())
+                .then_with(|| b.applied.cmp(&a.applied))
+                .then_with(|| a.subreddit.cmp(&b.subreddit))
+        });
+        Ok(Self(stats))
+    }
+}
+
+impl fmt::Display for SourcesReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return writeln!(f, "Nothing downloaded yet");
+        }
+
+        writeln!(
+            f,
+            "{:<24} {:>10} {:>8} {:>7}",
+            "subreddit", "downloaded", "applied", "ratio"
+        )?;
+        for stats in &self.0 {
+            writeln!(
+                f,
+                "{:<24} {:>10} {:>8} {:>6.0}%",
+                format!("r/{}", stats.subreddit),
+                stats.downloaded,
+                stats.applied,
+                stats.ratio() * 100.0
+            )?;
+        }
+
+        let prune = self
+            .0
+            .iter()
+            .filter(|stats| stats.prune_candidate())
+            .map(|stats| format!("r/{}", stats.subreddit))
+            .collect::<Vec<_>>();
+        if !prune.is_empty() {
+            writeln!(
+                f,
+                "\nNothing applied in the last {PRUNE_AFTER_DAYS} days from: {}",
+                prune.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the report for `redditbg sources-report`.
+pub fn run() -> Result<()> {
+    match crate::db::open_read_only()? {
+        Some(db) => print!("{}", SourcesReport::query(&db)?),
+        None => println!("Nothing downloaded yet"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_applied_again_only_count_once() {
+        let mut db = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut db).unwrap();
+        for (url, subreddit) in [("a", "wallpapers"), ("b", "wallpapers"), ("c", "EarthPorn")] {
+            db.execute(
+                "INSERT INTO ImageSources(url, subreddit) VALUES (?, ?)",
+                [format!("https://i.redd.it/{url}.png"), subreddit.to_owned()],
+            )
+            .unwrap();
+        }
+
+        // The archive put the same image back up three times, once long ago
+        for (url, subreddit, days_ago) in [
+            ("a", "wallpapers", 1),
+            ("a", "wallpapers", 10),
+            ("a", "wallpapers", 60),
+            ("c", "EarthPorn", 60),
+        ] {
+            db.execute(
+                "INSERT INTO AppliedHistory(image_hash, url, subreddit, timestamp)
+                 VALUES (x'00', ?, ?, datetime('now', ?))",
+                [
+                    format!("https://i.redd.it/{url}.png"),
+                    subreddit.to_owned(),
+                    format!("-{days_ago} days"),
+                ],
+            )
+            .unwrap();
+        }
+
+        let report = SourcesReport::query(&db).unwrap();
+        let stats = |name: &str| report.0.iter().find(|stats| stats.subreddit == name).unwrap();
+        let wallpapers = stats("wallpapers");
+        assert_eq!(
+            (wallpapers.downloaded, wallpapers.applied, wallpapers.applied_recently),
+            (2, 1, 1)
+        );
+        assert_eq!(wallpapers.ratio(), 0.5);
+
+        let earthporn = stats("earthporn");
+        assert_eq!(
+            (earthporn.downloaded, earthporn.applied, earthporn.applied_recently),
+            (1, 1, 0)
+        );
+        assert!(earthporn.prune_candidate());
+        assert_eq!(report.0[0].subreddit, "earthporn");
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    fmt, fs, io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use eyre::{Result, WrapErr};
+use rusqlite::Connection;
+
+use crate::{
+    db::{self, AppliedImagesRepo, BandwidthRepo},
+    fetcher, platform,
+    utils::{format_bytes, format_duration},
+};
+
+/// A snapshot of the state we've persisted across runs.
+#[derive(Debug)]
+pub struct Status {
+    pub cached_images: usize,
+    pub cache_bytes: u64,
+    pub free_bytes: Option<u64>,
+    pub applied_images: usize,
+    pub downloaded_today: u64,
+    pub last_applied: Option<SystemTime>,
+    pub next_change: Option<Duration>,
+}
+
+impl Status {
+    /// Gather our status from the images directory, the database and the current background.
+    ///
+    /// Anything that doesn't exist yet (e.g. on first run) is simply treated as empty.
+    pub fn gather(profile: &str, next_change: Option<Duration>) -> Result<Self> {
+        // Not knowing how much space is free shouldn't keep us from reporting everything else
+        let free_bytes = platform::free_disk_space(crate::paths::root()).ok();
+        let db = db::open_read_only()?;
+        Self::gather_from(
+            &fetcher::images_dir(profile),
+            db.as_ref(),
+            &crate::background_path(),
+            free_bytes,
+            next_change,
+        )
+    }
+
+    /// Gather our status from the given images directory, database and background, none of which need exist yet.
+    fn gather_from(
+        images: &Path,
+        db: Option<&Connection>,
+        background: &Path,
+        free_bytes: Option<u64>,
+        next_change: Option<Duration>,
+    ) -> Result<Self> {
+        let cached_images = match images.read_dir() {
+            Ok(entries) => entries.count(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error).wrap_err("Could not read images directory"),
+        };
+        let cache_bytes = match fetcher::cache_size(images) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(error).wrap_err("Could not measure images directory"),
+        };
+
+        // The tables are only created once we first pick or fetch an image, so their absence just means zero.
+        let (applied_images, downloaded_today) = match db {
+            Some(db) => (
+                AppliedImagesRepo::new(db).count().unwrap_or(0),
+                BandwidthRepo::new(db).today().map_or(0, |(_, bytes)| bytes),
+            ),
+            None => (0, 0),
+        };
+
+        let last_applied = match fs::metadata(background) {
+            Ok(metadata) => Some(metadata.modified()?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error).wrap_err("Could not stat background"),
+        };
+
+        Ok(Self {
+            cached_images,
+            cache_bytes,
+            free_bytes,
+            applied_images,
+            downloaded_today,
+            last_applied,
+            next_change,
+        })
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} cached images ({}), {} applied so far, {} downloaded today",
+            self.cached_images,
+            format_bytes(self.cache_bytes),
+            self.applied_images,
+            format_bytes(self.downloaded_today)
+        )?;
+
+        if let Some(free_bytes) = self.free_bytes {
+            write!(f, ", {} free on disk", format_bytes(free_bytes))?;
+        }
+
+        match self.last_applied.and_then(|time| time.elapsed().ok()) {
+            Some(elapsed) => write!(f, ", last wallpaper set {} ago", format_duration(elapsed))?,
+            None => write!(f, ", no wallpaper set yet")?,
+        }
+
+        if let Some(next_change) = self.next_change {
+            write!(f, ", next change in {}", format_duration(next_change))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_runs_have_nothing_to_report() {
+        let root = tempfile::tempdir().unwrap();
+        let status = Status::gather_from(
+            &root.path().join("images"),
+            None,
+            &root.path().join("background.png"),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            (status.cached_images, status.cache_bytes, status.applied_images),
+            (0, 0, 0)
+        );
+        assert_eq!((status.downloaded_today, status.last_applied), (0, None));
+        assert_eq!(
+            status.to_string(),
+            "0 cached images (0 B), 0 applied so far, 0 B downloaded today, no wallpaper set yet"
+        );
+    }
+
+    #[test]
+    fn status_is_gathered_from_the_data_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let images = root.path().join("images");
+        fs::create_dir_all(&images).unwrap();
+        fs::write(images.join("a.png"), [0; 1500]).unwrap();
+        fs::write(images.join("b.jpeg"), [0; 500]).unwrap();
+        let background = root.path().join("background.png");
+        fs::write(&background, [0; 10]).unwrap();
+
+        let mut db = Connection::open_in_memory().unwrap();
+        db::migrate(&mut db).unwrap();
+        let applied = AppliedImagesRepo::new(&db);
+        applied.insert(b"first", None, None, None).unwrap();
+        applied.insert(b"second", None, None, None).unwrap();
+        applied.insert(b"first", None, None, None).unwrap();
+        BandwidthRepo::new(&db).record(2_500_000).unwrap();
+        BandwidthRepo::new(&db).record_on("2000-01-01", 1_000_000).unwrap();
+
+        let next_change = Some(Duration::from_secs(60 * 60));
+        let status = Status::gather_from(&images, Some(&db), &background, Some(5_000_000_000), next_change).unwrap();
+        assert_eq!((status.cached_images, status.cache_bytes), (2, 2000));
+        assert_eq!((status.applied_images, status.downloaded_today), (2, 2_500_000));
+        assert!(status.last_applied.unwrap().elapsed().unwrap() < Duration::from_secs(60));
+        assert_eq!(status.free_bytes, Some(5_000_000_000));
+        assert_eq!(status.next_change, next_change);
+        assert!(
+            status.to_string().starts_with(
+                "2 cached images (2.0 KB), 2 applied so far, 2.5 MB downloaded today, 5.0 GB free on disk"
+            ),
+            "{}",
+            status
+        );
+    }
+}
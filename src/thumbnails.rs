@@ -0,0 +1,239 @@
+//! Small JPEG versions of our images, made on demand and kept around so that showing them doesn't mean decoding a 4K
+//! image every time.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use eyre::{Result, WrapErr};
+use image::ImageFormat;
+use tracing::{debug, trace};
+
+use crate::DIRS;
+
+/// How big thumbnails are along their longest side
+pub const SIZE: u32 = 320;
+
+/// How much space thumbnails may take up before the least recently used ones are evicted
+const MAX_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Get the directory the thumbnails of the given profile's images are cached in.
+pub fn dir(profile: &str) -> PathBuf {
+    DIRS.cache_dir().join("thumbnails").join(profile)
+}
+
+/// Get the thumbnail for the image at `path`, making it if we don't have one yet or if the one we have is corrupted.
+///
+/// Thumbnails are keyed by the image's contents, so a file that's been replaced never gets a stale one.
+#[tracing::instrument]
+pub fn get_or_create(profile: &str, path: &Path) -> Result<PathBuf> {
+    get_or_create_in(&dir(profile), path, MAX_BYTES)
+}
+
+fn get_or_create_in(dir: &Path, path: &Path, max_bytes: u64) -> Result<PathBuf> {
+    let body = fs::read(path).wrap_err("Could not read image")?;
+    let thumbnail = dir.join(format!("{:016x}.jpg", xxhash_rust::xxh3::xxh3_64(&body)));
+
+    // Thumbnails are small enough to decode whole, which unlike reading the header catches one that was cut short
+    match image::open(&thumbnail) {
+        Ok(_) => {
+            trace!("thumbnail hit");
+            touch(&thumbnail)?;
+            return Ok(thumbnail);
+        }
+        Err(image::ImageError::IoError(error)) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => debug!(?error, "regenerating corrupted thumbnail"),
+    }
+
+    let img = image::load_from_memory(&body).wrap_err("Could not decode image")?;
+    fs::create_dir_all(dir)?;
+    // Written next to where it's going and then renamed into place, so that it's never seen half-written
+    let mut encoded = io::Cursor::new(Vec::new());
+    img.thumbnail(SIZE, SIZE)
+        .to_rgb8()
+        .write_to(&mut encoded, ImageFormat::Jpeg)
+        .wrap_err("Could not encode thumbnail")?;
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(encoded.get_ref())?;
+    file.persist(&thumbnail).wrap_err("Could not save thumbnail")?;
+
+    evict(dir, &thumbnail, max_bytes)?;
+    Ok(thumbnail)
+}
+
+/// Remove every thumbnail of the given profile's images not in `keep`, for when the images they were made from are
+/// gone.
+pub fn retain(profile: &str, keep: &HashSet<PathBuf>) -> Result<()> {
+    retain_in(&dir(profile), keep)?;
+
+    // Thumbnails from before they were kept per profile would otherwise never be cleaned up
+    for (path, _, _) in entries(&DIRS.cache_dir().join("thumbnails"))? {
+        if path.is_file() {
+            fs::remove_file(&path).wrap_err("Could not remove stale thumbnail")?;
+        }
+    }
+    Ok(())
+}
+
+fn retain_in(dir: &Path, keep: &HashSet<PathBuf>) -> Result<()> {
+    for (path, _, _) in entries(dir)? {
+        if !keep.contains(&path) {
+            fs::remove_file(&path).wrap_err("Could not remove stale thumbnail")?;
+        }
+    }
+    Ok(())
+}
+
+/// Mark a thumbnail as just used, as eviction goes by modification time.
+fn touch(path: &Path) -> Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .wrap_err("Could not touch thumbnail")
+}
+
+/// Evict the least recently used thumbnails until we're within `max_bytes`, sparing the one we just made.
+fn evict(dir: &Path, spare: &Path, max_bytes: u64) -> Result<()> {
+    let mut entries = entries(dir)?;
+    let mut total: u64 = entries.iter().map(|&(_, size, _)| size).sum();
+    entries.sort_by_key(|&(_, _, modified)| modified);
+
+    for (path, size, _) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        if path == spare {
+            continue;
+        }
+        debug!(path = %path.display(), "evicting thumbnail");
+        fs::remove_file(&path).wrap_err("Could not evict thumbnail")?;
+        total -= size;
+    }
+    Ok(())
+}
+
+/// List the thumbnails in `dir` along with their size and when they were last used.
+fn entries(dir: &Path) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let read_dir = match dir.read_dir() {
+        Ok(read_dir) => read_dir,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error).wrap_err("Could not read thumbnails directory"),
+    };
+
+    let mut entries = Vec::new();
+    for entry in read_dir {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        entries.push((entry.path(), metadata.len(), metadata.modified()?));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a gradient of the given size as a PNG, to make thumbnails of.
+    fn write_image(path: &Path, (width, height): (u32, u32), seed: u8) {
+        image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, seed])
+        })
+        .save_with_format(path, ImageFormat::Png)
+        .unwrap();
+    }
+
+    fn used_at(path: &Path, secs_ago: u64) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(SystemTime::now() - std::time::Duration::from_secs(secs_ago)))
+            .unwrap();
+    }
+
+    #[test]
+    fn thumbnails_are_made_once_and_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, thumbnails) = (dir.path().join("image.png"), dir.path().join("thumbnails"));
+        write_image(&source, (1280, 720), 0);
+
+        let thumbnail = get_or_create_in(&thumbnails, &source, MAX_BYTES).unwrap();
+        assert_eq!(image::image_dimensions(&thumbnail).unwrap(), (SIZE, 180));
+        used_at(&thumbnail, 60);
+        let modified = fs::metadata(&thumbnail).unwrap().modified().unwrap();
+
+        assert_eq!(get_or_create_in(&thumbnails, &source, MAX_BYTES).unwrap(), thumbnail);
+        // A hit counts as a use, but doesn't leave anything else behind
+        assert!(fs::metadata(&thumbnail).unwrap().modified().unwrap() > modified);
+        assert_eq!(entries(&thumbnails).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn truncated_thumbnails_are_made_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, thumbnails) = (dir.path().join("image.png"), dir.path().join("thumbnails"));
+        write_image(&source, (1280, 720), 0);
+
+        let thumbnail = get_or_create_in(&thumbnails, &source, MAX_BYTES).unwrap();
+        let whole = fs::read(&thumbnail).unwrap();
+        fs::write(&thumbnail, &whole[..whole.len() / 2]).unwrap();
+
+        assert_eq!(get_or_create_in(&thumbnails, &source, MAX_BYTES).unwrap(), thumbnail);
+        assert_eq!(fs::read(&thumbnail).unwrap(), whole);
+    }
+
+    #[test]
+    fn the_least_recently_used_thumbnails_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let thumbnails = dir.path().join("thumbnails");
+        let mut made = Vec::new();
+        for seed in 0..3 {
+            let source = dir.path().join(format!("{seed}.png"));
+            write_image(&source, (640, 360), seed);
+            let thumbnail = get_or_create_in(&thumbnails, &source, MAX_BYTES).unwrap();
+            used_at(&thumbnail, 60 * (3 - u64::from(seed)));
+            made.push(thumbnail);
+        }
+
+        // The budget is what the three take up, so the oldest has to go to make room for a fourth
+        let budget = entries(&thumbnails)
+            .unwrap()
+            .iter()
+            .map(|&(_, size, _)| size)
+            .sum::<u64>();
+        let source = dir.path().join("new.png");
+        write_image(&source, (640, 360), 3);
+        let new = get_or_create_in(&thumbnails, &source, budget).unwrap();
+
+        assert!(new.exists());
+        assert!(!made[0].exists());
+        assert!(made[1].exists() || made[2].exists());
+        let total = entries(&thumbnails)
+            .unwrap()
+            .iter()
+            .map(|&(_, size, _)| size)
+            .sum::<u64>();
+        assert!(total <= budget);
+    }
+
+    #[test]
+    fn retaining_one_profiles_thumbnails_spares_the_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ours, theirs) = (dir.path().join("default"), dir.path().join("other"));
+        let source = dir.path().join("image.png");
+        write_image(&source, (640, 360), 0);
+        let kept = get_or_create_in(&ours, &source, MAX_BYTES).unwrap();
+        let stale = ours.join("stale.jpg");
+        fs::write(&stale, "").unwrap();
+        let other = get_or_create_in(&theirs, &source, MAX_BYTES).unwrap();
+
+        retain_in(&ours, &HashSet::from([kept.clone()])).unwrap();
+        assert!(kept.exists());
+        assert!(!stale.exists());
+        assert!(other.exists());
+    }
+}
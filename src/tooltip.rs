@@ -0,0 +1,139 @@
+//! Throttling for tooltip updates, as rewriting the tray icon too often makes it flicker on some Windows builds.
+
+use std::time::{Duration, Instant};
+
+/// Coalesces tooltip updates so that at most one is applied per interval, with only the latest one surviving.
+#[derive(Debug)]
+pub struct Coalescer {
+    interval: Duration,
+    shown: Option<String>,
+    last_applied: Option<Instant>,
+    pending: Option<String>,
+}
+
+impl Coalescer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            shown: None,
+            last_applied: None,
+            pending: None,
+        }
+    }
+
+    /// Offer a new tooltip, returning it if it should be applied right away.
+    ///
+    /// Urgent updates, for state changes the user is waiting to see, skip the throttle. Anything else is held back
+    /// until [`Coalescer::poll`] says its time has come, replacing any update that was already waiting.
+    pub fn push(&mut self, tooltip: String, urgent: bool, now: Instant) -> Option<String> {
+        if self.shown.as_ref() == Some(&tooltip) {
+            self.pending = None;
+            return None;
+        }
+
+        let throttled = matches!(
+            self.last_applied,
+            Some(last_applied) if now.saturating_duration_since(last_applied) < self.interval
+        );
+        if urgent || !throttled {
+            return Some(self.applied(tooltip, now));
+        }
+
+        self.pending = Some(tooltip);
+        None
+    }
+
+    /// Take the update that's been held back, if there is one and it may be applied now.
+    pub fn poll(&mut self, now: Instant) -> Option<String> {
+        match self.deadline() {
+            Some(deadline) if deadline <= now => {
+                let tooltip = self.pending.take()?;
+                Some(self.applied(tooltip, now))
+            }
+            _ => None,
+        }
+    }
+
+    /// When the update that's being held back may be applied, if there is one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref()?;
+        Some(
+            self.last_applied
+                .map_or_else(Instant::now, |last_applied| last_applied + self.interval),
+        )
+    }
+
+    fn applied(&mut self, tooltip: String, now: Instant) -> String {
+        self.pending = None;
+        self.shown = Some(tooltip.clone());
+        self.last_applied = Some(now);
+        tooltip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(15);
+
+    #[test]
+    fn bursts_of_updates_are_coalesced_into_the_latest() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut coalescer = Coalescer::new(INTERVAL);
+
+        // The first update goes through, and the ones right after it wait for the interval to be up
+        assert_eq!(
+            coalescer.push("1 image".to_owned(), false, at(0)).as_deref(),
+            Some("1 image")
+        );
+        for (secs, tooltip) in [(1, "2 images"), (2, "3 images"), (3, "4 images")] {
+            assert_eq!(coalescer.push(tooltip.to_owned(), false, at(secs)), None);
+        }
+        assert_eq!(coalescer.deadline(), Some(at(15)));
+        assert_eq!(coalescer.poll(at(14)), None);
+
+        // Only the last of them is applied once it is
+        assert_eq!(coalescer.poll(at(15)).as_deref(), Some("4 images"));
+        assert_eq!(coalescer.poll(at(30)), None);
+        assert_eq!(coalescer.deadline(), None);
+
+        // Going back to what's shown drops what was waiting, as there's nothing left to update
+        assert_eq!(coalescer.push("5 images".to_owned(), false, at(16)), None);
+        assert_eq!(coalescer.push("4 images".to_owned(), false, at(17)), None);
+        assert_eq!(coalescer.deadline(), None);
+
+        // And updates that come slowly enough are never held back
+        assert_eq!(
+            coalescer.push("5 images".to_owned(), false, at(31)).as_deref(),
+            Some("5 images")
+        );
+        assert_eq!(
+            coalescer.push("6 images".to_owned(), false, at(46)).as_deref(),
+            Some("6 images")
+        );
+    }
+
+    #[test]
+    fn state_changes_go_through_right_away() {
+        let start = Instant::now();
+        let mut coalescer = Coalescer::new(INTERVAL);
+        assert_eq!(
+            coalescer.push("Fetching".to_owned(), true, start).as_deref(),
+            Some("Fetching")
+        );
+        assert_eq!(coalescer.push("1 image".to_owned(), false, start), None);
+
+        // An urgent update replaces the one that was waiting, and restarts the interval
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            coalescer.push("Paused".to_owned(), true, later).as_deref(),
+            Some("Paused")
+        );
+        assert_eq!(coalescer.deadline(), None);
+        assert_eq!(coalescer.push("Paused".to_owned(), true, later), None);
+        assert_eq!(coalescer.push("2 images".to_owned(), false, later), None);
+        assert_eq!(coalescer.deadline(), Some(later + INTERVAL));
+    }
+}
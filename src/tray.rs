@@ -0,0 +1,678 @@
+//! A minimal system tray icon with a popup menu.
+//!
+//! The tray lives on its own thread, as Win32 windows belong to the thread that created them; every other thread
+//! talks to it through a [`TrayHandle`].
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc::sync_channel,
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use eyre::{format_err, Result, WrapErr};
+use tracing::{debug, error, trace};
+
+use crate::tooltip::Coalescer;
+
+type Callback = Box<dyn FnMut(&TrayHandle, bool) + Send>;
+
+/// Identifies a menu item, so that it can be updated after the tray has been created
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ItemId(u16);
+
+// Menu item IDs must be unique across submenus, so we hand them out globally
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+// How often we're willing to rewrite the tooltip, unless something the user's waiting on changed
+const TOOLTIP_INTERVAL: Duration = Duration::from_secs(15);
+
+// The timer we use to apply tooltip updates that were held back
+const TOOLTIP_TIMER: usize = 1;
+
+#[derive(Clone, Copy)]
+enum ItemKind {
+    Plain,
+    Check(bool),
+    Radio { group: u16, checked: bool },
+}
+
+enum Entry {
+    Item {
+        id: ItemId,
+        label: String,
+        kind: ItemKind,
+        callback: Callback,
+    },
+    Submenu {
+        label: String,
+        menu: Menu,
+    },
+    Separator,
+}
+
+/// The contents of a (sub)menu
+#[derive(Default)]
+pub struct Menu {
+    entries: Vec<Entry>,
+}
+
+impl Menu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, label: &str, kind: ItemKind, callback: Callback) -> ItemId {
+        let id = ItemId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        self.entries.push(Entry::Item {
+            id,
+            label: label.to_owned(),
+            kind,
+            callback,
+        });
+        id
+    }
+
+    /// Add an item which calls `callback` when clicked.
+    pub fn item(&mut self, label: &str, mut callback: impl FnMut(&TrayHandle) + Send + 'static) -> ItemId {
+        self.push(label, ItemKind::Plain, Box::new(move |handle, _| callback(handle)))
+    }
+
+    /// Add a checkable item which toggles itself when clicked and calls `callback` with its new state.
+    pub fn check_item(
+        &mut self,
+        label: &str,
+        checked: bool,
+        callback: impl FnMut(&TrayHandle, bool) + Send + 'static,
+    ) -> ItemId {
+        self.push(label, ItemKind::Check(checked), Box::new(callback))
+    }
+
+    /// Add an item which, when clicked, becomes the only checked one among those with the same `group`.
+    pub fn radio_item(
+        &mut self,
+        label: &str,
+        group: u16,
+        checked: bool,
+        mut callback: impl FnMut(&TrayHandle) + Send + 'static,
+    ) -> ItemId {
+        self.push(
+            label,
+            ItemKind::Radio { group, checked },
+            Box::new(move |handle, _| callback(handle)),
+        )
+    }
+
+    pub fn submenu(&mut self, label: &str, menu: Menu) {
+        self.entries.push(Entry::Submenu {
+            label: label.to_owned(),
+            menu,
+        });
+    }
+
+    pub fn separator(&mut self) {
+        self.entries.push(Entry::Separator);
+    }
+}
+
+enum Command {
+    SetTooltip { tooltip: String, urgent: bool },
+    PrependItem(ItemId, String, Callback),
+    SetIcon(PathBuf),
+    Quit,
+}
+
+/// A way to update the tray from any thread
+#[derive(Clone)]
+pub struct TrayHandle {
+    // HWNDs aren't Send, but posting messages to them from other threads is fine
+    hwnd: usize,
+    commands: Arc<Mutex<VecDeque<Command>>>,
+}
+
+impl TrayHandle {
+    fn send(&self, command: Command) -> Result<()> {
+        use winapi::um::winuser::PostMessageW;
+
+        self.commands.lock().unwrap().push_back(command);
+        if unsafe { PostMessageW(self.hwnd as _, WM_APP_COMMAND, 0, 0) } == 0 {
+            return Err(io::Error::last_os_error()).wrap_err("Failed to post message to tray");
+        }
+        Ok(())
+    }
+
+    /// Update the tooltip eventually, skipping it if it's replaced before the throttle allows another update.
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<()> {
+        self.send(Command::SetTooltip {
+            tooltip: tooltip.to_owned(),
+            urgent: false,
+        })
+    }
+
+    /// Update the tooltip right away, for changes the user is waiting to see.
+    pub fn set_tooltip_now(&self, tooltip: &str) -> Result<()> {
+        self.send(Command::SetTooltip {
+            tooltip: tooltip.to_owned(),
+            urgent: true,
+        })
+    }
+
+    /// Add an item at the top of the menu, which calls `callback` when clicked.
+    pub fn prepend_item(&self, label: &str, mut callback: impl FnMut(&TrayHandle) + Send + 'static) -> Result<ItemId> {
+        let id = ItemId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        self.send(Command::PrependItem(
+            id,
+            label.to_owned(),
+            Box::new(move |handle, _| callback(handle)),
+        ))?;
+        Ok(id)
+    }
+
+    /// Replace the icon with the one in the file at `path`.
+    pub fn set_icon(&self, path: &Path) -> Result<()> {
+        self.send(Command::SetIcon(path.to_owned()))
+    }
+
+    /// Remove the tray icon and stop its thread.
+    pub fn quit(&self) -> Result<()> {
+        self.send(Command::Quit)
+    }
+}
+
+const WM_APP_TRAY: u32 = winapi::um::winuser::WM_APP + 1;
+const WM_APP_COMMAND: u32 = winapi::um::winuser::WM_APP + 2;
+
+struct ItemState {
+    kind: ItemKind,
+    callback: Option<Callback>,
+}
+
+struct TrayState {
+    handle: TrayHandle,
+    hmenu: winapi::shared::windef::HMENU,
+    icon: winapi::shared::windef::HICON,
+    tooltip: String,
+    tooltips: Coalescer,
+    items: HashMap<u16, ItemState>,
+    taskbar_created: u32,
+}
+
+thread_local!(static STATE: RefCell<Option<TrayState>> = const { RefCell::new(None) });
+
+fn to_wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+fn notify_icon_data(state: &TrayState) -> winapi::um::shellapi::NOTIFYICONDATAW {
+    use winapi::um::shellapi::{NIF_ICON, NIF_MESSAGE, NIF_TIP, NOTIFYICONDATAW};
+
+    let mut nid: NOTIFYICONDATAW = unsafe { std::mem::zeroed() };
+    nid.cbSize = std::mem::size_of::<NOTIFYICONDATAW>() as u32;
+    nid.hWnd = state.handle.hwnd as _;
+    nid.uID = 1;
+    nid.uFlags = NIF_MESSAGE | NIF_TIP | NIF_ICON;
+    nid.uCallbackMessage = WM_APP_TRAY;
+    nid.hIcon = state.icon;
+
+    // The tooltip has a fixed size buffer and must be null-terminated
+    for (dst, src) in nid.szTip.iter_mut().zip(state.tooltip.encode_utf16().take(127)) {
+        *dst = src;
+    }
+
+    nid
+}
+
+fn load_icon(path: &Path) -> Result<winapi::shared::windef::HICON> {
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::um::winuser::{LoadImageW, IMAGE_ICON, LR_DEFAULTSIZE, LR_LOADFROMFILE};
+
+    let path_utf16 = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let icon = unsafe {
+        LoadImageW(
+            std::ptr::null_mut(),
+            path_utf16.as_ptr(),
+            IMAGE_ICON,
+            0,
+            0,
+            LR_LOADFROMFILE | LR_DEFAULTSIZE,
+        )
+    };
+    if icon.is_null() {
+        return Err(io::Error::last_os_error()).wrap_err_with(|| format!("Failed to load icon {path:?}"));
+    }
+    Ok(icon.cast())
+}
+
+fn build_menu(menu: Menu, items: &mut HashMap<u16, ItemState>) -> Result<winapi::shared::windef::HMENU> {
+    use winapi::um::winuser::{AppendMenuW, CreatePopupMenu, MF_CHECKED, MF_POPUP, MF_SEPARATOR, MF_STRING};
+
+    let hmenu = unsafe { CreatePopupMenu() };
+    if hmenu.is_null() {
+        return Err(io::Error::last_os_error()).wrap_err("Failed to create menu");
+    }
+
+    for entry in menu.entries {
+        let appended = match entry {
+            Entry::Item {
+                id,
+                label,
+                kind,
+                callback,
+            } => {
+                let flags = match kind {
+                    ItemKind::Check(true) | ItemKind::Radio { checked: true, .. } => MF_STRING | MF_CHECKED,
+                    _ => MF_STRING,
+                };
+                items.insert(
+                    id.0,
+                    ItemState {
+                        kind,
+                        callback: Some(callback),
+                    },
+                );
+                unsafe { AppendMenuW(hmenu, flags, usize::from(id.0), to_wide(&label).as_ptr()) }
+            }
+
+            Entry::Submenu { label, menu } => {
+                let submenu = build_menu(menu, items)?;
+                unsafe { AppendMenuW(hmenu, MF_POPUP, submenu as usize, to_wide(&label).as_ptr()) }
+            }
+
+            Entry::Separator => unsafe { AppendMenuW(hmenu, MF_SEPARATOR, 0, std::ptr::null()) },
+        };
+
+        if appended == 0 {
+            return Err(io::Error::last_os_error()).wrap_err("Failed to append menu item");
+        }
+    }
+
+    Ok(hmenu)
+}
+
+fn set_checked(state: &mut TrayState, id: u16, checked: bool) {
+    use winapi::um::winuser::{CheckMenuItem, MF_BYCOMMAND, MF_CHECKED, MF_UNCHECKED};
+
+    if let Some(item) = state.items.get_mut(&id) {
+        match item.kind {
+            ItemKind::Check(ref mut current)
+            | ItemKind::Radio {
+                checked: ref mut current,
+                ..
+            } => *current = checked,
+            ItemKind::Plain => {}
+        }
+    }
+
+    let flags = MF_BYCOMMAND | if checked { MF_CHECKED } else { MF_UNCHECKED };
+    unsafe { CheckMenuItem(state.hmenu, u32::from(id), flags) };
+}
+
+fn show_tooltip(state: &mut TrayState, tooltip: String) -> Result<()> {
+    use winapi::um::shellapi::{Shell_NotifyIconW, NIM_MODIFY};
+
+    trace!(?tooltip, "showing tooltip");
+    state.tooltip = tooltip;
+    let mut nid = notify_icon_data(state);
+    if unsafe { Shell_NotifyIconW(NIM_MODIFY, &mut nid) } == 0 {
+        return Err(format_err!("Failed to set tooltip"));
+    }
+    Ok(())
+}
+
+/// Make sure we wake up in time to apply the tooltip update that's being held back, if there is one.
+fn schedule_tooltip(state: &TrayState) {
+    use std::convert::TryFrom;
+    use winapi::um::winuser::{KillTimer, SetTimer};
+
+    let hwnd = state.handle.hwnd as _;
+    match state.tooltips.deadline() {
+        Some(deadline) => {
+            let delay = deadline.saturating_duration_since(Instant::now());
+            let millis = u32::try_from(delay.as_millis()).unwrap_or(u32::MAX).max(1);
+            unsafe { SetTimer(hwnd, TOOLTIP_TIMER, millis, None) };
+        }
+        None => {
+            unsafe { KillTimer(hwnd, TOOLTIP_TIMER) };
+        }
+    }
+}
+
+/// Carry out a command, apart from quitting, which has to happen without the state borrowed.
+fn apply(state: &mut TrayState, command: Command) -> Result<()> {
+    use winapi::um::winuser::{InsertMenuW, MF_BYPOSITION, MF_STRING};
+
+    match command {
+        Command::SetTooltip { tooltip, urgent } => {
+            if let Some(tooltip) = state.tooltips.push(tooltip, urgent, Instant::now()) {
+                show_tooltip(state, tooltip)?;
+            }
+            schedule_tooltip(state);
+        }
+
+        Command::PrependItem(id, label, callback) => {
+            if unsafe {
+                InsertMenuW(
+                    state.hmenu,
+                    0,
+                    MF_BYPOSITION | MF_STRING,
+                    usize::from(id.0),
+                    to_wide(&label).as_ptr(),
+                )
+            } == 0
+            {
+                return Err(io::Error::last_os_error()).wrap_err("Failed to insert menu item");
+            }
+            state.items.insert(
+                id.0,
+                ItemState {
+                    kind: ItemKind::Plain,
+                    callback: Some(callback),
+                },
+            );
+        }
+
+        Command::SetIcon(path) => {
+            use winapi::um::{
+                shellapi::{Shell_NotifyIconW, NIM_MODIFY},
+                winuser::DestroyIcon,
+            };
+
+            let old = std::mem::replace(&mut state.icon, load_icon(&path)?);
+            let mut nid = notify_icon_data(state);
+            if unsafe { Shell_NotifyIconW(NIM_MODIFY, &mut nid) } == 0 {
+                return Err(format_err!("Failed to set icon"));
+            }
+            unsafe { DestroyIcon(old) };
+        }
+
+        Command::Quit => unreachable!("quitting is handled by the window procedure"),
+    }
+
+    Ok(())
+}
+
+fn on_click(id: u16) {
+    // Take the callback out while it runs so that it's free to use the tray itself
+    let taken = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut()?;
+
+        let (kind, callback) = {
+            let item = state.items.get_mut(&id)?;
+            (item.kind, item.callback.take()?)
+        };
+
+        // Check items toggle themselves, radio items uncheck the rest of their group
+        let checked = match kind {
+            ItemKind::Plain => false,
+            ItemKind::Check(checked) => {
+                set_checked(state, id, !checked);
+                !checked
+            }
+            ItemKind::Radio { group, .. } => {
+                let siblings = state
+                    .items
+                    .iter()
+                    .filter(|(_, item)| matches!(item.kind, ItemKind::Radio { group: g, .. } if g == group))
+                    .map(|(&sibling, _)| sibling)
+                    .collect::<Vec<_>>();
+                for sibling in siblings {
+                    set_checked(state, sibling, sibling == id);
+                }
+                true
+            }
+        };
+
+        Some((state.handle.clone(), callback, checked))
+    });
+
+    if let Some((handle, mut callback, checked)) = taken {
+        trace!(id, checked, "menu item clicked");
+        callback(&handle, checked);
+        STATE.with(|state| {
+            if let Some(item) = state.borrow_mut().as_mut().and_then(|state| state.items.get_mut(&id)) {
+                item.callback = Some(callback);
+            }
+        });
+    }
+}
+
+unsafe extern "system" fn window_proc(
+    hwnd: winapi::shared::windef::HWND,
+    msg: u32,
+    wparam: usize,
+    lparam: isize,
+) -> isize {
+    use winapi::{
+        shared::{minwindef::LOWORD, windef::POINT},
+        um::{
+            shellapi::{Shell_NotifyIconW, NIM_ADD, NIM_DELETE},
+            winuser::{
+                DefWindowProcW, DestroyWindow, GetCursorPos, PostMessageW, PostQuitMessage, SetForegroundWindow,
+                TrackPopupMenu, TPM_BOTTOMALIGN, TPM_LEFTALIGN, WM_COMMAND, WM_DESTROY, WM_LBUTTONUP, WM_NULL,
+                WM_RBUTTONUP, WM_TIMER,
+            },
+        },
+    };
+
+    match msg {
+        WM_APP_TRAY if matches!(lparam as u32, WM_LBUTTONUP | WM_RBUTTONUP) => {
+            // The menu runs a modal loop that dispatches our other messages, so the state mustn't stay borrowed
+            let hmenu = STATE.with(|state| state.borrow().as_ref().map(|state| state.hmenu));
+            let mut point = POINT { x: 0, y: 0 };
+            if let (Some(hmenu), true) = (hmenu, GetCursorPos(&mut point) != 0) {
+                // Without this dance the menu doesn't go away when clicking elsewhere
+                SetForegroundWindow(hwnd);
+                TrackPopupMenu(
+                    hmenu,
+                    TPM_BOTTOMALIGN | TPM_LEFTALIGN,
+                    point.x,
+                    point.y,
+                    0,
+                    hwnd,
+                    std::ptr::null(),
+                );
+                PostMessageW(hwnd, WM_NULL, 0, 0);
+            }
+            0
+        }
+
+        WM_COMMAND => {
+            on_click(LOWORD(wparam as u32));
+            0
+        }
+
+        WM_APP_COMMAND => {
+            let Some(commands) = STATE.with(|state| state.borrow().as_ref().map(|state| state.handle.commands.clone()))
+            else {
+                return 0;
+            };
+            loop {
+                let command = commands.lock().unwrap().pop_front();
+                match command {
+                    None => break,
+
+                    // Destroying the window sends it WM_DESTROY right away, which needs the state for itself
+                    Some(Command::Quit) => {
+                        if DestroyWindow(hwnd) == 0 {
+                            let error = eyre::Report::from(io::Error::last_os_error());
+                            error!(?error, "could not destroy tray window");
+                        }
+                        break;
+                    }
+
+                    Some(command) => STATE.with(|state| {
+                        if let Some(ref mut state) = *state.borrow_mut() {
+                            if let Err(error) = apply(state, command) {
+                                error!(?error, "could not update tray");
+                            }
+                        }
+                    }),
+                }
+            }
+            0
+        }
+
+        WM_TIMER if wparam == TOOLTIP_TIMER => {
+            STATE.with(|state| {
+                if let Some(ref mut state) = *state.borrow_mut() {
+                    if let Some(tooltip) = state.tooltips.poll(Instant::now()) {
+                        if let Err(error) = show_tooltip(state, tooltip) {
+                            error!(?error, "could not update tray");
+                        }
+                    }
+                    schedule_tooltip(state);
+                }
+            });
+            0
+        }
+
+        WM_DESTROY => {
+            STATE.with(|state| {
+                if let Some(ref state) = *state.borrow() {
+                    let mut nid = notify_icon_data(state);
+                    Shell_NotifyIconW(NIM_DELETE, &mut nid);
+                }
+            });
+            PostQuitMessage(0);
+            0
+        }
+
+        // Explorer restarted, so our icon is gone and needs to be added back
+        _ if msg != 0
+            && STATE.with(|state| state.borrow().as_ref().map(|state| state.taskbar_created)) == Some(msg) =>
+        {
+            debug!("taskbar recreated, re-adding tray icon");
+            STATE.with(|state| {
+                if let Some(ref state) = *state.borrow() {
+                    let mut nid = notify_icon_data(state);
+                    Shell_NotifyIconW(NIM_ADD, &mut nid);
+                }
+            });
+            0
+        }
+
+        _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+}
+
+fn create_window() -> Result<winapi::shared::windef::HWND> {
+    use winapi::um::{
+        libloaderapi::GetModuleHandleW,
+        winuser::{CreateWindowExW, RegisterClassW, CW_USEDEFAULT, WNDCLASSW, WS_OVERLAPPEDWINDOW},
+    };
+
+    let class_name = to_wide(concat!(env!("CARGO_PKG_NAME"), "_tray"));
+    let hinstance = unsafe { GetModuleHandleW(std::ptr::null()) };
+
+    let mut class: WNDCLASSW = unsafe { std::mem::zeroed() };
+    class.lpfnWndProc = Some(window_proc);
+    class.hInstance = hinstance;
+    class.lpszClassName = class_name.as_ptr();
+    if unsafe { RegisterClassW(&class) } == 0 {
+        return Err(io::Error::last_os_error()).wrap_err("Failed to register tray window class");
+    }
+
+    // The window is never shown, it only exists to receive messages
+    let hwnd = unsafe {
+        CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            class_name.as_ptr(),
+            WS_OVERLAPPEDWINDOW,
+            CW_USEDEFAULT,
+            0,
+            CW_USEDEFAULT,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            hinstance,
+            std::ptr::null_mut(),
+        )
+    };
+    if hwnd.is_null() {
+        return Err(io::Error::last_os_error()).wrap_err("Failed to create tray window");
+    }
+    Ok(hwnd)
+}
+
+fn setup(tooltip: &str, icon: &Path, menu: Menu) -> Result<TrayHandle> {
+    use winapi::um::{
+        shellapi::{Shell_NotifyIconW, NIM_ADD},
+        winuser::RegisterWindowMessageW,
+    };
+
+    let hwnd = create_window()?;
+    let handle = TrayHandle {
+        hwnd: hwnd as usize,
+        commands: Arc::default(),
+    };
+
+    let mut tooltips = Coalescer::new(TOOLTIP_INTERVAL);
+    tooltips.push(tooltip.to_owned(), true, Instant::now());
+
+    let mut items = HashMap::new();
+    let state = TrayState {
+        handle: handle.clone(),
+        hmenu: build_menu(menu, &mut items)?,
+        icon: load_icon(icon)?,
+        tooltip: tooltip.to_owned(),
+        tooltips,
+        items,
+        taskbar_created: unsafe { RegisterWindowMessageW(to_wide("TaskbarCreated").as_ptr()) },
+    };
+
+    let mut nid = notify_icon_data(&state);
+    if unsafe { Shell_NotifyIconW(NIM_ADD, &mut nid) } == 0 {
+        return Err(format_err!("Failed to add tray icon"));
+    }
+
+    STATE.with(|cell| *cell.borrow_mut() = Some(state));
+    Ok(handle)
+}
+
+/// Show the tray icon with the given menu on a new thread, which runs until [`TrayHandle::quit`] is called.
+pub fn spawn(tooltip: &str, icon: &Path, menu: Menu) -> Result<(TrayHandle, std::thread::JoinHandle<Result<()>>)> {
+    use winapi::um::winuser::{DispatchMessageW, GetMessageW, TranslateMessage, MSG};
+
+    let (tx, rx) = sync_channel(1);
+    let (tooltip, icon) = (tooltip.to_owned(), icon.to_owned());
+
+    let thread = std::thread::Builder::new()
+        .name("systray".to_owned())
+        .spawn(move || -> Result<()> {
+            match setup(&tooltip, &icon, menu) {
+                Ok(handle) => {
+                    let _ = tx.send(Ok(handle));
+                }
+                Err(error) => {
+                    let _ = tx.send(Err(error));
+                    return Ok(());
+                }
+            }
+
+            let mut msg: MSG = unsafe { std::mem::zeroed() };
+            loop {
+                match unsafe { GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) } {
+                    0 => break,
+                    -1 => return Err(io::Error::last_os_error()).wrap_err("Failed to get message"),
+                    _ => unsafe {
+                        TranslateMessage(&msg);
+                        DispatchMessageW(&msg);
+                    },
+                }
+            }
+
+            STATE.with(|state| state.borrow_mut().take());
+            Ok(())
+        })?;
+
+    let handle = rx.recv().wrap_err("Tray thread died during setup")??;
+    Ok((handle, thread))
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use eyre::{Result, WrapErr};
+use futures::prelude::*;
+use reqwest::Client;
+use semver::Version;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{db, utils::with_backoff};
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/PurpleMyst/redditbg.rs/releases/latest";
+
+// How long we wait between update checks, even across restarts
+const CHECK_INTERVAL: Duration = Duration::from_secs(20 * 60 * 60);
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+}
+
+/// A release newer than the running version
+#[derive(Debug)]
+pub struct Update {
+    pub version: Version,
+    pub url: String,
+}
+
+/// Record that we're checking for updates now, returning whether it's been long enough since the last check.
+fn check_due() -> Result<bool> {
+    let db = db::open()?;
+
+    let due: bool = db.query_row(
+        "SELECT COALESCE(MAX(timestamp), 0) <= CAST(strftime('%s', 'now') AS INTEGER) - ? FROM UpdateChecks",
+        [CHECK_INTERVAL.as_secs()],
+        |row| row.get(0),
+    )?;
+    if due {
+        db.execute(
+            "INSERT INTO UpdateChecks(timestamp) VALUES (CAST(strftime('%s', 'now') AS INTEGER))",
+            [],
+        )?;
+    }
+    Ok(due)
+}
+
+async fn try_check(client: &Client) -> Result<Option<Update>> {
+    if !check_due()? {
+        debug!("checked for updates recently, skipping");
+        return Ok(None);
+    }
+
+    let release: Release = with_backoff(|| {
+        client
+            .get(LATEST_RELEASE_URL)
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .and_then(reqwest::Response::json)
+    })
+    .await
+    .wrap_err("Could not fetch latest release")?;
+
+    let version = Version::parse(release.tag_name.trim_start_matches('v'))
+        .wrap_err_with(|| format!("Invalid release tag {:?}", release.tag_name))?;
+    let current = Version::parse(env!("CARGO_PKG_VERSION"))?;
+    debug!(%version, %current, "got latest release");
+
+    Ok((version > current).then_some(Update {
+        version,
+        url: release.html_url,
+    }))
+}
+
+/// Check GitHub for a newer release, at most once every `CHECK_INTERVAL`.
+///
+/// This is a nicety, so any failure is only logged at debug level.
+pub async fn check(client: &Client) -> Option<Update> {
+    match try_check(client).await {
+        Ok(update) => update,
+        Err(error) => {
+            debug!(?error, "could not check for updates");
+            None
+        }
+    }
+}
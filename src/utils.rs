@@ -1,5 +1,6 @@
 use std::{
     fmt::{Debug, Display},
+    path::PathBuf,
     time::Duration,
 };
 
@@ -7,11 +8,14 @@ use exponential_backoff::Backoff;
 use eyre::Result;
 use futures::Future;
 use futures_retry::{ErrorHandler, FutureRetry, RetryPolicy};
-use rusqlite::{params, OptionalExtension};
 use tokio::sync::OnceCell;
-use tracing::{debug, error, trace};
+use tracing::{debug, error, info, trace, warn};
 
-use crate::DIRS;
+use crate::{
+    db::{self, BandwidthRepo, DeadPostsRepo, MetadataRepo, StoredFile, VisitedRepo},
+    fetcher,
+    processing::Orientation,
+};
 
 pub struct BackoffPolicy<'a>(pub exponential_backoff::Iter<'a>);
 
@@ -33,57 +37,231 @@ pub fn report_ie(ie: deadpool_sqlite::InteractError) -> eyre::Report {
 
 static DB_POOL: OnceCell<deadpool_sqlite::Pool> = OnceCell::const_new();
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct PersistentSet {
-    name: &'static str,
+    name: String,
+}
+
+async fn init_db_pool() -> Result<&'static deadpool_sqlite::Pool> {
+    DB_POOL
+        .get_or_try_init(|| async {
+            let cfg = deadpool_sqlite::Config::new(db::path());
+            let pool = cfg.builder(deadpool_sqlite::Runtime::Tokio1)?.build()?;
+            pool.get().await?.interact(db::migrate).await.map_err(report_ie)??;
+            Ok::<_, eyre::Report>(pool)
+        })
+        .await
+}
+
+/// Build the connection pool ahead of time, so that the first cycle doesn't have to wait on it.
+pub async fn warm_up_db() -> Result<()> {
+    let conn = init_db_pool().await?.get().await?;
+    drop(conn);
+    Ok(())
 }
 
 impl PersistentSet {
-    pub async fn new(name: &'static str) -> Result<Self> {
-        let _pool = DB_POOL
-            .get_or_try_init(|| async {
-                let cfg = deadpool_sqlite::Config::new(DIRS.data_local_dir().join("db.sqlite3"));
-                let pool = cfg.builder(deadpool_sqlite::Runtime::Tokio1)?.build()?;
-                pool.get()
-                    .await?
-                    .interact(|conn| conn.execute_batch(include_str!("persistent_set.sql")))
-                    .await
-                    .map_err(report_ie)??;
-                Ok::<_, eyre::Report>(pool)
-            })
-            .await?;
-        Ok(Self { name })
+    pub async fn new(name: impl Into<String>) -> Result<Self> {
+        init_db_pool().await?;
+        Ok(Self { name: name.into() })
     }
 
     pub async fn insert(&self, url: String) -> Result<()> {
         trace!(?self, ?url, "inserting into persistent set");
-        let name = self.name; // so that the closure is able to Copy the static str into it
+        let name = self.name.clone(); // so that the closure is able to own it
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| VisitedRepo::new(conn).insert(&name, &url))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    /// Insert every url in one transaction, which is much cheaper than separate inserts on slow disks.
+    pub async fn insert_many(&self, urls: Vec<String>) -> Result<()> {
+        trace!(?self, count = urls.len(), "inserting many into persistent set");
+        if urls.is_empty() {
+            return Ok(());
+        }
+
+        let name = self.name.clone();
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| VisitedRepo::new(conn).insert_many(&name, &urls))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    pub async fn contains(&self, url: String) -> Result<bool> {
+        trace!(?self, ?url, "checking persistent set");
+        let name = self.name.clone();
+        let conn = DB_POOL.get().unwrap().get().await?;
+        Ok(conn
+            .interact(move |conn| VisitedRepo::new(conn).contains(&name, &url))
+            .await
+            .map_err(report_ie)??)
+    }
+
+    /// How many urls were added to the set today, local time.
+    pub async fn count_today(&self) -> Result<usize> {
+        let name = self.name.clone();
+        let conn = DB_POOL.get().unwrap().get().await?;
+        Ok(conn
+            .interact(move |conn| VisitedRepo::new(conn).count_today(&name))
+            .await
+            .map_err(report_ie)??)
+    }
+}
+
+/// Information about downloaded images that we can't get from the stored files themselves
+#[derive(Clone, Copy, Debug)]
+pub struct ImageMetadata;
+
+impl ImageMetadata {
+    pub async fn new() -> Result<Self> {
+        init_db_pool().await?;
+        Ok(Self)
+    }
+
+    /// Record the original dimensions of the image downloaded from `url`, before any resizing.
+    pub async fn insert_dimensions(&self, url: String, width: u32, height: u32) -> Result<()> {
+        trace!(?url, width, height, "recording image dimensions");
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| MetadataRepo::new(conn).insert_dimensions(&url, width, height))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    /// Record which subreddit the image downloaded from `url` was posted in, and the title, link and score of the post.
+    pub async fn insert_source(
+        &self,
+        url: String,
+        subreddit: String,
+        title: String,
+        permalink: Option<String>,
+        score: i64,
+    ) -> Result<()> {
+        trace!(?url, ?subreddit, ?title, ?permalink, score, "recording image source");
         let conn = DB_POOL.get().unwrap().get().await?;
         conn.interact(move |conn| {
-            conn.execute(
-                "INSERT OR IGNORE INTO PersistentSets(name, url) VALUES (?, ?)",
-                params![name, url],
-            )
+            MetadataRepo::new(conn).insert_source(&url, &subreddit, &title, permalink.as_deref(), score)
         })
         .await
         .map_err(report_ie)??;
         Ok(())
     }
 
-    pub async fn contains(&self, url: String) -> Result<bool> {
-        trace!(?self, ?url, "checking persistent set");
-        let name = self.name;
+    /// Record that the image downloaded from `url` is cached under the given filename.
+    pub async fn insert_file(&self, filename: String, url: String) -> Result<()> {
+        trace!(?filename, ?url, "recording cached file");
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| MetadataRepo::new(conn).insert_file(&filename, &url))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    /// Record what the file cached under the given filename holds.
+    pub async fn insert_stored(&self, filename: String, url: String, stored: StoredFile) -> Result<()> {
+        trace!(?filename, ?stored, "recording stored file");
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| MetadataRepo::new(conn).insert_stored(&filename, &url, stored))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    /// Recover the url the cached image at `path` was downloaded from.
+    pub async fn url_for_file(&self, path: PathBuf) -> Result<Option<String>> {
+        let conn = DB_POOL.get().unwrap().get().await?;
+        Ok(conn
+            .interact(move |conn| fetcher::url_for_file(&MetadataRepo::new(conn), &path))
+            .await
+            .map_err(report_ie)??)
+    }
+
+    /// Get the orientation of the monitor the cached image at `path` is meant for, if we can tell.
+    pub async fn orientation_of_file(&self, path: PathBuf) -> Result<Option<Orientation>> {
+        let conn = DB_POOL.get().unwrap().get().await?;
+        Ok(conn
+            .interact(move |conn| fetcher::orientation_of_file(&MetadataRepo::new(conn), &path))
+            .await
+            .map_err(report_ie)??)
+    }
+
+    /// Record the dimensions of the image with the given body, as they were when we checked its aspect ratio.
+    pub async fn insert_probed(&self, body_hash: u64, width: u32, height: u32) -> Result<()> {
+        trace!(body_hash, width, height, "recording probed dimensions");
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| MetadataRepo::new(conn).insert_probed(body_hash, width, height))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    /// Get the dimensions we recorded for an image with the given body, if we've seen identical bytes before.
+    pub async fn probed(&self, body_hash: u64) -> Result<Option<(u32, u32)>> {
+        let conn = DB_POOL.get().unwrap().get().await?;
+        Ok(conn
+            .interact(move |conn| MetadataRepo::new(conn).probed(body_hash))
+            .await
+            .map_err(report_ie)??)
+    }
+}
+
+/// How many bytes we've downloaded each (local) day
+#[derive(Clone, Copy, Debug)]
+pub struct Bandwidth;
+
+impl Bandwidth {
+    pub async fn new() -> Result<Self> {
+        init_db_pool().await?;
+        Ok(Self)
+    }
+
+    /// Add `bytes` to today's total.
+    pub async fn record(&self, bytes: u64) -> Result<()> {
+        trace!(bytes, "recording bandwidth");
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| BandwidthRepo::new(conn).record(bytes))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    /// Get today's date along with how many bytes we've downloaded so far today.
+    pub async fn today(&self) -> Result<(String, u64)> {
         let conn = DB_POOL.get().unwrap().get().await?;
         Ok(conn
-            .interact(move |conn| {
-                conn.query_row(
-                    "SELECT rowid FROM PersistentSets WHERE name = ? AND url = ?",
-                    params![name, url],
-                    |_| Ok(()),
-                )
-                .optional()
-                .map(|o| o.is_some())
-            })
+            .interact(|conn| BandwidthRepo::new(conn).today())
+            .await
+            .map_err(report_ie)??)
+    }
+}
+
+/// Posts whose image was deleted, so that we can skip them without looking at their links
+#[derive(Clone, Copy, Debug)]
+pub struct DeadPosts;
+
+impl DeadPosts {
+    pub async fn new() -> Result<Self> {
+        init_db_pool().await?;
+        Ok(Self)
+    }
+
+    pub async fn insert(&self, id: String) -> Result<()> {
+        trace!(?id, "recording dead post");
+        let conn = DB_POOL.get().unwrap().get().await?;
+        conn.interact(move |conn| DeadPostsRepo::new(conn).insert(&id))
+            .await
+            .map_err(report_ie)??;
+        Ok(())
+    }
+
+    pub async fn contains(&self, id: String) -> Result<bool> {
+        let conn = DB_POOL.get().unwrap().get().await?;
+        Ok(conn
+            .interact(move |conn| DeadPostsRepo::new(conn).contains(&id))
             .await
             .map_err(report_ie)??)
     }
@@ -105,6 +283,189 @@ where
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+#[error("No internet connection")]
+pub struct NoInternet;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Daily download budget exhausted")]
+pub struct BudgetExhausted;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Low on disk space")]
+pub struct LowDiskSpace;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Storage unavailable")]
+pub struct StorageUnavailable;
+
+/// Whether an I/O error means the drive holding our data isn't there right now, e.g. because it's been unplugged or
+/// is asleep.
+pub fn is_storage_unavailable(error: &std::io::Error) -> bool {
+    // ERROR_NOT_READY and ERROR_DEV_NOT_EXIST
+    error.kind() == std::io::ErrorKind::NotFound || matches!(error.raw_os_error(), Some(21 | 55))
+}
+
+/// Make sure the directory some of our data lives in can be reached, as it may have been moved to a drive that comes
+/// and goes.
+pub fn check_storage(dir: &std::path::Path) -> Result<()> {
+    match std::fs::metadata(dir) {
+        Ok(_) => Ok(()),
+        Err(error) if is_storage_unavailable(&error) => Err(eyre::Report::new(error).wrap_err(StorageUnavailable)),
+        Err(error) => Err(error.into()),
+    }
+}
+
+// How long we trust the result of a connectivity probe for
+const PROBE_TTL: Duration = Duration::from_secs(60);
+
+static LAST_PROBE: std::sync::Mutex<Option<(std::time::Instant, bool)>> = std::sync::Mutex::new(None);
+
+/// Check whether we can reach Reddit at all, so that we don't waste minutes on retries when offline.
+///
+/// The result is cached for a minute so that repeatedly changing the background doesn't probe every time.
+pub async fn is_online(client: &reqwest::Client) -> bool {
+    if let Some((at, online)) = *LAST_PROBE.lock().unwrap() {
+        if at.elapsed() < PROBE_TTL {
+            trace!(online, "using cached connectivity probe");
+            return online;
+        }
+    }
+
+    // Any response at all, even an error status, means we've got a connection
+    let online = match client
+        .head("https://www.reddit.com")
+        .timeout(Duration::from_secs(3))
+        .send()
+        .await
+    {
+        Ok(_) => true,
+        Err(error) => {
+            debug!(?error, "connectivity probe failed");
+            false
+        }
+    };
+
+    *LAST_PROBE.lock().unwrap() = Some((std::time::Instant::now(), online));
+    online
+}
+
+/// Format a duration roughly, using only its largest unit (e.g. "3h" or "12m").
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Format a number of bytes with a decimal unit (e.g. "12.3 MB").
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+struct Task {
+    name: &'static str,
+    handle: tokio::task::AbortHandle,
+    // Blocking tasks can't be aborted, only waited on
+    abortable: bool,
+}
+
+/// Keeps track of the tasks we spawn so that we can tell what was still running when we shut down.
+#[derive(Default)]
+pub struct TaskRegistry {
+    tasks: std::sync::Mutex<Vec<Task>>,
+}
+
+pub static TASKS: once_cell::sync::Lazy<TaskRegistry> = once_cell::sync::Lazy::new(TaskRegistry::default);
+
+impl TaskRegistry {
+    fn register(&self, name: &'static str, handle: tokio::task::AbortHandle, abortable: bool) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.handle.is_finished());
+        tasks.push(Task {
+            name,
+            handle,
+            abortable,
+        });
+    }
+
+    /// Spawn a named task on the current runtime.
+    pub fn spawn<F>(&self, name: &'static str, future: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let handle = tokio::spawn(future);
+        self.register(name, handle.abort_handle(), true);
+        handle
+    }
+
+    /// Spawn a named blocking task on the current runtime.
+    pub fn spawn_blocking<F, R>(&self, name: &'static str, f: F) -> tokio::task::JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let handle = tokio::task::spawn_blocking(f);
+        self.register(name, handle.abort_handle(), false);
+        handle
+    }
+
+    /// Abort every task that can be aborted and wait up to `timeout` for the rest to finish, logging what's left.
+    ///
+    /// Returns the names of the tasks that didn't finish in time.
+    pub async fn shutdown(&self, timeout: Duration) -> Vec<&'static str> {
+        let pending = {
+            let mut tasks = self.tasks.lock().unwrap();
+            tasks.retain(|task| !task.handle.is_finished());
+            std::mem::take(&mut *tasks)
+        };
+
+        if pending.is_empty() {
+            debug!("no tasks pending at shutdown");
+            return Vec::new();
+        }
+
+        let names = pending.iter().map(|task| task.name).collect::<Vec<_>>();
+        info!(?names, "tasks pending at shutdown");
+
+        for task in pending.iter().filter(|task| task.abortable) {
+            trace!(name = task.name, "aborting task");
+            task.handle.abort();
+        }
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while pending.iter().any(|task| !task.handle.is_finished()) && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let unfinished = pending
+            .iter()
+            .filter(|task| !task.handle.is_finished())
+            .map(|task| task.name)
+            .collect::<Vec<_>>();
+        if !unfinished.is_empty() {
+            warn!(?unfinished, "tasks did not finish before shutdown timeout");
+        }
+        unfinished
+    }
+}
+
 pub struct JoinOnDrop {
     handle: Option<std::thread::JoinHandle<Result<()>>>,
 }
@@ -138,3 +499,36 @@ impl Drop for JoinOnDrop {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tasks_still_running_at_shutdown_are_reported_and_finished_ones_forgotten() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let registry = TaskRegistry::default();
+        let names = |registry: &TaskRegistry| {
+            registry
+                .tasks
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|task| task.name)
+                .collect::<Vec<_>>()
+        };
+
+        runtime.block_on(async {
+            registry.spawn("quick", async {}).await.unwrap();
+            registry.spawn("slow", tokio::time::sleep(Duration::from_secs(60)));
+            registry.spawn_blocking("slow write", || std::thread::sleep(Duration::from_millis(200)));
+            registry.spawn_blocking("stuck write", || std::thread::sleep(Duration::from_secs(2)));
+        });
+        assert_eq!(names(&registry), ["slow", "slow write", "stuck write"]);
+
+        // Slow tasks are aborted and blocking ones waited on, for as long as we're willing to
+        let unfinished = runtime.block_on(registry.shutdown(Duration::from_secs(1)));
+        assert_eq!(unfinished, ["stuck write"]);
+        assert!(names(&registry).is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// What to do when another program changes the background behind our back
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForeignWallpaperPolicy {
+    /// Don't even check
+    #[default]
+    Ignore,
+
+    /// Put our background back up
+    Reapply,
+
+    /// Leave the other program's background up until our next change is due, counting from when we noticed it
+    Adopt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Nothing,
+    Reapply,
+    Adopt,
+}
+
+/// Compare paths the way Windows does, i.e. ignoring case and the kind of slashes used.
+fn same_path(a: &Path, b: &Path) -> bool {
+    let normalize = |path: &Path| path.to_string_lossy().replace('/', "\\").to_lowercase();
+    normalize(a) == normalize(b)
+}
+
+/// Whether the background that's up (`observed`) replaced ours (`expected`) because a group policy forces it
+/// (`enforced`), in which case there's no point in changing it.
+pub fn is_policy_conflict(observed: &Path, expected: &Path, enforced: Option<&Path>) -> bool {
+    !same_path(observed, expected) && enforced.is_some_and(|enforced| same_path(observed, enforced))
+}
+
+/// Decide what to do given the background that's up (`observed`) and the one we expect to be up (`expected`).
+pub fn decide(policy: ForeignWallpaperPolicy, observed: &Path, expected: &Path) -> Action {
+    if same_path(observed, expected) {
+        return Action::Nothing;
+    }
+
+    match policy {
+        ForeignWallpaperPolicy::Ignore => Action::Nothing,
+        ForeignWallpaperPolicy::Reapply => Action::Reapply,
+        ForeignWallpaperPolicy::Adopt => Action::Adopt,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_only_conflict_when_theirs_replaced_ours() {
+        let (ours, theirs) = (
+            Path::new(r"C:\Cache\background.png"),
+            Path::new(r"C:\Corp\wallpaper.jpg"),
+        );
+
+        assert!(is_policy_conflict(theirs, ours, Some(theirs)));
+        // Windows doesn't care about case or which way the slashes go, so neither do we
+        assert!(is_policy_conflict(
+            Path::new("c:/corp/WALLPAPER.JPG"),
+            ours,
+            Some(theirs)
+        ));
+        // Ours is up, so the policy isn't getting in the way
+        assert!(!is_policy_conflict(ours, ours, Some(theirs)));
+        // Someone else's is up, but not because of a policy
+        assert!(!is_policy_conflict(theirs, ours, None));
+        assert!(!is_policy_conflict(
+            Path::new(r"C:\Other\image.png"),
+            ours,
+            Some(theirs)
+        ));
+    }
+
+    #[test]
+    fn only_foreign_backgrounds_call_for_action() {
+        let (ours, theirs) = (Path::new(r"C:\Cache\background.png"), Path::new(r"C:\Other\image.png"));
+
+        for policy in [
+            ForeignWallpaperPolicy::Ignore,
+            ForeignWallpaperPolicy::Reapply,
+            ForeignWallpaperPolicy::Adopt,
+        ] {
+            assert_eq!(
+                decide(policy, Path::new(r"c:\cache\BACKGROUND.png"), ours),
+                Action::Nothing
+            );
+        }
+        assert_eq!(decide(ForeignWallpaperPolicy::Ignore, theirs, ours), Action::Nothing);
+        assert_eq!(decide(ForeignWallpaperPolicy::Reapply, theirs, ours), Action::Reapply);
+        assert_eq!(decide(ForeignWallpaperPolicy::Adopt, theirs, ours), Action::Adopt);
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+
+use eyre::{Result, WrapErr};
+use reqwest::Client;
+
+use crate::{
+    config::Config,
+    db::{self, AppliedImagesRepo, VisitedRepo},
+    fetcher,
+    policy::ImagePolicy,
+    reddit,
+    sources::RatioRule,
+};
+
+/// What we'd make of the image at the URL if we fetched it right now
+#[derive(Debug)]
+pub enum Verdict {
+    Accepted { dimensions: (u32, u32) },
+    Rejected(String),
+    DownloadFailed(String),
+}
+
+/// Everything we know about why a URL did or didn't end up as a background
+#[derive(Debug)]
+pub struct Report {
+    pub url: String,
+    /// The URL as we'd request it, if that differs from what was given
+    pub canonical: Option<String>,
+    /// The persistent sets the URL is in, along with when it was added to them
+    pub sets: Vec<(String, String)>,
+    pub verdict: Verdict,
+    /// When an image with the same hash was applied, if ever
+    pub applied: Option<Option<String>>,
+}
+
+impl Report {
+    /// Look into a URL, downloading and evaluating it without touching the cache or the database.
+    pub async fn gather(client: &Client, config: &Config, url: &str) -> Result<Self> {
+        let canonical = Some(reddit::unescape_url(url)).filter(|canonical| canonical != url);
+        let url = canonical.as_deref().unwrap_or(url);
+
+        let db = db::open_read_only()?;
+
+        // The tables may not exist yet, in which case there's nothing to find in them
+        let sets = match db {
+            Some(ref db) => VisitedRepo::new(db).memberships(url).unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        let body = client
+            .get(url)
+            .header("Accept", "image/*")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let body = match body {
+            Ok(response) => response.bytes().await,
+            Err(error) => Err(error),
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(error) => {
+                return Ok(Self {
+                    url: url.to_owned(),
+                    canonical,
+                    sets,
+                    verdict: Verdict::DownloadFailed(error.to_string()),
+                    applied: None,
+                })
+            }
+        };
+
+        let (verdict, applied) = match fetcher::evaluate(
+            config,
+            &ImagePolicy::current()?.configure(config),
+            RatioRule::default(),
+            &body,
+        ) {
+            Ok(evaluated) => {
+                // The picker hashes the image as stored, so resize it just like the fetcher would
+                let hash = image_hasher::HasherConfig::new()
+                    .to_hasher()
+                    .hash_image(&evaluated.scaled());
+                let applied = match db {
+                    Some(ref db) => applied_at(db, hash.as_bytes()),
+                    None => None,
+                };
+                (
+                    Verdict::Accepted {
+                        dimensions: evaluated.dimensions,
+                    },
+                    applied,
+                )
+            }
+            Err(error) => (Verdict::Rejected(format!("{error:#}")), None),
+        };
+
+        Ok(Self {
+            url: url.to_owned(),
+            canonical,
+            sets,
+            verdict,
+            applied,
+        })
+    }
+}
+
+/// Find out whether an image with the given hash was applied and when, if we know.
+fn applied_at(db: &rusqlite::Connection, hash: &[u8]) -> Option<Option<String>> {
+    let applied = AppliedImagesRepo::new(db);
+    if !applied.contains(hash).unwrap_or(false) {
+        return None;
+    }
+
+    // Images applied before we kept a history have no timestamp
+    Some(applied.last_applied(hash).unwrap_or(None))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "URL: {}", self.url)?;
+        if let Some(ref canonical) = self.canonical {
+            writeln!(f, "  we request it as {canonical}")?;
+        }
+
+        if self.sets.is_empty() {
+            writeln!(f, "Never examined before")?;
+        }
+        for (name, timestamp) in &self.sets {
+            match name.as_str() {
+                // We don't record why, but the verdict below should tell
+                "invalid" => writeln!(f, "Marked invalid on {timestamp}, and won't be retried")?,
+                name => writeln!(f, "In {name:?} since {timestamp}, and won't be downloaded again")?,
+            }
+        }
+
+        match self.verdict {
+            Verdict::Accepted {
+                dimensions: (width, height),
+            } => writeln!(f, "Would be accepted now ({width}x{height})")?,
+            Verdict::Rejected(ref reason) => writeln!(f, "Would be rejected now: {reason}")?,
+            Verdict::DownloadFailed(ref reason) => writeln!(f, "Could not download it: {reason}")?,
+        }
+
+        match self.applied {
+            Some(Some(ref timestamp)) => writeln!(f, "An identical image was applied on {timestamp}")?,
+            Some(None) => writeln!(f, "An identical image was applied before")?,
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Print a report on why the given URL did or didn't end up as a background.
+pub fn run(config: &Config, client: &Client, url: &str) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime
+        .block_on(Report::gather(client, config, url))
+        .wrap_err("Could not gather report")?;
+    print!("{report}");
+    Ok(())
+}